@@ -0,0 +1,187 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+
+use super::rpc;
+use super::session::Session;
+use super::token_matches;
+
+/*
+ *   The API over HTTP/1.1: JSON-RPC requests are POSTed to / and events stream from /events as
+ *   server-sent events. Every request needs an `Authorization: Bearer <token>` header. There is
+ *   one request per connection, so no keep-alive.
+ */
+
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+// Comments sent on quiet event streams, so a client that went away is noticed
+const EVENTS_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+pub async fn serve(listener: TcpListener, session: Arc<Session>, token: Arc<String>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(_) => continue,
+        };
+        let session = Arc::clone(&session);
+        let token = Arc::clone(&token);
+        tokio::spawn(async move {
+            let _ = handle_connection(stream, &session, &token).await;
+        });
+    }
+}
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    authorization: Option<String>,
+    body: Vec<u8>,
+}
+
+// Reads no further than the longest head allows, so a line without an end can't fill memory
+async fn next_line(
+    stream: &mut BufReader<TcpStream>,
+    line: &mut String,
+    head_size: &mut usize,
+) -> anyhow::Result<()> {
+    line.clear();
+    let left = MAX_HEAD_SIZE - *head_size;
+    let read = (&mut *stream).take(left as u64).read_line(line).await?;
+    *head_size += read;
+    if !line.ends_with('\n') {
+        anyhow::ensure!(*head_size < MAX_HEAD_SIZE, "Request head is too long");
+        anyhow::bail!("Connection closed");
+    }
+    Ok(())
+}
+
+async fn read_request(stream: &mut BufReader<TcpStream>) -> anyhow::Result<HttpRequest> {
+    let mut head_size = 0;
+    let mut line = String::new();
+    next_line(stream, &mut line, &mut head_size).await?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+
+    let mut authorization = None;
+    let mut content_length = 0;
+    loop {
+        next_line(stream, &mut line, &mut head_size).await?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let (name, value) = header
+            .split_once(':')
+            .ok_or(anyhow::anyhow!("Wrong header {}", header))?;
+        if name.eq_ignore_ascii_case("authorization") {
+            authorization = Some(value.trim().to_string());
+        } else if name.eq_ignore_ascii_case("content-length") {
+            content_length = value.trim().parse()?;
+        }
+    }
+
+    anyhow::ensure!(content_length <= MAX_BODY_SIZE, "Request body is too long");
+    let mut body = vec![0; content_length];
+    stream.read_exact(&mut body).await?;
+    Ok(HttpRequest {
+        method,
+        path,
+        authorization,
+        body,
+    })
+}
+
+fn http_response(status: &str, extra_headers: &str, body: &[u8]) -> Vec<u8> {
+    let mut response = format!(
+        "HTTP/1.1 {}\r\n{}Content-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        status,
+        extra_headers,
+        body.len()
+    )
+    .into_bytes();
+    response.extend_from_slice(body);
+    response
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    session: &Arc<Session>,
+    token: &str,
+) -> anyhow::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let request = tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await??;
+    let mut stream = stream.into_inner();
+
+    let authorized = request
+        .authorization
+        .as_deref()
+        .and_then(|authorization| authorization.strip_prefix("Bearer "))
+        .is_some_and(|given| token_matches(given.trim(), token));
+    if !authorized {
+        let response = http_response(
+            "401 Unauthorized",
+            "WWW-Authenticate: Bearer\r\n",
+            br#"{"error":"A valid token is needed"}"#,
+        );
+        return Ok(stream.write_all(&response).await?);
+    }
+
+    let response = match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/events") => return stream_events(stream, session).await,
+        ("POST", "/") | ("POST", "/rpc") => {
+            let response = match std::str::from_utf8(&request.body) {
+                Ok(body) => match rpc::parse_request(body) {
+                    Ok(request) => rpc::call(session, request).await,
+                    Err(response) => Some(response),
+                },
+                Err(err) => Some(rpc::error_response(
+                    serde_json::Value::Null,
+                    rpc::PARSE_ERROR,
+                    &err.to_string(),
+                )),
+            };
+            match response {
+                Some(response) => http_response("200 OK", "", response.to_string().as_bytes()),
+                // A notification
+                None => http_response("204 No Content", "", b""),
+            }
+        }
+        (_, "/") | (_, "/rpc") | (_, "/events") => http_response("405 Method Not Allowed", "", b""),
+        _ => http_response("404 Not Found", "", b""),
+    };
+    stream.write_all(&response).await?;
+    Ok(())
+}
+
+async fn stream_events(mut stream: TcpStream, session: &Session) -> anyhow::Result<()> {
+    let mut events = session.subscribe();
+    stream
+        .write_all(
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\
+              Connection: close\r\n\r\n",
+        )
+        .await?;
+    // The first tick of an interval is at once, the stream has only just started
+    let mut keep_alive = tokio::time::interval_at(
+        tokio::time::Instant::now() + EVENTS_KEEP_ALIVE,
+        EVENTS_KEEP_ALIVE,
+    );
+    loop {
+        let message = tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => format!("data: {}\n\n", event),
+                // Too slow, the missed events are gone
+                Err(RecvError::Lagged(missed)) => format!(": {} events missed\n\n", missed),
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            _ = keep_alive.tick() => ": keep-alive\n\n".to_string(),
+        };
+        stream.write_all(message.as_bytes()).await?;
+    }
+}
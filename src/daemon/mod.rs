@@ -0,0 +1,438 @@
+mod http;
+mod rpc;
+mod session;
+mod unix;
+
+use std::io::Write;
+use std::net::SocketAddr;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tokio::net::{TcpListener, UnixListener};
+
+pub use session::Session;
+
+/*
+ *   A long-running process hosting a session, managed through a JSON-RPC API over HTTP and a
+ *   Unix socket (see rpc.rs for the calls). Both need the daemon's token: a random one is made
+ *   and kept in the state directory unless one is given.
+ */
+
+pub const DEFAULT_HTTP_ADDRESS: &str = "127.0.0.1:7879";
+
+pub struct DaemonOptions {
+    pub http: Option<SocketAddr>,
+    pub socket: Option<PathBuf>,
+    pub token: String,
+}
+
+// Serves the API until it fails
+pub async fn run(session: Arc<Session>, options: DaemonOptions) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        options.http.is_some() || options.socket.is_some(),
+        "The daemon needs an HTTP address or a Unix socket to listen on"
+    );
+    let token = Arc::new(options.token);
+    let mut servers = tokio::task::JoinSet::new();
+    if let Some(address) = options.http {
+        let listener = TcpListener::bind(address).await?;
+        println!("API listening on http://{}", listener.local_addr()?);
+        servers.spawn(http::serve(
+            listener,
+            Arc::clone(&session),
+            Arc::clone(&token),
+        ));
+    }
+    if let Some(path) = &options.socket {
+        // Left over from a daemon that didn't stop cleanly
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        println!("API listening on {}", path.display());
+        servers.spawn(unix::serve(
+            listener,
+            Arc::clone(&session),
+            Arc::clone(&token),
+        ));
+    }
+    servers.join_next().await;
+    anyhow::bail!("The API stopped")
+}
+
+// The token kept in `state_dir`, made on first use. Only the owner can read it.
+pub fn token(state_dir: &Path) -> anyhow::Result<String> {
+    let path = state_dir.join("token");
+    if let Ok(token) = std::fs::read_to_string(&path) {
+        return Ok(token.trim().to_string());
+    }
+    std::fs::create_dir_all(state_dir)?;
+    let token: String = (0..16)
+        .map(|_| format!("{:02x}", rand::random::<u8>()))
+        .collect();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)?
+        .write_all(token.as_bytes())?;
+    println!("New API token saved to {}", path.display());
+    Ok(token)
+}
+
+// Takes as long for every wrong token of the right length, so it can't be guessed byte by byte
+fn token_matches(given: &str, token: &str) -> bool {
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |differences, (a, b)| differences | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::DownloadOptions;
+    use crate::torrent_file_handler::torrent_creator::{self, TorrentOptions};
+    use serde_json::{json, Value};
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpStream, UnixStream};
+
+    const TOKEN: &str = "secret";
+
+    // A private torrent of two files, so nothing is looked for on the network
+    fn make_torrent(dir: &Path) -> (PathBuf, String) {
+        let content = dir.join("content");
+        std::fs::create_dir_all(&content).unwrap();
+        std::fs::write(content.join("a"), vec![1; 50_000]).unwrap();
+        std::fs::write(content.join("b"), vec![2; 70_000]).unwrap();
+        let options = TorrentOptions {
+            private: true,
+            ..Default::default()
+        };
+        let (torrent, info_hash) = torrent_creator::create_torrent(&content, &options).unwrap();
+        let path = dir.join("content.torrent");
+        std::fs::write(&path, torrent).unwrap();
+        let id = info_hash
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        (path, id)
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "rusty_torrent_daemon_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    async fn post(addr: SocketAddr, token: &str, body: &Value) -> (String, Value) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let body = body.to_string();
+        let request = format!(
+            "POST / HTTP/1.1\r\nAuthorization: Bearer {}\r\nContent-Length: {}\r\n\r\n{}",
+            token,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.lines().next().unwrap().to_string();
+        (status, serde_json::from_str(body).unwrap_or(Value::Null))
+    }
+
+    async fn call(addr: SocketAddr, method: &str, params: Value) -> Value {
+        let request = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+        post(addr, TOKEN, &request).await.1
+    }
+
+    #[tokio::test]
+    async fn torrents_are_managed_over_http() {
+        let dir = test_dir("http");
+        let (torrent, id) = make_torrent(&dir);
+        let session = Session::start(DownloadOptions::default(), dir.join("state"))
+            .await
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(http::serve(
+            listener,
+            Arc::clone(&session),
+            Arc::new(TOKEN.to_string()),
+        ));
+
+        let list = json!({ "jsonrpc": "2.0", "id": 1, "method": "torrent.list" });
+        assert_eq!(
+            post(addr, "wrong", &list).await.0,
+            "HTTP/1.1 401 Unauthorized"
+        );
+
+        let mut events = TcpStream::connect(addr).await.unwrap();
+        events
+            .write_all(b"GET /events HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n")
+            .await
+            .unwrap();
+        let mut events = BufReader::new(events).lines();
+        while !events.next_line().await.unwrap().unwrap().is_empty() {}
+
+        let added = call(
+            addr,
+            "torrent.add",
+            json!({ "file": torrent, "paused": true }),
+        )
+        .await;
+        assert_eq!(added["result"]["id"], id);
+        let event = events.next_line().await.unwrap().unwrap();
+        let event: Value = serde_json::from_str(event.strip_prefix("data: ").unwrap()).unwrap();
+        assert_eq!(
+            event,
+            json!({ "event": "added", "id": id, "name": "content" })
+        );
+
+        let again = call(addr, "torrent.add", json!({ "file": torrent })).await;
+        assert_eq!(again["error"]["code"], rpc::CALL_FAILED);
+        let magnet = call(
+            addr,
+            "torrent.add",
+            json!({ "magnet": "magnet:?xt=urn:btih:00" }),
+        )
+        .await;
+        assert_eq!(magnet["error"]["code"], rpc::NOT_SUPPORTED);
+        assert!(magnet["error"]["message"]
+            .as_str()
+            .unwrap()
+            .contains("BEP 9"));
+        let unknown = call(addr, "torrent.start", json!({})).await;
+        assert_eq!(unknown["error"]["code"], rpc::METHOD_NOT_FOUND);
+        let missing = call(addr, "torrent.pause", json!({})).await;
+        assert_eq!(missing["error"]["code"], rpc::INVALID_PARAMS);
+
+        let changed = call(
+            addr,
+            "torrent.set_file_priority",
+            json!({ "id": id, "file": 1, "priority": "skip" }),
+        )
+        .await;
+        assert_eq!(changed["result"], Value::Null);
+        call(
+            addr,
+            "torrent.set_limits",
+            json!({ "id": id, "download": 1000 }),
+        )
+        .await;
+        call(addr, "session.set_limits", json!({ "peer_upload": 500 })).await;
+
+        let files = call(addr, "torrent.files", json!({ "id": id })).await;
+        assert_eq!(files["result"][0]["path"], "content/a");
+        assert_eq!(files["result"][1]["priority"], "skip");
+        let listed = call(addr, "torrent.list", json!({})).await;
+        assert_eq!(listed["result"][0]["state"], "paused");
+        assert_eq!(listed["result"][0]["limits"]["download"], 1000);
+        let stats = call(addr, "session.stats", json!({})).await;
+        assert_eq!(stats["result"]["torrents"], 1);
+        assert_eq!(stats["result"]["peer_limits"]["upload"], 500);
+
+        // A restarted daemon gets the same list back
+        let restarted = Session::start(DownloadOptions::default(), dir.join("state"))
+            .await
+            .unwrap();
+        assert_eq!(restarted.list()[0]["limits"]["download"], 1000);
+        assert_eq!(restarted.files(&id).unwrap()[1]["priority"], "skip");
+
+        let removed = call(
+            addr,
+            "torrent.remove",
+            json!({ "id": id, "delete_data": true }),
+        )
+        .await;
+        assert_eq!(removed["result"], Value::Null);
+        assert_eq!(
+            call(addr, "torrent.list", json!({})).await["result"],
+            json!([])
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    async fn send(stream: &mut UnixStream, method: &str, params: Value) {
+        let request = json!({ "jsonrpc": "2.0", "id": method, "method": method, "params": params });
+        let line = format!("{}\n", request);
+        stream.write_all(line.as_bytes()).await.unwrap();
+    }
+
+    async fn receive(lines: &mut BufReader<UnixStream>) -> Value {
+        let mut line = String::new();
+        lines.read_line(&mut line).await.unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[tokio::test]
+    async fn events_over_unix_socket() {
+        let dir = test_dir("unix");
+        let (torrent, id) = make_torrent(&dir);
+        let session = Session::start(DownloadOptions::default(), dir.join("state"))
+            .await
+            .unwrap();
+        let path = dir.join("api.sock");
+        tokio::spawn(unix::serve(
+            UnixListener::bind(&path).unwrap(),
+            Arc::clone(&session),
+            Arc::new(TOKEN.to_string()),
+        ));
+
+        let mut lines = BufReader::new(UnixStream::connect(&path).await.unwrap());
+
+        send(lines.get_mut(), "torrent.list", json!({})).await;
+        assert_eq!(
+            receive(&mut lines).await["error"]["code"],
+            rpc::UNAUTHORIZED
+        );
+        send(lines.get_mut(), "authenticate", json!({ "token": TOKEN })).await;
+        assert_eq!(receive(&mut lines).await["result"], Value::Null);
+        send(lines.get_mut(), "events.subscribe", json!({})).await;
+        receive(&mut lines).await;
+
+        // Every file skipped, the download fails at once
+        session.add_file(&torrent, true).unwrap();
+        for file in 0..2 {
+            let params = json!({ "id": id, "file": file, "priority": "skip" });
+            send(lines.get_mut(), "torrent.set_file_priority", params).await;
+        }
+        send(lines.get_mut(), "torrent.resume", json!({ "id": id })).await;
+
+        let mut events = Vec::new();
+        while events.last().map(|event: &Value| &event["event"]) != Some(&json!("failed")) {
+            let message = receive(&mut lines).await;
+            if message["method"] == "event" {
+                events.push(message["params"].clone());
+            } else {
+                assert_eq!(message["result"], Value::Null);
+            }
+        }
+        let names: Vec<_> = events.iter().map(|event| event["event"].clone()).collect();
+        assert_eq!(names, [json!("added"), json!("resumed"), json!("failed")]);
+        assert_eq!(events[2]["error"], "Every file is skipped");
+        assert_eq!(session.list()[0]["state"], "failed");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Serves `/torrent`, a file without info at `/other`, a body announced as too large at
+    // `/large` and one which turns out too large at `/endless`
+    async fn torrent_server(torrent: Vec<u8>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut lines = BufReader::new(stream);
+                let mut request_line = String::new();
+                lines.read_line(&mut request_line).await.unwrap();
+                let mut line = String::new();
+                while line != "\r\n" {
+                    line.clear();
+                    lines.read_line(&mut line).await.unwrap();
+                }
+                let (head, body) = match request_line.split(' ').nth(1).unwrap() {
+                    "/torrent" => (
+                        format!("Content-Length: {}\r\n", torrent.len()),
+                        torrent.clone(),
+                    ),
+                    "/other" => ("Content-Length: 10\r\n".to_string(), b"d3:fooi1ee".to_vec()),
+                    "/large" => ("Content-Length: 1000000000\r\n".to_string(), Vec::new()),
+                    _ => (String::new(), vec![b'd'; 9 * 1024 * 1024]),
+                };
+                let mut stream = lines.into_inner();
+                let head = format!("HTTP/1.1 200 OK\r\n{}Connection: close\r\n\r\n", head);
+                let _ = stream.write_all(head.as_bytes()).await;
+                let _ = stream.write_all(&body).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn torrents_are_fetched_by_url() {
+        let dir = test_dir("url");
+        let (torrent, id) = make_torrent(&dir);
+        let addr = torrent_server(std::fs::read(&torrent).unwrap()).await;
+        let session = Session::start(DownloadOptions::default(), dir.join("state"))
+            .await
+            .unwrap();
+
+        let url = |path: &str| format!("http://{}{}", addr, path);
+        assert_eq!(session.add_url(&url("/torrent"), true).await.unwrap(), id);
+        for path in ["/other", "/large", "/endless"] {
+            assert!(session.add_url(&url(path), true).await.is_err(), "{}", path);
+        }
+        // Nothing is left behind by the ones that failed
+        let mut stored: Vec<_> = std::fs::read_dir(dir.join("state").join("torrents"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        stored.sort();
+        assert_eq!(stored, [format!("{}.torrent", id)]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Answered by a closed connection, after at most a bounded read
+    async fn is_refused<S: AsyncReadExt + AsyncWriteExt + Unpin>(mut stream: S) {
+        let line = vec![b'a'; 2 * 1024 * 1024];
+        // The server may close the connection before it has all of it
+        let _ = stream.write_all(&line).await;
+        let mut rest = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut rest));
+        assert!(read.await.is_ok(), "The line is still being read");
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn endless_lines_are_refused() {
+        let dir = test_dir("lines");
+        let session = Session::start(DownloadOptions::default(), dir.join("state"))
+            .await
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(http::serve(
+            listener,
+            Arc::clone(&session),
+            Arc::new(TOKEN.to_string()),
+        ));
+        let path = dir.join("api.sock");
+        tokio::spawn(unix::serve(
+            UnixListener::bind(&path).unwrap(),
+            Arc::clone(&session),
+            Arc::new(TOKEN.to_string()),
+        ));
+
+        is_refused(TcpStream::connect(addr).await.unwrap()).await;
+        is_refused(UnixStream::connect(&path).await.unwrap()).await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn tokens() {
+        assert!(token_matches("abc", "abc"));
+        assert!(!token_matches("abd", "abc"));
+        assert!(!token_matches("ab", "abc"));
+
+        let dir = test_dir("token");
+        let token = token(&dir).unwrap();
+        assert_eq!(token.len(), 32);
+        assert_eq!(super::token(&dir).unwrap(), token);
+        let mode = std::fs::metadata(dir.join("token"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
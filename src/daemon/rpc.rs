@@ -0,0 +1,214 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use serde_json::{json, Value};
+
+use super::session::Session;
+use crate::download::rate_limit::Limits;
+use crate::download::FilePriority;
+
+/*
+ *   JSON-RPC 2.0 calls on a session, the same over every transport. Limits are in bytes per
+ *   second, 0 is unlimited; limits left out of a call stay as they are.
+ *
+ *     torrent.add               {file | url | magnet, paused?}   -> {id}
+ *     torrent.list                                              -> [torrent]
+ *     torrent.files             {id}                            -> [file]
+ *     torrent.pause             {id}
+ *     torrent.resume            {id}
+ *     torrent.remove            {id, delete_data?}
+ *     torrent.set_file_priority {id, file, priority}
+ *     torrent.set_limits        {id, download?, upload?}
+ *     session.set_limits        {download?, upload?, peer_download?, peer_upload?}
+ *     session.stats                                             -> stats
+ *
+ *   Magnet links are refused with NOT_SUPPORTED: their metadata has to come from peers over the
+ *   extension protocol (BEP 10, BEP 9), which isn't implemented. Add the torrent's file or URL.
+ */
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+// Errors of the call itself, like an unknown torrent
+pub const CALL_FAILED: i64 = -32000;
+pub const UNAUTHORIZED: i64 = -32001;
+pub const NOT_SUPPORTED: i64 = -32002;
+
+// An error with a code of its own, others are reported as CALL_FAILED
+#[derive(Debug)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl std::fmt::Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+fn rpc_error(code: i64, message: String) -> anyhow::Error {
+    RpcError { code, message }.into()
+}
+
+pub struct Request {
+    // Notifications have none and get no response
+    pub id: Option<Value>,
+    pub method: String,
+    pub params: Value,
+}
+
+// The error response to send back if `text` isn't a request
+pub fn parse_request(text: &str) -> Result<Request, Value> {
+    let request: Value = serde_json::from_str(text)
+        .map_err(|err| error_response(Value::Null, PARSE_ERROR, &err.to_string()))?;
+    let id = request.get("id").cloned();
+    let method = match (&request["jsonrpc"], &request["method"]) {
+        (Value::String(version), Value::String(method)) if version == "2.0" => method.clone(),
+        _ => {
+            return Err(error_response(
+                id.unwrap_or(Value::Null),
+                INVALID_REQUEST,
+                "Not a JSON-RPC 2.0 request",
+            ))
+        }
+    };
+    Ok(Request {
+        id,
+        method,
+        params: request.get("params").cloned().unwrap_or(json!({})),
+    })
+}
+
+pub fn response(id: Option<Value>, result: anyhow::Result<Value>) -> Option<Value> {
+    let id = id?;
+    Some(match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(err) => match err.downcast_ref::<RpcError>() {
+            Some(err) => error_response(id, err.code, &err.message),
+            None => error_response(id, CALL_FAILED, &format!("{:#}", err)),
+        },
+    })
+}
+
+pub fn error_response(id: Value, code: i64, message: &str) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+pub fn notification(method: &str, params: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "method": method, "params": params })
+}
+
+// Runs a request, the response is none for notifications
+pub async fn call(session: &Arc<Session>, request: Request) -> Option<Value> {
+    let result = dispatch(session, &request.method, &request.params).await;
+    response(request.id, result)
+}
+
+async fn dispatch(session: &Arc<Session>, method: &str, params: &Value) -> anyhow::Result<Value> {
+    match method {
+        "torrent.add" => {
+            let paused = optional(params, "paused", Value::as_bool)?.unwrap_or(false);
+            let id = if let Some(file) = optional(params, "file", Value::as_str)? {
+                session.add_file(Path::new(file), paused)?
+            } else if let Some(url) = optional(params, "url", Value::as_str)? {
+                session.add_url(url, paused).await?
+            } else if optional(params, "magnet", Value::as_str)?.is_some() {
+                return Err(rpc_error(
+                    NOT_SUPPORTED,
+                    "Magnet links need the metadata exchange with peers (BEP 9), which isn't \
+                     supported: add the torrent's file or URL instead"
+                        .to_string(),
+                ));
+            } else {
+                return Err(rpc_error(
+                    INVALID_PARAMS,
+                    "torrent.add needs a file, url or magnet".to_string(),
+                ));
+            };
+            Ok(json!({ "id": id }))
+        }
+        "torrent.list" => Ok(session.list()),
+        "torrent.files" => session.files(required(params, "id", Value::as_str)?),
+        "torrent.pause" => {
+            session.pause(required(params, "id", Value::as_str)?)?;
+            Ok(Value::Null)
+        }
+        "torrent.resume" => {
+            session.resume(required(params, "id", Value::as_str)?)?;
+            Ok(Value::Null)
+        }
+        "torrent.remove" => {
+            let delete_data = optional(params, "delete_data", Value::as_bool)?.unwrap_or(false);
+            session.remove(required(params, "id", Value::as_str)?, delete_data)?;
+            Ok(Value::Null)
+        }
+        "torrent.set_file_priority" => {
+            let priority: FilePriority = required(params, "priority", Value::as_str)?
+                .parse()
+                .map_err(|err| rpc_error(INVALID_PARAMS, format!("{}", err)))?;
+            session.set_file_priority(
+                required(params, "id", Value::as_str)?,
+                required(params, "file", Value::as_u64)? as usize,
+                priority,
+            )?;
+            Ok(Value::Null)
+        }
+        "torrent.set_limits" => {
+            let id = required(params, "id", Value::as_str)?;
+            let limits = changed_limits(session.limits(id)?, params, "download", "upload")?;
+            session.set_limits(id, limits)?;
+            Ok(Value::Null)
+        }
+        "session.set_limits" => {
+            let (limits, peer_limits) = session.session_limits();
+            session.set_session_limits(
+                changed_limits(limits, params, "download", "upload")?,
+                changed_limits(peer_limits, params, "peer_download", "peer_upload")?,
+            );
+            Ok(Value::Null)
+        }
+        "session.stats" => Ok(session.stats()),
+        _ => Err(rpc_error(
+            METHOD_NOT_FOUND,
+            format!("Unknown method {}", method),
+        )),
+    }
+}
+
+fn optional<'a, T>(
+    params: &'a Value,
+    name: &str,
+    get: fn(&'a Value) -> Option<T>,
+) -> anyhow::Result<Option<T>> {
+    match params.get(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => get(value)
+            .map(Some)
+            .ok_or_else(|| rpc_error(INVALID_PARAMS, format!("Wrong {}: {}", name, value))),
+    }
+}
+
+fn required<'a, T>(
+    params: &'a Value,
+    name: &str,
+    get: fn(&'a Value) -> Option<T>,
+) -> anyhow::Result<T> {
+    optional(params, name, get)?
+        .ok_or_else(|| rpc_error(INVALID_PARAMS, format!("Missing {}", name)))
+}
+
+fn changed_limits(
+    limits: Limits,
+    params: &Value,
+    download: &str,
+    upload: &str,
+) -> anyhow::Result<Limits> {
+    Ok(Limits {
+        download: optional(params, download, Value::as_u64)?.unwrap_or(limits.download),
+        upload: optional(params, upload, Value::as_u64)?.unwrap_or(limits.upload),
+    })
+}
@@ -0,0 +1,638 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use tokio::sync::{broadcast, watch};
+use tokio::task::JoinHandle;
+
+use crate::download::rate_limit::{Limits, Throttle, Throttles};
+use crate::download::{self, BannedPeers, DownloadOptions, FilePriority, Progress};
+use crate::proxy;
+use crate::torrent_file_handler::{torrent_data_extractor, torrent_file_parser};
+use crate::tracker::TrackerClient;
+
+/*
+ *   The torrents a daemon hosts. They share the session's limits, connection caps, IP filter and
+ *   proxies, and each runs as a task of its own: pausing a torrent stops its task, resuming
+ *   starts it again over the pieces it saved so far. The torrent list is saved to the state
+ *   directory after every change, so a restarted daemon picks up where it stopped.
+ */
+
+// How often transfer rates are sampled
+const RATE_INTERVAL: Duration = Duration::from_secs(1);
+// Events a slow subscriber may fall behind by before it misses some
+const EVENT_BACKLOG: usize = 256;
+const TORRENT_FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+// Far larger than any .torrent file
+const MAX_TORRENT_SIZE: usize = 8 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TorrentState {
+    Downloading,
+    Paused,
+    Finished,
+    Failed,
+}
+
+impl TorrentState {
+    fn name(self) -> &'static str {
+        match self {
+            TorrentState::Downloading => "downloading",
+            TorrentState::Paused => "paused",
+            TorrentState::Finished => "finished",
+            TorrentState::Failed => "failed",
+        }
+    }
+
+    fn parse(name: &str) -> anyhow::Result<TorrentState> {
+        match name {
+            "downloading" => Ok(TorrentState::Downloading),
+            "paused" => Ok(TorrentState::Paused),
+            "finished" => Ok(TorrentState::Finished),
+            "failed" => Ok(TorrentState::Failed),
+            _ => anyhow::bail!("Unknown torrent state {}", name),
+        }
+    }
+}
+
+struct Torrent {
+    name: String,
+    torrent_file: PathBuf,
+    // Torrents fetched from a URL are kept in the state directory and deleted with their data
+    owns_torrent_file: bool,
+    files: Vec<(String, usize)>,
+    file_priorities: HashMap<usize, FilePriority>,
+    throttle: Arc<Throttle>,
+    progress: Arc<Progress>,
+    state: TorrentState,
+    error: Option<String>,
+    task: Option<JoinHandle<()>>,
+    // Tells a task that ended from the one started after it
+    generation: u64,
+    // Bytes per second over the last sample and the totals it was taken at
+    rates: (u64, u64),
+    sampled: (u64, u64),
+}
+
+impl Torrent {
+    fn stop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+
+    fn saved(&self, id: &str) -> Value {
+        let limits = self.throttle.limit().get();
+        json!({
+            "id": id,
+            "torrent_file": self.torrent_file.to_string_lossy(),
+            "owns_torrent_file": self.owns_torrent_file,
+            "state": self.state.name(),
+            "file_priorities": self
+                .file_priorities
+                .iter()
+                .map(|(index, priority)| (index.to_string(), json!(priority.to_string())))
+                .collect::<serde_json::Map<_, _>>(),
+            "limits": { "download": limits.download, "upload": limits.upload },
+        })
+    }
+}
+
+pub struct Session {
+    // Settings every torrent starts with, the throttles of the session level are shared
+    defaults: DownloadOptions,
+    state_dir: PathBuf,
+    torrents: Mutex<BTreeMap<String, Torrent>>,
+    events: broadcast::Sender<Value>,
+    rates: Mutex<((u64, u64), (u64, u64))>,
+}
+
+impl Session {
+    pub async fn start(
+        mut defaults: DownloadOptions,
+        state_dir: PathBuf,
+    ) -> anyhow::Result<Arc<Session>> {
+        std::fs::create_dir_all(state_dir.join("torrents"))?;
+        // One tracker client, so each torrent doesn't bind sockets and start proxy bridges again
+        if defaults.tracker_client.is_none() {
+            defaults.tracker_client = Some(Arc::new(TrackerClient::new(&defaults.proxies).await?));
+        }
+        // Peers banned by any torrent are banned for all of them, and for the next sessions
+        defaults.banned_peers = Arc::new(BannedPeers::load(state_dir.join("banned_peers"))?);
+        let session = Arc::new(Session {
+            defaults,
+            state_dir,
+            torrents: Mutex::new(BTreeMap::new()),
+            events: broadcast::channel(EVENT_BACKLOG).0,
+            rates: Mutex::new(((0, 0), (0, 0))),
+        });
+        session.restore()?;
+        tokio::spawn(sample_rates(Arc::downgrade(&session)));
+        Ok(session)
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Value> {
+        self.events.subscribe()
+    }
+
+    fn notify(&self, event: Value) {
+        // Nobody may be listening
+        let _ = self.events.send(event);
+    }
+
+    fn session_file(&self) -> PathBuf {
+        self.state_dir.join("session.json")
+    }
+
+    fn pieces_dir(&self, id: &str) -> PathBuf {
+        self.state_dir.join("pieces").join(id)
+    }
+
+    fn restore(self: &Arc<Self>) -> anyhow::Result<()> {
+        let text = match std::fs::read_to_string(self.session_file()) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        let saved: Value = serde_json::from_str(&text)?;
+        let mut torrents = self.torrents.lock().unwrap();
+        for entry in saved["torrents"].as_array().into_iter().flatten() {
+            let restored = self.restore_torrent(entry);
+            match restored {
+                Ok((id, mut torrent)) => {
+                    if torrent.state == TorrentState::Downloading {
+                        self.run(&id, &mut torrent);
+                    }
+                    torrents.insert(id, torrent);
+                }
+                Err(err) => println!("Couldn't restore torrent {}: {:?}", entry, err),
+            }
+        }
+        Ok(())
+    }
+
+    fn restore_torrent(&self, entry: &Value) -> anyhow::Result<(String, Torrent)> {
+        let torrent_file = entry["torrent_file"]
+            .as_str()
+            .ok_or(anyhow::anyhow!("No torrent file"))?;
+        let (id, mut torrent) = load_torrent(
+            Path::new(torrent_file),
+            entry["owns_torrent_file"].as_bool().unwrap_or(false),
+        )?;
+        torrent.state = TorrentState::parse(entry["state"].as_str().unwrap_or("paused"))?;
+        for (index, priority) in entry["file_priorities"].as_object().into_iter().flatten() {
+            torrent.file_priorities.insert(
+                index.parse()?,
+                priority.as_str().unwrap_or_default().parse()?,
+            );
+        }
+        torrent.throttle.limit().set(Limits {
+            download: entry["limits"]["download"].as_u64().unwrap_or(0),
+            upload: entry["limits"]["upload"].as_u64().unwrap_or(0),
+        });
+        Ok((id, torrent))
+    }
+
+    fn save(&self, torrents: &BTreeMap<String, Torrent>) {
+        let saved = json!({
+            "torrents": torrents
+                .iter()
+                .map(|(id, torrent)| torrent.saved(id))
+                .collect::<Vec<_>>(),
+        });
+        // Written aside and renamed, so a crash doesn't leave half a list
+        let temporary = self.state_dir.join("session.json.new");
+        let written = std::fs::write(&temporary, saved.to_string())
+            .and_then(|_| std::fs::rename(&temporary, self.session_file()));
+        if let Err(err) = written {
+            println!("Couldn't save the torrent list: {}", err);
+        }
+    }
+
+    // Starts downloading in a task of its own, which reports back when it ends
+    fn run(self: &Arc<Self>, id: &str, torrent: &mut Torrent) {
+        torrent.stop();
+        torrent.state = TorrentState::Downloading;
+        torrent.error = None;
+        torrent.generation += 1;
+
+        let defaults = &self.defaults;
+        let options = DownloadOptions {
+            encryption: defaults.encryption,
+            mode: defaults.mode,
+            file_priorities: torrent.file_priorities.clone(),
+            throttles: Throttles {
+                session: Arc::clone(&defaults.throttles.session),
+                torrent: Arc::clone(&torrent.throttle),
+                peer_limit: Arc::clone(&defaults.throttles.peer_limit),
+                include_overhead: Arc::clone(&defaults.throttles.include_overhead),
+            },
+            connections: Arc::clone(&defaults.connections),
+            max_connections: defaults.max_connections,
+            ip_filter: Arc::clone(&defaults.ip_filter),
+            banned_peers: Arc::clone(&defaults.banned_peers),
+            proxies: defaults.proxies.clone(),
+            tracker_client: defaults.tracker_client.clone(),
+            pieces_dir: self.pieces_dir(id).to_string_lossy().into_owned(),
+            progress: Arc::clone(&torrent.progress),
+            // Nothing reads torrents of the daemon while they download
+            read_cursor: watch::channel(0).1,
+        };
+        let filename = torrent.torrent_file.to_string_lossy().into_owned();
+        let session = Arc::downgrade(self);
+        let id = id.to_string();
+        let generation = torrent.generation;
+        torrent.task = Some(tokio::spawn(async move {
+            let result = download::download(filename, options).await;
+            if let Some(session) = session.upgrade() {
+                session.ended(&id, generation, result);
+            }
+        }));
+    }
+
+    fn ended(&self, id: &str, generation: u64, result: anyhow::Result<()>) {
+        let mut torrents = self.torrents.lock().unwrap();
+        let torrent = match torrents.get_mut(id) {
+            Some(torrent) if torrent.generation == generation => torrent,
+            // Paused, restarted or removed meanwhile
+            _ => return,
+        };
+        torrent.task = None;
+        match result {
+            Ok(()) => {
+                torrent.state = TorrentState::Finished;
+                self.notify(json!({ "event": "finished", "id": id, "name": torrent.name }));
+            }
+            Err(err) => {
+                let error = format!("{:#}", err);
+                torrent.state = TorrentState::Failed;
+                torrent.error = Some(error.clone());
+                self.notify(
+                    json!({ "event": "failed", "id": id, "name": torrent.name, "error": error }),
+                );
+            }
+        }
+        self.save(&torrents);
+    }
+
+    pub fn add_file(self: &Arc<Self>, path: &Path, paused: bool) -> anyhow::Result<String> {
+        // The list outlives the directory the request was made from
+        let path = path
+            .canonicalize()
+            .map_err(|err| anyhow::anyhow!("Couldn't open {}: {}", path.display(), err))?;
+        self.add(&path, false, paused)
+    }
+
+    pub async fn add_url(self: &Arc<Self>, url: &str, paused: bool) -> anyhow::Result<String> {
+        let proxies = &self.defaults.proxies;
+        anyhow::ensure!(
+            proxies.trackers.is_some() || !proxies.force,
+            "Direct connections are refused, but there is no proxy for trackers"
+        );
+        let builder = reqwest::Client::builder().timeout(TORRENT_FETCH_TIMEOUT);
+        let client = proxy::http_client(builder, proxies.trackers.as_ref())?;
+        let mut response = client.get(url).send().await?.error_for_status()?;
+        let too_large = || anyhow::anyhow!("{} is larger than a torrent file", url);
+        if response
+            .content_length()
+            .is_some_and(|length| length > MAX_TORRENT_SIZE as u64)
+        {
+            return Err(too_large());
+        }
+        let mut torrent = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            if torrent.len() + chunk.len() > MAX_TORRENT_SIZE {
+                return Err(too_large());
+            }
+            torrent.extend_from_slice(&chunk);
+        }
+
+        // Files are written and parsed away from the runtime's threads
+        let session = Arc::clone(self);
+        tokio::task::spawn_blocking(move || session.add_fetched(&torrent, paused)).await?
+    }
+
+    fn add_fetched(self: &Arc<Self>, torrent: &[u8], paused: bool) -> anyhow::Result<String> {
+        // Named after the info hash once it is known
+        let fetched = self
+            .state_dir
+            .join("torrents")
+            .join(format!("fetched-{}.torrent", rand::random::<u32>()));
+        std::fs::write(&fetched, torrent)?;
+        let id = match torrent_file_parser::parse_torrent_file_hashes(
+            fetched.to_string_lossy().into_owned(),
+        ) {
+            Ok((_, info_hashes)) => hex(&info_hashes[0]),
+            Err(err) => {
+                let _ = std::fs::remove_file(&fetched);
+                return Err(err);
+            }
+        };
+        let path = self
+            .state_dir
+            .join("torrents")
+            .join(format!("{}.torrent", id));
+        std::fs::rename(&fetched, &path)?;
+        self.add(&path, true, paused)
+    }
+
+    fn add(self: &Arc<Self>, path: &Path, owned: bool, paused: bool) -> anyhow::Result<String> {
+        let (id, mut torrent) = load_torrent(path, owned)?;
+        let mut torrents = self.torrents.lock().unwrap();
+        anyhow::ensure!(
+            !torrents.contains_key(&id),
+            "Torrent {} was already added",
+            id
+        );
+        if paused {
+            torrent.state = TorrentState::Paused;
+        } else {
+            self.run(&id, &mut torrent);
+        }
+        self.notify(json!({ "event": "added", "id": id, "name": torrent.name }));
+        torrents.insert(id.clone(), torrent);
+        self.save(&torrents);
+        Ok(id)
+    }
+
+    // Runs `change` on the torrent and saves the list
+    fn with_torrent<T>(
+        &self,
+        id: &str,
+        change: impl FnOnce(&mut Torrent) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let mut torrents = self.torrents.lock().unwrap();
+        let torrent = torrents
+            .get_mut(id)
+            .ok_or(anyhow::anyhow!("No torrent {}", id))?;
+        let result = change(torrent)?;
+        self.save(&torrents);
+        Ok(result)
+    }
+
+    pub fn pause(&self, id: &str) -> anyhow::Result<()> {
+        self.with_torrent(id, |torrent| {
+            anyhow::ensure!(
+                torrent.state == TorrentState::Downloading || torrent.state == TorrentState::Failed,
+                "Torrent {} is {}",
+                id,
+                torrent.state.name()
+            );
+            torrent.stop();
+            torrent.state = TorrentState::Paused;
+            self.notify(json!({ "event": "paused", "id": id, "name": torrent.name }));
+            Ok(())
+        })
+    }
+
+    pub fn resume(self: &Arc<Self>, id: &str) -> anyhow::Result<()> {
+        self.with_torrent(id, |torrent| {
+            anyhow::ensure!(
+                torrent.state == TorrentState::Paused || torrent.state == TorrentState::Failed,
+                "Torrent {} is {}",
+                id,
+                torrent.state.name()
+            );
+            self.run(id, torrent);
+            self.notify(json!({ "event": "resumed", "id": id, "name": torrent.name }));
+            Ok(())
+        })
+    }
+
+    // Downloaded files are left alone, `delete_data` only deletes the saved pieces and a
+    // torrent file fetched from a URL
+    pub fn remove(&self, id: &str, delete_data: bool) -> anyhow::Result<()> {
+        let mut torrents = self.torrents.lock().unwrap();
+        let mut torrent = torrents
+            .remove(id)
+            .ok_or(anyhow::anyhow!("No torrent {}", id))?;
+        torrent.stop();
+        self.save(&torrents);
+        drop(torrents);
+
+        if delete_data {
+            let _ = std::fs::remove_dir_all(self.pieces_dir(id));
+            if torrent.owns_torrent_file {
+                let _ = std::fs::remove_file(&torrent.torrent_file);
+            }
+        }
+        self.notify(json!({ "event": "removed", "id": id, "name": torrent.name }));
+        Ok(())
+    }
+
+    // A torrent being downloaded starts over with the new priorities, its pieces are kept.
+    // A finished one only starts again if a skipped file is wanted now.
+    pub fn set_file_priority(
+        self: &Arc<Self>,
+        id: &str,
+        file: usize,
+        priority: FilePriority,
+    ) -> anyhow::Result<()> {
+        self.with_torrent(id, |torrent| {
+            anyhow::ensure!(
+                file < torrent.files.len(),
+                "Torrent {} has no file {}",
+                id,
+                file
+            );
+            let previous = torrent
+                .file_priorities
+                .insert(file, priority)
+                .unwrap_or(FilePriority::Normal);
+            let restart = match torrent.state {
+                TorrentState::Downloading => previous != priority,
+                TorrentState::Finished => {
+                    previous == FilePriority::Skip && priority != FilePriority::Skip
+                }
+                _ => false,
+            };
+            if restart {
+                self.run(id, torrent);
+            }
+            Ok(())
+        })
+    }
+
+    pub fn set_limits(&self, id: &str, limits: Limits) -> anyhow::Result<()> {
+        self.with_torrent(id, |torrent| {
+            torrent.throttle.limit().set(limits);
+            Ok(())
+        })
+    }
+
+    pub fn limits(&self, id: &str) -> anyhow::Result<Limits> {
+        let torrents = self.torrents.lock().unwrap();
+        let torrent = torrents
+            .get(id)
+            .ok_or(anyhow::anyhow!("No torrent {}", id))?;
+        Ok(torrent.throttle.limit().get())
+    }
+
+    pub fn session_limits(&self) -> (Limits, Limits) {
+        let throttles = &self.defaults.throttles;
+        (throttles.session.limit().get(), throttles.peer_limit.get())
+    }
+
+    pub fn set_session_limits(&self, limits: Limits, peer_limits: Limits) {
+        let throttles = &self.defaults.throttles;
+        throttles.session.limit().set(limits);
+        throttles.peer_limit.set(peer_limits);
+    }
+
+    pub fn list(&self) -> Value {
+        let torrents = self.torrents.lock().unwrap();
+        torrents
+            .iter()
+            .map(|(id, torrent)| {
+                let pieces = torrent.progress.pieces.load(Ordering::Relaxed);
+                let total_pieces = torrent.progress.total_pieces.load(Ordering::Relaxed);
+                let progress = match torrent.state {
+                    TorrentState::Finished => 1.0,
+                    _ if total_pieces == 0 => 0.0,
+                    _ => pieces as f64 / total_pieces as f64,
+                };
+                let peers = match torrent.state {
+                    TorrentState::Downloading => torrent.progress.peers.load(Ordering::Relaxed),
+                    _ => 0,
+                };
+                let (downloaded, uploaded) = torrent.throttle.totals();
+                let limits = torrent.throttle.limit().get();
+                json!({
+                    "id": id,
+                    "name": torrent.name,
+                    "state": torrent.state.name(),
+                    "error": torrent.error,
+                    "progress": progress,
+                    "pieces": pieces,
+                    "total_pieces": total_pieces,
+                    "peers": peers,
+                    "download_rate": torrent.rates.0,
+                    "upload_rate": torrent.rates.1,
+                    "downloaded": downloaded,
+                    "uploaded": uploaded,
+                    "limits": { "download": limits.download, "upload": limits.upload },
+                })
+            })
+            .collect()
+    }
+
+    pub fn files(&self, id: &str) -> anyhow::Result<Value> {
+        let torrents = self.torrents.lock().unwrap();
+        let torrent = torrents
+            .get(id)
+            .ok_or(anyhow::anyhow!("No torrent {}", id))?;
+        Ok(torrent
+            .files
+            .iter()
+            .enumerate()
+            .map(|(index, (path, size))| {
+                let priority = torrent
+                    .file_priorities
+                    .get(&index)
+                    .copied()
+                    .unwrap_or(FilePriority::Normal);
+                json!({
+                    "index": index,
+                    "path": path,
+                    "size": size,
+                    "priority": priority.to_string(),
+                })
+            })
+            .collect())
+    }
+
+    pub fn stats(&self) -> Value {
+        let torrents = self.torrents.lock().unwrap().len();
+        let (downloaded, uploaded) = self.defaults.throttles.session.totals();
+        let ((download_rate, upload_rate), _) = *self.rates.lock().unwrap();
+        let (limits, peer_limits) = self.session_limits();
+        json!({
+            "torrents": torrents,
+            "download_rate": download_rate,
+            "upload_rate": upload_rate,
+            "downloaded": downloaded,
+            "uploaded": uploaded,
+            "limits": { "download": limits.download, "upload": limits.upload },
+            "peer_limits": { "download": peer_limits.download, "upload": peer_limits.upload },
+        })
+    }
+
+    fn sample(&self) {
+        for torrent in self.torrents.lock().unwrap().values_mut() {
+            let totals = torrent.throttle.totals();
+            torrent.rates = per_second(torrent.sampled, totals);
+            torrent.sampled = totals;
+        }
+        let totals = self.defaults.throttles.session.totals();
+        let mut rates = self.rates.lock().unwrap();
+        *rates = (per_second(rates.1, totals), totals);
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        for torrent in self.torrents.get_mut().unwrap().values_mut() {
+            torrent.stop();
+        }
+    }
+}
+
+async fn sample_rates(session: Weak<Session>) {
+    let mut interval = tokio::time::interval(RATE_INTERVAL);
+    loop {
+        interval.tick().await;
+        match session.upgrade() {
+            Some(session) => session.sample(),
+            None => return,
+        }
+    }
+}
+
+fn per_second(previous: (u64, u64), totals: (u64, u64)) -> (u64, u64) {
+    let seconds = RATE_INTERVAL.as_secs().max(1);
+    (
+        totals.0.saturating_sub(previous.0) / seconds,
+        totals.1.saturating_sub(previous.1) / seconds,
+    )
+}
+
+// Torrents are known by the hex form of their (first) info hash
+fn load_torrent(path: &Path, owned: bool) -> anyhow::Result<(String, Torrent)> {
+    let (torrent_data, info_hashes) =
+        torrent_file_parser::parse_torrent_file_hashes(path.to_string_lossy().into_owned())?;
+    let torrent_data = torrent_data_extractor::extract_data(torrent_data)?;
+    let files: Vec<_> = torrent_data
+        .files
+        .iter()
+        .map(|file| (file.path_to_file.join("/"), file.size))
+        .collect();
+    let torrent = Torrent {
+        // Every path starts with the torrent's name
+        name: torrent_data
+            .files
+            .first()
+            .and_then(|file| file.path_to_file.first())
+            .cloned()
+            .unwrap_or_default(),
+        torrent_file: path.to_path_buf(),
+        owns_torrent_file: owned,
+        files,
+        file_priorities: HashMap::new(),
+        throttle: Arc::new(Throttle::unlimited()),
+        progress: Arc::new(Progress::default()),
+        state: TorrentState::Paused,
+        error: None,
+        task: None,
+        generation: 0,
+        rates: (0, 0),
+        sampled: (0, 0),
+    };
+    Ok((hex(&info_hashes[0]), torrent))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
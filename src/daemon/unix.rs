@@ -0,0 +1,129 @@
+use std::sync::Arc;
+
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use super::rpc;
+use super::session::Session;
+use super::token_matches;
+
+/*
+ *   The API over a Unix socket: one JSON-RPC message per line each way. A connection starts with
+ *   `authenticate {token}`. After `events.subscribe` events come as `event` notifications between
+ *   the responses.
+ */
+
+// Longest message we take, a line without an end isn't read on forever
+const MAX_LINE_SIZE: usize = 1024 * 1024;
+
+pub async fn serve(listener: UnixListener, session: Arc<Session>, token: Arc<String>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(_) => continue,
+        };
+        let session = Arc::clone(&session);
+        let token = Arc::clone(&token);
+        tokio::spawn(async move {
+            let _ = handle_connection(stream, &session, &token).await;
+        });
+    }
+}
+
+async fn send(writer: &mut OwnedWriteHalf, message: &Value) -> anyhow::Result<()> {
+    let mut line = message.to_string();
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await?;
+    Ok(())
+}
+
+// Waits forever until subscribed
+async fn next_event(events: &mut Option<broadcast::Receiver<Value>>) -> Result<Value, RecvError> {
+    match events {
+        Some(events) => events.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+// False once the connection is closed
+async fn next_line(
+    reader: &mut BufReader<OwnedReadHalf>,
+    line: &mut String,
+) -> anyhow::Result<bool> {
+    line.clear();
+    let read = (&mut *reader)
+        .take(MAX_LINE_SIZE as u64)
+        .read_line(line)
+        .await?;
+    anyhow::ensure!(
+        read < MAX_LINE_SIZE || line.ends_with('\n'),
+        "Message is too long"
+    );
+    Ok(read > 0)
+}
+
+async fn handle_connection(
+    stream: UnixStream,
+    session: &Arc<Session>,
+    token: &str,
+) -> anyhow::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+    let mut authenticated = false;
+    let mut events = None;
+    loop {
+        tokio::select! {
+            read = next_line(&mut reader, &mut line) => {
+                if !read? {
+                    return Ok(());
+                }
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let request = match rpc::parse_request(&line) {
+                    Ok(request) => request,
+                    Err(response) => {
+                        send(&mut writer, &response).await?;
+                        continue;
+                    }
+                };
+                let response = match request.method.as_str() {
+                    "authenticate" => {
+                        authenticated = request.params["token"]
+                            .as_str()
+                            .is_some_and(|given| token_matches(given, token));
+                        if authenticated {
+                            rpc::response(request.id, Ok(Value::Null))
+                        } else {
+                            request.id.map(|id| rpc::error_response(id, rpc::UNAUTHORIZED, "Wrong token"))
+                        }
+                    }
+                    _ if !authenticated => request.id.map(|id| {
+                        rpc::error_response(id, rpc::UNAUTHORIZED, "Authenticate first")
+                    }),
+                    "events.subscribe" => {
+                        events = Some(session.subscribe());
+                        rpc::response(request.id, Ok(Value::Null))
+                    }
+                    _ => rpc::call(session, request).await,
+                };
+                if let Some(response) = response {
+                    send(&mut writer, &response).await?;
+                }
+            }
+            event = next_event(&mut events) => {
+                let params = match event {
+                    Ok(event) => event,
+                    // Too slow, the missed events are gone
+                    Err(RecvError::Lagged(missed)) => json!({ "event": "missed", "count": missed }),
+                    Err(RecvError::Closed) => return Ok(()),
+                };
+                send(&mut writer, &rpc::notification("event", params)).await?;
+            }
+        }
+    }
+}
@@ -0,0 +1,324 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+/*
+ *   Decides which peers of a torrent we connect to. Peers from every source are pooled here and
+ *   connected to as far as the caps allow: connections of the whole session, of the torrent, and
+ *   half-open ones (still connecting or handshaking), which are what home routers choke on.
+ *   A peer that couldn't be reached or dropped us without sending anything is retried after a
+ *   back-off growing with its failures. Peers that sent us data before are tried first.
+ */
+
+const MAX_CONNECTIONS: usize = 200;
+const MAX_HALF_OPEN: usize = 20;
+pub const MAX_CONNECTIONS_PER_TORRENT: usize = 50;
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(30);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+// Peers failing this many times in a row are forgotten until a tracker gives them again
+const MAX_FAILS: u32 = 8;
+
+// Caps shared by every torrent of a session
+pub struct SessionConnections {
+    connections: Arc<Semaphore>,
+    half_open: Arc<Semaphore>,
+}
+
+impl SessionConnections {
+    pub fn new(max_connections: usize, max_half_open: usize) -> SessionConnections {
+        SessionConnections {
+            connections: Arc::new(Semaphore::new(max_connections)),
+            half_open: Arc::new(Semaphore::new(max_half_open)),
+        }
+    }
+}
+
+impl Default for SessionConnections {
+    fn default() -> SessionConnections {
+        SessionConnections::new(MAX_CONNECTIONS, MAX_HALF_OPEN)
+    }
+}
+
+// An outgoing connection to make. The permits are held for as long as the connection is open and
+// until the handshake is done respectively.
+pub struct Attempt {
+    pub peer: SocketAddr,
+    pub info_hash: Vec<u8>,
+    pub peer_id: Vec<u8>,
+    pub connection: OwnedSemaphorePermit,
+    pub half_open: OwnedSemaphorePermit,
+}
+
+struct PeerEntry {
+    // The swarm the peer was found in and the peer id we announced there
+    info_hash: Vec<u8>,
+    peer_id: Vec<u8>,
+    connected: bool,
+    // Incoming connections come from ports we can't connect back to
+    incoming: bool,
+    fails: u32,
+    retry_at: Instant,
+    downloaded: u64,
+}
+
+pub struct ConnectionManager {
+    session: Arc<SessionConnections>,
+    max_connections: usize,
+    peers: HashMap<SocketAddr, PeerEntry>,
+    // Including half-open ones
+    connections: usize,
+}
+
+impl ConnectionManager {
+    pub fn new(session: Arc<SessionConnections>, max_connections: usize) -> ConnectionManager {
+        ConnectionManager {
+            session,
+            max_connections,
+            peers: HashMap::new(),
+            connections: 0,
+        }
+    }
+
+    // Peers we already know keep their state, so a tracker can't cut a back-off short
+    pub fn add_peers(&mut self, info_hash: &[u8], peer_id: &[u8], peers: &[SocketAddr]) {
+        let now = Instant::now();
+        for &peer in peers {
+            self.peers.entry(peer).or_insert_with(|| PeerEntry {
+                info_hash: info_hash.to_vec(),
+                peer_id: peer_id.to_vec(),
+                connected: false,
+                incoming: false,
+                fails: 0,
+                retry_at: now,
+                downloaded: 0,
+            });
+        }
+    }
+
+    // As many connections to ready peers as the caps allow, best peers first
+    pub fn next_attempts(&mut self, now: Instant) -> Vec<Attempt> {
+        let mut ready: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, entry)| !entry.connected && !entry.incoming && entry.retry_at <= now)
+            .map(|(peer, entry)| (*peer, entry.downloaded, entry.fails))
+            .collect();
+        ready.sort_by_key(|(_, downloaded, fails)| (std::cmp::Reverse(*downloaded), *fails));
+
+        let mut attempts = Vec::new();
+        for (peer, _, _) in ready {
+            if self.connections >= self.max_connections {
+                break;
+            }
+            let half_open = match Arc::clone(&self.session.half_open).try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => break,
+            };
+            let connection = match Arc::clone(&self.session.connections).try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => break,
+            };
+
+            let entry = self.peers.get_mut(&peer).unwrap();
+            entry.connected = true;
+            self.connections += 1;
+            attempts.push(Attempt {
+                peer,
+                info_hash: entry.info_hash.clone(),
+                peer_id: entry.peer_id.clone(),
+                connection,
+                half_open,
+            });
+        }
+        attempts
+    }
+
+    // A peer connecting to us, refused if we are full or already connected to it
+    pub fn accept(&mut self, peer: SocketAddr) -> Option<OwnedSemaphorePermit> {
+        if self.connections >= self.max_connections
+            || self.peers.get(&peer).is_some_and(|entry| entry.connected)
+        {
+            return None;
+        }
+        let permit = Arc::clone(&self.session.connections)
+            .try_acquire_owned()
+            .ok()?;
+
+        self.connections += 1;
+        let entry = self.peers.entry(peer).or_insert_with(|| PeerEntry {
+            info_hash: Vec::new(),
+            peer_id: Vec::new(),
+            connected: false,
+            incoming: true,
+            fails: 0,
+            retry_at: Instant::now(),
+            downloaded: 0,
+        });
+        entry.connected = true;
+        Some(permit)
+    }
+
+    // `downloaded` is the payload the connection brought, none means it failed us
+    pub fn finished(&mut self, peer: SocketAddr, downloaded: u64, now: Instant) {
+        let entry = match self.peers.get_mut(&peer) {
+            Some(entry) if entry.connected => entry,
+            _ => return,
+        };
+        entry.connected = false;
+        self.connections -= 1;
+        if entry.incoming {
+            self.peers.remove(&peer);
+            return;
+        }
+
+        if downloaded > 0 {
+            entry.downloaded += downloaded;
+            entry.fails = 0;
+            entry.retry_at = now + FIRST_RETRY_DELAY;
+        } else {
+            entry.fails += 1;
+            if entry.fails >= MAX_FAILS {
+                self.peers.remove(&peer);
+                return;
+            }
+            entry.retry_at = now + retry_delay(entry.fails);
+        }
+    }
+
+    // Banned peers are never tried again, open connections to them end on their own
+    pub fn ban(&mut self, ip: IpAddr) {
+        self.peers
+            .retain(|peer, entry| peer.ip() != ip || entry.connected);
+    }
+
+    // Including half-open ones
+    pub fn connected(&self) -> usize {
+        self.connections
+    }
+
+    // Nothing is connected and no known peer can be tried before `soon`
+    pub fn needs_peers(&self, soon: Instant) -> bool {
+        self.connections == 0
+            && self
+                .peers
+                .values()
+                .all(|entry| entry.incoming || entry.retry_at > soon)
+    }
+}
+
+fn retry_delay(fails: u32) -> Duration {
+    FIRST_RETRY_DELAY
+        .checked_mul(1 << (fails - 1).min(16))
+        .unwrap_or(MAX_RETRY_DELAY)
+        .min(MAX_RETRY_DELAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    #[tokio::test]
+    async fn caps_are_enforced() {
+        let session = Arc::new(SessionConnections::new(3, 2));
+        let mut manager = ConnectionManager::new(Arc::clone(&session), 10);
+        let mut other = ConnectionManager::new(Arc::clone(&session), 10);
+        manager.add_peers(&[1; 20], &[2; 20], &(1..=5).map(addr).collect::<Vec<_>>());
+        other.add_peers(&[3; 20], &[2; 20], &[addr(6)]);
+
+        let now = Instant::now();
+        let attempts = manager.next_attempts(now);
+        // Only two may be half-open at once
+        assert_eq!(attempts.len(), 2);
+        assert!(manager.next_attempts(now).is_empty());
+
+        // Handshakes done, but the session allows three connections in all
+        let first: Vec<_> = attempts
+            .into_iter()
+            .map(|attempt| attempt.connection)
+            .collect();
+        let attempts = manager.next_attempts(now);
+        assert_eq!(attempts.len(), 1);
+        assert!(other.next_attempts(now).is_empty());
+        assert!(other.accept(addr(7)).is_none());
+
+        let closed = attempts[0].peer;
+        drop(attempts);
+        manager.finished(closed, 0, now);
+        assert_eq!(other.next_attempts(now).len(), 1);
+        drop(first);
+    }
+
+    #[tokio::test]
+    async fn torrent_cap_and_incoming_peers() {
+        let mut manager = ConnectionManager::new(Arc::new(SessionConnections::default()), 2);
+        manager.add_peers(&[1; 20], &[2; 20], &[addr(1), addr(2)]);
+        let incoming = manager.accept(addr(9)).unwrap();
+        let attempts = manager.next_attempts(Instant::now());
+        assert_eq!(attempts.len(), 1);
+        assert_eq!(manager.connections, 2);
+
+        drop(incoming);
+        manager.finished(addr(9), 0, Instant::now());
+        assert_eq!(manager.connections, 1);
+        // Never connected back to
+        let next = manager.next_attempts(Instant::now());
+        assert_eq!(next.len(), 1);
+        assert_ne!(next[0].peer, attempts[0].peer);
+        assert_ne!(next[0].peer, addr(9));
+    }
+
+    #[tokio::test]
+    async fn failed_peers_back_off_and_good_ones_go_first() {
+        let mut manager = ConnectionManager::new(Arc::new(SessionConnections::default()), 10);
+        manager.add_peers(&[1; 20], &[2; 20], &[addr(1), addr(2)]);
+        let now = Instant::now();
+        assert_eq!(manager.next_attempts(now).len(), 2);
+
+        manager.finished(addr(1), 0, now);
+        manager.finished(addr(2), 16384, now);
+        assert!(manager.next_attempts(now).is_empty());
+        assert!(manager.needs_peers(now));
+
+        let later = now + FIRST_RETRY_DELAY;
+        let attempts = manager.next_attempts(later);
+        assert_eq!(
+            attempts
+                .iter()
+                .map(|attempt| attempt.peer)
+                .collect::<Vec<_>>(),
+            vec![addr(2), addr(1)]
+        );
+
+        // The second failure in a row waits twice as long
+        manager.finished(addr(1), 0, later);
+        assert!(manager.next_attempts(later + FIRST_RETRY_DELAY).is_empty());
+        assert_eq!(
+            manager.next_attempts(later + 2 * FIRST_RETRY_DELAY).len(),
+            1
+        );
+
+        // A tracker announcing it again doesn't reset that
+        manager.finished(addr(1), 0, later);
+        manager.add_peers(&[1; 20], &[2; 20], &[addr(1)]);
+        assert!(manager.next_attempts(later).is_empty());
+
+        manager.finished(addr(2), 0, later);
+        manager.ban(addr(1).ip());
+        assert!(manager.peers.is_empty());
+    }
+
+    #[test]
+    fn retry_delays() {
+        assert_eq!(retry_delay(1), FIRST_RETRY_DELAY);
+        assert_eq!(retry_delay(3), 4 * FIRST_RETRY_DELAY);
+        assert_eq!(retry_delay(30), MAX_RETRY_DELAY);
+    }
+}
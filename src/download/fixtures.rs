@@ -0,0 +1,97 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+use super::download_status::DownloadStatus;
+use super::piece_picker::{DownloadMode, FilePriority, PiecePicker};
+use super::rate_limit::Throttles;
+use super::smart_ban::SmartBan;
+use super::worker::DownloadContext;
+use crate::torrent_file_handler::torrent_data_extractor::{File, TorrentData};
+
+/*
+ *   Shared by the tests of the download modules: torrents to download into a directory and a
+ *   stand-in HTTP server on loopback.
+ */
+
+// A torrent of one file named "file" holding `data`
+pub fn single_file(data: &[u8], piece_length: usize) -> TorrentData {
+    TorrentData {
+        pieces: data
+            .chunks(piece_length)
+            .map(|piece| Sha1::digest(piece).to_vec())
+            .collect(),
+        piece_length,
+        files: vec![File {
+            path_to_file: vec!["file".to_string()],
+            size: data.len(),
+            ..Default::default()
+        }],
+        ..Default::default()
+    }
+}
+
+// Every piece wanted in order, saved to `dir`
+pub fn context(torrent_data: TorrentData, dir: &str) -> DownloadContext {
+    let pieces = torrent_data.piece_count();
+    DownloadContext {
+        torrent_data,
+        picker: Mutex::new(PiecePicker::new(
+            vec![FilePriority::Normal; pieces],
+            DownloadMode::Sequential,
+        )),
+        download_status: Mutex::new(DownloadStatus {
+            total_pieces: pieces as u32,
+            pieces_downloaded: 0,
+        }),
+        saved_pieces_dir_name: dir.to_string(),
+        piece_hashes_v2: Mutex::new(Vec::new()),
+        throttles: Throttles::default(),
+        smart_ban: Mutex::new(SmartBan::default()),
+    }
+}
+
+// Answers every request with `respond(request head)`, one request per connection
+pub async fn http_server<F>(respond: F) -> SocketAddr
+where
+    F: Fn(&str) -> Vec<u8> + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let respond = Arc::new(respond);
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let respond = Arc::clone(&respond);
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let len = stream.read(&mut buf).await.unwrap();
+                    if len == 0 {
+                        return;
+                    }
+                    request.extend_from_slice(&buf[..len]);
+                }
+                let response = respond(&String::from_utf8(request).unwrap());
+                stream.write_all(&response).await.unwrap();
+            });
+        }
+    });
+    addr
+}
+
+pub fn http_response(status: &str, headers: &str, body: &[u8]) -> Vec<u8> {
+    let mut response = format!(
+        "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        headers,
+        body.len()
+    )
+    .into_bytes();
+    response.extend_from_slice(body);
+    response
+}
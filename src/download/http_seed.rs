@@ -0,0 +1,129 @@
+use std::time::Duration;
+
+use reqwest::StatusCode;
+
+use super::web_seed::{self, RetryAfter};
+use super::worker::DownloadContext;
+
+/*
+ *   HTTP seeds (BEP 17): a script which serves whole pieces by info hash and piece index. When it
+ *   is busy it answers 503 with the seconds to wait in the body.
+ *
+ *   The optional `ranges` parameter, asking for parts of a piece, isn't sent: a seed is given
+ *   a piece to itself and a piece that fails its hash is fetched again whole, so there is never
+ *   a part of one worth keeping.
+ */
+
+// For a busy answer without a usable delay
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+pub async fn run(client: &reqwest::Client, url: &str, info_hash: &[u8], context: &DownloadContext) {
+    web_seed::run_seed(context, |index| {
+        fetch_piece(client, url, info_hash, index, context)
+    })
+    .await;
+}
+
+async fn fetch_piece(
+    client: &reqwest::Client,
+    url: &str,
+    info_hash: &[u8],
+    index: usize,
+    context: &DownloadContext,
+) -> anyhow::Result<Vec<u8>> {
+    let response = client.get(piece_url(url, info_hash, index)).send().await?;
+    if response.status() == StatusCode::SERVICE_UNAVAILABLE {
+        let header_delay = web_seed::retry_after(&response);
+        let body = response.text().await.unwrap_or_default();
+        let delay = body
+            .trim()
+            .parse()
+            .map(Duration::from_secs)
+            .ok()
+            .or(header_delay)
+            .unwrap_or(DEFAULT_RETRY_DELAY);
+        return Err(RetryAfter(delay).into());
+    }
+
+    let piece = response.error_for_status()?.bytes().await?;
+    let expected = context.torrent_data.piece_size(index);
+    anyhow::ensure!(
+        piece.len() == expected,
+        "HTTP seed sent {} bytes instead of {}",
+        piece.len(),
+        expected
+    );
+    Ok(piece.to_vec())
+}
+
+fn piece_url(url: &str, info_hash: &[u8], index: usize) -> String {
+    let separator = if url.contains('?') { '&' } else { '?' };
+    format!(
+        "{}{}info_hash={}&piece={}",
+        url,
+        separator,
+        url::form_urlencoded::byte_serialize(info_hash).collect::<String>(),
+        index
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::fixtures;
+    use crate::filewriter;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Instant;
+
+    #[test]
+    fn piece_urls() {
+        assert_eq!(
+            piece_url("http://seed.example/seed.php", &[0x12, b' ', b'a'], 3),
+            "http://seed.example/seed.php?info_hash=%12+a&piece=3"
+        );
+        assert_eq!(
+            piece_url("http://seed.example/seed?id=1", b"a", 0),
+            "http://seed.example/seed?id=1&info_hash=a&piece=0"
+        );
+    }
+
+    // Answers the first request with 503 and a one second delay, then serves pieces of `data`
+    async fn busy_seed(data: Vec<u8>, piece_length: usize) -> String {
+        let requests = AtomicUsize::new(0);
+        let addr = fixtures::http_server(move |request| {
+            let path = request.split(' ').nth(1).unwrap();
+            assert!(path.starts_with("/seed?info_hash=%09%09"));
+            let index: usize = path.rsplit("piece=").next().unwrap().parse().unwrap();
+
+            if requests.fetch_add(1, Ordering::SeqCst) == 0 {
+                fixtures::http_response("503 Service Unavailable", "", b"1")
+            } else {
+                let piece = data.chunks(piece_length).nth(index).unwrap();
+                fixtures::http_response("200 OK", "", piece)
+            }
+        })
+        .await;
+        format!("http://{}/seed", addr)
+    }
+
+    #[tokio::test]
+    async fn pieces_from_a_busy_http_seed() {
+        let data: Vec<u8> = (0..300).map(|i| (i * 7 % 256) as u8).collect();
+        let url = busy_seed(data.clone(), 64).await;
+
+        let dir =
+            std::env::temp_dir().join(format!("rusty_torrent_http_seed_{}", std::process::id()));
+        let dir = dir.to_str().unwrap().to_string();
+        filewriter::create_directory(&dir).await.unwrap();
+        let context = fixtures::context(fixtures::single_file(&data, 64), &dir);
+
+        let started = Instant::now();
+        run(&web_seed::client(None).unwrap(), &url, &[9; 20], &context).await;
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert_eq!(context.download_status.lock().unwrap().pieces_downloaded, 5);
+        for (index, piece) in data.chunks(64).enumerate() {
+            assert_eq!(std::fs::read(format!("{}/.{}", dir, index)).unwrap(), piece);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,389 +1,428 @@
+mod connection_manager;
 mod download_status;
-
-use std::collections::VecDeque;
-use std::sync::{Arc, Mutex};
-
-use rand::prelude::*;
-use rand::Rng;
-use sha1::{Digest, Sha1};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+#[cfg(test)]
+mod fixtures;
+mod http_seed;
+mod piece_picker;
+pub mod rate_limit;
+mod smart_ban;
+mod web_seed;
+mod worker;
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
 
 use crate::filewriter;
-use crate::p2p::bitfields;
-use crate::p2p::handshake;
-use crate::p2p::messages;
-use crate::torrent_file_handler::torrent_data_extractor;
+use crate::ip_filter::IpFilter;
+use crate::lsd::LocalDiscovery;
+use crate::p2p::handshake::{self, Handshake};
+use crate::p2p::listener::PeerListener;
+use crate::p2p::mse::EncryptionPolicy;
+use crate::p2p::peer_stream::BoxedPeerStream;
+use crate::proxy::ProxySettings;
+use crate::torrent_file_handler::torrent_data_extractor::{self, MetaVersion};
 use crate::torrent_file_handler::torrent_file_parser;
-use crate::tracker;
+use crate::tracker::announce_scheduler::AnnounceScheduler;
+use crate::tracker::{AnnounceRequest, TrackerClient, Transferred};
+use crate::utp::UtpSocket;
+use connection_manager::ConnectionManager;
+pub use connection_manager::SessionConnections;
+use piece_picker::PiecePicker;
+pub use piece_picker::{DownloadMode, FilePriority};
+use rate_limit::Throttles;
+pub use smart_ban::BannedPeers;
+use smart_ban::SmartBan;
+use worker::DownloadContext;
+
+const LISTEN_PORT: u16 = 7878;
+// How often peers whose back-off ran out are connected to
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+pub struct DownloadOptions {
+    pub encryption: EncryptionPolicy,
+    pub mode: DownloadMode,
+    // By index in the torrent's file list, the others are normal
+    pub file_priorities: HashMap<usize, FilePriority>,
+    // Share `session` between the torrents of a session. Every limit can be changed while
+    // downloading.
+    pub throttles: Throttles,
+    // Connection caps, `connections` is shared between the torrents of a session
+    pub connections: Arc<SessionConnections>,
+    pub max_connections: usize,
+    // Peers in these ranges are never connected to or accepted. Share it between the torrents of a
+    // session, it can be replaced while downloading.
+    pub ip_filter: Arc<RwLock<IpFilter>>,
+    // Peers caught sending corrupt data. Share it between the torrents of a session.
+    pub banned_peers: Arc<BannedPeers>,
+    pub proxies: ProxySettings,
+    // Share it between the torrents of a session, a download without one makes its own
+    pub tracker_client: Option<Arc<TrackerClient>>,
+    // Where downloaded pieces are kept until the files are put together. Pieces found there from
+    // an earlier run aren't downloaded again.
+    pub pieces_dir: String,
+    pub progress: Arc<Progress>,
+    // Streaming: the piece the reader is at. Send to it to seek.
+    pub read_cursor: watch::Receiver<usize>,
+}
 
-const BLOCK_SIZE: usize = 16384;
+// How far a download is, for whoever runs it
+#[derive(Debug, Default)]
+pub struct Progress {
+    // Of the wanted pieces
+    pub pieces: AtomicU32,
+    pub total_pieces: AtomicU32,
+    pub peers: AtomicUsize,
+}
 
-#[tokio::main]
-pub async fn download(filename: String) -> anyhow::Result<()> {
-    let (torrent_data, info_hash) = torrent_file_parser::parse_torrent_file(filename)?;
-    let torrent_data = torrent_data_extractor::extract_data(torrent_data)?;
+impl Default for DownloadOptions {
+    fn default() -> DownloadOptions {
+        DownloadOptions {
+            encryption: EncryptionPolicy::Enabled,
+            mode: DownloadMode::Random,
+            file_priorities: HashMap::new(),
+            throttles: Throttles::default(),
+            connections: Arc::new(SessionConnections::default()),
+            max_connections: connection_manager::MAX_CONNECTIONS_PER_TORRENT,
+            ip_filter: Arc::new(RwLock::new(IpFilter::default())),
+            banned_peers: Arc::new(BannedPeers::default()),
+            proxies: ProxySettings::default(),
+            tracker_client: None,
+            pieces_dir: ".test".to_string(),
+            progress: Arc::new(Progress::default()),
+            read_cursor: watch::channel(0).1,
+        }
+    }
+}
 
-    let mut rng = rand::thread_rng();
-    let peer_id: Vec<u8> = (0..20).map(|_| rng.gen::<u8>()).collect(); // random peer id
+pub async fn download(filename: String, options: DownloadOptions) -> anyhow::Result<()> {
+    let proxies = options.proxies;
+    anyhow::ensure!(
+        proxies.peers.is_some() || !proxies.force,
+        "Direct connections are refused, but there is no proxy for peers"
+    );
+    let (torrent_data, info_hashes) = torrent_file_parser::parse_torrent_file_hashes(filename)?;
+    // HTTP seeds only know the first one
+    let info_hash = info_hashes[0].clone();
+    let torrent_data = torrent_data_extractor::extract_data(torrent_data)?;
 
-    let pieces_len = torrent_data.pieces.len();
-    let bitfield_expected_length = pieces_len / 8 + (pieces_len % 8 > 0) as usize;
+    let peer_id: Vec<u8> = (0..20).map(|_| rand::random::<u8>()).collect(); // random peer id
 
-    let piece_size = torrent_data.piece_length;
+    let mut file_priorities = vec![FilePriority::Normal; torrent_data.files.len()];
+    for (&index, &priority) in &options.file_priorities {
+        *file_priorities
+            .get_mut(index)
+            .ok_or(anyhow::anyhow!("The torrent has no file {}", index))? = priority;
+    }
+    let piece_priorities = piece_picker::piece_priorities(&torrent_data, &file_priorities);
+    let wanted_pieces = piece_priorities
+        .iter()
+        .filter(|priority| **priority != FilePriority::Skip)
+        .count();
+    anyhow::ensure!(wanted_pieces > 0, "Every file is skipped");
     let download_status = download_status::DownloadStatus {
-        total_pieces: pieces_len as u32,
+        total_pieces: wanted_pieces as u32,
         pieces_downloaded: 0,
     };
 
-    let saved_pieces_dir_name = ".test".to_string();
+    let saved_pieces_dir_name = options.pieces_dir;
     filewriter::create_directory(&saved_pieces_dir_name).await?;
 
-    let mut pieces_queue = VecDeque::new();
-    for i in 0..pieces_len {
-        pieces_queue.push_back(i);
+    let piece_hashes_v2 = Mutex::new(torrent_data.piece_hashes_v2.clone());
+    let context = Arc::new(DownloadContext {
+        torrent_data,
+        picker: Mutex::new(PiecePicker::new(piece_priorities.clone(), options.mode)),
+        download_status: Mutex::new(download_status),
+        saved_pieces_dir_name,
+        piece_hashes_v2,
+        throttles: options.throttles,
+        smart_ban: Mutex::new(SmartBan::new(options.banned_peers)),
+    });
+    let resumed = worker::resume_saved_pieces(&context).await;
+    if resumed > 0 {
+        println!("{} pieces were downloaded before", resumed);
     }
-    pieces_queue
-        .make_contiguous()
-        .shuffle(&mut rand::thread_rng());
-    let queue_ptr = Arc::new(Mutex::new(pieces_queue));
-
-    let torrent_data_ptr = Arc::new(torrent_data);
-    let download_status_ptr = Arc::new(Mutex::new(download_status));
-
-    loop {
-        let mut workers = Vec::new();
-        let (peers, _) =
-            tracker::request_peers(&*torrent_data_ptr, &peer_id, 7878, &info_hash).await?;
-
-        let current_progress = { download_status_ptr.lock().unwrap().pieces_downloaded };
-
-        for peer in peers.iter() {
-            let queue_len = { queue_ptr.lock().unwrap().len() };
-
-            let peer_clone = peer.clone();
-            let info_hash_clone = info_hash.clone();
-            let peer_id_clone = peer_id.clone();
-            let queue_ptr_clone = Arc::clone(&queue_ptr);
-            let torrent_data_ptr_clone = Arc::clone(&torrent_data_ptr);
-            let download_status_ptr_clone = Arc::clone(&download_status_ptr);
-            let saved_pieces_dir_name_clone = saved_pieces_dir_name.clone();
+    let v2 = context.torrent_data.meta_version != MetaVersion::V1;
+    let ip_filter = options.ip_filter;
+    let refused = |ip: IpAddr| context.is_banned(ip) || ip_filter.read().unwrap().is_blocked(ip);
+
+    // Another client may already be using the port, then any free one will do
+    let listener = match PeerListener::bind(LISTEN_PORT).await {
+        Ok(listener) => listener,
+        Err(_) => PeerListener::bind(0).await?,
+    };
+    // uTP peers reach us on the same port over UDP. Without it we can still connect out over uTP.
+    let utp = match UtpSocket::bind(listener.port()).await {
+        Ok(utp) => utp,
+        Err(_) => UtpSocket::bind(0).await?,
+    };
 
-            if queue_len == 0 {
-                filewriter::compose_files(
-                    &*torrent_data_ptr,
-                    saved_pieces_dir_name.to_string().clone(),
-                )?;
-                // filewriter::remove_directory(&saved_pieces_dir_name.to_string());
-                println!("Success!");
-                for byte in info_hash {
-                    print!("{} ", byte);
-                }
-                return Ok(());
-            } else if queue_len < 10 {
-                create_download_worker(
-                    peer_clone,
-                    info_hash_clone,
-                    peer_id_clone,
-                    piece_size,
-                    bitfield_expected_length,
-                    queue_ptr_clone,
-                    torrent_data_ptr_clone,
-                    download_status_ptr_clone,
-                    saved_pieces_dir_name_clone,
+    let tracker_client = match options.tracker_client {
+        Some(tracker_client) => tracker_client,
+        None => Arc::new(TrackerClient::new(&proxies).await?),
+    };
+    // Trackers are told what this run transferred
+    let (downloaded_before, uploaded_before) = context.throttles.torrent.totals();
+    let transferred = Arc::new(Transferred::default());
+    let mut counted_pieces = None;
+    let mut scheduler = AnnounceScheduler::start(
+        tracker_client,
+        &context.torrent_data,
+        info_hashes
+            .iter()
+            .map(|info_hash| {
+                AnnounceRequest::new(
+                    &context.torrent_data,
+                    info_hash.clone(),
+                    peer_id.clone(),
+                    listener.port(),
                 )
-                .await;
-            } else {
-                workers.push({
-                    create_download_worker(
-                        peer_clone,
-                        info_hash_clone,
-                        peer_id_clone,
-                        piece_size,
-                        bitfield_expected_length,
-                        queue_ptr_clone,
-                        torrent_data_ptr_clone,
-                        download_status_ptr_clone,
-                        saved_pieces_dir_name_clone,
-                    )
-                });
-            }
-        }
-
-        futures::future::join_all(workers).await;
-
-        {
-            let download_status = download_status_ptr.lock().unwrap();
-
-            anyhow::ensure!(
-                download_status.pieces_downloaded != current_progress,
-                "Seems like there is no available leechers/seeders. Aborting."
-            );
+            })
+            .collect(),
+        Arc::clone(&transferred),
+    );
+    // BEP 27: private torrents only get peers from their trackers. Multicast can't go through
+    // a proxy.
+    let mut local_discovery = if context.torrent_data.private || proxies.force {
+        LocalDiscovery::start_on(&[], Vec::new(), listener.port())
+    } else {
+        LocalDiscovery::start(info_hashes.clone(), listener.port())
+    };
+    let mut connections =
+        ConnectionManager::new(Arc::clone(&options.connections), options.max_connections);
+    let mut retry = tokio::time::interval(RETRY_INTERVAL);
+    // Dropping the set stops the workers, when the download is stopped from outside
+    let mut workers = JoinSet::new();
+
+    let web_seed_client = web_seed::client(proxies.peers.as_ref())?;
+    for url in &context.torrent_data.url_list {
+        if !web_seed::is_supported(url) {
+            println!("Skipping web seed {}: only HTTP(S) is supported", url);
+            continue;
         }
+        let client = web_seed_client.clone();
+        let url = url.clone();
+        let context = Arc::clone(&context);
+        workers.spawn(async move {
+            web_seed::run(&client, &url, &context).await;
+            None
+        });
     }
-}
-
-async fn create_download_worker(
-    peer: String,
-    info_hash: Vec<u8>,
-    peer_id: Vec<u8>,
-    piece_size: usize,
-    expected_length: usize,
-    queue_ptr: Arc<Mutex<VecDeque<usize>>>,
-    torrent_data_ptr: Arc<torrent_data_extractor::TorrentData>,
-    download_status_ptr: Arc<Mutex<download_status::DownloadStatus>>,
-    saved_pieces_dir_name: String,
-) {
-    let mut connection;
-    if let Ok(peer_connection) = handshake::perform_handshake(peer, info_hash, peer_id, None).await
-    {
-        connection = peer_connection;
-    } else {
-        return;
+    for url in &context.torrent_data.http_seeds {
+        let client = web_seed_client.clone();
+        let url = url.clone();
+        let info_hash = info_hash.clone();
+        let context = Arc::clone(&context);
+        workers.spawn(async move {
+            http_seed::run(&client, &url, &info_hash, &context).await;
+            None
+        });
     }
 
-    /*connection
-        .set_read_timeout(Some(time::Duration::new(20, 0)))
-        .expect("set_read_timeout call failed");
-    connection
-        .set_write_timeout(Some(time::Duration::new(10, 0)))
-        .expect("set_write_timeout call failed");*/
-
-    let bitfield;
-    match bitfields::parse_bitfield(&mut connection, expected_length).await {
-        Ok(returned_bitfield) => {
-            bitfield = returned_bitfield;
+    let progress = options.progress;
+    let mut read_cursor = options.read_cursor;
+    let complete_at_start = {
+        let download_status = context.download_status.lock().unwrap();
+        download_status.pieces_downloaded == download_status.total_pieces
+    };
+    loop {
+        let (pieces_downloaded, total_pieces) = {
+            let download_status = context.download_status.lock().unwrap();
+            (
+                download_status.pieces_downloaded,
+                download_status.total_pieces,
+            )
+        };
+        progress.pieces.store(pieces_downloaded, Ordering::Relaxed);
+        progress.total_pieces.store(total_pieces, Ordering::Relaxed);
+        progress
+            .peers
+            .store(connections.connected(), Ordering::Relaxed);
+        let (downloaded, uploaded) = context.throttles.torrent.totals();
+        transferred.downloaded.store(
+            downloaded.saturating_sub(downloaded_before),
+            Ordering::Relaxed,
+        );
+        transferred
+            .uploaded
+            .store(uploaded.saturating_sub(uploaded_before), Ordering::Relaxed);
+        if counted_pieces != Some(pieces_downloaded) {
+            counted_pieces = Some(pieces_downloaded);
+            transferred
+                .left
+                .store(bytes_left(&context, &piece_priorities), Ordering::Relaxed);
         }
-        Err(err) => {
-            println!("{:?}", err);
-            return;
+        if pieces_downloaded == total_pieces {
+            filewriter::compose_files(
+                &context.torrent_data,
+                context.saved_pieces_dir_name.clone(),
+                &file_priorities
+                    .iter()
+                    .map(|priority| *priority != FilePriority::Skip)
+                    .collect::<Vec<_>>(),
+            )?;
+            // filewriter::remove_directory(&saved_pieces_dir_name.to_string());
+            println!("Success!");
+            for byte in info_hash {
+                print!("{} ", byte);
+            }
+            scheduler.stop(!complete_at_start).await;
+            return Ok(());
         }
-    }
-
-    if let Err(_) = connection.write_all(&messages::create_unchoke_msg()).await {
-        return;
-    }
-
-    if let Err(_) = connection
-        .write_all(&messages::create_interested_msg())
-        .await
-    {
-        return;
-    }
-
-    let mut index_opt = { queue_ptr.lock().unwrap().pop_front() };
-
-    let mut index;
-    let mut fails = 0;
-    let mut buffer_overlow = false;
-    let mut times_choked: u8 = 0;
 
-    while index_opt.is_some() {
-        index = index_opt.unwrap();
-
-        if bitfield[index / 8] & (1 << 7 - index % 8) == 0 {
-            // the case when peer doesn't have this index piece
-            index_opt = {
-                let mut queue = queue_ptr.lock().unwrap();
-                queue.push_back(index);
-                queue.pop_front()
-            };
-
-            fails += 1;
-            if fails == 5 {
-                let mut queue = queue_ptr.lock().unwrap();
-                queue.push_back(index);
-                return;
+        tokio::select! {
+            announced = scheduler.next_peers() => {
+                let announced =
+                    announced.ok_or(anyhow::anyhow!("Announce scheduler stopped"))?;
+                let peers: Vec<_> = announced
+                    .peers
+                    .into_iter()
+                    .filter(|peer| !refused(peer.ip()))
+                    .collect();
+                connections.add_peers(&announced.info_hash, &announced.peer_id, &peers);
             }
-        } else {
-            // downloading piece
-            let mut piece = Vec::with_capacity(piece_size);
-            let number_of_blocks: u32 =
-                (piece_size / BLOCK_SIZE) as u32 + (piece_size % BLOCK_SIZE != 0) as u32;
-            let mut piece_msg: [u8; BLOCK_SIZE + 18] = [0; BLOCK_SIZE + 18];
-
-            for i in 0..number_of_blocks {
-                if let Err(_) = connection
-                    .write_all(&messages::create_request_msg(
-                        index as u32,
-                        i * (BLOCK_SIZE as u32),
-                        BLOCK_SIZE as u32,
-                    ))
-                    .await
-                {
-                    let mut queue = queue_ptr.lock().unwrap();
-                    queue.push_back(index);
-                    return;
+            Some((swarm_info_hash, peer)) = local_discovery.next_peer() => {
+                if !refused(peer.ip()) {
+                    connections.add_peers(&swarm_info_hash, &peer_id, &[peer]);
                 }
-
-                let mut block: Vec<u8> = Vec::with_capacity(BLOCK_SIZE);
-                let mut bytes_got = 0;
-                let mut current_message: Vec<u8> = Vec::with_capacity(BLOCK_SIZE + 18);
-
-                let mut counter: u8 = 0;
-
-                loop {
-                    counter += 1;
-                    if counter == 21 {
-                        // too slow download
-                        let mut queue = queue_ptr.lock().unwrap();
-                        queue.push_back(index);
-                        return;
-                    }
-
-                    let bytes_got_this_iter;
-
-                    match connection.read(&mut piece_msg).await {
-                        Ok(number_of_bytes) => bytes_got_this_iter = number_of_bytes,
-                        Err(e) => {
-                            let mut queue = queue_ptr.lock().unwrap();
-                            queue.push_back(index);
-                            println!("{:?}", e);
-                            return;
-                        }
-                    }
-                    bytes_got += bytes_got_this_iter;
-
-                    for i in 0..bytes_got_this_iter {
-                        current_message.push(piece_msg[i])
-                    }
-
-                    if bytes_got == 5 || bytes_got == BLOCK_SIZE + 13 {
-                        let choked;
-                        let result;
-
-                        if let Ok((choked_result, bytes)) =
-                            messages::read_message(current_message[0..bytes_got].to_vec())
-                        {
-                            choked = choked_result;
-                            result = bytes;
-                        } else {
-                            let mut queue = queue_ptr.lock().unwrap();
-                            queue.push_back(index);
-                            return;
-                        }
-
-                        if choked {
-                            times_choked += 1;
-                            if times_choked == 4 {
-                                let mut queue = queue_ptr.lock().unwrap();
-                                queue.push_back(index);
-                                return;
-                            }
-                        }
-
-                        if let Some(bytes) = result {
-                            for byte in bytes {
-                                block.push(byte);
-                            }
-                        }
-                        break;
-                    } else if bytes_got == BLOCK_SIZE + 18 {
-                        let choked;
-
-                        if let Ok((choked_result, _)) =
-                            messages::read_message(current_message[0..5].to_vec())
-                        {
-                            choked = choked_result;
-                        } else {
-                            let mut queue = queue_ptr.lock().unwrap();
-                            queue.push_back(index);
-                            return;
-                        }
-
-                        if choked {
-                            times_choked += 1;
-                            if times_choked == 4 {
-                                let mut queue = queue_ptr.lock().unwrap();
-                                queue.push_back(index);
-                                return;
-                            }
-                        }
-
-                        let choked;
-                        let result;
-
-                        if let Ok((choked_result, bytes)) =
-                            messages::read_message(current_message[5..].to_vec())
-                        {
-                            choked = choked_result;
-                            result = bytes;
-                        } else {
-                            let mut queue = queue_ptr.lock().unwrap();
-                            queue.push_back(index);
-                            return;
-                        }
-
-                        if choked {
-                            times_choked += 1;
-                            if times_choked == 4 {
-                                let mut queue = queue_ptr.lock().unwrap();
-                                queue.push_back(index);
-                                return;
-                            }
-                        }
-
-                        if let Some(bytes) = result {
-                            for byte in bytes {
-                                block.push(byte);
-                            }
-                        }
-                        break;
-                    } else if bytes_got > BLOCK_SIZE + 18 {
-                        buffer_overlow = true;
-                        break;
+            }
+            Ok((stream, peer)) = accept_peer(&listener, &utp) => {
+                // Only connections we make go through the proxy
+                if refused(peer.ip()) || proxies.force {
+                    continue;
+                }
+                let permit = match connections.accept(peer) {
+                    Some(permit) => permit,
+                    None => continue,
+                };
+
+                let worker = create_download_worker(
+                    handshake::accept_handshake(
+                        stream,
+                        info_hashes.clone(),
+                        peer_id.clone(),
+                        v2,
+                        options.encryption,
+                    ),
+                    peer,
+                    Arc::clone(&context),
+                );
+                workers.spawn(async move {
+                    let downloaded = worker.await;
+                    drop(permit);
+                    Some((peer, downloaded))
+                });
+            }
+            Ok(()) = read_cursor.changed() => {
+                let cursor = *read_cursor.borrow_and_update();
+                context
+                    .picker
+                    .lock()
+                    .unwrap()
+                    .set_cursor(cursor, std::time::Instant::now());
+            }
+            _ = retry.tick() => {}
+            Some(finished) = workers.join_next() => {
+                // Web and HTTP seeds have no address
+                if let Some((peer, downloaded)) = finished? {
+                    connections.finished(peer, downloaded, Instant::now());
+                    if context.is_banned(peer.ip()) {
+                        connections.ban(peer.ip());
                     }
                 }
 
-                // if buffer_overlow {
-                //     break;
-                // }
-
-                for byte in block.iter() {
-                    piece.push(*byte);
+                if connections.needs_peers(Instant::now() + RETRY_INTERVAL) {
+                    println!("No connected peers left, waiting for trackers");
+                    scheduler.request_more_peers();
                 }
             }
+        }
 
-            if !check_piece(&piece, &(*torrent_data_ptr).pieces[index]) || buffer_overlow {
-                fails += 1;
-                let mut queue = queue_ptr.lock().unwrap();
-                queue.push_back(index);
-                if fails == 5 {
-                    return;
-                }
-            } else {
-                filewriter::save_piece(saved_pieces_dir_name.clone(), piece.clone(), index)
-                    .await
-                    .unwrap();
-                let mut download_status = download_status_ptr.lock().unwrap();
-                download_status.pieces_downloaded += 1;
-                let progress =
-                    100 * download_status.pieces_downloaded / download_status.total_pieces;
-                println!(
-                    "[{}/{}, {}%] Piece {} downloaded",
-                    download_status.pieces_downloaded,
-                    download_status.total_pieces,
-                    progress,
-                    index
-                );
+        for attempt in connections.next_attempts(Instant::now()) {
+            let peer = attempt.peer;
+            if context.is_banned(peer.ip()) {
+                connections.finished(peer, 0, Instant::now());
+                connections.ban(peer.ip());
+                continue;
             }
-
-            buffer_overlow = false;
-            let mut queue = queue_ptr.lock().unwrap();
-            index_opt = queue.pop_front();
+            // The filter may have been reloaded since the peer was found, and may be again, so
+            // the peer is only put off like one that couldn't be reached
+            if ip_filter.read().unwrap().is_blocked(peer.ip()) {
+                connections.finished(peer, 0, Instant::now());
+                continue;
+            }
+            let half_open = attempt.half_open;
+            let handshake = handshake::perform_handshake(
+                peer,
+                attempt.info_hash,
+                attempt.peer_id,
+                v2,
+                options.encryption,
+                Some(utp.clone()),
+                proxies.peers.clone(),
+            );
+            let worker = create_download_worker(
+                async move {
+                    let connection = handshake.await;
+                    drop(half_open);
+                    connection
+                },
+                peer,
+                Arc::clone(&context),
+            );
+            let permit = attempt.connection;
+            workers.spawn(async move {
+                let downloaded = worker.await;
+                drop(permit);
+                Some((peer, downloaded))
+            });
         }
     }
 }
 
-fn check_piece(piece: &Vec<u8>, expected_hash: &Vec<u8>) -> bool {
-    let mut hasher = Sha1::new();
-    hasher.update(&piece);
-    let piece_hash = hasher.finalize();
-
-    for i in 0..20 {
-        if piece_hash[i] != expected_hash[i] {
-            return false;
+// Incoming peers reach us over TCP or uTP, both are handled alike
+async fn accept_peer(
+    listener: &PeerListener,
+    utp: &UtpSocket,
+) -> anyhow::Result<(BoxedPeerStream, SocketAddr)> {
+    tokio::select! {
+        accepted = listener.accept() => {
+            let (stream, peer) = accepted?;
+            Ok((Box::new(stream), peer))
+        }
+        accepted = utp.accept() => {
+            let (stream, peer) = accepted?;
+            Ok((Box::new(stream), peer))
         }
     }
+}
+
+// Bytes of the wanted pieces that aren't downloaded yet
+fn bytes_left(context: &DownloadContext, priorities: &[FilePriority]) -> u64 {
+    let picker = context.picker.lock().unwrap();
+    priorities
+        .iter()
+        .enumerate()
+        .filter(|(index, priority)| **priority != FilePriority::Skip && !picker.is_finished(*index))
+        .map(|(index, _)| context.torrent_data.piece_size(index) as u64)
+        .sum()
+}
 
-    true
+// Outgoing and incoming connections only differ in how the handshake is done
+async fn create_download_worker(
+    connection: impl Future<Output = anyhow::Result<(BoxedPeerStream, Handshake)>>,
+    peer: SocketAddr,
+    context: Arc<DownloadContext>,
+) -> u64 {
+    match connection.await {
+        Ok((stream, handshake)) => worker::run(stream, handshake, peer, &context).await,
+        Err(_) => 0,
+    }
 }
@@ -0,0 +1,470 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use rand::prelude::*;
+
+use crate::p2p::bitfields::Bitfield;
+use crate::torrent_file_handler::torrent_data_extractor::TorrentData;
+
+// Pieces ahead of the read cursor that streaming downloads first
+const STREAMING_WINDOW: usize = 16;
+// Each piece further from the cursor gets this much more time
+const PIECE_DEADLINE_STEP: Duration = Duration::from_secs(1);
+// While streaming, every n-th pick goes to the rarest piece so the swarm doesn't lose it
+const RARE_PICK_INTERVAL: u64 = 4;
+// Peers that may work on an overdue piece at the same time
+const MAX_PEERS_PER_PIECE: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownloadMode {
+    // Random order, which spreads the pieces over the swarm best
+    Random,
+    // Piece by piece from the start
+    Sequential,
+    // The pieces right after the read cursor first, rare ones in the background
+    Streaming,
+}
+
+impl std::str::FromStr for DownloadMode {
+    type Err = anyhow::Error;
+
+    fn from_str(mode: &str) -> anyhow::Result<DownloadMode> {
+        match mode {
+            "random" => Ok(DownloadMode::Random),
+            "sequential" => Ok(DownloadMode::Sequential),
+            "streaming" => Ok(DownloadMode::Streaming),
+            _ => anyhow::bail!("Unknown download mode {}", mode),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FilePriority {
+    Skip,
+    Low,
+    Normal,
+    High,
+}
+
+impl std::str::FromStr for FilePriority {
+    type Err = anyhow::Error;
+
+    fn from_str(priority: &str) -> anyhow::Result<FilePriority> {
+        match priority {
+            "skip" => Ok(FilePriority::Skip),
+            "low" => Ok(FilePriority::Low),
+            "normal" => Ok(FilePriority::Normal),
+            "high" => Ok(FilePriority::High),
+            _ => anyhow::bail!("Unknown file priority {}", priority),
+        }
+    }
+}
+
+impl std::fmt::Display for FilePriority {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            FilePriority::Skip => "skip",
+            FilePriority::Low => "low",
+            FilePriority::Normal => "normal",
+            FilePriority::High => "high",
+        };
+        f.write_str(name)
+    }
+}
+
+// A piece shared by two files gets the higher priority of them, so it is only skipped when all
+// of its files are
+pub fn piece_priorities(torrent_data: &TorrentData, files: &[FilePriority]) -> Vec<FilePriority> {
+    let mut priorities = vec![FilePriority::Skip; torrent_data.piece_count()];
+    for (index, priority) in files.iter().enumerate() {
+        // Padding only shares pieces with the file before it
+        if torrent_data.files[index].attributes.padding {
+            continue;
+        }
+        for piece in torrent_data.file_pieces(index) {
+            priorities[piece] = priorities[piece].max(*priority);
+        }
+    }
+    priorities
+}
+
+/*
+ *   Decides which piece a worker downloads next. Pieces are pending until a worker picks them,
+ *   in progress until it finishes or gives them back, then finished. Pieces of skipped files
+ *   are never pending, higher priorities go first in every mode but streaming's window.
+ *
+ *   Streaming reads the file as it arrives: the cursor is where the reader is, moved by seeks and
+ *   past the pieces that arrive, and the window after it has deadlines. A piece which misses its
+ *   deadline may be picked by a second peer, whichever finishes first wins.
+ */
+pub struct PiecePicker {
+    mode: DownloadMode,
+    // In the order they are downloaded: shuffled for random mode, ascending otherwise
+    pending: VecDeque<usize>,
+    priorities: Vec<FilePriority>,
+    in_progress: HashMap<usize, u32>,
+    finished: Bitfield,
+    // How many connected peers have each piece
+    availability: Vec<u32>,
+    cursor: usize,
+    deadlines: HashMap<usize, Instant>,
+    picks: u64,
+}
+
+impl PiecePicker {
+    pub fn new(priorities: Vec<FilePriority>, mode: DownloadMode) -> PiecePicker {
+        let pieces = priorities.len();
+        let mut pending: VecDeque<usize> = (0..pieces)
+            .filter(|index| priorities[*index] != FilePriority::Skip)
+            .collect();
+        if mode == DownloadMode::Random {
+            pending.make_contiguous().shuffle(&mut rand::thread_rng());
+        }
+        let mut picker = PiecePicker {
+            mode,
+            pending,
+            priorities,
+            in_progress: HashMap::new(),
+            finished: Bitfield::empty(pieces),
+            availability: vec![0; pieces],
+            cursor: 0,
+            deadlines: HashMap::new(),
+            picks: 0,
+        };
+        picker.advance_cursor(Instant::now());
+        picker
+    }
+
+    pub fn pending(&self) -> impl Iterator<Item = usize> + '_ {
+        self.pending.iter().copied()
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    pub fn is_finished(&self, index: usize) -> bool {
+        self.finished.has_piece(index)
+    }
+
+    pub fn add_peer_piece(&mut self, index: usize) {
+        if let Some(count) = self.availability.get_mut(index) {
+            *count += 1;
+        }
+    }
+
+    pub fn add_peer(&mut self, pieces: &Bitfield) {
+        for index in 0..self.availability.len() {
+            if pieces.has_piece(index) {
+                self.availability[index] += 1;
+            }
+        }
+    }
+
+    pub fn remove_peer(&mut self, pieces: &Bitfield) {
+        for index in 0..self.availability.len() {
+            if pieces.has_piece(index) {
+                self.availability[index] = self.availability[index].saturating_sub(1);
+            }
+        }
+    }
+
+    // Suggested pieces are only followed in random mode, the others have their own order
+    pub fn pick(
+        &mut self,
+        suggested: &VecDeque<usize>,
+        can_request: impl Fn(usize) -> bool,
+        now: Instant,
+    ) -> Option<usize> {
+        self.picks += 1;
+        let index = match self.mode {
+            DownloadMode::Random => self.most_important(
+                suggested
+                    .iter()
+                    .copied()
+                    .filter(|index| self.pending.contains(index))
+                    .chain(self.pending())
+                    .filter(|index| can_request(*index)),
+            ),
+            DownloadMode::Sequential => {
+                self.most_important(self.pending().filter(|index| can_request(*index)))
+            }
+            DownloadMode::Streaming => {
+                let rare_pick = self.picks.is_multiple_of(RARE_PICK_INTERVAL);
+                let window = self.cursor..self.cursor + STREAMING_WINDOW;
+                let in_window = |index: usize| window.contains(&index);
+                let rarest = |only_outside_window: bool| {
+                    self.pending()
+                        .filter(|index| !(only_outside_window && in_window(*index)))
+                        .filter(|index| can_request(*index))
+                        .min_by_key(|index| {
+                            (Reverse(self.priorities[*index]), self.availability[*index])
+                        })
+                };
+                let urgent = || {
+                    self.pending()
+                        .find(|index| in_window(*index) && can_request(*index))
+                        .or_else(|| self.overdue(now, &can_request))
+                };
+                if rare_pick {
+                    rarest(true).or_else(urgent)
+                } else {
+                    urgent().or_else(|| rarest(false))
+                }
+            }
+        }?;
+
+        self.pending.retain(|pending| *pending != index);
+        *self.in_progress.entry(index).or_insert(0) += 1;
+        Some(index)
+    }
+
+    // The first of the pieces with the highest priority
+    fn most_important(&self, pieces: impl Iterator<Item = usize>) -> Option<usize> {
+        pieces.min_by_key(|index| Reverse(self.priorities[*index]))
+    }
+
+    fn overdue(&self, now: Instant, can_request: impl Fn(usize) -> bool) -> Option<usize> {
+        self.in_progress
+            .iter()
+            .filter(|(index, peers)| **peers < MAX_PEERS_PER_PIECE && can_request(**index))
+            .filter_map(|(index, _)| Some((*index, *self.deadlines.get(index)?)))
+            .filter(|(_, deadline)| *deadline <= now)
+            .min_by_key(|(_, deadline)| *deadline)
+            .map(|(index, _)| index)
+    }
+
+    // A worker gave up on the piece (or it failed the hash check)
+    pub fn put_back(&mut self, index: usize) {
+        if let Some(peers) = self.in_progress.get_mut(&index) {
+            *peers -= 1;
+            if *peers > 0 {
+                return;
+            }
+            self.in_progress.remove(&index);
+        }
+        if self.finished.has_piece(index) || self.pending.contains(&index) {
+            return;
+        }
+        match self.mode {
+            DownloadMode::Random => self.pending.push_back(index),
+            _ => {
+                let position = self.pending.partition_point(|pending| *pending < index);
+                self.pending.insert(position, index);
+            }
+        }
+    }
+
+    // False if another peer finished the piece first
+    pub fn finish(&mut self, index: usize, now: Instant) -> bool {
+        if let Some(peers) = self.in_progress.get_mut(&index) {
+            *peers -= 1;
+            if *peers == 0 {
+                self.in_progress.remove(&index);
+            }
+        }
+        if self.finished.has_piece(index) {
+            return false;
+        }
+        self.finished.set_piece(index).unwrap();
+        self.pending.retain(|pending| *pending != index);
+        self.deadlines.remove(&index);
+        self.advance_cursor(now);
+        true
+    }
+
+    // The reader moved to `index`. The old window's deadlines go, the pieces after `index` get new
+    // ones.
+    pub fn set_cursor(&mut self, index: usize, now: Instant) {
+        self.cursor = index.min(self.priorities.len());
+        self.deadlines.clear();
+        self.advance_cursor(now);
+    }
+
+    // Pieces which are done or skipped don't hold up reading
+    fn advance_cursor(&mut self, now: Instant) {
+        while self.cursor < self.priorities.len()
+            && (self.finished.has_piece(self.cursor)
+                || self.priorities[self.cursor] == FilePriority::Skip)
+        {
+            self.cursor += 1;
+        }
+        self.update_deadlines(now);
+    }
+
+    // Pieces entering the window get deadlines further out the further they are from the cursor
+    fn update_deadlines(&mut self, now: Instant) {
+        if self.mode != DownloadMode::Streaming {
+            return;
+        }
+        let end = (self.cursor + STREAMING_WINDOW).min(self.availability.len());
+        for index in self.cursor..end {
+            if !self.finished.has_piece(index) && self.priorities[index] != FilePriority::Skip {
+                let distance = (index - self.cursor + 1) as u32;
+                self.deadlines
+                    .entry(index)
+                    .or_insert(now + PIECE_DEADLINE_STEP * distance);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normal(pieces: usize) -> Vec<FilePriority> {
+        vec![FilePriority::Normal; pieces]
+    }
+
+    fn pick_all(picker: &mut PiecePicker, now: Instant) -> Vec<usize> {
+        std::iter::from_fn(|| picker.pick(&VecDeque::new(), |_| true, now)).collect()
+    }
+
+    #[test]
+    fn sequential_order() {
+        let mut picker = PiecePicker::new(normal(5), DownloadMode::Sequential);
+        let now = Instant::now();
+        assert_eq!(
+            picker.pick(&VecDeque::from(vec![3]), |_| true, now),
+            Some(0)
+        );
+        assert_eq!(
+            picker.pick(&VecDeque::new(), |index| index != 1, now),
+            Some(2)
+        );
+        picker.put_back(0);
+        assert_eq!(pick_all(&mut picker, now), vec![0, 1, 3, 4]);
+        assert!(!picker.has_pending());
+    }
+
+    #[test]
+    fn random_order_follows_suggestions() {
+        let mut picker = PiecePicker::new(normal(50), DownloadMode::Random);
+        let now = Instant::now();
+        assert_eq!(
+            picker.pick(&VecDeque::from(vec![7]), |_| true, now),
+            Some(7)
+        );
+        let mut picked = pick_all(&mut picker, now);
+        picked.sort_unstable();
+        assert_eq!(
+            picked,
+            (0..50).filter(|index| *index != 7).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn streaming_window_and_rare_pieces() {
+        let pieces = STREAMING_WINDOW * 3;
+        let mut picker = PiecePicker::new(normal(pieces), DownloadMode::Streaming);
+        picker.add_peer(&Bitfield::full(pieces));
+        picker.add_peer(&Bitfield::full(pieces));
+        // One of the peers lost the last piece
+        let mut rare = Bitfield::empty(pieces);
+        rare.set_piece(pieces - 1).unwrap();
+        picker.remove_peer(&rare);
+
+        let now = Instant::now();
+        let picked = pick_all(&mut picker, now);
+        assert_eq!(&picked[..5], &[0, 1, 2, pieces - 1, 3]);
+        assert_eq!(picked.len(), pieces);
+
+        // Finishing the first piece moves the cursor and the window
+        assert!(picker.finish(0, now));
+        assert!(!picker.finish(0, now));
+        assert_eq!(picker.cursor, 1);
+        assert!(picker.deadlines.contains_key(&STREAMING_WINDOW));
+    }
+
+    #[test]
+    fn streaming_follows_a_moved_cursor() {
+        let pieces = STREAMING_WINDOW * 4;
+        let mut picker = PiecePicker::new(normal(pieces), DownloadMode::Streaming);
+        picker.add_peer(&Bitfield::full(pieces));
+        let now = Instant::now();
+        let any = VecDeque::new();
+        assert_eq!(picker.pick(&any, |_| true, now), Some(0));
+
+        // A seek past the window: the pieces from there on come first
+        let seek = STREAMING_WINDOW * 2;
+        picker.set_cursor(seek, now);
+        assert!(!picker.deadlines.contains_key(&1));
+        assert!(picker.deadlines.contains_key(&seek));
+        assert_eq!(picker.pick(&any, |_| true, now), Some(seek));
+        assert_eq!(
+            picker.pick(&any, |index| index != seek + 1, now),
+            Some(seek + 2)
+        );
+
+        // Finished pieces at the cursor move it on, pieces before it aren't waited for
+        assert!(picker.finish(seek, now));
+        assert_eq!(picker.cursor, seek + 1);
+        assert!(picker.finish(0, now));
+        assert_eq!(picker.cursor, seek + 1);
+
+        // Back to the start, which is done already. This is a pick for the rare pieces outside the
+        // window, the next one is in it again.
+        picker.set_cursor(0, now);
+        assert_eq!(picker.cursor, 1);
+        assert_eq!(picker.pick(&any, |_| true, now), Some(STREAMING_WINDOW + 1));
+        assert_eq!(picker.pick(&any, |_| true, now), Some(1));
+    }
+
+    #[test]
+    fn overdue_pieces_get_a_second_peer() {
+        let mut picker = PiecePicker::new(normal(2), DownloadMode::Streaming);
+        let now = Instant::now();
+        assert_eq!(
+            picker.pick(&VecDeque::new(), |index| index == 0, now),
+            Some(0)
+        );
+        assert_eq!(picker.pick(&VecDeque::new(), |index| index == 0, now), None);
+
+        let late = now + PIECE_DEADLINE_STEP * 2;
+        assert_eq!(
+            picker.pick(&VecDeque::new(), |index| index == 0, late),
+            Some(0)
+        );
+        assert_eq!(
+            picker.pick(&VecDeque::new(), |index| index == 0, late),
+            None
+        );
+
+        // The slower peer gives up after the piece is done, it doesn't come back
+        assert!(picker.finish(0, late));
+        assert!(picker.is_finished(0));
+        picker.put_back(0);
+        assert_eq!(picker.pending().collect::<Vec<_>>(), vec![1]);
+    }
+
+    #[test]
+    fn file_priorities() {
+        use crate::torrent_file_handler::torrent_data_extractor::File;
+        let file = |size| File {
+            path_to_file: vec!["file".to_string()],
+            size,
+            ..Default::default()
+        };
+        // Pieces of 10 bytes: the skipped file shares piece 1 with the first and piece 2 with
+        // the last file
+        let torrent_data = TorrentData {
+            pieces: vec![vec![0; 20]; 5],
+            piece_length: 10,
+            files: vec![file(15), file(10), file(0), file(25)],
+            ..Default::default()
+        };
+        use FilePriority::*;
+        let priorities = piece_priorities(&torrent_data, &[Low, Skip, High, High]);
+        assert_eq!(priorities, vec![Low, Low, High, High, High]);
+
+        let priorities = vec![Low, Skip, High, Normal, Skip, Low];
+        let mut picker = PiecePicker::new(priorities.clone(), DownloadMode::Sequential);
+        assert_eq!(pick_all(&mut picker, Instant::now()), vec![2, 3, 0, 5]);
+
+        let mut picker = PiecePicker::new(priorities, DownloadMode::Streaming);
+        assert!(picker.finish(0, Instant::now()));
+        assert_eq!(picker.cursor, 2);
+    }
+}
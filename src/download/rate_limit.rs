@@ -0,0 +1,347 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::time::Instant;
+
+/*
+ *   Bandwidth limits are token buckets at three levels: the session (every torrent), the torrent
+ *   and the peer. Traffic has to get tokens from the buckets of every level it belongs to.
+ *   Tokens are taken after the data is read, so a peer going over its rate is held back before it
+ *   reads again, and TCP pushes back on the sender.
+ *   Waiters queue on the bucket's async mutex, which is fair, so peers get their turns in order
+ *   and share a limited rate evenly.
+ */
+
+// At most a second worth of unused rate is saved up
+const BURST: Duration = Duration::from_secs(1);
+// Waits are cut in slices, so a changed rate applies soon
+const MAX_WAIT: Duration = Duration::from_millis(100);
+const CHECK_SCHEDULE_EVERY: Duration = Duration::from_secs(30);
+
+// Bytes per second, 0 is unlimited
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Limits {
+    pub download: u64,
+    pub upload: u64,
+}
+
+// Shared by every bucket made from it, so a change applies to all of them right away
+#[derive(Debug, Default)]
+pub struct RateLimit {
+    download: AtomicU64,
+    upload: AtomicU64,
+}
+
+impl RateLimit {
+    pub fn new(limits: Limits) -> RateLimit {
+        RateLimit {
+            download: AtomicU64::new(limits.download),
+            upload: AtomicU64::new(limits.upload),
+        }
+    }
+
+    pub fn get(&self) -> Limits {
+        Limits {
+            download: self.download.load(Ordering::Relaxed),
+            upload: self.upload.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn set(&self, limits: Limits) {
+        self.download.store(limits.download, Ordering::Relaxed);
+        self.upload.store(limits.upload, Ordering::Relaxed);
+    }
+}
+
+struct BucketState {
+    // Goes below zero when more than the saved up tokens is taken at once
+    tokens: f64,
+    last_refill: Instant,
+}
+
+struct TokenBucket {
+    state: tokio::sync::Mutex<BucketState>,
+}
+
+impl TokenBucket {
+    fn new() -> TokenBucket {
+        TokenBucket {
+            state: tokio::sync::Mutex::new(BucketState {
+                tokens: 0.0,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    async fn take(&self, bytes: usize, rate: &AtomicU64) {
+        if bytes == 0 {
+            return;
+        }
+        let mut state = self.state.lock().await;
+        loop {
+            let now = Instant::now();
+            let rate = rate.load(Ordering::Relaxed) as f64;
+            if rate == 0.0 {
+                state.tokens = 0.0;
+                state.last_refill = now;
+                return;
+            }
+            let elapsed = (now - state.last_refill).as_secs_f64();
+            state.tokens = (state.tokens + elapsed * rate).min(rate * BURST.as_secs_f64());
+            state.last_refill = now;
+
+            if state.tokens >= 0.0 {
+                state.tokens -= bytes as f64;
+                return;
+            }
+            let wait = Duration::from_secs_f64(-state.tokens / rate);
+            tokio::time::sleep(wait.min(MAX_WAIT)).await;
+        }
+    }
+}
+
+// One level of limits
+pub struct Throttle {
+    limit: Arc<RateLimit>,
+    download: TokenBucket,
+    upload: TokenBucket,
+    // Every byte that went through, for rates and statistics
+    downloaded_total: AtomicU64,
+    uploaded_total: AtomicU64,
+}
+
+impl Throttle {
+    pub fn new(limit: Arc<RateLimit>) -> Throttle {
+        Throttle {
+            limit,
+            download: TokenBucket::new(),
+            upload: TokenBucket::new(),
+            downloaded_total: AtomicU64::new(0),
+            uploaded_total: AtomicU64::new(0),
+        }
+    }
+
+    pub fn unlimited() -> Throttle {
+        Throttle::new(Arc::new(RateLimit::default()))
+    }
+
+    pub fn limit(&self) -> &Arc<RateLimit> {
+        &self.limit
+    }
+
+    // Downloaded and uploaded bytes
+    pub fn totals(&self) -> (u64, u64) {
+        (
+            self.downloaded_total.load(Ordering::Relaxed),
+            self.uploaded_total.load(Ordering::Relaxed),
+        )
+    }
+
+    async fn downloaded(&self, bytes: usize) {
+        self.downloaded_total
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.download.take(bytes, &self.limit.download).await;
+    }
+
+    async fn uploaded(&self, bytes: usize) {
+        self.uploaded_total
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.upload.take(bytes, &self.limit.upload).await;
+    }
+}
+
+// The limits one torrent is downloaded with
+pub struct Throttles {
+    pub session: Arc<Throttle>,
+    pub torrent: Arc<Throttle>,
+    // Every peer gets a bucket of its own with this rate
+    pub peer_limit: Arc<RateLimit>,
+    // Count message headers and non-payload messages too, not just piece data
+    pub include_overhead: Arc<AtomicBool>,
+}
+
+impl Default for Throttles {
+    fn default() -> Throttles {
+        Throttles {
+            session: Arc::new(Throttle::unlimited()),
+            torrent: Arc::new(Throttle::unlimited()),
+            peer_limit: Arc::new(RateLimit::default()),
+            include_overhead: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+impl Throttles {
+    pub fn peer(&self) -> PeerThrottle<'_> {
+        PeerThrottle {
+            own: Throttle::new(Arc::clone(&self.peer_limit)),
+            throttles: self,
+        }
+    }
+
+    // Web and HTTP seeds only have the torrent and session levels
+    pub async fn downloaded(&self, bytes: usize) {
+        self.torrent.downloaded(bytes).await;
+        self.session.downloaded(bytes).await;
+    }
+
+    fn counted(&self, payload: usize, overhead: usize) -> usize {
+        if self.include_overhead.load(Ordering::Relaxed) {
+            payload + overhead
+        } else {
+            payload
+        }
+    }
+}
+
+pub struct PeerThrottle<'a> {
+    own: Throttle,
+    throttles: &'a Throttles,
+}
+
+impl PeerThrottle<'_> {
+    pub async fn received(&self, payload: usize, overhead: usize) {
+        let bytes = self.throttles.counted(payload, overhead);
+        self.own.downloaded(bytes).await;
+        self.throttles.downloaded(bytes).await;
+    }
+
+    pub async fn sent(&self, payload: usize, overhead: usize) {
+        let bytes = self.throttles.counted(payload, overhead);
+        self.own.uploaded(bytes).await;
+        self.throttles.torrent.uploaded(bytes).await;
+        self.throttles.session.uploaded(bytes).await;
+    }
+}
+
+// Alternative limits used every day between `start` and `end`, in minutes after midnight UTC.
+// The window may wrap around midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LimitSchedule {
+    pub start: u32,
+    pub end: u32,
+    pub limits: Limits,
+}
+
+impl LimitSchedule {
+    pub fn is_active(&self, minute_of_day: u32) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minute_of_day)
+        } else {
+            minute_of_day >= self.start || minute_of_day < self.end
+        }
+    }
+}
+
+// Switches `limit` to the alternative limits when the schedule starts and back when it ends.
+// Limits changed by hand in between are kept until the next switch.
+pub async fn follow_schedule(limit: Arc<RateLimit>, schedule: LimitSchedule) {
+    let mut normal = None;
+    loop {
+        match (schedule.is_active(minute_of_day()), normal) {
+            (true, None) => {
+                normal = Some(limit.get());
+                limit.set(schedule.limits);
+            }
+            (false, Some(limits)) => {
+                limit.set(limits);
+                normal = None;
+            }
+            _ => {}
+        }
+        tokio::time::sleep(CHECK_SCHEDULE_EVERY).await;
+    }
+}
+
+fn minute_of_day() -> u32 {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0);
+    (secs / 60 % (24 * 60)) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn bucket_holds_traffic_to_its_rate() {
+        let throttle = Throttle::new(Arc::new(RateLimit::new(Limits {
+            download: 1000,
+            upload: 0,
+        })));
+        let start = Instant::now();
+        for _ in 0..5 {
+            throttle.downloaded(1000).await;
+        }
+        // The first kilobyte goes through at once, the others wait for their second
+        assert_eq!(start.elapsed().as_secs(), 4);
+
+        let start = Instant::now();
+        throttle.uploaded(1_000_000).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_changes_apply_at_once() {
+        let throttle = Throttle::new(Arc::new(RateLimit::new(Limits {
+            download: 10,
+            upload: 0,
+        })));
+        throttle.downloaded(100_000).await;
+        throttle.limit().set(Limits::default());
+        let start = Instant::now();
+        throttle.downloaded(1).await;
+        assert!(start.elapsed() <= MAX_WAIT);
+        assert_eq!(throttle.totals(), (100_001, 0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn peers_share_the_torrent_rate() {
+        let throttles = Throttles::default();
+        throttles.torrent.limit().set(Limits {
+            download: 1000,
+            upload: 0,
+        });
+        let peers: Vec<_> = (0..2).map(|_| throttles.peer()).collect();
+        let received = Arc::new(std::sync::Mutex::new(vec![0_usize; 2]));
+
+        let start = Instant::now();
+        let sessions = peers.iter().enumerate().map(|(index, peer)| {
+            let received = Arc::clone(&received);
+            async move {
+                while start.elapsed() < Duration::from_secs(10) {
+                    peer.received(100, 13).await;
+                    received.lock().unwrap()[index] += 100;
+                }
+            }
+        });
+        futures::future::join_all(sessions).await;
+
+        let received = received.lock().unwrap();
+        let total = received[0] + received[1];
+        assert!((10_000..=11_200).contains(&total), "{}", total);
+        assert!(received[0].abs_diff(received[1]) <= 200, "{:?}", received);
+    }
+
+    #[test]
+    fn schedule_windows() {
+        let schedule = LimitSchedule {
+            start: 9 * 60,
+            end: 18 * 60,
+            limits: Limits::default(),
+        };
+        assert!(schedule.is_active(12 * 60));
+        assert!(!schedule.is_active(18 * 60));
+        let overnight = LimitSchedule {
+            start: 22 * 60,
+            end: 6 * 60,
+            ..schedule
+        };
+        assert!(overnight.is_active(23 * 60));
+        assert!(overnight.is_active(60));
+        assert!(!overnight.is_active(12 * 60));
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::net::IpAddr;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use sha1::{Digest, Sha1};
+
+/*
+ *   Finds the peers sending us corrupt data. Every block of a piece is remembered with the peer
+ *   it came from. When the piece fails its hash check, every peer that sent part of it gets a
+ *   strike, and the hashes of their blocks are kept. Once the piece passes (from other peers or
+ *   the same ones), the kept blocks are compared with the good data: whoever sent a block that
+ *   differs is banned right away. Peers are known by their IP, as they reconnect from other ports.
+ *   Bans hold for every torrent of the session. They are written to a file and read back by the
+ *   next session.
+ */
+
+// Failed pieces a peer may have blocks in before it's banned without proof
+const MAX_STRIKES: u32 = 5;
+
+// Which peer sent which part of a piece
+pub type Provenance = Vec<(Range<usize>, IpAddr)>;
+
+// A block of a piece that failed its check
+struct Suspect {
+    range: Range<usize>,
+    ip: IpAddr,
+    hash: Vec<u8>,
+}
+
+// The peers banned in a session, shared by its torrents
+#[derive(Default)]
+pub struct BannedPeers {
+    banned: Mutex<HashSet<IpAddr>>,
+    // Where bans are kept between sessions, none keeps them in memory only
+    path: Option<PathBuf>,
+}
+
+impl BannedPeers {
+    // One IP per line. A missing file is an empty ban list.
+    pub fn load(path: PathBuf) -> anyhow::Result<BannedPeers> {
+        let banned = match fs::read_to_string(&path) {
+            Ok(contents) => contents
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(|line| {
+                    line.trim()
+                        .parse()
+                        .map_err(|err| anyhow::anyhow!("Wrong banned IP {}: {}", line, err))
+                })
+                .collect::<anyhow::Result<_>>()?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashSet::new(),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(BannedPeers {
+            banned: Mutex::new(banned),
+            path: Some(path),
+        })
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.banned.lock().unwrap().contains(&ip)
+    }
+
+    fn ban(&self, ip: IpAddr) {
+        if !self.banned.lock().unwrap().insert(ip) {
+            return;
+        }
+        println!("Banned {} for sending corrupt data", ip);
+        if let Some(path) = &self.path {
+            let saved = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{}", ip));
+            if let Err(err) = saved {
+                println!("Couldn't save ban of {}: {}", ip, err);
+            }
+        }
+    }
+}
+
+// The evidence is kept by torrent, as pieces are
+#[derive(Default)]
+pub struct SmartBan {
+    // By piece, the blocks of its failed downloads
+    suspects: HashMap<usize, Vec<Suspect>>,
+    strikes: HashMap<IpAddr, u32>,
+    banned: Arc<BannedPeers>,
+}
+
+impl SmartBan {
+    pub fn new(banned: Arc<BannedPeers>) -> SmartBan {
+        SmartBan {
+            banned,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.banned.is_banned(ip)
+    }
+
+    pub fn piece_failed(&mut self, index: usize, data: &[u8], provenance: &Provenance) {
+        let mut senders = HashSet::new();
+        let suspects = self.suspects.entry(index).or_default();
+        for (range, ip) in provenance {
+            suspects.push(Suspect {
+                range: range.clone(),
+                ip: *ip,
+                hash: Sha1::digest(&data[range.clone()]).to_vec(),
+            });
+            senders.insert(*ip);
+        }
+
+        for ip in senders {
+            let strikes = self.strikes.entry(ip).or_default();
+            *strikes += 1;
+            if *strikes >= MAX_STRIKES {
+                self.banned.ban(ip);
+            }
+        }
+    }
+
+    pub fn piece_passed(&mut self, index: usize, data: &[u8]) {
+        let suspects = match self.suspects.remove(&index) {
+            Some(suspects) => suspects,
+            None => return,
+        };
+        for suspect in suspects {
+            let good = data
+                .get(suspect.range.clone())
+                .is_some_and(|block| Sha1::digest(block)[..] == suspect.hash[..]);
+            if !good {
+                self.banned.ban(suspect.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, last])
+    }
+
+    #[test]
+    fn peer_with_differing_block_is_banned() {
+        let mut smart_ban = SmartBan::default();
+        let good = vec![1; 32];
+        let mut bad = good.clone();
+        bad[20] = 0;
+
+        // Two peers shared the piece, the second one lied
+        let provenance = vec![(0..16, ip(1)), (16..32, ip(2))];
+        smart_ban.piece_failed(3, &bad, &provenance);
+        assert!(!smart_ban.is_banned(ip(1)) && !smart_ban.is_banned(ip(2)));
+
+        smart_ban.piece_passed(3, &good);
+        assert!(!smart_ban.is_banned(ip(1)));
+        assert!(smart_ban.is_banned(ip(2)));
+        assert!(smart_ban.suspects.is_empty());
+    }
+
+    #[test]
+    fn repeated_failures_ban_without_proof() {
+        let mut smart_ban = SmartBan::default();
+        for index in 0..MAX_STRIKES as usize {
+            assert!(!smart_ban.is_banned(ip(1)));
+            smart_ban.piece_failed(index, &[0; 16], &vec![(0..16, ip(1))]);
+        }
+        assert!(smart_ban.is_banned(ip(1)));
+    }
+
+    #[test]
+    fn bans_are_persisted() {
+        let path = std::env::temp_dir().join(format!("rusty_torrent_bans_{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut smart_ban = SmartBan::new(Arc::new(BannedPeers::load(path.clone()).unwrap()));
+        smart_ban.piece_failed(0, &[0; 16], &vec![(0..16, "::1".parse().unwrap())]);
+        smart_ban.piece_passed(0, &[1; 16]);
+        smart_ban.banned.ban(ip(7));
+
+        let loaded = BannedPeers::load(path.clone()).unwrap();
+        assert!(loaded.is_banned("::1".parse().unwrap()));
+        assert!(loaded.is_banned(ip(7)));
+        assert!(!loaded.is_banned(ip(1)));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn bans_hold_for_every_torrent() {
+        let banned = Arc::new(BannedPeers::default());
+        let mut first = SmartBan::new(Arc::clone(&banned));
+        let second = SmartBan::new(Arc::clone(&banned));
+        first.piece_failed(0, &[0; 16], &vec![(0..16, ip(1))]);
+        first.piece_passed(0, &[1; 16]);
+        assert!(second.is_banned(ip(1)));
+        assert!(banned.is_banned(ip(1)));
+    }
+}
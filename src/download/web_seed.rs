@@ -0,0 +1,402 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+use reqwest::header::{CONTENT_RANGE, RANGE, RETRY_AFTER};
+use reqwest::StatusCode;
+
+use super::worker::{self, DownloadContext};
+use crate::p2p::bitfields::Bitfield;
+use crate::proxy::{self, HttpClient, Proxy};
+use crate::torrent_file_handler::torrent_data_extractor::TorrentData;
+
+/*
+ *   Web seeds (BEP 19): servers holding the torrent's files, from which pieces are fetched with
+ *   HTTP range requests. They have every piece and are picked from like any peer. HTTP seeds
+ *   (BEP 17) are driven the same way.
+ */
+
+const WEB_SEED_TIMEOUT: Duration = Duration::from_secs(60);
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+// Longest a busy server is waited for, whatever it asks
+const LONGEST_BUSY_DELAY: Duration = Duration::from_secs(60 * 60);
+const MAX_FAILS: u32 = 5;
+// Pieces peers gave up on come back to the picker, so we look again after a while
+const IDLE_DELAY: Duration = Duration::from_secs(1);
+
+pub fn is_supported(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+// Without compression, a range of a compressed body isn't the range we asked for
+pub fn client(proxy: Option<&Proxy>) -> anyhow::Result<HttpClient> {
+    let builder = reqwest::Client::builder()
+        .timeout(WEB_SEED_TIMEOUT)
+        .no_gzip();
+    proxy::http_client(builder, proxy)
+}
+
+// A busy server told us when to come back
+#[derive(Debug)]
+pub struct RetryAfter(pub Duration);
+
+impl std::fmt::Display for RetryAfter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Server is busy for {:?}", self.0)
+    }
+}
+
+impl std::error::Error for RetryAfter {}
+
+// The Retry-After header in seconds, its date form isn't used by seeds
+pub fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    let seconds = response.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    Some(Duration::from_secs(seconds.trim().parse().ok()?))
+}
+
+pub async fn run(client: &reqwest::Client, url: &str, context: &DownloadContext) {
+    run_seed(context, |index| {
+        fetch_piece(client, url, &context.torrent_data, index)
+    })
+    .await;
+}
+
+// Downloads pieces from a server until all of them are done, gives up after failing in a row.
+// Waiting for a busy server isn't a failure.
+pub async fn run_seed<F, Fut>(context: &DownloadContext, fetch: F)
+where
+    F: Fn(usize) -> Fut,
+    Fut: Future<Output = anyhow::Result<Vec<u8>>>,
+{
+    let pieces = Bitfield::full(context.torrent_data.piece_count());
+    context.picker.lock().unwrap().add_peer(&pieces);
+
+    let mut fails = 0;
+    let mut retry_delay = FIRST_RETRY_DELAY;
+    while fails < MAX_FAILS {
+        let picked = context.picker.lock().unwrap().pick(
+            &VecDeque::new(),
+            |index| context.can_check_piece(index),
+            Instant::now(),
+        );
+        let index = match picked {
+            Some(index) => index,
+            None if is_finished(context) => break,
+            None => {
+                tokio::time::sleep(IDLE_DELAY).await;
+                continue;
+            }
+        };
+
+        let stored = match fetch(index).await {
+            Ok(data) => {
+                context.throttles.downloaded(data.len()).await;
+                // Servers aren't banned
+                worker::store_piece(context, index, data, &Vec::new()).await
+            }
+            Err(err) => {
+                context.picker.lock().unwrap().put_back(index);
+                Err(err)
+            }
+        };
+        match stored {
+            Ok(true) => {
+                fails = 0;
+                retry_delay = FIRST_RETRY_DELAY;
+            }
+            Err(err) if err.is::<RetryAfter>() => {
+                let RetryAfter(delay) = err.downcast().unwrap();
+                tokio::time::sleep(busy_delay(delay)).await;
+            }
+            Ok(false) | Err(_) => {
+                fails += 1;
+                tokio::time::sleep(retry_delay).await;
+                retry_delay *= 2;
+            }
+        }
+    }
+
+    context.picker.lock().unwrap().remove_peer(&pieces);
+}
+
+fn busy_delay(delay: Duration) -> Duration {
+    delay.clamp(FIRST_RETRY_DELAY, LONGEST_BUSY_DELAY)
+}
+
+// The body, which must not be longer than `limit`. Read as it comes, so a server sending far more
+// than asked for is stopped early.
+async fn read_body(mut response: reqwest::Response, limit: usize) -> anyhow::Result<Vec<u8>> {
+    let mut body = Vec::with_capacity(limit);
+    while let Some(chunk) = response.chunk().await? {
+        anyhow::ensure!(
+            body.len() + chunk.len() <= limit,
+            "Server sent more than the {} bytes asked for",
+            limit
+        );
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+fn is_finished(context: &DownloadContext) -> bool {
+    let download_status = context.download_status.lock().unwrap();
+    download_status.pieces_downloaded == download_status.total_pieces
+}
+
+async fn fetch_piece(
+    client: &reqwest::Client,
+    url: &str,
+    torrent_data: &TorrentData,
+    index: usize,
+) -> anyhow::Result<Vec<u8>> {
+    let mut piece = Vec::with_capacity(torrent_data.piece_size(index));
+    for (file, range) in piece_ranges(torrent_data, index) {
+        // Padding files are zeros which servers don't have
+        if torrent_data.files[file].attributes.padding {
+            piece.resize(piece.len() + range.len(), 0);
+            continue;
+        }
+        let response = client
+            .get(file_url(url, torrent_data, file)?)
+            .header(RANGE, format!("bytes={}-{}", range.start, range.end - 1))
+            .send()
+            .await?;
+        if response.status() == StatusCode::SERVICE_UNAVAILABLE {
+            if let Some(delay) = retry_after(&response) {
+                return Err(RetryAfter(delay).into());
+            }
+        }
+        let response = response.error_for_status()?;
+        if response.status() == StatusCode::PARTIAL_CONTENT {
+            anyhow::ensure!(
+                response
+                    .headers()
+                    .get(CONTENT_RANGE)
+                    .is_none_or(|content_range| {
+                        content_range
+                            .as_bytes()
+                            .starts_with(format!("bytes {}-", range.start).as_bytes())
+                    }),
+                "Web seed sent the wrong range"
+            );
+        } else {
+            // A server ignoring the range sends the whole file, which is only what we asked for
+            // when the range is the whole file
+            let file_size = torrent_data.files[file].size;
+            anyhow::ensure!(
+                range.start == 0 && range.end == file_size,
+                "Web seed doesn't support ranges"
+            );
+        }
+        let data = read_body(response, range.len()).await?;
+        anyhow::ensure!(
+            data.len() == range.len(),
+            "Web seed sent {} bytes instead of {}",
+            data.len(),
+            range.len()
+        );
+        piece.extend_from_slice(&data);
+    }
+    Ok(piece)
+}
+
+// The parts of files a piece is made of, by file index and range inside the file
+fn piece_ranges(torrent_data: &TorrentData, index: usize) -> Vec<(usize, Range<usize>)> {
+    let piece_start = index * torrent_data.piece_length;
+    let piece_end = piece_start + torrent_data.piece_size(index);
+    let mut ranges = Vec::new();
+    for (file, file_data) in torrent_data.files.iter().enumerate() {
+        let file_start = torrent_data.file_offset(file);
+        let file_end = file_start + file_data.size;
+        let from = piece_start.max(file_start);
+        let to = piece_end.min(file_end);
+        if from < to {
+            ranges.push((file, from - file_start..to - file_start));
+        }
+    }
+    ranges
+}
+
+// A url ending with a slash is the directory holding the torrent: the file's path is added to it.
+// Otherwise it is the file itself, which only works for single file torrents.
+fn file_url(url: &str, torrent_data: &TorrentData, file: usize) -> anyhow::Result<String> {
+    let path = &torrent_data.files[file].path_to_file;
+    if !url.ends_with('/') && torrent_data.files.len() == 1 && path.len() == 1 {
+        return Ok(url.to_string());
+    }
+    let mut parsed = url::Url::parse(url)?;
+    parsed
+        .path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("Web seed url {} can't have a path", url))?
+        .pop_if_empty()
+        .extend(path);
+    Ok(parsed.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::fixtures;
+    use crate::filewriter;
+    use crate::torrent_file_handler::torrent_data_extractor::File;
+    use sha1::{Digest, Sha1};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn torrent_data(files: Vec<(&str, usize)>, piece_length: usize) -> TorrentData {
+        TorrentData {
+            piece_length,
+            files: files
+                .into_iter()
+                .map(|(name, size)| File {
+                    path_to_file: vec!["dir".to_string(), name.to_string()],
+                    size,
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn pieces_map_to_file_ranges() {
+        let torrent_data = torrent_data(vec![("a", 15), ("b", 0), ("c", 10), ("d", 5)], 10);
+        assert_eq!(piece_ranges(&torrent_data, 0), vec![(0, 0..10)]);
+        assert_eq!(piece_ranges(&torrent_data, 1), vec![(0, 10..15), (2, 0..5)]);
+        assert_eq!(piece_ranges(&torrent_data, 2), vec![(2, 5..10), (3, 0..5)]);
+    }
+
+    #[test]
+    fn file_urls() {
+        let multi_file = torrent_data(vec![("a b", 1), ("c", 1)], 10);
+        assert_eq!(
+            file_url("http://seed.example/files/", &multi_file, 0).unwrap(),
+            "http://seed.example/files/dir/a%20b"
+        );
+        assert_eq!(
+            file_url("http://seed.example/files", &multi_file, 1).unwrap(),
+            "http://seed.example/files/dir/c"
+        );
+
+        let mut single_file = torrent_data(vec![], 10);
+        single_file.files.push(File {
+            path_to_file: vec!["name".to_string()],
+            size: 1,
+            ..Default::default()
+        });
+        assert_eq!(
+            file_url("http://seed.example/files/", &single_file, 0).unwrap(),
+            "http://seed.example/files/name"
+        );
+        assert_eq!(
+            file_url("http://seed.example/other", &single_file, 0).unwrap(),
+            "http://seed.example/other"
+        );
+    }
+
+    // Serves `files` by path with range support, a `flaky` one fails the first request
+    async fn file_server(files: HashMap<String, Vec<u8>>, flaky: bool) -> String {
+        let requests = AtomicUsize::new(0);
+        let addr = fixtures::http_server(move |request| {
+            let path = request.split(' ').nth(1).unwrap();
+            let range = request
+                .lines()
+                .find_map(|line| {
+                    line.to_lowercase()
+                        .strip_prefix("range: bytes=")
+                        .map(str::to_string)
+                })
+                .unwrap();
+            let (start, end) = range.split_once('-').unwrap();
+            let (start, end): (usize, usize) = (start.parse().unwrap(), end.parse().unwrap());
+
+            let failing = requests.fetch_add(1, Ordering::SeqCst) == 0 && flaky;
+            match files.get(path) {
+                Some(data) if !failing => {
+                    let content_range =
+                        format!("Content-Range: bytes {}-{}/{}\r\n", start, end, data.len());
+                    fixtures::http_response(
+                        "206 Partial Content",
+                        &content_range,
+                        &data[start..=end],
+                    )
+                }
+                Some(_) => fixtures::http_response("503 Service Unavailable", "", b""),
+                None => fixtures::http_response("404 Not Found", "", b""),
+            }
+        })
+        .await;
+        format!("http://{}/seed/", addr)
+    }
+
+    async fn download_from_web_seed(name: &str, flaky: bool) {
+        let data: Vec<u8> = (0..1000).map(|i| (i * 13 % 256) as u8).collect();
+        let mut torrent_data = torrent_data(vec![("a", 250), ("b", 0), ("c", 700), ("d", 50)], 64);
+        torrent_data.pieces = data
+            .chunks(64)
+            .map(|piece| Sha1::digest(piece).to_vec())
+            .collect();
+        let mut files = HashMap::new();
+        let mut offset = 0;
+        for file in &torrent_data.files {
+            let path = format!("/seed/dir/{}", file.path_to_file[1]);
+            files.insert(path, data[offset..offset + file.size].to_vec());
+            offset += file.size;
+        }
+        let url = file_server(files, flaky).await;
+
+        let dir =
+            std::env::temp_dir().join(format!("rusty_torrent_{}_{}", name, std::process::id()));
+        let dir = dir.to_str().unwrap().to_string();
+        filewriter::create_directory(&dir).await.unwrap();
+        let context = fixtures::context(torrent_data, &dir);
+
+        run(&client(None).unwrap(), &url, &context).await;
+        assert!(is_finished(&context));
+        for (index, piece) in data.chunks(64).enumerate() {
+            assert_eq!(std::fs::read(format!("{}/.{}", dir, index)).unwrap(), piece);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn servers_ignoring_ranges_give_whole_files_only() {
+        let data = vec![7; 100];
+        let body = data.clone();
+        let addr =
+            fixtures::http_server(move |_| fixtures::http_response("200 OK", "", &body)).await;
+        let url = format!("http://{}/seed/", addr);
+        let client = client(None).unwrap();
+
+        // A piece holding all of "a" is fine, one starting inside "b" isn't
+        let whole = torrent_data(vec![("a", 100), ("b", 100)], 100);
+        assert_eq!(fetch_piece(&client, &url, &whole, 0).await.unwrap(), data);
+        let inside = torrent_data(vec![("a", 50), ("b", 100)], 100);
+        let err = fetch_piece(&client, &url, &inside, 1).await.unwrap_err();
+        assert_eq!(err.to_string(), "Web seed doesn't support ranges");
+        // A file larger than the torrent says is refused once it runs over
+        let shorter = torrent_data(vec![("a", 60), ("b", 100)], 60);
+        assert!(fetch_piece(&client, &url, &shorter, 0).await.is_err());
+    }
+
+    #[test]
+    fn busy_delays_are_kept_in_bounds() {
+        assert_eq!(busy_delay(Duration::ZERO), FIRST_RETRY_DELAY);
+        assert_eq!(busy_delay(Duration::from_secs(30)), Duration::from_secs(30));
+        assert_eq!(
+            busy_delay(Duration::from_secs(u64::MAX)),
+            LONGEST_BUSY_DELAY
+        );
+    }
+
+    #[tokio::test]
+    async fn pieces_from_a_web_seed() {
+        download_from_web_seed("web_seed", false).await;
+    }
+
+    #[tokio::test]
+    async fn web_seed_errors_are_retried() {
+        download_from_web_seed("flaky_web_seed", true).await;
+    }
+}
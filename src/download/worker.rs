@@ -0,0 +1,1042 @@
+use std::collections::{HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use sha1::{Digest, Sha1};
+use tokio::io::AsyncWriteExt;
+use tokio::time::Instant;
+
+use super::download_status::DownloadStatus;
+use super::piece_picker::PiecePicker;
+use super::rate_limit::{PeerThrottle, Throttles};
+use super::smart_ban::{Provenance, SmartBan};
+use crate::filewriter;
+use crate::p2p::bitfields::Bitfield;
+use crate::p2p::handshake::Handshake;
+use crate::p2p::messages::{HashRange, Message, MessageReader};
+use crate::p2p::peer_stream::BoxedPeerStream;
+use crate::torrent_file_handler::merkle::{self, HASH_SIZE};
+use crate::torrent_file_handler::torrent_data_extractor::TorrentData;
+
+const BLOCK_SIZE: usize = 16384;
+// Requests kept in flight to hide the round trip time
+const MAX_PENDING_REQUESTS: usize = 5;
+// Quiet connections are kept open by keep-alives every two minutes (BEP 3), a peer sending
+// nothing for longer is gone
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(120);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(180);
+// No block for this long while requests are pending means the peer is sitting on them
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_FAILS: u32 = 5;
+const MAX_SUGGESTED_PIECES: usize = 32;
+// Peers may refuse to send more hashes at once (BEP 52)
+const MAX_HASHES_PER_REQUEST: usize = 512;
+
+// Everything the workers of one torrent share
+pub struct DownloadContext {
+    pub torrent_data: TorrentData,
+    pub picker: Mutex<PiecePicker>,
+    pub download_status: Mutex<DownloadStatus>,
+    pub saved_pieces_dir_name: String,
+    // v2 piece hashes, the layers missing from the torrent are filled in from peers
+    pub piece_hashes_v2: Mutex<Vec<Vec<u8>>>,
+    pub throttles: Throttles,
+    pub smart_ban: Mutex<SmartBan>,
+}
+
+impl DownloadContext {
+    // Pieces of v2 torrents can't be checked until their layer is known
+    pub fn can_check_piece(&self, index: usize) -> bool {
+        self.torrent_data.piece_hashes_v2.is_empty()
+            || !self.piece_hashes_v2.lock().unwrap()[index].is_empty()
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.smart_ban.lock().unwrap().is_banned(ip)
+    }
+}
+
+struct PieceInProgress {
+    index: usize,
+    data: Vec<u8>,
+    requested: Vec<bool>,
+    received: Vec<bool>,
+}
+
+impl PieceInProgress {
+    fn new(index: usize, size: usize) -> PieceInProgress {
+        let blocks = size / BLOCK_SIZE + !size.is_multiple_of(BLOCK_SIZE) as usize;
+        PieceInProgress {
+            index,
+            data: vec![0; size],
+            requested: vec![false; blocks],
+            received: vec![false; blocks],
+        }
+    }
+
+    fn block_length(&self, block: usize) -> usize {
+        BLOCK_SIZE.min(self.data.len() - block * BLOCK_SIZE)
+    }
+
+    fn pending_requests(&self) -> usize {
+        self.requested
+            .iter()
+            .zip(&self.received)
+            .filter(|(requested, received)| **requested && !**received)
+            .count()
+    }
+
+    fn next_request(&mut self) -> Option<Message> {
+        let block = self.requested.iter().position(|requested| !requested)?;
+        self.requested[block] = true;
+        Some(Message::Request {
+            index: self.index as u32,
+            begin: (block * BLOCK_SIZE) as u32,
+            length: self.block_length(block) as u32,
+        })
+    }
+
+    // Requests a peer drops when choking us have to be sent again
+    fn forget_requests(&mut self) {
+        self.requested = self.received.clone();
+    }
+
+    fn add_block(&mut self, begin: usize, block: &[u8]) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            begin.is_multiple_of(BLOCK_SIZE) && begin / BLOCK_SIZE < self.received.len(),
+            "Block at {} wasn't requested",
+            begin
+        );
+        let index = begin / BLOCK_SIZE;
+        anyhow::ensure!(
+            block.len() == self.block_length(index),
+            "Block has wrong length {}",
+            block.len()
+        );
+        self.data[begin..begin + block.len()].copy_from_slice(block);
+        self.received[index] = true;
+        Ok(())
+    }
+
+    fn is_complete(&self) -> bool {
+        self.received.iter().all(|received| *received)
+    }
+}
+
+struct PeerSession<'a> {
+    stream: BoxedPeerStream,
+    peer: SocketAddr,
+    context: &'a DownloadContext,
+    throttle: PeerThrottle<'a>,
+    reader: MessageReader,
+    last_sent: Instant,
+    last_received: Instant,
+    // When the last block came or requests were sent with none pending
+    waiting_since: Instant,
+    // Payload received, for preferring peers that gave us data
+    downloaded: u64,
+    fast_extension: bool,
+    // The peer understands hash requests (BEP 52)
+    v2: bool,
+    peer_pieces: Bitfield,
+    // The bitfield (or have_all/have_none) may only be the first message
+    first_message: bool,
+    choked: bool,
+    interested: bool,
+    // Fast Extension state: pieces we may request while choked, the peer's hints and
+    // the pieces it refused to give us
+    allowed_fast: HashSet<usize>,
+    suggested: VecDeque<usize>,
+    rejected: HashSet<usize>,
+    // Files whose piece layer was asked from the peer
+    requested_layers: HashSet<usize>,
+    piece: Option<PieceInProgress>,
+    fails: u32,
+}
+
+// Downloads pieces from an already handshaken peer until the queue runs dry or the peer fails us.
+// Returns the payload received.
+pub async fn run(
+    stream: BoxedPeerStream,
+    handshake: Handshake,
+    peer: SocketAddr,
+    context: &DownloadContext,
+) -> u64 {
+    let now = Instant::now();
+    let mut session = PeerSession {
+        stream,
+        peer,
+        context,
+        throttle: context.throttles.peer(),
+        reader: MessageReader::default(),
+        last_sent: now,
+        last_received: now,
+        waiting_since: now,
+        downloaded: 0,
+        fast_extension: handshake.supports_fast_extension(),
+        v2: handshake.supports_v2(),
+        peer_pieces: Bitfield::empty(context.torrent_data.piece_count()),
+        first_message: true,
+        choked: true,
+        interested: false,
+        allowed_fast: HashSet::new(),
+        suggested: VecDeque::new(),
+        rejected: HashSet::new(),
+        requested_layers: HashSet::new(),
+        piece: None,
+        fails: 0,
+    };
+    let _ = session.download().await;
+    session.return_piece();
+    context
+        .picker
+        .lock()
+        .unwrap()
+        .remove_peer(&session.peer_pieces);
+    session.downloaded
+}
+
+impl PeerSession<'_> {
+    async fn download(&mut self) -> anyhow::Result<()> {
+        // With the Fast Extension our first message must tell which pieces we have (BEP 6). We
+        // don't upload, so we offer none, and give no allowed fast set either.
+        if self.fast_extension {
+            self.send(Message::HaveNone).await?;
+        }
+        self.send(Message::Unchoke).await?;
+
+        loop {
+            let finished_elsewhere = self.piece.as_ref().is_some_and(|piece| {
+                let picker = self.context.picker.lock().unwrap();
+                picker.is_finished(piece.index)
+            });
+            if finished_elsewhere {
+                self.return_piece();
+            }
+            if self.choked
+                && self.piece.as_ref().is_some_and(|piece| {
+                    piece.pending_requests() == 0 && !self.allowed_fast.contains(&piece.index)
+                })
+            {
+                // Somebody else may get it sooner
+                self.return_piece();
+            }
+            if self.piece.is_none() {
+                self.piece = self.pick_piece();
+            }
+            if self.piece.is_none() && !self.context.picker.lock().unwrap().has_pending() {
+                return Ok(());
+            }
+            self.update_interest().await?;
+            self.request_piece_layers().await?;
+            self.send_requests().await?;
+
+            let wait = self
+                .next_deadline()
+                .saturating_duration_since(Instant::now());
+            let message = match tokio::time::timeout(wait, self.reader.read(&mut self.stream)).await
+            {
+                Ok(message) => message?,
+                Err(_) => {
+                    self.handle_timeouts().await?;
+                    continue;
+                }
+            };
+            self.last_received = Instant::now();
+            let (payload, overhead) = wire_sizes(&message);
+            self.throttle.received(payload, overhead).await;
+            self.handle_message(message).await?;
+        }
+    }
+
+    async fn send(&mut self, message: Message) -> anyhow::Result<()> {
+        let (payload, overhead) = wire_sizes(&message);
+        self.throttle.sent(payload, overhead).await;
+        self.stream.write_all(&message.serialize()).await?;
+        self.stream.flush().await?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    fn next_deadline(&self) -> Instant {
+        let deadline =
+            (self.last_sent + KEEP_ALIVE_INTERVAL).min(self.last_received + IDLE_TIMEOUT);
+        if self.has_pending_requests() {
+            deadline.min(self.waiting_since + REQUEST_TIMEOUT)
+        } else {
+            deadline
+        }
+    }
+
+    async fn handle_timeouts(&mut self) -> anyhow::Result<()> {
+        let now = Instant::now();
+        anyhow::ensure!(
+            now < self.last_received + IDLE_TIMEOUT,
+            "Peer sent nothing for {:?}",
+            IDLE_TIMEOUT
+        );
+        if self.has_pending_requests() && now >= self.waiting_since + REQUEST_TIMEOUT {
+            // Somebody else may get the piece sooner
+            self.return_piece();
+            self.fails += 1;
+            anyhow::ensure!(self.fails < MAX_FAILS, "Peer keeps ignoring our requests");
+        }
+        if now >= self.last_sent + KEEP_ALIVE_INTERVAL {
+            self.send(Message::KeepAlive).await?;
+        }
+        Ok(())
+    }
+
+    fn has_pending_requests(&self) -> bool {
+        self.piece
+            .as_ref()
+            .is_some_and(|piece| piece.pending_requests() > 0)
+    }
+
+    // Peers which have nothing we need may get new pieces later, so we wait for their have messages
+    async fn update_interest(&mut self) -> anyhow::Result<()> {
+        let interested = self.piece.is_some() || {
+            let picker = self.context.picker.lock().unwrap();
+            let wanted = picker
+                .pending()
+                .any(|index| self.peer_pieces.has_piece(index) && !self.rejected.contains(&index));
+            wanted
+        };
+        if interested != self.interested {
+            self.interested = interested;
+            self.send(if interested {
+                Message::Interested
+            } else {
+                Message::NotInterested
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    fn can_request(&self, index: usize) -> bool {
+        self.peer_pieces.has_piece(index)
+            && !self.rejected.contains(&index)
+            && (!self.choked || self.allowed_fast.contains(&index))
+            && self.context.can_check_piece(index)
+    }
+
+    fn pick_piece(&mut self) -> Option<PieceInProgress> {
+        let index = self.context.picker.lock().unwrap().pick(
+            &self.suggested,
+            |index| self.can_request(index),
+            std::time::Instant::now(),
+        )?;
+        self.suggested.retain(|suggested| *suggested != index);
+        Some(PieceInProgress::new(
+            index,
+            self.context.torrent_data.piece_size(index),
+        ))
+    }
+
+    fn return_piece(&mut self) {
+        if let Some(piece) = self.piece.take() {
+            self.context.picker.lock().unwrap().put_back(piece.index);
+        }
+    }
+
+    // The whole layer of every file the peer has pieces of, in chunks checked against the file's
+    // root by their uncle hashes
+    async fn request_piece_layers(&mut self) -> anyhow::Result<()> {
+        if !self.v2 {
+            return Ok(());
+        }
+        let torrent_data = &self.context.torrent_data;
+        let mut requests = Vec::new();
+        for (file, file_data) in torrent_data.files.iter().enumerate() {
+            let pieces = torrent_data.file_pieces(file);
+            let layer_known = {
+                let piece_hashes = self.context.piece_hashes_v2.lock().unwrap();
+                piece_hashes.is_empty()
+                    || pieces.clone().all(|piece| !piece_hashes[piece].is_empty())
+            };
+            if layer_known
+                || self.requested_layers.contains(&file)
+                || !pieces
+                    .clone()
+                    .any(|piece| self.peer_pieces.has_piece(piece))
+            {
+                continue;
+            }
+            self.requested_layers.insert(file);
+
+            let pieces_root = match &file_data.pieces_root {
+                Some(pieces_root) => pieces_root,
+                None => continue,
+            };
+            let width = pieces.len().next_power_of_two();
+            let length = width.min(MAX_HASHES_PER_REQUEST);
+            for index in (0..pieces.len()).step_by(length) {
+                requests.push(Message::HashRequest(HashRange {
+                    pieces_root: pieces_root.clone(),
+                    base_layer: merkle::piece_layer(torrent_data.piece_length),
+                    index: index as u32,
+                    length: length as u32,
+                    proof_layers: (width / length).trailing_zeros(),
+                }));
+            }
+        }
+        for request in requests {
+            self.send(request).await?;
+        }
+        Ok(())
+    }
+
+    // Hashes we didn't ask for are ignored, wrong ones end the session
+    fn add_hashes(&mut self, range: HashRange, hashes: &[u8]) -> anyhow::Result<()> {
+        let torrent_data = &self.context.torrent_data;
+        let file = match torrent_data
+            .files
+            .iter()
+            .position(|file| file.pieces_root.as_ref() == Some(&range.pieces_root))
+        {
+            Some(file) if self.requested_layers.contains(&file) => file,
+            _ => return Ok(()),
+        };
+        let base_layer = merkle::piece_layer(torrent_data.piece_length);
+        let (index, length) = (range.index as usize, range.length as usize);
+        if range.base_layer != base_layer || hashes.len() < length * HASH_SIZE {
+            return Ok(());
+        }
+
+        let pieces = torrent_data.file_pieces(file);
+        let hashes: Vec<Vec<u8>> = hashes.chunks(HASH_SIZE).map(<[u8]>::to_vec).collect();
+        let (layer, uncles) = hashes.split_at(length);
+        anyhow::ensure!(
+            merkle::verify_hashes(
+                &range.pieces_root,
+                pieces.len().next_power_of_two(),
+                &merkle::pad_hash(base_layer),
+                index,
+                layer,
+                uncles,
+            ),
+            "Peer sent wrong hashes"
+        );
+        let mut piece_hashes = self.context.piece_hashes_v2.lock().unwrap();
+        for (offset, hash) in layer.iter().enumerate() {
+            if index + offset < pieces.len() {
+                piece_hashes[pieces.start + index + offset] = hash.clone();
+            }
+        }
+        Ok(())
+    }
+
+    async fn send_requests(&mut self) -> anyhow::Result<()> {
+        let mut requests = Vec::new();
+        if let Some(piece) = &mut self.piece {
+            if self.choked && !self.allowed_fast.contains(&piece.index) {
+                return Ok(());
+            }
+            if piece.pending_requests() == 0 {
+                self.waiting_since = Instant::now();
+            }
+            while piece.pending_requests() < MAX_PENDING_REQUESTS {
+                match piece.next_request() {
+                    Some(request) => requests.push(request),
+                    None => break,
+                }
+            }
+        }
+        for request in requests {
+            self.send(request).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_message(&mut self, message: Message) -> anyhow::Result<()> {
+        let pieces = self.context.torrent_data.piece_count();
+        let first_message = std::mem::replace(&mut self.first_message, false);
+        match message {
+            Message::Choke => {
+                self.choked = true;
+                // With the Fast Extension the peer rejects every request it won't serve instead
+                if !self.fast_extension {
+                    if let Some(piece) = &mut self.piece {
+                        piece.forget_requests();
+                    }
+                }
+            }
+            Message::Unchoke => self.choked = false,
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                if let Some(piece) = &mut self.piece {
+                    if piece.index == index as usize {
+                        piece.add_block(begin as usize, &block)?;
+                        self.downloaded += block.len() as u64;
+                        self.waiting_since = Instant::now();
+                        if piece.is_complete() {
+                            self.finish_piece().await?;
+                        }
+                    }
+                }
+            }
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => {
+                // We don't upload, and peers supporting the Fast Extension expect an answer
+                if self.fast_extension {
+                    self.send(Message::Reject {
+                        index,
+                        begin,
+                        length,
+                    })
+                    .await?;
+                }
+            }
+            Message::Have(index) => {
+                let index = index as usize;
+                if !self.peer_pieces.has_piece(index) {
+                    self.peer_pieces.set_piece(index)?;
+                    self.context.picker.lock().unwrap().add_peer_piece(index);
+                }
+            }
+            // Peers supporting the Fast Extension may send have_all or have_none instead
+            Message::Bitfield(bitfield) if first_message => {
+                self.peer_pieces = Bitfield::from_bytes(bitfield, pieces)?;
+                self.context
+                    .picker
+                    .lock()
+                    .unwrap()
+                    .add_peer(&self.peer_pieces);
+            }
+            Message::HaveAll if first_message && self.fast_extension => {
+                self.peer_pieces = Bitfield::full(pieces);
+                self.context
+                    .picker
+                    .lock()
+                    .unwrap()
+                    .add_peer(&self.peer_pieces);
+            }
+            Message::HaveNone if first_message && self.fast_extension => {}
+            Message::Bitfield(_) | Message::HaveAll | Message::HaveNone => {
+                anyhow::bail!("Piece availability can only be sent right after the handshake")
+            }
+            Message::Suggest(index) if self.fast_extension => {
+                if (index as usize) < pieces && self.suggested.len() < MAX_SUGGESTED_PIECES {
+                    self.suggested.push_back(index as usize);
+                }
+            }
+            Message::AllowedFast(index) if self.fast_extension => {
+                if (index as usize) < pieces {
+                    self.allowed_fast.insert(index as usize);
+                }
+            }
+            Message::Reject { index, .. } => {
+                anyhow::ensure!(self.fast_extension, "Reject without the Fast Extension");
+                if self.piece.as_ref().map(|piece| piece.index) == Some(index as usize) {
+                    self.rejected.insert(index as usize);
+                    self.return_piece();
+                }
+            }
+            Message::Suggest(_) | Message::AllowedFast(_) => {
+                anyhow::bail!("Fast Extension message without the Fast Extension")
+            }
+            // We don't upload, so we have no hashes to give either
+            Message::HashRequest(range) => self.send(Message::HashReject(range)).await?,
+            Message::Hashes { range, hashes } => self.add_hashes(range, &hashes)?,
+            Message::HashReject(_) => {}
+            Message::KeepAlive
+            | Message::Interested
+            | Message::NotInterested
+            | Message::Cancel { .. }
+            | Message::Port(_)
+            | Message::Unknown(_) => {}
+        }
+        Ok(())
+    }
+
+    async fn finish_piece(&mut self) -> anyhow::Result<()> {
+        let piece = match self.piece.take() {
+            Some(piece) => piece,
+            None => return Ok(()),
+        };
+
+        // Every block came from this peer
+        let provenance: Provenance = (0..piece.received.len())
+            .map(|block| {
+                let begin = block * BLOCK_SIZE;
+                (begin..begin + piece.block_length(block), self.peer.ip())
+            })
+            .collect();
+        if !store_piece(self.context, piece.index, piece.data, &provenance).await? {
+            self.fails += 1;
+            anyhow::ensure!(
+                self.fails < MAX_FAILS,
+                "Peer sent too many corrupted pieces"
+            );
+        }
+        // Maybe for a piece it sent earlier
+        anyhow::ensure!(!self.context.is_banned(self.peer.ip()), "Peer is banned");
+        Ok(())
+    }
+}
+
+// Saves a downloaded piece if it matches its hash, otherwise it goes back to the picker.
+// The peers that sent it are blamed for bad data.
+pub async fn store_piece(
+    context: &DownloadContext,
+    index: usize,
+    data: Vec<u8>,
+    provenance: &Provenance,
+) -> anyhow::Result<bool> {
+    if !check_piece(context, index, &data) {
+        context
+            .smart_ban
+            .lock()
+            .unwrap()
+            .piece_failed(index, &data, provenance);
+        context.picker.lock().unwrap().put_back(index);
+        return Ok(false);
+    }
+    context.smart_ban.lock().unwrap().piece_passed(index, &data);
+
+    if let Err(err) =
+        filewriter::save_piece(context.saved_pieces_dir_name.clone(), data, index).await
+    {
+        context.picker.lock().unwrap().put_back(index);
+        return Err(err);
+    }
+    // Overdue pieces may be downloaded twice, only the first one counts
+    if !context
+        .picker
+        .lock()
+        .unwrap()
+        .finish(index, std::time::Instant::now())
+    {
+        return Ok(true);
+    }
+
+    let mut download_status = context.download_status.lock().unwrap();
+    download_status.pieces_downloaded += 1;
+    let progress = 100 * download_status.pieces_downloaded / download_status.total_pieces;
+    println!(
+        "[{}/{}, {}%] Piece {} downloaded",
+        download_status.pieces_downloaded, download_status.total_pieces, progress, index
+    );
+    Ok(true)
+}
+
+// Pieces saved by an earlier run over the same directory count as downloaded if they still
+// match their hash. Returns how many there were.
+pub async fn resume_saved_pieces(context: &DownloadContext) -> u32 {
+    let pending: Vec<usize> = context.picker.lock().unwrap().pending().collect();
+    let mut resumed = 0;
+    for index in pending {
+        let piece = match filewriter::read_piece(&context.saved_pieces_dir_name, index).await {
+            Ok(piece) => piece,
+            Err(_) => continue,
+        };
+        if !check_piece(context, index, &piece)
+            || !context
+                .picker
+                .lock()
+                .unwrap()
+                .finish(index, std::time::Instant::now())
+        {
+            continue;
+        }
+        context.download_status.lock().unwrap().pieces_downloaded += 1;
+        resumed += 1;
+    }
+    resumed
+}
+
+// Piece data is payload, everything else on the wire is protocol overhead
+fn wire_sizes(message: &Message) -> (usize, usize) {
+    match message {
+        Message::Piece { block, .. } => (block.len(), 13),
+        message => (0, message.serialize().len()),
+    }
+}
+
+// v1 pieces are checked by their SHA-1 hash, v2 ones by the root of their merkle subtree
+fn check_piece(context: &DownloadContext, index: usize, piece: &[u8]) -> bool {
+    let torrent_data = &context.torrent_data;
+    if !torrent_data.pieces.is_empty() && Sha1::digest(piece)[..] != torrent_data.pieces[index][..]
+    {
+        return false;
+    }
+    if torrent_data.piece_hashes_v2.is_empty() {
+        return true;
+    }
+    let expected_hash = context.piece_hashes_v2.lock().unwrap()[index].clone();
+    let file = match torrent_data.piece_file(index) {
+        Some(file) => file,
+        None => return false,
+    };
+    // The padding after a file of a hybrid torrent is only part of the v1 piece
+    let file_size = torrent_data.files[file].size;
+    let file_end = torrent_data.file_offset(file) + file_size;
+    let piece = &piece[..piece
+        .len()
+        .min(file_end - index * torrent_data.piece_length)];
+    !expected_hash.is_empty()
+        && merkle::piece_hash(piece, file_size, torrent_data.piece_length) == expected_hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::fixtures;
+    use crate::p2p::messages;
+    use crate::torrent_file_handler::torrent_data_extractor::{File, MetaVersion};
+    use crate::utp::UtpSocket;
+    use std::net::SocketAddr;
+    use tokio::net::{TcpListener, TcpStream};
+
+    const PIECE_LENGTH: usize = 2 * BLOCK_SIZE;
+
+    fn context(data: &[u8], dir: &str) -> DownloadContext {
+        fixtures::context(fixtures::single_file(data, PIECE_LENGTH), dir)
+    }
+
+    // The v2 torrent of a single file, missing its piece layer
+    fn context_v2(data: &[u8], dir: &str) -> DownloadContext {
+        let layer: Vec<Vec<u8>> = data
+            .chunks(PIECE_LENGTH)
+            .map(|piece| merkle::piece_hash(piece, data.len(), PIECE_LENGTH))
+            .collect();
+        let pieces = layer.len();
+        let mut context = context(data, dir);
+        context.torrent_data = TorrentData {
+            piece_length: PIECE_LENGTH,
+            files: vec![File {
+                path_to_file: vec!["file".to_string()],
+                size: data.len(),
+                pieces_root: Some(merkle::root_from_piece_layer(&layer, PIECE_LENGTH)),
+                ..Default::default()
+            }],
+            meta_version: MetaVersion::V2,
+            piece_hashes_v2: vec![Vec::new(); pieces],
+            ..Default::default()
+        };
+        context.piece_hashes_v2 = Mutex::new(vec![Vec::new(); pieces]);
+        context
+    }
+
+    fn piece_message(data: &[u8], index: u32, begin: u32, length: u32) -> Vec<u8> {
+        let start = index as usize * PIECE_LENGTH + begin as usize;
+        Message::Piece {
+            index,
+            begin,
+            block: data[start..start + length as usize].to_vec(),
+        }
+        .serialize()
+    }
+
+    // Runs a session against `seeder` and checks the downloaded pieces were saved
+    async fn download_from<F, Fut>(
+        name: &str,
+        data: &[u8],
+        over_utp: bool,
+        make_context: fn(&[u8], &str) -> DownloadContext,
+        seeder: F,
+    ) -> DownloadContext
+    where
+        F: FnOnce(BoxedPeerStream, Vec<u8>) -> Fut + Send + 'static,
+        Fut: std::future::Future<Output = ()> + Send,
+    {
+        let dir =
+            std::env::temp_dir().join(format!("rusty_torrent_{}_{}", name, std::process::id()));
+        let dir = dir.to_str().unwrap().to_string();
+        filewriter::create_directory(&dir).await.unwrap();
+
+        let seeder_data = data.to_vec();
+        let stream: BoxedPeerStream = if over_utp {
+            let listener = UtpSocket::bind(0).await.unwrap();
+            let addr = SocketAddr::new("127.0.0.1".parse().unwrap(), listener.local_addr().port());
+            tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                seeder(Box::new(stream), seeder_data).await;
+            });
+            let utp = UtpSocket::bind(0).await.unwrap();
+            Box::new(utp.connect(addr).await.unwrap())
+        } else {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                seeder(Box::new(stream), seeder_data).await;
+            });
+            Box::new(TcpStream::connect(addr).await.unwrap())
+        };
+
+        let context = make_context(data, &dir);
+        // With the Fast Extension and v2 bits
+        let handshake = Handshake {
+            reserved: [0, 0, 0, 0, 0, 0, 0, 0x14],
+            info_hash: vec![0; 20],
+            peer_id: vec![0; 20],
+        };
+        run(stream, handshake, "127.0.0.1:1".parse().unwrap(), &context).await;
+
+        for (index, piece) in data.chunks(PIECE_LENGTH).enumerate() {
+            if let Ok(saved) = std::fs::read(format!("{}/.{}", dir, index)) {
+                assert_eq!(saved, piece);
+            }
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+        context
+    }
+
+    // Chokes us and serves only its allowed fast piece, then unchokes and rejects everything.
+    // Hangs up once we aren't interested anymore.
+    async fn choking_seeder(mut stream: BoxedPeerStream, data: Vec<u8>) {
+        assert_eq!(
+            messages::read_message(&mut stream).await.unwrap(),
+            Message::HaveNone
+        );
+        for message in [
+            Message::HaveAll,
+            Message::AllowedFast(1),
+            Message::Suggest(1),
+        ] {
+            stream.write_all(&message.serialize()).await.unwrap();
+        }
+
+        while let Ok(message) = messages::read_message(&mut stream).await {
+            match message {
+                Message::Request {
+                    index: 1,
+                    begin,
+                    length,
+                } => {
+                    let mut response = piece_message(&data, 1, begin, length);
+                    response.extend_from_slice(&Message::Unchoke.serialize());
+                    stream.write_all(&response).await.unwrap();
+                }
+                Message::Request {
+                    index,
+                    begin,
+                    length,
+                } => {
+                    let reject = Message::Reject {
+                        index,
+                        begin,
+                        length,
+                    };
+                    stream.write_all(&reject.serialize()).await.unwrap();
+                }
+                Message::NotInterested => return,
+                _ => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn fast_extension_session() {
+        let data: Vec<u8> = (0..PIECE_LENGTH + 7000).map(|i| i as u8).collect();
+        let context = download_from("fast", &data, false, context, choking_seeder).await;
+
+        // The allowed fast piece is downloaded while choked, the rejected one goes back to the queue
+        assert_eq!(context.download_status.lock().unwrap().pieces_downloaded, 1);
+        assert_eq!(
+            context.picker.lock().unwrap().pending().collect::<Vec<_>>(),
+            vec![0]
+        );
+    }
+
+    // Skips the bitfield and announces its pieces one by one, the second one only after
+    // we lost interest
+    async fn lazy_seeder(mut stream: BoxedPeerStream, data: Vec<u8>) {
+        for message in [Message::Unchoke, Message::Have(1)] {
+            stream.write_all(&message.serialize()).await.unwrap();
+        }
+
+        while let Ok(message) = messages::read_message(&mut stream).await {
+            match message {
+                Message::Request {
+                    index,
+                    begin,
+                    length,
+                } => {
+                    let response = piece_message(&data, index, begin, length);
+                    stream.write_all(&response).await.unwrap();
+                }
+                Message::NotInterested => {
+                    stream
+                        .write_all(&Message::Have(0).serialize())
+                        .await
+                        .unwrap();
+                }
+                _ => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn pieces_announced_by_have_messages() {
+        let data: Vec<u8> = (0..PIECE_LENGTH * 2).map(|i| (i / 3) as u8).collect();
+        let context = download_from("lazy", &data, false, context, lazy_seeder).await;
+
+        assert_eq!(context.download_status.lock().unwrap().pieces_downloaded, 2);
+        assert!(!context.picker.lock().unwrap().has_pending());
+    }
+
+    #[tokio::test]
+    async fn session_over_utp() {
+        let data: Vec<u8> = (0..PIECE_LENGTH * 2).map(|i| (i / 5) as u8).collect();
+        let context = download_from("utp", &data, true, context, lazy_seeder).await;
+
+        assert_eq!(context.download_status.lock().unwrap().pieces_downloaded, 2);
+        assert!(!context.picker.lock().unwrap().has_pending());
+    }
+
+    // Sends the piece layer the way BEP 52 asks: padded to the requested length
+    async fn v2_seeder(mut stream: BoxedPeerStream, data: Vec<u8>) {
+        for message in [Message::HaveAll, Message::Unchoke] {
+            stream.write_all(&message.serialize()).await.unwrap();
+        }
+
+        let mut layer: Vec<Vec<u8>> = data
+            .chunks(PIECE_LENGTH)
+            .map(|piece| merkle::piece_hash(piece, data.len(), PIECE_LENGTH))
+            .collect();
+        while let Ok(message) = messages::read_message(&mut stream).await {
+            match message {
+                Message::HashRequest(range) => {
+                    assert_eq!((range.index, range.length), (0, 4));
+                    layer.resize(4, merkle::pad_hash(range.base_layer));
+                    let hashes = Message::Hashes {
+                        range,
+                        hashes: layer.concat(),
+                    };
+                    stream.write_all(&hashes.serialize()).await.unwrap();
+                }
+                Message::Request {
+                    index,
+                    begin,
+                    length,
+                } => {
+                    let response = piece_message(&data, index, begin, length);
+                    stream.write_all(&response).await.unwrap();
+                }
+                Message::NotInterested => return,
+                _ => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn v2_session_with_hashes_from_peer() {
+        let data: Vec<u8> = (0..PIECE_LENGTH * 2 + 100).map(|i| (i / 7) as u8).collect();
+        let context = download_from("v2", &data, false, context_v2, v2_seeder).await;
+
+        assert_eq!(context.download_status.lock().unwrap().pieces_downloaded, 3);
+        assert!(context
+            .piece_hashes_v2
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|hash| !hash.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn saved_pieces_are_resumed() {
+        let data: Vec<u8> = (0..PIECE_LENGTH * 3).map(|i| (i / 11) as u8).collect();
+        let dir = std::env::temp_dir().join(format!("rusty_torrent_resume_{}", std::process::id()));
+        let dir = dir.to_str().unwrap().to_string();
+        filewriter::create_directory(&dir).await.unwrap();
+        let pieces: Vec<_> = data.chunks(PIECE_LENGTH).collect();
+        filewriter::save_piece(dir.clone(), pieces[0].to_vec(), 0)
+            .await
+            .unwrap();
+        // A piece cut short by a crash
+        filewriter::save_piece(dir.clone(), pieces[2][..10].to_vec(), 2)
+            .await
+            .unwrap();
+
+        let context = context(&data, &dir);
+        assert_eq!(resume_saved_pieces(&context).await, 1);
+        assert_eq!(context.download_status.lock().unwrap().pieces_downloaded, 1);
+        assert_eq!(
+            context.picker.lock().unwrap().pending().collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn quiet_session() -> (tokio::task::JoinHandle<u64>, tokio::io::DuplexStream) {
+        let context = context(&[5; PIECE_LENGTH], "unused");
+        let (ours, theirs) = tokio::io::duplex(1 << 16);
+        let handshake = Handshake {
+            reserved: [0; 8],
+            info_hash: vec![0; 20],
+            peer_id: vec![0; 20],
+        };
+        let session = tokio::spawn(async move {
+            run(
+                Box::new(ours),
+                handshake,
+                "127.0.0.1:1".parse().unwrap(),
+                &context,
+            )
+            .await
+        });
+        (session, theirs)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn quiet_peers_get_keep_alives_and_are_dropped() {
+        let start = Instant::now();
+        let (session, mut theirs) = quiet_session();
+        theirs
+            .write_all(&Message::Bitfield(vec![0]).serialize())
+            .await
+            .unwrap();
+
+        let mut reader = MessageReader::default();
+        while reader.read(&mut theirs).await.unwrap() != Message::KeepAlive {}
+        assert!(start.elapsed() >= KEEP_ALIVE_INTERVAL);
+
+        assert_eq!(session.await.unwrap(), 0);
+        assert!(start.elapsed() >= IDLE_TIMEOUT);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ignored_requests_time_out() {
+        let start = Instant::now();
+        let (session, mut theirs) = quiet_session();
+        theirs
+            .write_all(&Message::Bitfield(vec![0x80]).serialize())
+            .await
+            .unwrap();
+        theirs
+            .write_all(&Message::Unchoke.serialize())
+            .await
+            .unwrap();
+
+        // Alive, but it never sends a block
+        let mut reader = MessageReader::default();
+        let mut requests = 0;
+        let mut keep_alive = tokio::time::interval(Duration::from_secs(50));
+        loop {
+            tokio::select! {
+                message = reader.read(&mut theirs) => match message {
+                    Ok(Message::Request { .. }) => requests += 1,
+                    Ok(_) => {}
+                    Err(_) => break,
+                },
+                _ = keep_alive.tick() => {
+                    theirs.write_all(&Message::KeepAlive.serialize()).await.unwrap();
+                }
+            }
+        }
+        // The piece is asked again after every timeout until the peer is given up on
+        assert_eq!(requests, MAX_FAILS as usize * 2);
+        assert!(start.elapsed() >= REQUEST_TIMEOUT * MAX_FAILS);
+        assert_eq!(session.await.unwrap(), 0);
+    }
+}
@@ -1,3 +1,5 @@
+use std::collections::HashSet;
+use std::os::unix::fs::PermissionsExt;
 use std::os::unix::prelude::FileExt;
 use tokio::fs;
 use tokio::io::AsyncWriteExt;
@@ -15,27 +17,57 @@ pub async fn remove_directory(path: &String) -> anyhow::Result<()> {
     Ok(())
 }
 
+fn piece_filename(path: &str, index: usize) -> String {
+    format!("{}/.{}", path, index)
+}
+
 pub async fn save_piece(path: String, piece: Vec<u8>, index: usize) -> anyhow::Result<()> {
-    let mut filename = path;
-    filename.push_str("/.");
-    filename.push_str(&index.to_string());
+    let filename = piece_filename(&path, index);
 
     let mut file = fs::File::create(filename).await?;
     file.write_all(&piece).await?;
+    // Tokio finishes the write in the background otherwise
+    file.flush().await?;
 
     Ok(())
 }
 
+pub async fn read_piece(path: &str, index: usize) -> anyhow::Result<Vec<u8>> {
+    Ok(fs::read(piece_filename(path, index)).await?)
+}
+
+// Skipped and padding files aren't created, the parts of their pieces shared with wanted files
+// are used
 pub fn compose_files(
     torrent_data: &torrent_data_extractor::TorrentData,
     saved_pieces_dir_name: String,
+    wanted_files: &[bool],
 ) -> anyhow::Result<()> {
     let piece_size = torrent_data.piece_length;
-    let mut current_piece = 0;
 
-    let mut bytes_from_prev_piece: Vec<u8> = Vec::new();
+    // Symlink targets stay inside the torrent, but what is written through one could land on
+    // any file it points to
+    let symlinks: HashSet<&[String]> = torrent_data
+        .files
+        .iter()
+        .filter(|file| file.symlink_path.is_some())
+        .map(|file| file.path_to_file.as_slice())
+        .collect();
+    for file in &torrent_data.files {
+        let path = &file.path_to_file;
+        let through_symlink = (1..path.len()).any(|len| symlinks.contains(&path[..len]))
+            || (file.symlink_path.is_none() && symlinks.contains(path.as_slice()));
+        anyhow::ensure!(
+            !through_symlink,
+            "{} is inside a symlink of the torrent",
+            path.join("/")
+        );
+    }
 
-    for file in torrent_data.files.iter() {
+    for (index, file) in torrent_data.files.iter().enumerate() {
+        if !wanted_files[index] || file.attributes.padding {
+            continue;
+        }
         let mut path = file.path_to_file.clone();
         let mut filename: String;
         if path.len() == 1 {
@@ -58,57 +90,151 @@ pub fn compose_files(
 
         println!("{}", filename);
 
-        let mut bytes_written_into_file = 0;
-
-        let f = std::fs::File::create(filename)?;
-
-        if bytes_from_prev_piece.len() != 0 {
-            if file.size > bytes_from_prev_piece.len() {
-                f.write_at(&bytes_from_prev_piece, bytes_written_into_file as u64)?;
-                bytes_written_into_file += bytes_from_prev_piece.len();
-                bytes_from_prev_piece = Vec::new();
-            } else {
-                f.write_at(
-                    &bytes_from_prev_piece[0..file.size],
-                    bytes_written_into_file as u64,
-                )?;
-                //bytes_written_into_file += bytes_from_prev_piece.len();
-                bytes_from_prev_piece = bytes_from_prev_piece[file.size..].to_vec();
-                continue;
-            }
+        if let Some(target) = &file.symlink_path {
+            // The target is relative to the torrent's root, the link's directory is below it
+            let mut relative_target = vec![".."; file.path_to_file.len().saturating_sub(2)];
+            relative_target.extend(target.iter().map(String::as_str));
+            let _ = std::fs::remove_file(&filename);
+            std::os::unix::fs::symlink(relative_target.join("/"), filename)?;
+            continue;
         }
 
-        while file.size - bytes_written_into_file > piece_size {
-            if bytes_from_prev_piece.len() != 0 {
-                f.write_at(&bytes_from_prev_piece, bytes_written_into_file as u64)?;
-                bytes_written_into_file += bytes_from_prev_piece.len();
-                bytes_from_prev_piece = Vec::new();
-                continue;
-            }
+        let f = std::fs::File::create(&filename)?;
+        if file.attributes.executable {
+            std::fs::set_permissions(&filename, std::fs::Permissions::from_mode(0o755))?;
+        }
+        let file_start = torrent_data.file_offset(index);
+        let file_end = file_start + file.size;
+
+        for current_piece in torrent_data.file_pieces(index) {
             let mut current_piece_filename = saved_pieces_dir_name.clone();
             current_piece_filename.push_str("/.");
             current_piece_filename.push_str(&current_piece.to_string());
             let current_piece_bytes = std::fs::read(current_piece_filename.clone())?;
-            let total_bytes = current_piece_bytes.len();
-            f.write_at(&current_piece_bytes, bytes_written_into_file as u64)?;
-            bytes_written_into_file += total_bytes;
             //fs::remove_file(current_piece_filename)?;
-            current_piece += 1;
-        }
 
-        let mut current_piece_filename = saved_pieces_dir_name.clone();
-        current_piece_filename.push_str("/.");
-        current_piece_filename.push_str(&current_piece.to_string());
-        let current_piece_bytes = std::fs::read(current_piece_filename.clone())?;
-        f.write_at(
-            &current_piece_bytes[0..file.size - bytes_written_into_file],
-            bytes_written_into_file as u64,
-        )?;
-        for byte in current_piece_bytes[file.size - bytes_written_into_file..].iter() {
-            bytes_from_prev_piece.push(*byte);
+            let piece_start = current_piece * piece_size;
+            let from = file_start.max(piece_start);
+            let to = file_end.min(piece_start + current_piece_bytes.len());
+            anyhow::ensure!(from < to, "Piece {} is too short", current_piece);
+            f.write_at(
+                &current_piece_bytes[from - piece_start..to - piece_start],
+                (from - file_start) as u64,
+            )?;
         }
-        //fs::remove_file(current_piece_filename)?;
-        current_piece += 1;
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent_file_handler::torrent_data_extractor::{File, FileAttributes, TorrentData};
+
+    #[tokio::test]
+    async fn skipped_files_are_not_created() {
+        let dir =
+            std::env::temp_dir().join(format!("rusty_torrent_compose_{}", std::process::id()));
+        let dir = dir.to_str().unwrap().to_string();
+        let pieces_dir = format!("{}/.pieces", dir);
+        create_directory(&pieces_dir).await.unwrap();
+
+        let data: Vec<u8> = (0..50).collect();
+        for (index, piece) in data.chunks(10).enumerate() {
+            save_piece(pieces_dir.clone(), piece.to_vec(), index)
+                .await
+                .unwrap();
+        }
+        let file = |name: &str, size| File {
+            path_to_file: vec![dir.clone(), name.to_string()],
+            size,
+            ..Default::default()
+        };
+        let torrent_data = TorrentData {
+            pieces: vec![vec![0; 20]; 5],
+            piece_length: 10,
+            files: vec![file("a", 15), file("b", 10), file("c", 0), file("d", 25)],
+            ..Default::default()
+        };
+
+        compose_files(&torrent_data, pieces_dir, &[true, false, true, true]).unwrap();
+        assert_eq!(std::fs::read(format!("{}/a", dir)).unwrap(), &data[..15]);
+        assert!(!std::path::Path::new(&format!("{}/b", dir)).exists());
+        assert!(std::fs::read(format!("{}/c", dir)).unwrap().is_empty());
+        assert_eq!(std::fs::read(format!("{}/d", dir)).unwrap(), &data[25..]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn padding_symlinks_and_executables() {
+        let dir = std::env::temp_dir().join(format!("rusty_torrent_attrs_{}", std::process::id()));
+        let dir = dir.to_str().unwrap().to_string();
+        let pieces_dir = format!("{}/.pieces", dir);
+        create_directory(&pieces_dir).await.unwrap();
+
+        // "a" is padded to the end of the first piece
+        let data: Vec<u8> = vec![1, 2, 3, 0, 0, 0, 0, 0, 0, 0, 4, 5];
+        for (index, piece) in data.chunks(10).enumerate() {
+            save_piece(pieces_dir.clone(), piece.to_vec(), index)
+                .await
+                .unwrap();
+        }
+        let file = |name: &str, size, attr: &str| File {
+            path_to_file: vec![dir.clone(), name.to_string()],
+            size,
+            attributes: FileAttributes::parse(attr),
+            symlink_path: Some(vec!["b".to_string()]).filter(|_| attr == "l"),
+            ..Default::default()
+        };
+        let torrent_data = TorrentData {
+            pieces: vec![vec![0; 20]; 2],
+            piece_length: 10,
+            files: vec![
+                file("a", 3, ""),
+                file("pad", 7, "p"),
+                file("b", 2, "x"),
+                file("link", 0, "l"),
+            ],
+            ..Default::default()
+        };
+
+        compose_files(&torrent_data, pieces_dir, &[true; 4]).unwrap();
+        assert_eq!(std::fs::read(format!("{}/a", dir)).unwrap(), [1, 2, 3]);
+        assert!(!std::path::Path::new(&format!("{}/pad", dir)).exists());
+        let mode = std::fs::metadata(format!("{}/b", dir))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o111, 0o111);
+        assert_eq!(std::fs::read(format!("{}/link", dir)).unwrap(), [4, 5]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn files_are_not_written_through_symlinks() {
+        let dir =
+            std::env::temp_dir().join(format!("rusty_torrent_through_{}", std::process::id()));
+        let dir = dir.to_str().unwrap().to_string();
+        let torrent_data = TorrentData {
+            pieces: vec![vec![0; 20]],
+            piece_length: 10,
+            files: vec![
+                File {
+                    path_to_file: vec![dir.clone(), "link".to_string()],
+                    attributes: FileAttributes::parse("l"),
+                    symlink_path: Some(vec!["real".to_string()]),
+                    ..Default::default()
+                },
+                File {
+                    path_to_file: vec![dir.clone(), "link".to_string(), "file".to_string()],
+                    size: 10,
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+
+        assert!(compose_files(&torrent_data, dir.clone(), &[true; 2]).is_err());
+        assert!(!std::path::Path::new(&dir).exists());
+    }
+}
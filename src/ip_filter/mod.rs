@@ -0,0 +1,340 @@
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/*
+ *   Address ranges we never connect to or accept connections from. Lists are text, one range per
+ *   line, in any of the usual formats (mixed in one file too):
+ *
+ *     eMule ipfilter.dat:   001.002.003.000 - 001.002.003.255 , 100 , Description
+ *     PeerGuardian p2p:     Description:1.2.3.0-1.2.3.255
+ *     CIDR:                 1.2.3.0/24 or 2001:db8::/32
+ *     Plain:                1.2.3.4 or 1.2.3.4-1.2.3.9
+ *
+ *   eMule entries with an access level above 127 are allowed, as in eMule itself. Lines starting
+ *   with # or // are comments. Ranges are merged and sorted, so a lookup is a binary search even
+ *   for lists of hundreds of thousands of ranges.
+ *
+ *   Published lists often hold a few broken lines, which are skipped with a warning rather than
+ *   throwing the rest of the list away. Only a file without a single readable line is refused,
+ *   as it is most likely not a list at all.
+ */
+
+// How often a watched list file is checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(30);
+// eMule levels up to this one block
+const MAX_BLOCKED_LEVEL: u32 = 127;
+// Skipped lines warned about one by one, the rest are only counted
+const MAX_REPORTED_LINES: usize = 5;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct IpFilter {
+    // Sorted, non-overlapping inclusive ranges
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+    // Lines that couldn't be read
+    skipped: usize,
+}
+
+impl IpFilter {
+    pub fn parse(text: &str) -> anyhow::Result<IpFilter> {
+        let mut v4 = Vec::new();
+        let mut v6 = Vec::new();
+        let mut read = 0;
+        let mut skipped = 0;
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+            let range = parse_line(line).and_then(|range| match range {
+                Some((IpAddr::V4(_), IpAddr::V6(_))) | Some((IpAddr::V6(_), IpAddr::V4(_))) => {
+                    anyhow::bail!("Range mixes IPv4 and IPv6")
+                }
+                range => Ok(range),
+            });
+            match range {
+                Ok(Some((IpAddr::V4(first), IpAddr::V4(last)))) => {
+                    v4.push((u32::from(first), u32::from(last)))
+                }
+                Ok(Some((IpAddr::V6(first), IpAddr::V6(last)))) => {
+                    v6.push((u128::from(first), u128::from(last)))
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    skipped += 1;
+                    if skipped <= MAX_REPORTED_LINES {
+                        println!("Skipping line {} of IP filter: {}", number + 1, err);
+                    }
+                    continue;
+                }
+            }
+            read += 1;
+        }
+        anyhow::ensure!(
+            read > 0 || skipped == 0,
+            "None of the {} lines of the IP filter could be read",
+            skipped
+        );
+        if skipped > MAX_REPORTED_LINES {
+            println!("Skipped {} lines of IP filter in all", skipped);
+        }
+        Ok(IpFilter {
+            v4: merge(v4),
+            v6: merge(v6),
+            skipped,
+        })
+    }
+
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => contains(&self.v4, u32::from(ip)),
+            // Dual stack sockets show IPv4 peers as mapped addresses
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => contains(&self.v4, u32::from(ip)),
+                None => contains(&self.v6, u128::from(ip)),
+            },
+        }
+    }
+
+    pub fn ranges(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+}
+
+// The blocked range of a line, none for allowed eMule entries
+fn parse_line(line: &str) -> anyhow::Result<Option<(IpAddr, IpAddr)>> {
+    // eMule: the range, the access level, then a description that may contain anything
+    if let Some((range, rest)) = line.split_once(',') {
+        if let Ok(range) = parse_range(range) {
+            let level = rest.split(',').next().unwrap_or_default().trim();
+            let level: u32 = level
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Wrong access level {}", level))?;
+            return Ok(Some(range).filter(|_| level <= MAX_BLOCKED_LEVEL));
+        }
+    }
+
+    // PeerGuardian: the description comes first, IPv4 only
+    if let Some((_, range)) = line.rsplit_once(':') {
+        if range.contains('-') && !range.contains(':') {
+            return parse_range(range).map(Some);
+        }
+    }
+
+    if let Some((address, prefix)) = line.split_once('/') {
+        let prefix: u32 = prefix
+            .trim()
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Wrong prefix length {}", prefix))?;
+        return match parse_address(address)? {
+            IpAddr::V4(address) => {
+                anyhow::ensure!(prefix <= 32, "Prefix /{} is too long for IPv4", prefix);
+                let mask = u32::MAX.checked_shr(prefix).unwrap_or(0);
+                let first = u32::from(address) & !mask;
+                Ok(Some((
+                    Ipv4Addr::from(first).into(),
+                    Ipv4Addr::from(first | mask).into(),
+                )))
+            }
+            IpAddr::V6(address) => {
+                anyhow::ensure!(prefix <= 128, "Prefix /{} is too long for IPv6", prefix);
+                let mask = u128::MAX.checked_shr(prefix).unwrap_or(0);
+                let first = u128::from(address) & !mask;
+                Ok(Some((
+                    Ipv6Addr::from(first).into(),
+                    Ipv6Addr::from(first | mask).into(),
+                )))
+            }
+        };
+    }
+
+    parse_range(line).map(Some)
+}
+
+// "first - last" or a single address
+fn parse_range(range: &str) -> anyhow::Result<(IpAddr, IpAddr)> {
+    let (first, last) = match range.split_once('-') {
+        Some((first, last)) => (parse_address(first)?, parse_address(last)?),
+        None => {
+            let address = parse_address(range)?;
+            (address, address)
+        }
+    };
+    anyhow::ensure!(first <= last, "Range {} is backwards", range.trim());
+    Ok((first, last))
+}
+
+// eMule lists pad IPv4 numbers with zeros, which the standard parser refuses
+fn parse_address(address: &str) -> anyhow::Result<IpAddr> {
+    let address = address.trim();
+    if address.contains(':') {
+        return Ok(IpAddr::V6(address.parse()?));
+    }
+    let octets = address
+        .split('.')
+        .map(|octet| octet.parse::<u8>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| anyhow::anyhow!("Wrong address {}", address))?;
+    anyhow::ensure!(octets.len() == 4, "Wrong address {}", address);
+    Ok(IpAddr::V4(Ipv4Addr::new(
+        octets[0], octets[1], octets[2], octets[3],
+    )))
+}
+
+fn merge<T: Ord + Copy + Into<u128>>(mut ranges: Vec<(T, T)>) -> Vec<(T, T)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for (first, last) in ranges {
+        match merged.last_mut() {
+            // Overlapping or adjacent
+            Some(previous) if previous.1.into().saturating_add(1) >= first.into() => {
+                previous.1 = previous.1.max(last);
+            }
+            _ => merged.push((first, last)),
+        }
+    }
+    merged
+}
+
+fn contains<T: Ord + Copy>(ranges: &[(T, T)], ip: T) -> bool {
+    // The first range starting after the address, the one before it may hold it
+    let after = ranges.partition_point(|(first, _)| *first <= ip);
+    after > 0 && ranges[after - 1].1 >= ip
+}
+
+impl IpFilter {
+    pub fn load(path: &Path) -> anyhow::Result<IpFilter> {
+        let text = fs::read_to_string(path).map_err(|err| {
+            anyhow::anyhow!("Couldn't read IP filter {}: {}", path.display(), err)
+        })?;
+        IpFilter::parse(&text)
+    }
+}
+
+// Loads `path` into `filter` again whenever the file changes, so lists can be updated without a
+// restart. A list that fails to load leaves the previous one in place.
+pub async fn watch(path: PathBuf, filter: Arc<RwLock<IpFilter>>) {
+    let modified = || fs::metadata(&path).and_then(|metadata| metadata.modified());
+    let mut loaded = modified().ok();
+    loop {
+        tokio::time::sleep(WATCH_INTERVAL).await;
+        match modified() {
+            Ok(modified) if loaded != Some(modified) => {
+                loaded = Some(modified);
+                match IpFilter::load(&path) {
+                    Ok(new_filter) => {
+                        println!(
+                            "Loaded {} blocked ranges from {}, skipped {} lines",
+                            new_filter.ranges(),
+                            path.display(),
+                            new_filter.skipped()
+                        );
+                        *filter.write().unwrap() = new_filter;
+                    }
+                    Err(err) => println!("Keeping the old IP filter: {:?}", err),
+                }
+            }
+            Ok(_) => {}
+            Err(err) => println!("Couldn't check IP filter {}: {}", path.display(), err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blocked(filter: &IpFilter, ip: &str) -> bool {
+        filter.is_blocked(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn every_format() {
+        let filter = IpFilter::parse(
+            "# comment\n\
+             // another one\n\
+             001.002.003.000 - 001.002.003.255 , 100 , Some, company\n\
+             005.000.000.000 - 005.255.255.255 , 200 , Allowed\n\
+             Bad people:10.0.0.0-10.0.0.9\n\
+             Bad, Inc.:10.0.1.0-10.0.1.9\n\
+             \n\
+             192.168.0.0/16\n\
+             2001:db8::/32\n\
+             8.8.8.8\n",
+        )
+        .unwrap();
+        assert_eq!(filter.ranges(), 6);
+        assert_eq!(filter.skipped(), 0);
+
+        assert!(blocked(&filter, "1.2.3.0"));
+        assert!(blocked(&filter, "1.2.3.255"));
+        assert!(!blocked(&filter, "1.2.4.0"));
+        assert!(!blocked(&filter, "5.1.1.1"));
+        assert!(blocked(&filter, "10.0.0.9"));
+        assert!(!blocked(&filter, "10.0.0.10"));
+        assert!(blocked(&filter, "10.0.1.0"));
+        assert!(blocked(&filter, "192.168.77.1"));
+        assert!(blocked(&filter, "8.8.8.8"));
+        assert!(!blocked(&filter, "8.8.4.4"));
+        assert!(blocked(&filter, "2001:db8:ffff::1"));
+        assert!(!blocked(&filter, "2001:db9::1"));
+        assert!(blocked(&filter, "::ffff:10.0.0.1"));
+    }
+
+    #[test]
+    fn ranges_are_merged() {
+        let filter =
+            IpFilter::parse("1.0.0.0-1.0.0.10\n1.0.0.5-1.0.0.20\n1.0.0.21-1.0.0.30\n0.0.0.0/0")
+                .unwrap();
+        assert_eq!(filter.v4, vec![(0, u32::MAX)]);
+
+        let filter = IpFilter::parse("1.0.0.0-1.0.0.10\n1.0.0.12-1.0.0.20").unwrap();
+        assert_eq!(filter.ranges(), 2);
+        assert!(!blocked(&filter, "1.0.0.11"));
+    }
+
+    #[test]
+    fn wrong_lines_are_skipped() {
+        let wrong_lines = [
+            "1.2.3-1.2.3.4",
+            "1.2.3.9-1.2.3.4",
+            "1.2.3.4/33",
+            "1.2.3.4 - 1.2.3.5 , high , eMule",
+            "1.2.3.4-::1",
+        ];
+        for line in wrong_lines {
+            let filter = IpFilter::parse(&format!("8.8.8.8\n{}\n9.9.9.9", line)).unwrap();
+            assert_eq!(filter.skipped(), 1, "{}", line);
+            assert_eq!(filter.ranges(), 2, "{}", line);
+            assert!(blocked(&filter, "9.9.9.9"));
+        }
+
+        // Nothing readable at all is not a list
+        assert!(IpFilter::parse(&wrong_lines.join("\n")).is_err());
+        assert_eq!(IpFilter::parse("# only a comment\n").unwrap().ranges(), 0);
+    }
+
+    #[test]
+    fn large_lists() {
+        let text: String = (0..100_000u32)
+            .map(|i| {
+                format!(
+                    "{}-{}\n",
+                    Ipv4Addr::from(i * 16),
+                    Ipv4Addr::from(i * 16 + 7)
+                )
+            })
+            .collect();
+        let filter = IpFilter::parse(&text).unwrap();
+        assert_eq!(filter.ranges(), 100_000);
+        assert!(filter.is_blocked(Ipv4Addr::from(99_999 * 16 + 7).into()));
+        assert!(!filter.is_blocked(Ipv4Addr::from(99_999 * 16 + 8).into()));
+    }
+}
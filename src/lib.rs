@@ -0,0 +1,12 @@
+#![deny(warnings)]
+
+pub mod daemon;
+pub mod download;
+pub mod filewriter;
+pub mod ip_filter;
+pub mod lsd;
+pub mod p2p;
+pub mod proxy;
+pub mod torrent_file_handler;
+pub mod tracker;
+pub mod utp;
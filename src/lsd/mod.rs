@@ -0,0 +1,308 @@
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+/*
+ *   Local Service Discovery (BEP 14): peers on the same network find each other by multicasting
+ *   the info hashes they are in. An announcement looks like an HTTP request:
+ *
+ *     BT-SEARCH * HTTP/1.1\r\n
+ *     Host: 239.192.152.143:6771\r\n
+ *     Port: <listening port>\r\n
+ *     Infohash: <40 hex digits>\r\n   (once per torrent)
+ *     cookie: <random>\r\n
+ *     \r\n\r\n
+ *
+ *   The cookie lets us drop our own announcements, multicast loops them back to us.
+ */
+
+pub const IPV4_GROUP: SocketAddr = SocketAddr::new(
+    IpAddr::V4(Ipv4Addr::new(239, 192, 152, 143)),
+    MULTICAST_PORT,
+);
+pub const IPV6_GROUP: SocketAddr = SocketAddr::new(
+    IpAddr::V6(Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f)),
+    MULTICAST_PORT,
+);
+const MULTICAST_PORT: u16 = 6771;
+// BEP 14 asks for no more than one announce per minute, five is what other clients do
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+pub struct LocalDiscovery {
+    // Peers by the info hash they announced
+    peers: mpsc::UnboundedReceiver<(Vec<u8>, SocketAddr)>,
+    // Keeps `peers` open even if no group could be joined
+    _peers_tx: mpsc::UnboundedSender<(Vec<u8>, SocketAddr)>,
+}
+
+impl LocalDiscovery {
+    // Joins both groups on the default interfaces. A group that can't be joined (no IPv6, no
+    // multicast route) is skipped, then its peers are just never found.
+    pub fn start(info_hashes: Vec<Vec<u8>>, port: u16) -> LocalDiscovery {
+        LocalDiscovery::start_on(
+            &[
+                (IPV4_GROUP, IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+                (IPV6_GROUP, IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+            ],
+            info_hashes,
+            port,
+        )
+    }
+
+    // Every group is joined on the interface with the given address
+    pub fn start_on(
+        groups: &[(SocketAddr, IpAddr)],
+        info_hashes: Vec<Vec<u8>>,
+        port: u16,
+    ) -> LocalDiscovery {
+        let (peers_tx, peers) = mpsc::unbounded_channel();
+        let cookie = format!("{:016x}", rand::random::<u64>());
+
+        for &(group, interface) in groups {
+            match join_group(group, interface) {
+                Ok(socket) => {
+                    tokio::spawn(run_group(
+                        socket,
+                        group,
+                        announce_message(group, port, &info_hashes, &cookie),
+                        info_hashes.clone(),
+                        cookie.clone(),
+                        peers_tx.clone(),
+                    ));
+                }
+                Err(err) => println!("Local discovery on {} is off: {}", group, err),
+            }
+        }
+
+        LocalDiscovery {
+            peers,
+            _peers_tx: peers_tx,
+        }
+    }
+
+    pub async fn next_peer(&mut self) -> Option<(Vec<u8>, SocketAddr)> {
+        self.peers.recv().await
+    }
+}
+
+fn join_group(group: SocketAddr, interface: IpAddr) -> anyhow::Result<UdpSocket> {
+    let domain = match group {
+        SocketAddr::V4(_) => Domain::IPV4,
+        SocketAddr::V6(_) => Domain::IPV6,
+    };
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    // Other clients on this machine listen on the same port
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+
+    match (group.ip(), interface) {
+        (IpAddr::V4(group_ip), IpAddr::V4(interface)) => {
+            socket.bind(&SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), group.port()).into())?;
+            socket.join_multicast_v4(&group_ip, &interface)?;
+            socket.set_multicast_if_v4(&interface)?;
+            socket.set_multicast_loop_v4(true)?;
+        }
+        (IpAddr::V6(group_ip), IpAddr::V6(_)) => {
+            socket.set_only_v6(true)?;
+            socket.bind(&SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), group.port()).into())?;
+            // Interface 0 lets the system pick one
+            socket.join_multicast_v6(&group_ip, 0)?;
+            socket.set_multicast_loop_v6(true)?;
+        }
+        _ => anyhow::bail!(
+            "Group {} and interface {} differ in family",
+            group,
+            interface
+        ),
+    }
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+async fn run_group(
+    socket: UdpSocket,
+    group: SocketAddr,
+    message: Vec<u8>,
+    info_hashes: Vec<Vec<u8>>,
+    cookie: String,
+    peers_tx: mpsc::UnboundedSender<(Vec<u8>, SocketAddr)>,
+) {
+    let mut announce = tokio::time::interval(ANNOUNCE_INTERVAL);
+    let mut buf = vec![0; 1500];
+
+    loop {
+        tokio::select! {
+            _ = peers_tx.closed() => return,
+            _ = announce.tick() => {
+                if let Err(err) = socket.send_to(&message, group).await {
+                    println!("Local discovery announce to {} failed: {}", group, err);
+                }
+            }
+            received = socket.recv_from(&mut buf) => {
+                let (len, source) = match received {
+                    Ok(received) => received,
+                    Err(_) => continue,
+                };
+                let announcement = match parse_announce(&buf[..len]) {
+                    Some(announcement) => announcement,
+                    None => continue,
+                };
+                if announcement.cookie.as_deref() == Some(cookie.as_str()) {
+                    continue;
+                }
+
+                // The source keeps the scope of IPv6 link-local addresses
+                let mut peer = source;
+                peer.set_port(announcement.port);
+                for info_hash in announcement.info_hashes {
+                    if !info_hashes.contains(&info_hash) {
+                        continue;
+                    }
+                    if peers_tx.send((info_hash, peer)).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn announce_message(
+    group: SocketAddr,
+    port: u16,
+    info_hashes: &[Vec<u8>],
+    cookie: &str,
+) -> Vec<u8> {
+    let mut message = format!(
+        "BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n",
+        group, port
+    );
+    for info_hash in info_hashes {
+        let hex: String = info_hash
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        message.push_str(&format!("Infohash: {}\r\n", hex));
+    }
+    message.push_str(&format!("cookie: {}\r\n\r\n\r\n", cookie));
+    message.into_bytes()
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Announcement {
+    port: u16,
+    info_hashes: Vec<Vec<u8>>,
+    cookie: Option<String>,
+}
+
+// Header names are case insensitive, unknown headers and malformed info hashes are skipped
+fn parse_announce(message: &[u8]) -> Option<Announcement> {
+    let message = std::str::from_utf8(message).ok()?;
+    let mut lines = message.split("\r\n");
+    if lines.next()? != "BT-SEARCH * HTTP/1.1" {
+        return None;
+    }
+
+    let mut port = None;
+    let mut info_hashes = Vec::new();
+    let mut cookie = None;
+    for line in lines {
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name.trim().to_ascii_lowercase(), value.trim()),
+            None => continue,
+        };
+        match name.as_str() {
+            "port" => port = value.parse().ok(),
+            "infohash" => info_hashes.extend(parse_hex(value)),
+            "cookie" => cookie = Some(value.to_string()),
+            _ => {}
+        }
+    }
+
+    Some(Announcement {
+        port: port.filter(|port| *port != 0)?,
+        info_hashes,
+        cookie,
+    })
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() != 40 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn announce_round_trip() {
+        let info_hashes = vec![vec![0xab; 20], (0..20).collect()];
+        let message = announce_message(IPV4_GROUP, 6881, &info_hashes, "c00k1e");
+        assert!(message.starts_with(
+            b"BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nPort: 6881\r\nInfohash: abab"
+        ));
+        assert_eq!(
+            parse_announce(&message),
+            Some(Announcement {
+                port: 6881,
+                info_hashes,
+                cookie: Some("c00k1e".to_string()),
+            })
+        );
+    }
+
+    #[test]
+    fn other_clients_announcements() {
+        let message = b"BT-SEARCH * HTTP/1.1\r\nHOST: [ff15::efc0:988f]:6771\r\nport: 51413\r\n\
+            infohash: 0123456789ABCDEF0123456789ABCDEF01234567\r\nInfohash: nothex\r\n\r\n\r\n";
+        let announcement = parse_announce(message).unwrap();
+        assert_eq!(announcement.port, 51413);
+        assert_eq!(announcement.info_hashes.len(), 1);
+        assert_eq!(announcement.info_hashes[0][..2], [0x01, 0x23]);
+        assert_eq!(announcement.cookie, None);
+
+        assert_eq!(
+            parse_announce(b"M-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n"),
+            None
+        );
+        assert_eq!(
+            parse_announce(b"BT-SEARCH * HTTP/1.1\r\nPort: 0\r\n\r\n"),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn peers_on_loopback() {
+        // A port of our own, so a client running on this machine doesn't interfere
+        let group = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(239, 192, 152, 143)), 16771);
+        let groups = [(group, IpAddr::V4(Ipv4Addr::LOCALHOST))];
+        let info_hash = vec![7; 20];
+
+        let mut first = LocalDiscovery::start_on(&groups, vec![info_hash.clone()], 1111);
+        let mut second =
+            LocalDiscovery::start_on(&groups, vec![vec![1; 20], info_hash.clone()], 2222);
+
+        let timeout = Duration::from_secs(5);
+        let (found, peer) = tokio::time::timeout(timeout, second.next_peer())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(found, info_hash);
+        assert_eq!(peer, "127.0.0.1:1111".parse().unwrap());
+
+        // Only the torrent both share, never ourselves
+        let (found, peer) = tokio::time::timeout(timeout, first.next_peer())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(found, info_hash);
+        assert_eq!(peer, "127.0.0.1:2222".parse().unwrap());
+    }
+}
@@ -1,26 +1,383 @@
 #![deny(warnings)]
 
-mod download;
-mod filewriter;
-mod p2p;
-mod torrent_file_handler;
-mod tracker;
-
+use rusty_torrent::daemon::{self, DaemonOptions, Session};
+use rusty_torrent::download;
+use rusty_torrent::download::rate_limit::{self, LimitSchedule, Limits};
+use rusty_torrent::ip_filter::{self, IpFilter};
+use rusty_torrent::proxy;
+use rusty_torrent::torrent_file_handler::{
+    torrent_creator, torrent_data_extractor, torrent_file_parser,
+};
+use rusty_torrent::tracker;
 use std::env;
 
-fn main() {
+#[tokio::main]
+async fn main() {
     let args: Vec<String> = env::args().collect();
     if args.len() == 1 {
         println!("Please provide a torrent file name");
         return;
-    } else if args.len() > 2 {
-        println!("Too many arguments: please provide only a torrent file name");
+    }
+
+    if args[1] == "files" {
+        if args.len() != 3 {
+            println!("Please provide only a torrent file name to list its files");
+            return;
+        }
+        if let Err(err) = list_files(args[2].to_string()) {
+            println!("{:?}", err);
+        }
+        return;
+    }
+
+    if args[1] == "create" {
+        if let Err(err) = create(&args[2..]) {
+            println!("{:?}", err);
+        }
+        return;
+    }
+
+    if args[1] == "scrape" {
+        if args.len() != 3 {
+            println!("Please provide only a torrent file name to scrape");
+            return;
+        }
+        if let Err(err) = scrape(args[2].to_string()).await {
+            println!("{:?}", err);
+        }
+        return;
+    }
+
+    // The daemon takes the same session options, and a torrent to start with is added through
+    // its API instead
+    let is_daemon = args[1] == "daemon";
+    let flags = if is_daemon { &args[2..] } else { &args[1..] };
+    let mut daemon_options = DaemonOptions {
+        http: None,
+        socket: None,
+        token: String::new(),
+    };
+    let mut state_dir = std::path::PathBuf::from(".rusty_torrent");
+
+    let mut options = download::DownloadOptions::default();
+    let mut filename = None;
+    let mut session_limits = Limits::default();
+    let mut peer_limits = Limits::default();
+    let mut schedule = None;
+    let mut ip_filter_path = None;
+    for arg in flags {
+        let limit = IntoIterator::into_iter([
+            ("--download-limit=", &mut session_limits.download),
+            ("--upload-limit=", &mut session_limits.upload),
+            ("--peer-download-limit=", &mut peer_limits.download),
+            ("--peer-upload-limit=", &mut peer_limits.upload),
+        ])
+        .find_map(|(prefix, limit)| Some((arg.strip_prefix(prefix)?, limit)));
+        if let Some((value, limit)) = limit {
+            match value.parse::<u64>() {
+                Ok(kib) => *limit = kib * 1024,
+                Err(err) => {
+                    println!("{}: limits are in KiB/s, 0 is unlimited", err);
+                    return;
+                }
+            }
+            continue;
+        }
+
+        let proxy_arg = IntoIterator::into_iter(["--proxy=", "--tracker-proxy=", "--peer-proxy="])
+            .find_map(|prefix| Some((prefix, arg.strip_prefix(prefix)?)));
+        if let Some((prefix, url)) = proxy_arg {
+            let proxy: proxy::Proxy = match url.parse() {
+                Ok(proxy) => proxy,
+                Err(err) => {
+                    println!(
+                        "{}: use socks5://, socks4a:// or http://[user:password@]host:port",
+                        err
+                    );
+                    return;
+                }
+            };
+            if prefix != "--peer-proxy=" {
+                options.proxies.trackers = Some(proxy.clone());
+            }
+            if prefix != "--tracker-proxy=" {
+                options.proxies.peers = Some(proxy);
+            }
+            continue;
+        }
+
+        if arg == "--limit-overhead" {
+            options
+                .throttles
+                .include_overhead
+                .store(true, std::sync::atomic::Ordering::Relaxed);
+        } else if let Some(alternative) = arg.strip_prefix("--alt-limits=") {
+            match parse_limit_schedule(alternative) {
+                Ok(parsed) => schedule = Some(parsed),
+                Err(err) => {
+                    println!(
+                        "{}: use <download KiB/s>:<upload KiB/s>@<HH:MM>-<HH:MM>",
+                        err
+                    );
+                    return;
+                }
+            }
+        } else if arg == "--force-proxy" {
+            options.proxies.force = true;
+        } else if let Some(path) = arg.strip_prefix("--ip-filter=") {
+            ip_filter_path = Some(std::path::PathBuf::from(path));
+        } else if let Some(policy) = arg.strip_prefix("--encryption=") {
+            match policy.parse() {
+                Ok(policy) => options.encryption = policy,
+                Err(err) => {
+                    println!("{}: use disabled, enabled or forced", err);
+                    return;
+                }
+            }
+        } else if let Some(mode) = arg.strip_prefix("--mode=") {
+            match mode.parse() {
+                Ok(mode) => options.mode = mode,
+                Err(err) => {
+                    println!("{}: use random, sequential or streaming", err);
+                    return;
+                }
+            }
+        } else if let Some(priority) = arg.strip_prefix("--priority=") {
+            match parse_file_priority(priority) {
+                Ok((index, priority)) => {
+                    options.file_priorities.insert(index, priority);
+                }
+                Err(err) => {
+                    println!("{}: use <file index>:<skip, low, normal or high>", err);
+                    return;
+                }
+            }
+        } else if let Some(address) = arg.strip_prefix("--http=").filter(|_| is_daemon) {
+            match address.parse() {
+                Ok(address) => daemon_options.http = Some(address),
+                Err(err) => {
+                    println!("{}: use <address>:<port>", err);
+                    return;
+                }
+            }
+        } else if let Some(path) = arg.strip_prefix("--socket=").filter(|_| is_daemon) {
+            daemon_options.socket = Some(std::path::PathBuf::from(path));
+        } else if let Some(token) = arg.strip_prefix("--token=").filter(|_| is_daemon) {
+            daemon_options.token = token.to_string();
+        } else if let Some(dir) = arg.strip_prefix("--state-dir=").filter(|_| is_daemon) {
+            state_dir = std::path::PathBuf::from(dir);
+        } else if filename.is_none() && !is_daemon {
+            filename = Some(arg.to_string());
+        } else if is_daemon {
+            println!(
+                "Unknown daemon option {}: torrents are added through the API",
+                arg
+            );
+            return;
+        } else {
+            println!("Too many arguments: please provide only a torrent file name");
+            return;
+        }
+    }
+    if filename.is_none() && !is_daemon {
+        println!("Please provide a torrent file name");
         return;
     }
-    let filename = (&args[1]).to_string();
 
-    match download::download(filename) {
+    // A single torrent is the whole session, unless it is the daemon's
+    options.throttles.session.limit().set(session_limits);
+    options.throttles.peer_limit.set(peer_limits);
+    if let Some(schedule) = schedule {
+        tokio::spawn(rate_limit::follow_schedule(
+            std::sync::Arc::clone(options.throttles.session.limit()),
+            schedule,
+        ));
+    }
+    if let Some(path) = ip_filter_path {
+        match IpFilter::load(&path) {
+            Ok(filter) => *options.ip_filter.write().unwrap() = filter,
+            Err(err) => {
+                println!("{:?}", err);
+                return;
+            }
+        }
+        // Edits to the list apply without a restart
+        tokio::spawn(ip_filter::watch(
+            path,
+            std::sync::Arc::clone(&options.ip_filter),
+        ));
+    }
+
+    let filename = match filename {
+        Some(filename) => {
+            // The pieces directory outlives the download, so bans are kept there
+            let bans = std::path::Path::new(&options.pieces_dir).join("banned_peers");
+            match download::BannedPeers::load(bans) {
+                Ok(banned_peers) => options.banned_peers = std::sync::Arc::new(banned_peers),
+                Err(err) => {
+                    println!("{:?}", err);
+                    return;
+                }
+            }
+            filename
+        }
+        None => {
+            if let Err(err) = run_daemon(options, daemon_options, state_dir).await {
+                println!("{:?}", err);
+            }
+            return;
+        }
+    };
+    match download::download(filename, options).await {
         Ok(()) => println!("Download finished successfully"),
         Err(err) => println!("{:?}", err),
     }
 }
+
+// daemon [--http=<address>:<port>] [--socket=<path>] [--token=<token>] [--state-dir=<dir>]
+//        [session options]
+async fn run_daemon(
+    defaults: download::DownloadOptions,
+    mut options: DaemonOptions,
+    state_dir: std::path::PathBuf,
+) -> anyhow::Result<()> {
+    if options.http.is_none() && options.socket.is_none() {
+        options.http = Some(daemon::DEFAULT_HTTP_ADDRESS.parse()?);
+    }
+    if options.token.is_empty() {
+        options.token = daemon::token(&state_dir)?;
+    }
+    let session = Session::start(defaults, state_dir).await?;
+    daemon::run(session, options).await
+}
+
+fn parse_file_priority(arg: &str) -> anyhow::Result<(usize, download::FilePriority)> {
+    let (index, priority) = arg
+        .split_once(':')
+        .ok_or(anyhow::anyhow!("Wrong file priority {}", arg))?;
+    Ok((index.parse()?, priority.parse()?))
+}
+
+// <download KiB/s>:<upload KiB/s>@<HH:MM>-<HH:MM>, times are UTC
+fn parse_limit_schedule(arg: &str) -> anyhow::Result<LimitSchedule> {
+    let wrong = || anyhow::anyhow!("Wrong limit schedule {}", arg);
+    let (limits, window) = arg.split_once('@').ok_or_else(wrong)?;
+    let (download, upload) = limits.split_once(':').ok_or_else(wrong)?;
+    let (start, end) = window.split_once('-').ok_or_else(wrong)?;
+    let minute_of_day = |time: &str| -> anyhow::Result<u32> {
+        let (hours, minutes) = time.split_once(':').ok_or_else(wrong)?;
+        let (hours, minutes): (u32, u32) = (hours.parse()?, minutes.parse()?);
+        anyhow::ensure!(hours < 24 && minutes < 60, "Wrong time {}", time);
+        Ok(hours * 60 + minutes)
+    };
+    Ok(LimitSchedule {
+        start: minute_of_day(start)?,
+        end: minute_of_day(end)?,
+        limits: Limits {
+            download: download.parse::<u64>()? * 1024,
+            upload: upload.parse::<u64>()? * 1024,
+        },
+    })
+}
+
+// Shows the indices used by --priority
+fn list_files(filename: String) -> anyhow::Result<()> {
+    let (torrent_data, _) = torrent_file_parser::parse_torrent_file(filename)?;
+    let torrent_data = torrent_data_extractor::extract_data(torrent_data)?;
+    if torrent_data.private {
+        println!("Private torrent");
+    }
+    for (index, file) in torrent_data.files.iter().enumerate() {
+        println!(
+            "{}: {} ({} bytes)",
+            index,
+            file.path_to_file.join("/"),
+            file.size
+        );
+    }
+    Ok(())
+}
+
+// create <path> [--output=<file>] [--tracker=<url>[,<url>...]]... [--piece-length=<bytes>]
+//        [--comment=<text>] [--private] [--web-seed=<url>]... [--node=<host>:<port>]...
+fn create(args: &[String]) -> anyhow::Result<()> {
+    let mut options = torrent_creator::TorrentOptions {
+        created_by: Some(format!("rusty_torrent {}", env!("CARGO_PKG_VERSION"))),
+        creation_date: Some(
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs() as i64,
+        ),
+        ..Default::default()
+    };
+    let mut path = None;
+    let mut output = None;
+    for arg in args {
+        if let Some(file) = arg.strip_prefix("--output=") {
+            output = Some(file.to_string());
+        } else if let Some(tier) = arg.strip_prefix("--tracker=") {
+            // Every --tracker is a tier, commas separate trackers of the same tier
+            options
+                .trackers
+                .push(tier.split(',').map(str::to_string).collect());
+        } else if let Some(piece_length) = arg.strip_prefix("--piece-length=") {
+            options.piece_length = Some(piece_length.parse()?);
+        } else if let Some(comment) = arg.strip_prefix("--comment=") {
+            options.comment = Some(comment.to_string());
+        } else if arg == "--private" {
+            options.private = true;
+        } else if let Some(url) = arg.strip_prefix("--web-seed=") {
+            options.web_seeds.push(url.to_string());
+        } else if let Some(node) = arg.strip_prefix("--node=") {
+            let (host, port) = node
+                .rsplit_once(':')
+                .ok_or(anyhow::anyhow!("Wrong node {}: use <host>:<port>", node))?;
+            options.nodes.push((host.to_string(), port.parse()?));
+        } else if path.is_none() {
+            path = Some(arg.to_string());
+        } else {
+            anyhow::bail!("Too many arguments: please provide only a file or directory");
+        }
+    }
+    let path = path.ok_or(anyhow::anyhow!(
+        "Please provide a file or directory to create a torrent from"
+    ))?;
+
+    let path = std::path::Path::new(&path);
+    let (torrent, info_hash) = torrent_creator::create_torrent(path, &options)?;
+    let output = match output {
+        Some(output) => output,
+        None => format!(
+            "{}.torrent",
+            path.canonicalize()?
+                .file_name()
+                .ok_or(anyhow::anyhow!("Couldn't get name of {}", path.display()))?
+                .to_string_lossy()
+        ),
+    };
+    std::fs::write(&output, torrent)?;
+    let info_hash: String = info_hash
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    println!("{} created, info hash {}", output, info_hash);
+    Ok(())
+}
+
+async fn scrape(filename: String) -> anyhow::Result<()> {
+    let (torrent_data, info_hash) = torrent_file_parser::parse_torrent_file(filename)?;
+    let torrent_data = torrent_data_extractor::extract_data(torrent_data)?;
+
+    let client = tracker::TrackerClient::new(&Default::default()).await?;
+    for (tracker, response) in client.scrape_torrent(&torrent_data, &info_hash).await {
+        match response {
+            Ok(Some(stats)) => println!(
+                "{}: {} seeders, {} leechers, {} downloads",
+                tracker, stats.complete, stats.incomplete, stats.downloaded
+            ),
+            Ok(None) => println!("{}: torrent is unknown to the tracker", tracker),
+            Err(err) => println!("{}: {}", tracker, err),
+        }
+    }
+    Ok(())
+}
@@ -1,29 +1,109 @@
-use tokio::{io::AsyncReadExt, net::TcpStream};
+/*
+ *   The pieces a peer has. Starts from its bitfield (or have_all/have_none), or empty if the peer
+ *   skipped it, and is updated by every have message during the connection.
+ */
 
-pub async fn parse_bitfield(
-    stream: &mut TcpStream,
-    expected_length: usize,
-) -> anyhow::Result<Vec<u8>> {
-    let mut length: [u8; 4] = [0; 4];
-    stream.read_exact(&mut length).await?;
-    let length = u32::from_be_bytes(length) - 1; // bitfield length + 1 for id
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bits: Vec<u8>,
+    pieces: usize,
+}
+
+pub fn bitfield_length(pieces: usize) -> usize {
+    pieces / 8 + !pieces.is_multiple_of(8) as usize
+}
 
-    anyhow::ensure!(
-        length as usize == expected_length,
-        "Expected and recieved lengths don't match",
-    );
+impl Bitfield {
+    pub fn empty(pieces: usize) -> Bitfield {
+        Bitfield {
+            bits: vec![0; bitfield_length(pieces)],
+            pieces,
+        }
+    }
 
-    let mut buf: [u8; 1] = [0];
+    // What a have_all message stands for
+    pub fn full(pieces: usize) -> Bitfield {
+        let mut bits = vec![0xFF; bitfield_length(pieces)];
+        if !pieces.is_multiple_of(8) {
+            bits[pieces / 8] = !(0xFF >> (pieces % 8));
+        }
+        Bitfield { bits, pieces }
+    }
 
-    stream.read_exact(&mut buf).await?;
-    let id = buf[0];
+    // Peers have to send a bitfield of the exact length with the spare bits cleared
+    pub fn from_bytes(bits: Vec<u8>, pieces: usize) -> anyhow::Result<Bitfield> {
+        anyhow::ensure!(
+            bits.len() == bitfield_length(pieces),
+            "Expected and recieved lengths don't match",
+        );
+        if !pieces.is_multiple_of(8) {
+            let spare_bits = 0xFF >> (pieces % 8);
+            anyhow::ensure!(
+                bits[bits.len() - 1] & spare_bits == 0,
+                "Spare bits of the bitfield are set"
+            );
+        }
+        Ok(Bitfield { bits, pieces })
+    }
 
-    anyhow::ensure!(id == 5, "Wrong message id");
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+
+    pub fn has_piece(&self, index: usize) -> bool {
+        index < self.pieces && self.bits[index / 8] & (1 << (7 - index % 8)) != 0
+    }
+
+    pub fn set_piece(&mut self, index: usize) -> anyhow::Result<()> {
+        anyhow::ensure!(index < self.pieces, "Piece {} doesn't exist", index);
+        self.bits[index / 8] |= 1 << (7 - index % 8);
+        Ok(())
+    }
+
+    pub fn count(&self) -> usize {
+        self.bits
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.count() == self.pieces
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Bitfield;
+
+    #[test]
+    fn full_bitfield() {
+        let bitfield = Bitfield::full(10);
+        assert_eq!(bitfield.as_bytes(), [0xFF, 0b1100_0000]);
+        assert!(bitfield.has_piece(9));
+        assert!(!bitfield.has_piece(10));
+        assert!(bitfield.is_complete());
+        assert_eq!(
+            Bitfield::from_bytes(vec![0xFF, 0b1100_0000], 10).unwrap(),
+            bitfield
+        );
+    }
+
+    #[test]
+    fn invalid_bitfields() {
+        assert!(Bitfield::from_bytes(vec![0xFF, 0xFF], 10).is_err());
+        assert!(Bitfield::from_bytes(vec![0xFF], 10).is_err());
+    }
 
-    let mut bitfield = Vec::new();
-    for _ in 0..length {
-        stream.read_exact(&mut buf).await?;
-        bitfield.push(buf[0]);
+    #[test]
+    fn have_messages() {
+        let mut bitfield = Bitfield::empty(10);
+        assert_eq!(bitfield.count(), 0);
+        bitfield.set_piece(3).unwrap();
+        bitfield.set_piece(3).unwrap();
+        bitfield.set_piece(9).unwrap();
+        assert!(bitfield.has_piece(3) && bitfield.has_piece(9));
+        assert_eq!(bitfield.count(), 2);
+        assert!(bitfield.set_piece(10).is_err());
     }
-    Ok(bitfield)
 }
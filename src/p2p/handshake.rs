@@ -1,59 +1,231 @@
+use super::mse::{self, EncryptionPolicy, MseStream};
+use super::peer_stream::{BoxedPeerStream, PeerStream};
+use crate::proxy::{self, Proxy};
+use crate::utp::UtpSocket;
+use std::future::Future;
 use std::net::SocketAddr;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(3);
+// How long uTP is tried alone before TCP joins the race
+const UTP_HEAD_START: Duration = Duration::from_millis(500);
+const PROTOCOL: &[u8] = b"BitTorrent protocol";
+// Reserved bit announcing the Fast Extension (BEP 6)
+const FAST_EXTENSION_BIT: u8 = 0x04;
+// Reserved bit announcing a v2 capable client (BEP 52), sent for v2 and hybrid torrents
+const V2_BIT: u8 = 0x10;
+
+pub struct Handshake {
+    pub reserved: [u8; 8],
+    pub info_hash: Vec<u8>,
+    pub peer_id: Vec<u8>,
+}
+
+impl Handshake {
+    // Both sides have to set the bit for the extension to be used
+    pub fn supports_fast_extension(&self) -> bool {
+        self.reserved[7] & FAST_EXTENSION_BIT != 0
+    }
+
+    // Only such peers understand hash requests
+    pub fn supports_v2(&self) -> bool {
+        self.reserved[7] & V2_BIT != 0
+    }
+}
+
+// Through the proxy when one is given. Otherwise over uTP when a socket is given and the peer
+// answers on it quickly, else over whichever of uTP and TCP gets through first. `v2` for
+// torrents with v2 hashes.
 pub async fn perform_handshake(
-    peer_ip: String,
+    peer: SocketAddr,
     info_hash: Vec<u8>,
     peer_id: Vec<u8>,
-    pstr_option: Option<String>,
-) -> anyhow::Result<TcpStream> {
-    // println!("Performing handshake with {:?}", peer_ip);
-    let mut stream = tokio::time::timeout(
-        std::time::Duration::from_secs(3),
-        TcpStream::connect(&peer_ip.parse::<SocketAddr>()?),
-    )
-    .await??;
-    stream
-        .write_all(&mut create_handshake_msg(&info_hash, &peer_id, pstr_option))
-        .await?; // my panic code: 104, kind: ConnectionReset, message: "Connection reset by peer"
-    let mut buf: [u8; 1] = [0; 1];
+    v2: bool,
+    encryption: EncryptionPolicy,
+    utp: Option<UtpSocket>,
+    proxy: Option<Proxy>,
+) -> anyhow::Result<(BoxedPeerStream, Handshake)> {
+    // println!("Performing handshake with {:?}", peer);
+    let msg = create_handshake_msg(&info_hash, &peer_id, None, v2);
+
+    if let Some(proxy) = proxy {
+        let connect =
+            || async { Ok(Box::new(proxy::connect_to(&proxy, peer).await?) as BoxedPeerStream) };
+        return handshake_over(connect, &msg, &info_hash, encryption).await;
+    }
+
+    let connect_tcp = || async { Ok(Box::new(TcpStream::connect(peer).await?) as BoxedPeerStream) };
+    if let Some(utp) = utp {
+        let connect = || async { Ok(Box::new(utp.connect(peer).await?) as BoxedPeerStream) };
+        let over_utp = handshake_over(connect, &msg, &info_hash, encryption);
+        tokio::pin!(over_utp);
+        tokio::select! {
+            // A failed uTP handshake leaves TCP alone
+            connection = &mut over_utp => {
+                if let Ok(connection) = connection {
+                    return Ok(connection);
+                }
+            }
+            _ = tokio::time::sleep(UTP_HEAD_START) => {
+                let over_tcp = handshake_over(&connect_tcp, &msg, &info_hash, encryption);
+                tokio::pin!(over_tcp);
+                // The loser is dropped, closing its connection
+                return tokio::select! {
+                    connection = &mut over_utp => match connection {
+                        Ok(connection) => Ok(connection),
+                        Err(_) => over_tcp.await,
+                    },
+                    connection = &mut over_tcp => match connection {
+                        Ok(connection) => Ok(connection),
+                        Err(_) => over_utp.await,
+                    },
+                };
+            }
+        }
+    }
+    handshake_over(connect_tcp, &msg, &info_hash, encryption).await
+    // println!("Connected to {:?}", peer);
+}
+
+async fn handshake_over<F, C>(
+    connect: F,
+    msg: &[u8],
+    info_hash: &[u8],
+    encryption: EncryptionPolicy,
+) -> anyhow::Result<(BoxedPeerStream, Handshake)>
+where
+    F: Fn() -> C,
+    C: Future<Output = anyhow::Result<BoxedPeerStream>>,
+{
+    if encryption != EncryptionPolicy::Disabled {
+        // An unreachable peer isn't worth a second try in plaintext
+        let stream = tokio::time::timeout(HANDSHAKE_TIMEOUT, connect()).await??;
+        let encrypted = tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+            let stream = mse::initiate(stream, info_hash, encryption.crypto_methods()).await?;
+            exchange_handshakes(Box::new(stream), msg, info_hash).await
+        })
+        .await;
+        match encrypted {
+            Ok(Ok(connection)) => return Ok(connection),
+            Ok(Err(err)) if encryption == EncryptionPolicy::Forced => return Err(err),
+            Err(err) if encryption == EncryptionPolicy::Forced => return Err(err.into()),
+            // Peers without MSE support usually just drop the connection, so we reconnect
+            _ => {}
+        }
+    }
+
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+        exchange_handshakes(connect().await?, msg, info_hash).await
+    })
+    .await?
+}
+
+async fn exchange_handshakes(
+    mut stream: BoxedPeerStream,
+    msg: &[u8],
+    info_hash: &[u8],
+) -> anyhow::Result<(BoxedPeerStream, Handshake)> {
+    stream.write_all(msg).await?; // my panic code: 104, kind: ConnectionReset, message: "Connection reset by peer"
+    stream.flush().await?;
+    let handshake = read_handshake(&mut stream).await?;
+    anyhow::ensure!(handshake.info_hash == info_hash, "Hash infos do not match");
+    Ok((stream, handshake))
+}
+
+// Incoming connections: the peer sends its handshake first and we answer only if we
+// are downloading the torrent it asks for. Encrypted connections start with an MSE key
+// instead of the protocol string. A torrent in several swarms is known by several info hashes.
+pub async fn accept_handshake<S: PeerStream + 'static>(
+    stream: S,
+    info_hashes: Vec<Vec<u8>>,
+    peer_id: Vec<u8>,
+    v2: bool,
+    encryption: EncryptionPolicy,
+) -> anyhow::Result<(BoxedPeerStream, Handshake)> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+        let mut stream = stream;
+        let mut received = [0; 20];
+        stream.read_exact(&mut received).await?;
+
+        let mut stream: BoxedPeerStream = if received[0] as usize == PROTOCOL.len()
+            && &received[1..] == PROTOCOL
+        {
+            anyhow::ensure!(
+                encryption != EncryptionPolicy::Forced,
+                "Plaintext connections are refused"
+            );
+            Box::new(MseStream::plaintext(stream, received.to_vec()))
+        } else {
+            anyhow::ensure!(
+                encryption != EncryptionPolicy::Disabled,
+                "Encrypted connections are refused"
+            );
+            Box::new(
+                mse::respond(stream, &received, &info_hashes, encryption.crypto_methods()).await?,
+            )
+        };
+
+        let handshake = read_handshake(&mut stream).await?;
+        anyhow::ensure!(
+            info_hashes.contains(&handshake.info_hash),
+            "Hash infos do not match"
+        );
+        stream
+            .write_all(&create_handshake_msg(
+                &handshake.info_hash,
+                &peer_id,
+                None,
+                v2,
+            ))
+            .await?;
+        stream.flush().await?;
+        Ok((stream, handshake))
+    })
+    .await?
+}
+
+async fn read_handshake<S: AsyncRead + Unpin + ?Sized>(
+    stream: &mut S,
+) -> anyhow::Result<Handshake> {
     let mut pstr_len: [u8; 1] = [0];
-    let mut pstr_and_reserved = Vec::new();
     let mut hash: [u8; 20] = [0; 20];
     let mut id: [u8; 20] = [0; 20];
     stream.read_exact(&mut pstr_len).await?;
-    for _ in 0..pstr_len[0] + 8 {
-        stream.read_exact(&mut buf).await?;
-        pstr_and_reserved.push(buf[0]);
-    }
+    let mut pstr = vec![0; pstr_len[0] as usize];
+    stream.read_exact(&mut pstr).await?;
+    let mut reserved: [u8; 8] = [0; 8];
+    stream.read_exact(&mut reserved).await?;
     stream.read_exact(&mut hash).await?;
     stream.read_exact(&mut id).await?;
-    for i in 0..20 {
-        anyhow::ensure!(hash[i] == info_hash[i], "Hash infos do not match");
-    }
-    // println!("Connected to {:?}", peer_ip);
-    Ok(stream)
+    Ok(Handshake {
+        reserved,
+        info_hash: hash.to_vec(),
+        peer_id: id.to_vec(),
+    })
 }
 
 fn create_handshake_msg(
-    info_hash: &Vec<u8>,
-    peer_id: &Vec<u8>,
+    info_hash: &[u8],
+    peer_id: &[u8],
     pstr_option: Option<String>,
+    v2: bool,
 ) -> Vec<u8> {
     let mut msg: Vec<u8> = Vec::new();
-    let default_pstr = "BitTorrent protocol".to_string();
     let pstr = match &pstr_option {
         Some(string) => string.as_bytes(),
-        None => default_pstr.as_bytes(),
+        None => PROTOCOL,
     };
     msg.push(pstr.len() as u8);
     for byte in pstr.iter() {
         msg.push(*byte);
     }
-    for _ in 0..8 {
-        msg.push(0); // reserved part with 8 zero bytes
+    let mut reserved = [0, 0, 0, 0, 0, 0, 0, FAST_EXTENSION_BIT];
+    if v2 {
+        reserved[7] |= V2_BIT;
     }
+    msg.extend_from_slice(&reserved); // reserved part
     for byte in info_hash.iter() {
         msg.push(*byte);
     }
@@ -74,11 +246,14 @@ fn create_handshake_default_msg_test() {
     ];
     let result = vec![
         19, 66, 105, 116, 84, 111, 114, 114, 101, 110, 116, 32, 112, 114, 111, 116, 111, 99, 111,
-        108, 0, 0, 0, 0, 0, 0, 0, 0, 255, 125, 75, 51, 96, 126, 249, 69, 90, 173, 209, 54, 159, 46,
+        108, 0, 0, 0, 0, 0, 0, 0, 4, 255, 125, 75, 51, 96, 126, 249, 69, 90, 173, 209, 54, 159, 46,
         10, 142, 230, 141, 83, 200, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18,
         19, 20,
     ];
-    assert_eq!(create_handshake_msg(&info_hash, &peer_id, None), result);
+    assert_eq!(
+        create_handshake_msg(&info_hash, &peer_id, None, false),
+        result
+    );
 }
 
 #[test]
@@ -92,12 +267,202 @@ fn create_handshake_msg_test() {
     let pstr = "ajhfhavbghajgjahwygajbg".to_string();
     let result = vec![
         23, 97, 106, 104, 102, 104, 97, 118, 98, 103, 104, 97, 106, 103, 106, 97, 104, 119, 121,
-        103, 97, 106, 98, 103, 0, 0, 0, 0, 0, 0, 0, 0, 255, 125, 75, 51, 96, 126, 249, 69, 90, 173,
+        103, 97, 106, 98, 103, 0, 0, 0, 0, 0, 0, 0, 4, 255, 125, 75, 51, 96, 126, 249, 69, 90, 173,
         209, 54, 159, 46, 10, 142, 230, 141, 83, 200, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13,
         14, 15, 16, 17, 18, 19, 20,
     ];
     assert_eq!(
-        create_handshake_msg(&info_hash, &peer_id, Some(pstr)),
+        create_handshake_msg(&info_hash, &peer_id, Some(pstr), false),
         result
     );
 }
+
+#[test]
+fn v2_bit_is_set_for_v2_torrents() {
+    let msg = create_handshake_msg(&[1; 20], &[2; 20], None, true);
+    let mut reserved = [0; 8];
+    reserved.copy_from_slice(&msg[20..28]);
+    assert_eq!(reserved, [0, 0, 0, 0, 0, 0, 0, FAST_EXTENSION_BIT | V2_BIT]);
+
+    let handshake = Handshake {
+        reserved,
+        info_hash: vec![1; 20],
+        peer_id: vec![2; 20],
+    };
+    assert!(handshake.supports_v2());
+    assert!(handshake.supports_fast_extension());
+}
+
+#[cfg(test)]
+async fn connect_with(
+    outgoing: EncryptionPolicy,
+    incoming: EncryptionPolicy,
+) -> anyhow::Result<()> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let peer = listener.local_addr()?;
+    let info_hash = vec![9; 20];
+
+    // Hybrid torrents are accepted by either info hash
+    let accepted_info_hash = vec![vec![8; 20], info_hash.clone()];
+    let accepting = tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let accepted = accept_handshake(
+                stream,
+                accepted_info_hash.clone(),
+                vec![1; 20],
+                false,
+                incoming,
+            )
+            .await;
+            if let Ok(connection) = accepted {
+                return connection;
+            }
+        }
+    });
+
+    let connected =
+        perform_handshake(peer, info_hash, vec![2; 20], false, outgoing, None, None).await;
+    let (mut connected, _) = match connected {
+        Ok(connection) => connection,
+        Err(err) => {
+            accepting.abort();
+            return Err(err);
+        }
+    };
+    let (mut accepted, handshake) = accepting.await?;
+    assert_eq!(handshake.peer_id, vec![2; 20]);
+
+    // Both ends have to agree on the encryption
+    connected.write_all(b"ping").await?;
+    connected.flush().await?;
+    let mut buf = [0; 4];
+    accepted.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"ping");
+    Ok(())
+}
+
+#[tokio::test]
+async fn encryption_policies() {
+    use EncryptionPolicy::*;
+    for (outgoing, incoming) in [
+        (Enabled, Enabled),
+        (Forced, Enabled),
+        (Enabled, Disabled),
+        (Disabled, Enabled),
+    ] {
+        assert!(
+            connect_with(outgoing, incoming).await.is_ok(),
+            "{:?} to {:?}",
+            outgoing,
+            incoming
+        );
+    }
+
+    assert!(connect_with(Forced, Disabled).await.is_err());
+    assert!(connect_with(Disabled, Forced).await.is_err());
+}
+
+#[tokio::test]
+async fn utp_with_tcp_fallback() {
+    let info_hash = vec![9; 20];
+    let server = UtpSocket::bind(0).await.unwrap();
+    let peer = SocketAddr::new("127.0.0.1".parse().unwrap(), server.local_addr().port());
+
+    let accepted_info_hash = info_hash.clone();
+    let accepting = tokio::spawn(async move {
+        let (stream, _) = server.accept().await.unwrap();
+        let (mut accepted, _) = accept_handshake(
+            stream,
+            vec![accepted_info_hash],
+            vec![1; 20],
+            false,
+            EncryptionPolicy::Enabled,
+        )
+        .await
+        .unwrap();
+        let mut buf = [0; 4];
+        accepted.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    });
+    let utp = UtpSocket::bind(0).await.unwrap();
+    let (mut connected, _) = perform_handshake(
+        peer,
+        info_hash.clone(),
+        vec![2; 20],
+        false,
+        EncryptionPolicy::Enabled,
+        Some(utp.clone()),
+        None,
+    )
+    .await
+    .unwrap();
+    connected.write_all(b"ping").await.unwrap();
+    connected.flush().await.unwrap();
+    accepting.await.unwrap();
+
+    // Nobody answers on UDP, so the connection is made over TCP without waiting for uTP to give up
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let peer = listener.local_addr().unwrap();
+    let accepting = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        accept_handshake(
+            stream,
+            vec![vec![9; 20]],
+            vec![1; 20],
+            false,
+            EncryptionPolicy::Enabled,
+        )
+        .await
+        .unwrap();
+    });
+    let started = std::time::Instant::now();
+    let connected = perform_handshake(
+        peer,
+        info_hash,
+        vec![2; 20],
+        false,
+        EncryptionPolicy::Enabled,
+        Some(utp),
+        None,
+    )
+    .await;
+    assert!(connected.is_ok());
+    assert!(started.elapsed() < HANDSHAKE_TIMEOUT);
+    accepting.await.unwrap();
+}
+
+#[tokio::test]
+async fn handshake_through_proxy() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let peer = listener.local_addr().unwrap();
+    let accepting = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        accept_handshake(
+            stream,
+            vec![vec![9; 20]],
+            vec![1; 20],
+            false,
+            EncryptionPolicy::Enabled,
+        )
+        .await
+        .unwrap();
+    });
+
+    let proxy_addr = proxy::stand_in::socks5(None).await;
+    let proxy: Proxy = format!("socks5://{}", proxy_addr).parse().unwrap();
+    // uTP is left out, it can't go through the proxy
+    let (_, handshake) = perform_handshake(
+        peer,
+        vec![9; 20],
+        vec![2; 20],
+        false,
+        EncryptionPolicy::Enabled,
+        Some(UtpSocket::bind(0).await.unwrap()),
+        Some(proxy),
+    )
+    .await
+    .unwrap();
+    assert_eq!(handshake.peer_id, vec![1; 20]);
+    accepting.await.unwrap();
+}
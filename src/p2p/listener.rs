@@ -0,0 +1,108 @@
+use futures::future::select_all;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::{TcpListener, TcpStream};
+
+/*
+ *   Accepts incoming peer connections on the same port over IPv4 and IPv6. The IPv6 socket is
+ *   IPv6-only, so both families get their own socket and real peer addresses (no IPv4-mapped
+ *   ones). A system without IPv6 support only listens on IPv4.
+ */
+
+pub struct PeerListener {
+    listeners: Vec<TcpListener>,
+    port: u16,
+}
+
+impl PeerListener {
+    // Port 0 lets the system pick a free port
+    pub async fn bind(port: u16) -> anyhow::Result<PeerListener> {
+        let ipv4 =
+            TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port)).await?;
+        let port = ipv4.local_addr()?.port();
+
+        let mut listeners = vec![ipv4];
+        if let Ok(ipv6) = bind_ipv6_only(port) {
+            listeners.push(ipv6);
+        }
+
+        Ok(PeerListener { listeners, port })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub async fn accept(&self) -> std::io::Result<(TcpStream, SocketAddr)> {
+        let (result, _, _) = select_all(
+            self.listeners
+                .iter()
+                .map(|listener| Box::pin(listener.accept())),
+        )
+        .await;
+        result
+    }
+}
+
+fn bind_ipv6_only(port: u16) -> anyhow::Result<TcpListener> {
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_only_v6(true)?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port).into())?;
+    socket.listen(128)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::p2p::handshake;
+    use crate::p2p::mse::EncryptionPolicy;
+
+    async fn handshake_over(peer: SocketAddr) {
+        let listener = PeerListener::bind(0).await.unwrap();
+        let peer = SocketAddr::new(peer.ip(), listener.port());
+        let info_hash = vec![7; 20];
+
+        let accepting = async {
+            let (stream, remote) = listener.accept().await.unwrap();
+            assert_eq!(remote.is_ipv6(), peer.is_ipv6());
+            let (_, handshake) = handshake::accept_handshake(
+                stream,
+                vec![info_hash.clone()],
+                vec![1; 20],
+                false,
+                EncryptionPolicy::Enabled,
+            )
+            .await
+            .unwrap();
+            assert_eq!(handshake.peer_id, vec![2; 20]);
+        };
+        let connecting = async {
+            let (_, handshake) = handshake::perform_handshake(
+                peer,
+                info_hash.clone(),
+                vec![2; 20],
+                false,
+                EncryptionPolicy::Enabled,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+            assert!(handshake.supports_fast_extension());
+        };
+        futures::join!(accepting, connecting);
+    }
+
+    #[tokio::test]
+    async fn accepts_ipv4_peers() {
+        handshake_over("127.0.0.1:0".parse().unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn accepts_ipv6_peers() {
+        handshake_over("[::1]:0".parse().unwrap()).await;
+    }
+}
@@ -1,105 +1,442 @@
 use std::convert::TryInto;
-use std::{thread, time};
+use tokio::io::{AsyncRead, AsyncReadExt};
 
-pub fn create_request_msg(index: u32, begin: u32, length: u32) -> Vec<u8> {
-    let mut request = Vec::new();
+// Big enough for a 16 KiB block and the bitfield of any sane torrent
+const MAX_MESSAGE_LENGTH: usize = 1 << 21;
+// Pieces root and four numbers of hash requests
+const HASH_RANGE_LENGTH: usize = 48;
 
-    // prefix 13 in four-byte big-endian format
-    for byte in (13 as u32).to_be_bytes().iter() {
-        request.push(*byte);
-    }
+/*
+ *   Peer wire messages (BEP 3), including the ones added by the Fast Extension (BEP 6) and the
+ *   hash transfer of v2 torrents (BEP 52).
+ *   On the wire every message but keep-alive is <length prefix><id><payload>.
+ */
 
-    // id = 6
-    request.push(6);
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Vec<u8>),
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Port(u16),
+    // Fast Extension
+    Suggest(u32),
+    HaveAll,
+    HaveNone,
+    Reject {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    AllowedFast(u32),
+    // v2 torrents: `length` hashes of a file's merkle tree layer `base_layer` from `index`, with
+    // `proof_layers` layers of uncle hashes to check them against the file's root
+    HashRequest(HashRange),
+    Hashes {
+        range: HashRange,
+        hashes: Vec<u8>,
+    },
+    HashReject(HashRange),
+    // Messages of extensions we don't support are skipped
+    Unknown(u8),
+}
 
-    for byte in index.to_be_bytes().iter() {
-        request.push(*byte);
-    }
-    for byte in begin.to_be_bytes().iter() {
-        request.push(*byte);
-    }
-    for byte in length.to_be_bytes().iter() {
-        request.push(*byte);
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashRange {
+    pub pieces_root: Vec<u8>,
+    pub base_layer: u32,
+    pub index: u32,
+    pub length: u32,
+    pub proof_layers: u32,
+}
+
+impl HashRange {
+    fn serialize(&self, payload: &mut Vec<u8>) {
+        payload.extend_from_slice(&self.pieces_root);
+        for value in [self.base_layer, self.index, self.length, self.proof_layers] {
+            payload.extend_from_slice(&value.to_be_bytes());
+        }
     }
 
-    request
+    fn parse(payload: &[u8]) -> HashRange {
+        let read_u32 =
+            |from: usize| u32::from_be_bytes(payload[from..from + 4].try_into().unwrap());
+        HashRange {
+            pieces_root: payload[..32].to_vec(),
+            base_layer: read_u32(32),
+            index: read_u32(36),
+            length: read_u32(40),
+            proof_layers: read_u32(44),
+        }
+    }
 }
 
-#[allow(dead_code)]
-pub fn create_have_msg(index: u32) -> Vec<u8> {
-    let mut have = Vec::new();
+impl Message {
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        let id: u8 = match self {
+            Message::KeepAlive => return 0_u32.to_be_bytes().to_vec(),
+            Message::Choke => 0,
+            Message::Unchoke => 1,
+            Message::Interested => 2,
+            Message::NotInterested => 3,
+            Message::Have(index) => {
+                payload.extend_from_slice(&index.to_be_bytes());
+                4
+            }
+            Message::Bitfield(bitfield) => {
+                payload.extend_from_slice(bitfield);
+                5
+            }
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => {
+                push_block_info(&mut payload, *index, *begin, *length);
+                6
+            }
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                payload.extend_from_slice(&index.to_be_bytes());
+                payload.extend_from_slice(&begin.to_be_bytes());
+                payload.extend_from_slice(block);
+                7
+            }
+            Message::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                push_block_info(&mut payload, *index, *begin, *length);
+                8
+            }
+            Message::Port(port) => {
+                payload.extend_from_slice(&port.to_be_bytes());
+                9
+            }
+            Message::Suggest(index) => {
+                payload.extend_from_slice(&index.to_be_bytes());
+                0x0D
+            }
+            Message::HaveAll => 0x0E,
+            Message::HaveNone => 0x0F,
+            Message::Reject {
+                index,
+                begin,
+                length,
+            } => {
+                push_block_info(&mut payload, *index, *begin, *length);
+                0x10
+            }
+            Message::AllowedFast(index) => {
+                payload.extend_from_slice(&index.to_be_bytes());
+                0x11
+            }
+            Message::HashRequest(range) => {
+                range.serialize(&mut payload);
+                21
+            }
+            Message::Hashes { range, hashes } => {
+                range.serialize(&mut payload);
+                payload.extend_from_slice(hashes);
+                22
+            }
+            Message::HashReject(range) => {
+                range.serialize(&mut payload);
+                23
+            }
+            Message::Unknown(id) => *id,
+        };
 
-    // prefix 5 in four-byte big-endian format
-    for byte in (5 as u32).to_be_bytes().iter() {
-        have.push(*byte);
+        let mut msg = Vec::with_capacity(payload.len() + 5);
+        msg.extend_from_slice(&(payload.len() as u32 + 1).to_be_bytes());
+        msg.push(id);
+        msg.extend_from_slice(&payload);
+        msg
     }
 
-    // id = 4
-    have.push(4);
+    // `message` is everything after the length prefix
+    pub fn parse(message: &[u8]) -> anyhow::Result<Message> {
+        if message.is_empty() {
+            return Ok(Message::KeepAlive);
+        }
+        let id = message[0];
+        let payload = &message[1..];
+
+        let expect_len = |len: usize| {
+            anyhow::ensure!(
+                payload.len() == len,
+                "Message {} has payload of {} bytes, expected {}",
+                id,
+                payload.len(),
+                len
+            );
+            Ok(())
+        };
+        let read_u32 =
+            |from: usize| u32::from_be_bytes(payload[from..from + 4].try_into().unwrap());
 
-    for byte in index.to_be_bytes().iter() {
-        have.push(*byte);
+        let message = match id {
+            0 | 1 | 2 | 3 | 0x0E | 0x0F => {
+                expect_len(0)?;
+                match id {
+                    0 => Message::Choke,
+                    1 => Message::Unchoke,
+                    2 => Message::Interested,
+                    3 => Message::NotInterested,
+                    0x0E => Message::HaveAll,
+                    _ => Message::HaveNone,
+                }
+            }
+            4 | 0x0D | 0x11 => {
+                expect_len(4)?;
+                match id {
+                    4 => Message::Have(read_u32(0)),
+                    0x0D => Message::Suggest(read_u32(0)),
+                    _ => Message::AllowedFast(read_u32(0)),
+                }
+            }
+            5 => Message::Bitfield(payload.to_vec()),
+            6 | 8 | 0x10 => {
+                expect_len(12)?;
+                let (index, begin, length) = (read_u32(0), read_u32(4), read_u32(8));
+                match id {
+                    6 => Message::Request {
+                        index,
+                        begin,
+                        length,
+                    },
+                    8 => Message::Cancel {
+                        index,
+                        begin,
+                        length,
+                    },
+                    _ => Message::Reject {
+                        index,
+                        begin,
+                        length,
+                    },
+                }
+            }
+            7 => {
+                anyhow::ensure!(payload.len() >= 8, "Piece message is too short");
+                Message::Piece {
+                    index: read_u32(0),
+                    begin: read_u32(4),
+                    block: payload[8..].to_vec(),
+                }
+            }
+            9 => {
+                expect_len(2)?;
+                Message::Port(u16::from_be_bytes([payload[0], payload[1]]))
+            }
+            21 | 23 => {
+                expect_len(HASH_RANGE_LENGTH)?;
+                let range = HashRange::parse(payload);
+                match id {
+                    21 => Message::HashRequest(range),
+                    _ => Message::HashReject(range),
+                }
+            }
+            22 => {
+                anyhow::ensure!(
+                    payload.len() >= HASH_RANGE_LENGTH
+                        && (payload.len() - HASH_RANGE_LENGTH).is_multiple_of(32),
+                    "Hashes message has wrong length"
+                );
+                Message::Hashes {
+                    range: HashRange::parse(payload),
+                    hashes: payload[HASH_RANGE_LENGTH..].to_vec(),
+                }
+            }
+            _ => Message::Unknown(id),
+        };
+        Ok(message)
     }
-    have
 }
 
-pub fn create_unchoke_msg() -> Vec<u8> {
-    let mut msg = Vec::new();
+fn push_block_info(payload: &mut Vec<u8>, index: u32, begin: u32, length: u32) {
+    payload.extend_from_slice(&index.to_be_bytes());
+    payload.extend_from_slice(&begin.to_be_bytes());
+    payload.extend_from_slice(&length.to_be_bytes());
+}
 
-    for byte in (1 as u32).to_be_bytes().iter() {
-        msg.push(*byte);
-    }
-    msg.push(1);
-    msg
+pub async fn read_message<R: AsyncRead + Unpin>(stream: &mut R) -> anyhow::Result<Message> {
+    let mut length: [u8; 4] = [0; 4];
+    stream.read_exact(&mut length).await?;
+    let length = u32::from_be_bytes(length) as usize;
+    anyhow::ensure!(
+        length <= MAX_MESSAGE_LENGTH,
+        "Message of {} bytes is too long",
+        length
+    );
+
+    let mut message = vec![0; length];
+    stream.read_exact(&mut message).await?;
+    Message::parse(&message)
+}
+
+// Cancel safe, unlike `read_message`: a read cut off by a timeout keeps what arrived so far
+// for the next one
+#[derive(Default)]
+pub struct MessageReader {
+    buf: Vec<u8>,
 }
 
-pub fn create_interested_msg() -> Vec<u8> {
-    let mut msg = Vec::new();
+impl MessageReader {
+    pub async fn read<R: AsyncRead + Unpin>(&mut self, stream: &mut R) -> anyhow::Result<Message> {
+        loop {
+            if self.buf.len() >= 4 {
+                let length = u32::from_be_bytes(self.buf[..4].try_into().unwrap()) as usize;
+                anyhow::ensure!(
+                    length <= MAX_MESSAGE_LENGTH,
+                    "Message of {} bytes is too long",
+                    length
+                );
+                if self.buf.len() >= 4 + length {
+                    let message = Message::parse(&self.buf[4..4 + length]);
+                    self.buf.drain(..4 + length);
+                    return message;
+                }
+            }
 
-    for byte in (1 as u32).to_be_bytes().iter() {
-        msg.push(*byte);
+            let mut chunk = [0; 16384];
+            let read = stream.read(&mut chunk).await?;
+            anyhow::ensure!(read > 0, "Connection closed");
+            self.buf.extend_from_slice(&chunk[..read]);
+        }
     }
-    msg.push(2);
-    msg
 }
 
-pub fn read_message(message: Vec<u8>) -> anyhow::Result<(bool, Option<Vec<u8>>)> {
-    // returns true if peer choked us
-    let id = message[4];
-    match id {
-        0 => {
-            thread::sleep(time::Duration::new(30, 0));
-            return Ok((true, None));
-        }
-        7 => {
-            return Ok((false, Some(parse_piece_msg(message)?)));
+#[cfg(test)]
+mod tests {
+    use super::{HashRange, Message, MessageReader};
+
+    #[test]
+    fn request_msg() {
+        let request = Message::Request {
+            index: 1,
+            begin: 16384,
+            length: 16384,
+        };
+        assert_eq!(
+            request.serialize(),
+            vec![0, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 64, 0, 0, 0, 64, 0]
+        );
+    }
+
+    #[test]
+    fn fast_extension_msgs() {
+        assert_eq!(Message::HaveAll.serialize(), vec![0, 0, 0, 1, 0x0E]);
+        assert_eq!(
+            Message::AllowedFast(258).serialize(),
+            vec![0, 0, 0, 5, 0x11, 0, 0, 1, 2]
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let messages = vec![
+            Message::KeepAlive,
+            Message::Unchoke,
+            Message::Have(7),
+            Message::Bitfield(vec![0b1010_0000]),
+            Message::Piece {
+                index: 3,
+                begin: 0,
+                block: vec![1, 2, 3],
+            },
+            Message::Suggest(5),
+            Message::HaveNone,
+            Message::Reject {
+                index: 2,
+                begin: 16384,
+                length: 100,
+            },
+            Message::Hashes {
+                range: HashRange {
+                    pieces_root: vec![7; 32],
+                    base_layer: 1,
+                    index: 4,
+                    length: 2,
+                    proof_layers: 1,
+                },
+                hashes: vec![1; 96],
+            },
+        ];
+        for message in messages {
+            assert_eq!(Message::parse(&message.serialize()[4..]).unwrap(), message);
         }
-        _ => {}
-    };
-    Ok((false, None))
-}
+    }
 
-pub fn parse_piece_msg(message: Vec<u8>) -> anyhow::Result<Vec<u8>> {
-    anyhow::ensure!(message.len() >= 13, "Message is too short");
+    #[test]
+    fn wrong_payload_length() {
+        assert!(Message::parse(&[4, 0, 0]).is_err());
+        assert!(Message::parse(&[0x0E, 1]).is_err());
+        assert!(Message::parse(&[7, 0, 0, 0, 1]).is_err());
+        assert!(Message::parse(&[21; 40]).is_err());
+        assert!(Message::parse(&[22; 60]).is_err());
+    }
 
-    let id: u8 = message[4];
-    anyhow::ensure!(
-        id == 7,
-        format!("Not a piece message: wrong id, expected 7, got {}", id)
-    );
+    #[tokio::test]
+    async fn reading_from_stream() {
+        let mut data = Message::Choke.serialize();
+        data.extend_from_slice(&Message::Have(1).serialize());
+        let mut stream = data.as_slice();
+        assert_eq!(
+            super::read_message(&mut stream).await.unwrap(),
+            Message::Choke
+        );
+        assert_eq!(
+            super::read_message(&mut stream).await.unwrap(),
+            Message::Have(1)
+        );
+        assert!(super::read_message(&mut stream).await.is_err());
+    }
 
-    let len = u32::from_be_bytes(message[0..4].try_into()?);
-    anyhow::ensure!(len >= 9, "Message announced length is too small");
+    #[tokio::test]
+    async fn reader_survives_cancelled_reads() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let mut reader = MessageReader::default();
+        let message = Message::Have(9).serialize();
 
-    anyhow::ensure!(
-        message[13..].len() == (len - 9) as usize,
-        format!(
-            "Block length and announced length are different: expected {}, got {}, real len: {}",
-            len - 9,
-            message[13..].len(),
-            message.len()
+        tokio::io::AsyncWriteExt::write_all(&mut client, &message[..3])
+            .await
+            .unwrap();
+        let cut_off = tokio::time::timeout(
+            std::time::Duration::from_millis(10),
+            reader.read(&mut server),
         )
-    );
-    //println!("{:?}", message[13..].to_vec());
-    Ok(message[13..].to_vec())
+        .await;
+        assert!(cut_off.is_err());
+
+        tokio::io::AsyncWriteExt::write_all(&mut client, &message[3..])
+            .await
+            .unwrap();
+        assert_eq!(reader.read(&mut server).await.unwrap(), Message::Have(9));
+        drop(client);
+        assert!(reader.read(&mut server).await.is_err());
+    }
 }
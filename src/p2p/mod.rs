@@ -1,3 +1,6 @@
 pub mod bitfields;
 pub mod handshake;
+pub mod listener;
 pub mod messages;
+pub mod mse;
+pub mod peer_stream;
@@ -0,0 +1,497 @@
+use num_bigint::BigUint;
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/*
+ *   Message Stream Encryption: a Diffie-Hellman key exchange followed by RC4 obfuscation of the
+ *   stream. Both sides derive the keys from the shared secret and the info hash, so only peers
+ *   knowing the torrent can complete it. The initiator offers the methods it accepts
+ *   (crypto_provide) and the receiver picks one (crypto_select). The initiator never sends an
+ *   initial payload, the BitTorrent handshake follows the MSE handshake instead.
+ */
+
+const PRIME: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const KEY_LENGTH: usize = 96;
+const MAX_PADDING: usize = 512;
+const VERIFICATION_CONSTANT: [u8; 8] = [0; 8];
+
+pub const CRYPTO_PLAINTEXT: u32 = 0x01;
+pub const CRYPTO_RC4: u32 = 0x02;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncryptionPolicy {
+    // Plaintext connections only
+    Disabled,
+    // Encrypted connections are preferred, falling back to plaintext
+    Enabled,
+    // Encrypted connections only
+    Forced,
+}
+
+impl EncryptionPolicy {
+    // What we accept once the MSE handshake is done
+    pub fn crypto_methods(&self) -> u32 {
+        match self {
+            EncryptionPolicy::Disabled => CRYPTO_PLAINTEXT,
+            EncryptionPolicy::Enabled => CRYPTO_PLAINTEXT | CRYPTO_RC4,
+            EncryptionPolicy::Forced => CRYPTO_RC4,
+        }
+    }
+}
+
+impl std::str::FromStr for EncryptionPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(policy: &str) -> anyhow::Result<EncryptionPolicy> {
+        match policy {
+            "disabled" => Ok(EncryptionPolicy::Disabled),
+            "enabled" => Ok(EncryptionPolicy::Enabled),
+            "forced" => Ok(EncryptionPolicy::Forced),
+            _ => anyhow::bail!("Unknown encryption policy {}", policy),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Rc4 {
+        let mut state = [0; 256];
+        for (i, byte) in state.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let mut j: u8 = 0;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+        Rc4 { state, i: 0, j: 0 }
+    }
+
+    // MSE drops the first 1024 bytes of the keystream
+    fn for_mse(key_name: &[u8], secret: &[u8], skey: &[u8]) -> Rc4 {
+        let mut cipher = Rc4::new(&hash(&[key_name, secret, skey]));
+        cipher.apply(&mut [0; 1024]);
+        cipher
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data.iter_mut() {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let k = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[k as usize];
+        }
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().to_vec()
+}
+
+struct KeyPair {
+    private: BigUint,
+    public: Vec<u8>,
+}
+
+impl KeyPair {
+    fn generate() -> KeyPair {
+        let private = BigUint::from_bytes_be(&rand::thread_rng().gen::<[u8; 20]>());
+        let public = BigUint::from(2_u32).modpow(&private, &prime());
+        KeyPair {
+            private,
+            public: to_key_bytes(&public),
+        }
+    }
+
+    fn shared_secret(&self, other_public: &[u8]) -> Vec<u8> {
+        let other_public = BigUint::from_bytes_be(other_public);
+        to_key_bytes(&other_public.modpow(&self.private, &prime()))
+    }
+}
+
+fn prime() -> BigUint {
+    BigUint::parse_bytes(PRIME.as_bytes(), 16).unwrap()
+}
+
+fn to_key_bytes(number: &BigUint) -> Vec<u8> {
+    let bytes = number.to_bytes_be();
+    let mut key = vec![0; KEY_LENGTH - bytes.len()];
+    key.extend_from_slice(&bytes);
+    key
+}
+
+fn random_padding() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let len = rng.gen_range(0..=MAX_PADDING);
+    (0..len).map(|_| rng.gen()).collect()
+}
+
+// Reads until `pattern` shows up, giving up after `limit` bytes
+async fn synchronize<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    pattern: &[u8],
+    limit: usize,
+) -> anyhow::Result<()> {
+    let mut window = Vec::with_capacity(limit);
+    while !window.ends_with(pattern) {
+        anyhow::ensure!(window.len() < limit, "Couldn't synchronize MSE handshake");
+        window.push(stream.read_u8().await?);
+    }
+    Ok(())
+}
+
+async fn read_decrypted<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    cipher: &mut Rc4,
+    len: usize,
+) -> anyhow::Result<Vec<u8>> {
+    let mut data = vec![0; len];
+    stream.read_exact(&mut data).await?;
+    cipher.apply(&mut data);
+    Ok(data)
+}
+
+// Outgoing connections: offers `crypto_provide` to the receiver
+pub async fn initiate<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    info_hash: &[u8],
+    crypto_provide: u32,
+) -> anyhow::Result<MseStream<S>> {
+    let keys = KeyPair::generate();
+    let mut msg = keys.public.clone();
+    msg.extend_from_slice(&random_padding());
+    stream.write_all(&msg).await?;
+
+    let mut other_public = [0; KEY_LENGTH];
+    stream.read_exact(&mut other_public).await?;
+    let secret = keys.shared_secret(&other_public);
+
+    let mut encryptor = Rc4::for_mse(b"keyA", &secret, info_hash);
+    let mut decryptor = Rc4::for_mse(b"keyB", &secret, info_hash);
+
+    let mut msg = hash(&[b"req1", &secret]);
+    let req2 = hash(&[b"req2", info_hash]);
+    let req3 = hash(&[b"req3", &secret]);
+    msg.extend(req2.iter().zip(&req3).map(|(a, b)| a ^ b));
+    let mut encrypted = VERIFICATION_CONSTANT.to_vec();
+    encrypted.extend_from_slice(&crypto_provide.to_be_bytes());
+    encrypted.extend_from_slice(&0_u16.to_be_bytes()); // no padding
+    encrypted.extend_from_slice(&0_u16.to_be_bytes()); // no initial payload
+    encryptor.apply(&mut encrypted);
+    msg.extend_from_slice(&encrypted);
+    stream.write_all(&msg).await?;
+
+    // The receiver's padding has unknown length, its encrypted verification constant marks the end
+    let mut verification = VERIFICATION_CONSTANT;
+    decryptor.apply(&mut verification);
+    synchronize(&mut stream, &verification, MAX_PADDING + verification.len()).await?;
+
+    let response = read_decrypted(&mut stream, &mut decryptor, 6).await?;
+    let crypto_select = u32::from_be_bytes([response[0], response[1], response[2], response[3]]);
+    let padding_len = u16::from_be_bytes([response[4], response[5]]) as usize;
+    anyhow::ensure!(padding_len <= MAX_PADDING, "MSE padding is too long");
+    read_decrypted(&mut stream, &mut decryptor, padding_len).await?;
+
+    match crypto_select {
+        CRYPTO_RC4 if crypto_provide & CRYPTO_RC4 != 0 => Ok(MseStream::encrypted(
+            stream,
+            decryptor,
+            encryptor,
+            Vec::new(),
+        )),
+        CRYPTO_PLAINTEXT if crypto_provide & CRYPTO_PLAINTEXT != 0 => {
+            Ok(MseStream::plaintext(stream, Vec::new()))
+        }
+        _ => anyhow::bail!("Peer selected an unknown crypto method {}", crypto_select),
+    }
+}
+
+// Incoming connections whose first bytes (`received`) weren't a plaintext BitTorrent handshake
+pub async fn respond<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    received: &[u8],
+    info_hashes: &[Vec<u8>],
+    crypto_methods: u32,
+) -> anyhow::Result<MseStream<S>> {
+    anyhow::ensure!(received.len() <= KEY_LENGTH, "Too many bytes received");
+    let mut other_public = received.to_vec();
+    other_public.resize(KEY_LENGTH, 0);
+    stream
+        .read_exact(&mut other_public[received.len()..])
+        .await?;
+
+    let keys = KeyPair::generate();
+    let mut msg = keys.public.clone();
+    msg.extend_from_slice(&random_padding());
+    stream.write_all(&msg).await?;
+    let secret = keys.shared_secret(&other_public);
+
+    let req1 = hash(&[b"req1", &secret]);
+    synchronize(&mut stream, &req1, MAX_PADDING + req1.len()).await?;
+
+    let mut skey_hash = [0; 20];
+    stream.read_exact(&mut skey_hash).await?;
+    let req3 = hash(&[b"req3", &secret]);
+    // The peer may know the torrent by any of its info hashes
+    let info_hash = info_hashes
+        .iter()
+        .find(|info_hash| {
+            let req2 = hash(&[b"req2", info_hash]);
+            req2.iter().zip(&req3).map(|(a, b)| a ^ b).eq(skey_hash)
+        })
+        .ok_or(anyhow::anyhow!("Peer asks for another torrent"))?;
+
+    let mut decryptor = Rc4::for_mse(b"keyA", &secret, info_hash);
+    let mut encryptor = Rc4::for_mse(b"keyB", &secret, info_hash);
+
+    let request = read_decrypted(&mut stream, &mut decryptor, 14).await?;
+    anyhow::ensure!(
+        request[..8] == VERIFICATION_CONSTANT,
+        "Wrong MSE verification constant"
+    );
+    let crypto_provide = u32::from_be_bytes([request[8], request[9], request[10], request[11]]);
+    let padding_len = u16::from_be_bytes([request[12], request[13]]) as usize;
+    anyhow::ensure!(padding_len <= MAX_PADDING, "MSE padding is too long");
+    read_decrypted(&mut stream, &mut decryptor, padding_len).await?;
+    let payload_len = read_decrypted(&mut stream, &mut decryptor, 2).await?;
+    let payload_len = u16::from_be_bytes([payload_len[0], payload_len[1]]) as usize;
+    let initial_payload = read_decrypted(&mut stream, &mut decryptor, payload_len).await?;
+
+    let crypto_select = if crypto_provide & crypto_methods & CRYPTO_RC4 != 0 {
+        CRYPTO_RC4
+    } else if crypto_provide & crypto_methods & CRYPTO_PLAINTEXT != 0 {
+        CRYPTO_PLAINTEXT
+    } else {
+        anyhow::bail!("No common crypto method");
+    };
+    let mut msg = VERIFICATION_CONSTANT.to_vec();
+    msg.extend_from_slice(&crypto_select.to_be_bytes());
+    msg.extend_from_slice(&0_u16.to_be_bytes()); // no padding
+    encryptor.apply(&mut msg);
+    stream.write_all(&msg).await?;
+
+    if crypto_select == CRYPTO_RC4 {
+        Ok(MseStream::encrypted(
+            stream,
+            decryptor,
+            encryptor,
+            initial_payload,
+        ))
+    } else {
+        Ok(MseStream::plaintext(stream, initial_payload))
+    }
+}
+
+/*
+ *   The stream after the MSE handshake, RC4 encrypted or not. Data the peer sent along with the
+ *   handshake is handed out first. Encrypted writes are buffered until the inner stream takes them.
+ */
+pub struct MseStream<S> {
+    inner: S,
+    decryptor: Option<Rc4>,
+    encryptor: Option<Rc4>,
+    received: Vec<u8>,
+    to_send: Vec<u8>,
+    sent: usize,
+}
+
+impl<S> MseStream<S> {
+    fn encrypted(inner: S, decryptor: Rc4, encryptor: Rc4, received: Vec<u8>) -> MseStream<S> {
+        MseStream {
+            inner,
+            decryptor: Some(decryptor),
+            encryptor: Some(encryptor),
+            received,
+            to_send: Vec::new(),
+            sent: 0,
+        }
+    }
+
+    // Also used for plaintext connections whose first bytes were already read
+    pub fn plaintext(inner: S, received: Vec<u8>) -> MseStream<S> {
+        MseStream {
+            inner,
+            decryptor: None,
+            encryptor: None,
+            received,
+            to_send: Vec::new(),
+            sent: 0,
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encryptor.is_some()
+    }
+}
+
+impl<S: AsyncWrite + Unpin> MseStream<S> {
+    fn poll_send_buffered(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.sent < self.to_send.len() {
+            match Pin::new(&mut self.inner).poll_write(cx, &self.to_send[self.sent..]) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(written)) => self.sent += written,
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        self.to_send.clear();
+        self.sent = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MseStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.received.is_empty() {
+            let len = this.received.len().min(buf.remaining());
+            buf.put_slice(&this.received[..len]);
+            this.received.drain(..len);
+            return Poll::Ready(Ok(()));
+        }
+
+        let filled = buf.filled().len();
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => {
+                if let Some(decryptor) = &mut this.decryptor {
+                    decryptor.apply(&mut buf.filled_mut()[filled..]);
+                }
+                Poll::Ready(Ok(()))
+            }
+            other => other,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MseStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match this.poll_send_buffered(cx) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+            Poll::Pending => return Poll::Pending,
+        }
+
+        match &mut this.encryptor {
+            None => Pin::new(&mut this.inner).poll_write(cx, buf),
+            Some(encryptor) => {
+                let start = this.to_send.len();
+                this.to_send.extend_from_slice(buf);
+                encryptor.apply(&mut this.to_send[start..]);
+                // Whatever doesn't go out now is sent on the next write or flush
+                if let Poll::Ready(Err(err)) = this.poll_send_buffered(cx) {
+                    return Poll::Ready(Err(err));
+                }
+                Poll::Ready(Ok(buf.len()))
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_send_buffered(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_flush(cx),
+            other => other,
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_send_buffered(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_shutdown(cx),
+            other => other,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    #[test]
+    fn rc4_test_vector() {
+        let mut data = b"Plaintext".to_vec();
+        Rc4::new(b"Key").apply(&mut data);
+        assert_eq!(data, [0xBB, 0xF3, 0x16, 0xE8, 0xD9, 0x40, 0xAF, 0x0A, 0xD3]);
+    }
+
+    #[test]
+    fn key_exchange() {
+        let (a, b) = (KeyPair::generate(), KeyPair::generate());
+        assert_eq!(a.public.len(), KEY_LENGTH);
+        assert_eq!(a.shared_secret(&b.public), b.shared_secret(&a.public));
+    }
+
+    async fn exchange(
+        crypto_provide: u32,
+        crypto_methods: u32,
+    ) -> (
+        anyhow::Result<MseStream<tokio::io::DuplexStream>>,
+        anyhow::Result<MseStream<tokio::io::DuplexStream>>,
+    ) {
+        let (initiator, mut receiver) = duplex(4096);
+        let info_hash = [3; 20];
+        let responding = async {
+            let mut received = [0; 20];
+            receiver.read_exact(&mut received).await.unwrap();
+            respond(receiver, &received, &[info_hash.to_vec()], crypto_methods).await
+        };
+        futures::join!(initiate(initiator, &info_hash, crypto_provide), responding)
+    }
+
+    #[tokio::test]
+    async fn encrypted_connection() {
+        let (initiator, receiver) = exchange(CRYPTO_RC4 | CRYPTO_PLAINTEXT, CRYPTO_RC4).await;
+        let (mut initiator, mut receiver) = (initiator.unwrap(), receiver.unwrap());
+        assert!(initiator.is_encrypted() && receiver.is_encrypted());
+
+        initiator.write_all(b"hello").await.unwrap();
+        initiator.flush().await.unwrap();
+        let mut buf = [0; 5];
+        receiver.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        receiver.write_all(b"world").await.unwrap();
+        receiver.flush().await.unwrap();
+        initiator.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+    }
+
+    #[tokio::test]
+    async fn plaintext_selected() {
+        let (initiator, receiver) = exchange(CRYPTO_RC4 | CRYPTO_PLAINTEXT, CRYPTO_PLAINTEXT).await;
+        assert!(!initiator.unwrap().is_encrypted());
+        assert!(!receiver.unwrap().is_encrypted());
+    }
+
+    #[tokio::test]
+    async fn no_common_method() {
+        let (initiator, receiver) = exchange(CRYPTO_PLAINTEXT, CRYPTO_RC4).await;
+        assert!(receiver.is_err());
+        assert!(initiator.is_err());
+    }
+}
@@ -0,0 +1,11 @@
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/*
+ *   Whatever a peer session runs over: a plain TCP connection or one wrapped in protocol
+ *   encryption. Writers have to flush, as wrapped streams may buffer what they are given.
+ */
+pub trait PeerStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> PeerStream for T {}
+
+pub type BoxedPeerStream = Box<dyn PeerStream>;
@@ -0,0 +1,149 @@
+use std::net::SocketAddr;
+
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use url::Url;
+
+use super::{base64, connect, read_head, write_all, Proxy};
+
+/*
+ *   A local HTTP proxy passing connections on through another kind of proxy, for HTTP clients
+ *   that only talk to HTTP proxies. HTTPS comes as CONNECT tunnels. Plain HTTP requests come with
+ *   the whole URL and are passed on in origin form; their connections are closed after one
+ *   response, as the client could send the next request on it to another server.
+ *
+ *   Any program on the machine can reach a loopback port, so clients have to give the bridge's
+ *   password. The bridge stops accepting connections once it is dropped.
+ */
+
+pub const USERNAME: &str = "bridge";
+
+pub struct Bridge {
+    pub proxy: Proxy,
+    pub addr: SocketAddr,
+    pub password: String,
+    accepting: JoinHandle<()>,
+}
+
+impl Drop for Bridge {
+    fn drop(&mut self) {
+        self.accepting.abort();
+    }
+}
+
+pub fn start(proxy: Proxy) -> anyhow::Result<Bridge> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    let addr = listener.local_addr()?;
+    let password = format!("{:032x}", rand::random::<u128>());
+    let authorization = format!(
+        "Basic {}",
+        base64(format!("{}:{}", USERNAME, password).as_bytes())
+    );
+    let forwarded_proxy = proxy.clone();
+    let accepting = tokio::spawn(async move {
+        loop {
+            let client = match listener.accept().await {
+                Ok((client, _)) => client,
+                Err(_) => continue,
+            };
+            let proxy = forwarded_proxy.clone();
+            let authorization = authorization.clone();
+            tokio::spawn(async move {
+                let _ = forward(client, &proxy, &authorization).await;
+            });
+        }
+    });
+    Ok(Bridge {
+        proxy,
+        addr,
+        password,
+        accepting,
+    })
+}
+
+async fn forward(mut client: TcpStream, proxy: &Proxy, authorization: &str) -> anyhow::Result<()> {
+    let head = read_head(&mut client).await?;
+    let mut lines = head.split("\r\n").filter(|line| !line.is_empty());
+    let request_line = lines.next().unwrap_or_default();
+    let parts: Vec<_> = request_line.split(' ').collect();
+    anyhow::ensure!(parts.len() == 3, "Wrong request line {}", request_line);
+    let (method, target, version) = (parts[0], parts[1], parts[2]);
+
+    let authorized = lines.clone().any(|line| {
+        header_is(line, "proxy-authorization")
+            && line
+                .split_once(':')
+                .is_some_and(|(_, value)| value.trim() == authorization)
+    });
+    if !authorized {
+        write_all(
+            &mut client,
+            b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n",
+        )
+        .await?;
+        anyhow::bail!("Bridge client without the password");
+    }
+
+    let (host, port) = if method == "CONNECT" {
+        let (host, port) = target
+            .rsplit_once(':')
+            .ok_or(anyhow::anyhow!("Wrong CONNECT target {}", target))?;
+        (host.to_string(), port.parse()?)
+    } else {
+        let url = Url::parse(target)?;
+        let host = url
+            .host_str()
+            .ok_or(anyhow::anyhow!("Request for {} has no host", target))?;
+        let port = url
+            .port_or_known_default()
+            .ok_or(anyhow::anyhow!("Request for {} has no port", target))?;
+        (host.to_string(), port)
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let mut upstream = match connect(proxy, host, port).await {
+        Ok(upstream) => upstream,
+        Err(err) => {
+            write_all(&mut client, b"HTTP/1.1 502 Bad Gateway\r\n\r\n").await?;
+            return Err(err);
+        }
+    };
+
+    if method == "CONNECT" {
+        write_all(&mut client, b"HTTP/1.1 200 Connection established\r\n\r\n").await?;
+    } else {
+        let url = Url::parse(target)?;
+        let path = &url[url::Position::BeforePath..url::Position::AfterQuery];
+        let request = close_after(
+            format!("{} {} {}", method, path, version),
+            lines.filter(|line| !header_is(line, "proxy-authorization")),
+        );
+        write_all(&mut upstream, request.as_bytes()).await?;
+
+        let response = read_head(&mut upstream).await?;
+        let mut lines = response.split("\r\n").filter(|line| !line.is_empty());
+        let status_line = lines.next().unwrap_or_default().to_string();
+        write_all(&mut client, close_after(status_line, lines).as_bytes()).await?;
+    }
+    tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+    Ok(())
+}
+
+// A head with its connection headers replaced by `Connection: close`
+fn close_after<'a>(first_line: String, headers: impl Iterator<Item = &'a str>) -> String {
+    let mut head = first_line + "\r\n";
+    for header in
+        headers.filter(|line| !header_is(line, "connection") && !header_is(line, "keep-alive"))
+    {
+        head += header;
+        head += "\r\n";
+    }
+    head + "Connection: close\r\n\r\n"
+}
+
+fn header_is(line: &str, name: &str) -> bool {
+    line.split(':')
+        .next()
+        .is_some_and(|header| header.trim().eq_ignore_ascii_case(name))
+}
@@ -0,0 +1,177 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+use super::*;
+
+/*
+ *   Minimal proxies on loopback for tests: they take any request they understand, check the
+ *   credentials they are given and connect wherever they are asked to.
+ */
+
+type Credentials = Option<(&'static str, &'static str)>;
+
+async fn serve<F, C>(handle: F) -> SocketAddr
+where
+    F: Fn(TcpStream) -> C + Send + 'static,
+    C: std::future::Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(handle(stream));
+        }
+    });
+    addr
+}
+
+async fn resolve(host: &str, port: u16) -> anyhow::Result<SocketAddr> {
+    tokio::net::lookup_host((host, port))
+        .await?
+        .find(SocketAddr::is_ipv4)
+        .ok_or(anyhow::anyhow!("Couldn't resolve {}", host))
+}
+
+pub async fn socks5(credentials: Credentials) -> SocketAddr {
+    serve(move |mut client| async move {
+        let mut greeting = [0; 2];
+        client.read_exact(&mut greeting).await?;
+        let mut methods = vec![0; greeting[1] as usize];
+        client.read_exact(&mut methods).await?;
+
+        if let Some((username, password)) = credentials {
+            anyhow::ensure!(methods.contains(&SOCKS5_PASSWORD_AUTH));
+            client
+                .write_all(&[SOCKS5_VERSION, SOCKS5_PASSWORD_AUTH])
+                .await?;
+            client.read_u8().await?;
+            let mut given_username = vec![0; client.read_u8().await? as usize];
+            client.read_exact(&mut given_username).await?;
+            let mut given_password = vec![0; client.read_u8().await? as usize];
+            client.read_exact(&mut given_password).await?;
+            let accepted =
+                given_username == username.as_bytes() && given_password == password.as_bytes();
+            client.write_all(&[1, if accepted { 0 } else { 1 }]).await?;
+            anyhow::ensure!(accepted);
+        } else {
+            client.write_all(&[SOCKS5_VERSION, SOCKS5_NO_AUTH]).await?;
+        }
+
+        let mut request = [0; 4];
+        client.read_exact(&mut request).await?;
+        let requested = match request[3] {
+            SOCKS5_DOMAIN => {
+                let mut name = vec![0; client.read_u8().await? as usize];
+                client.read_exact(&mut name).await?;
+                let port = client.read_u16().await?;
+                resolve(&String::from_utf8(name)?, port).await?
+            }
+            address_type => read_address(&mut client, address_type).await?,
+        };
+
+        if request[1] == SOCKS5_UDP_ASSOCIATE {
+            let relay = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+            let mut reply = vec![SOCKS5_VERSION, 0, 0];
+            reply.extend_from_slice(&socket_address(relay.local_addr()?));
+            client.write_all(&reply).await?;
+            let relaying = tokio::spawn(relay_datagrams(relay));
+            // The association lasts as long as the control connection
+            let _ = client.read_u8().await;
+            relaying.abort();
+            return Ok(());
+        }
+
+        let mut upstream = TcpStream::connect(requested).await?;
+        let mut reply = vec![SOCKS5_VERSION, 0, 0];
+        reply.extend_from_slice(&socket_address(upstream.local_addr()?));
+        client.write_all(&reply).await?;
+        tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+        Ok(())
+    })
+    .await
+}
+
+// Datagrams from the client are unwrapped and sent on, the answers wrapped and sent back
+async fn relay_datagrams(relay: Arc<UdpSocket>) -> anyhow::Result<()> {
+    let mut client = None;
+    let mut buf = [0; 4096];
+    loop {
+        let (len, from) = relay.recv_from(&mut buf).await?;
+        if client.is_none() || client == Some(from) {
+            client = Some(from);
+            let (target, data) = parse_udp_datagram(&buf[..len])?;
+            relay.send_to(data, target).await?;
+        } else if let Some(client) = client {
+            relay
+                .send_to(&udp_datagram(from, &buf[..len]), client)
+                .await?;
+        }
+    }
+}
+
+pub async fn socks4a() -> SocketAddr {
+    serve(|mut client| async move {
+        let mut request = [0; 8];
+        client.read_exact(&mut request).await?;
+        // The user id isn't checked
+        while client.read_u8().await? != 0 {}
+        let port = u16::from_be_bytes([request[2], request[3]]);
+        let requested = if request[4..7] == [0, 0, 0] {
+            let mut name = Vec::new();
+            loop {
+                match client.read_u8().await? {
+                    0 => break,
+                    byte => name.push(byte),
+                }
+            }
+            resolve(&String::from_utf8(name)?, port).await?
+        } else {
+            let octets: [u8; 4] = request[4..8].try_into()?;
+            SocketAddr::from((octets, port))
+        };
+
+        let mut upstream = TcpStream::connect(requested).await?;
+        client
+            .write_all(&[0, SOCKS4_GRANTED, 0, 0, 0, 0, 0, 0])
+            .await?;
+        tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+        Ok(())
+    })
+    .await
+}
+
+pub async fn http(credentials: Credentials) -> SocketAddr {
+    serve(move |mut client| async move {
+        let head = read_head(&mut client).await?;
+        let target = head
+            .split_whitespace()
+            .nth(1)
+            .ok_or(anyhow::anyhow!("No target"))?;
+        if let Some((username, password)) = credentials {
+            let expected = format!(
+                "Proxy-Authorization: Basic {}",
+                base64(format!("{}:{}", username, password).as_bytes())
+            );
+            if !head.lines().any(|line| line == expected) {
+                client
+                    .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                    .await?;
+                return Ok(());
+            }
+        }
+
+        let (host, port) = target.rsplit_once(':').ok_or(anyhow::anyhow!("No port"))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let mut upstream = TcpStream::connect(resolve(host, port.parse()?).await?).await?;
+        client
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await?;
+        tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+        Ok(())
+    })
+    .await
+}
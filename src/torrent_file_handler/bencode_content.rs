@@ -1,11 +1,13 @@
 use std::collections::HashMap;
 
+// Dictionary keys are kept as raw bytes: they are usually text, but some are binary (e.g. info
+// hashes in scrape responses)
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum Content {
     Str(String),
     List(Vec<Content>),
     Int(i64),
-    Dict(HashMap<String, Content>),
+    Dict(HashMap<Vec<u8>, Content>),
     Bytes(Vec<u8>),
 }
 
@@ -28,7 +30,7 @@ impl Content {
             _ => None,
         }
     }
-    pub fn get_dict(&self) -> Option<&HashMap<String, Content>> {
+    pub fn get_dict(&self) -> Option<&HashMap<Vec<u8>, Content>> {
         match self {
             Content::Dict(c) => Some(c),
             _ => None,
@@ -41,3 +43,41 @@ impl Content {
         }
     }
 }
+
+// Dictionary keys are sorted by their raw bytes, so equal contents always give equal bytes
+pub fn encode(content: &Content) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    encode_into(content, &mut encoded);
+    encoded
+}
+
+fn encode_into(content: &Content, encoded: &mut Vec<u8>) {
+    match content {
+        Content::Str(string) => encode_bytes(string.as_bytes(), encoded),
+        Content::Bytes(bytes) => encode_bytes(bytes, encoded),
+        Content::Int(int) => encoded.extend_from_slice(format!("i{}e", int).as_bytes()),
+        Content::List(list) => {
+            encoded.push(b'l');
+            for elem in list {
+                encode_into(elem, encoded);
+            }
+            encoded.push(b'e');
+        }
+        Content::Dict(dict) => {
+            let mut keys: Vec<&Vec<u8>> = dict.keys().collect();
+            keys.sort();
+            encoded.push(b'd');
+            for key in keys {
+                encode_bytes(key, encoded);
+                encode_into(&dict[key], encoded);
+            }
+            encoded.push(b'e');
+        }
+    }
+}
+
+fn encode_bytes(bytes: &[u8], encoded: &mut Vec<u8>) {
+    encoded.extend_from_slice(bytes.len().to_string().as_bytes());
+    encoded.push(b':');
+    encoded.extend_from_slice(bytes);
+}
@@ -0,0 +1,147 @@
+use sha2::{Digest, Sha256};
+
+/*
+ *   Merkle trees of v2 torrents (BEP 52). The leaves are SHA-256 hashes of 16 KiB blocks of a file,
+ *   padded with zero hashes to a power of two. A piece hash is the root of the subtree covering the
+ *   piece, those hashes form the "piece layer" of the tree.
+ */
+
+pub const BLOCK_SIZE: usize = 16384;
+pub const HASH_SIZE: usize = 32;
+
+fn hash_pair(left: &[u8], right: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().to_vec()
+}
+
+// Root of `layer` padded with `pad` up to `width` nodes, a power of two
+pub fn root(layer: &[Vec<u8>], width: usize, pad: &[u8]) -> Vec<u8> {
+    let mut nodes = layer.to_vec();
+    nodes.resize(width.max(1), pad.to_vec());
+    while nodes.len() > 1 {
+        nodes = nodes
+            .chunks(2)
+            .map(|pair| hash_pair(&pair[0], &pair[1]))
+            .collect();
+    }
+    nodes.remove(0)
+}
+
+// Root of a subtree of zero leaves, `layer` levels above them
+pub fn pad_hash(layer: u32) -> Vec<u8> {
+    (0..layer).fold(vec![0; HASH_SIZE], |hash, _| hash_pair(&hash, &hash))
+}
+
+// The layer holding piece hashes, the leaves are layer 0
+pub fn piece_layer(piece_length: usize) -> u32 {
+    (piece_length / BLOCK_SIZE).trailing_zeros()
+}
+
+// Files bigger than a piece are covered by whole pieces, the hash of a smaller one is its root
+pub fn piece_hash(piece: &[u8], file_size: usize, piece_length: usize) -> Vec<u8> {
+    let leaves: Vec<Vec<u8>> = piece
+        .chunks(BLOCK_SIZE)
+        .map(|block| Sha256::digest(block).to_vec())
+        .collect();
+    let width = if file_size > piece_length {
+        piece_length / BLOCK_SIZE
+    } else {
+        leaves.len().next_power_of_two()
+    };
+    root(&leaves, width, &[0; HASH_SIZE])
+}
+
+// Root of a file's tree from its piece layer
+pub fn root_from_piece_layer(layer: &[Vec<u8>], piece_length: usize) -> Vec<u8> {
+    root(
+        layer,
+        layer.len().next_power_of_two(),
+        &pad_hash(piece_layer(piece_length)),
+    )
+}
+
+// Checks `hashes` starting at `index` of a layer `width` nodes wide against the root, with
+// `uncles` being the proof from the bottom up
+pub fn verify_hashes(
+    expected_root: &[u8],
+    width: usize,
+    pad: &[u8],
+    index: usize,
+    hashes: &[Vec<u8>],
+    uncles: &[Vec<u8>],
+) -> bool {
+    let length = hashes.len();
+    if length == 0
+        || !length.is_power_of_two()
+        || !index.is_multiple_of(length)
+        || index + length > width
+        || (width / length) != 1 << uncles.len()
+    {
+        return false;
+    }
+    let mut position = index / length;
+    let mut node = root(hashes, length, pad);
+    for uncle in uncles {
+        node = if position.is_multiple_of(2) {
+            hash_pair(&node, uncle)
+        } else {
+            hash_pair(uncle, &node)
+        };
+        position /= 2;
+    }
+    node == expected_root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_file_root() {
+        // Three blocks are padded with a zero leaf
+        let data = vec![7; 2 * BLOCK_SIZE + 10];
+        let leaves: Vec<Vec<u8>> = data
+            .chunks(BLOCK_SIZE)
+            .map(|block| Sha256::digest(block).to_vec())
+            .collect();
+        let expected = hash_pair(
+            &hash_pair(&leaves[0], &leaves[1]),
+            &hash_pair(&leaves[2], &[0; HASH_SIZE]),
+        );
+        assert_eq!(piece_hash(&data, data.len(), 4 * BLOCK_SIZE), expected);
+        assert_eq!(piece_hash(&data, data.len(), 8 * BLOCK_SIZE), expected);
+    }
+
+    #[test]
+    fn piece_layer_of_big_file() {
+        let piece_length = 2 * BLOCK_SIZE;
+        let data: Vec<u8> = (0..5 * BLOCK_SIZE).map(|i| (i % 253) as u8).collect();
+        let layer: Vec<Vec<u8>> = data
+            .chunks(piece_length)
+            .map(|piece| piece_hash(piece, data.len(), piece_length))
+            .collect();
+        // The last piece is a single block padded to the piece width
+        assert_eq!(
+            layer[2],
+            hash_pair(&Sha256::digest(&data[4 * BLOCK_SIZE..]), &[0; HASH_SIZE])
+        );
+
+        // The tree of the whole file has 8 leaves
+        let leaves: Vec<Vec<u8>> = data
+            .chunks(BLOCK_SIZE)
+            .map(|block| Sha256::digest(block).to_vec())
+            .collect();
+        let file_root = root(&leaves, 8, &[0; HASH_SIZE]);
+        assert_eq!(root_from_piece_layer(&layer, piece_length), file_root);
+
+        let pad = pad_hash(piece_layer(piece_length));
+        let uncles = [root(&layer[2..], 2, &pad)];
+        assert!(verify_hashes(&file_root, 4, &pad, 0, &layer[..2], &uncles));
+        assert!(!verify_hashes(&file_root, 4, &pad, 2, &layer[..2], &uncles));
+        let mut whole_layer = layer.clone();
+        whole_layer.push(pad.clone());
+        assert!(verify_hashes(&file_root, 4, &pad, 0, &whole_layer, &[]));
+    }
+}
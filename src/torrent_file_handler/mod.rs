@@ -1,3 +1,5 @@
 pub mod bencode_content;
+pub mod merkle;
+pub mod torrent_creator;
 pub mod torrent_data_extractor;
 pub mod torrent_file_parser;
@@ -0,0 +1,357 @@
+use super::bencode_content::{encode, Content};
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::prelude::FileExt;
+use std::path::{Path, PathBuf};
+
+/*
+ *   Makes a .torrent from a file or a directory. The info dictionary is encoded canonically (keys
+ *   sorted, files sorted by path) so the same data gives the same info hash as other creators.
+ */
+
+const MIN_PIECE_LENGTH: usize = 16 * 1024;
+const MAX_PIECE_LENGTH: usize = 16 * 1024 * 1024;
+// Auto piece length aims for about this many pieces
+const TARGET_PIECES: usize = 1500;
+
+#[derive(Debug, Clone, Default)]
+pub struct TorrentOptions {
+    // Picked from the total size if not set
+    pub piece_length: Option<usize>,
+    // Tiers of trackers (BEP 12), the first one is also written as 'announce'
+    pub trackers: Vec<Vec<String>>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    // Seconds since the Unix epoch
+    pub creation_date: Option<i64>,
+    // BEP 27
+    pub private: bool,
+    // BEP 19
+    pub web_seeds: Vec<String>,
+    // DHT bootstrap nodes (BEP 5)
+    pub nodes: Vec<(String, u16)>,
+}
+
+struct InputFile {
+    // Relative to the torrent root, empty in single file mode
+    path: Vec<String>,
+    full_path: PathBuf,
+    size: usize,
+}
+
+// Returns the contents of the .torrent and its info hash
+pub fn create_torrent(path: &Path, options: &TorrentOptions) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+    let name = path
+        .canonicalize()?
+        .file_name()
+        .ok_or(anyhow::anyhow!("Couldn't get name of {}", path.display()))?
+        .to_str()
+        .ok_or(anyhow::anyhow!("Name of {} isn't UTF-8", path.display()))?
+        .to_string();
+
+    let single_file = !fs::metadata(path)?.is_dir();
+    let files = if single_file {
+        vec![InputFile {
+            path: Vec::new(),
+            full_path: path.to_path_buf(),
+            size: fs::metadata(path)?.len() as usize,
+        }]
+    } else {
+        let mut files = Vec::new();
+        walk_directory(path, &mut Vec::new(), &mut files)?;
+        anyhow::ensure!(!files.is_empty(), "No files in {}", path.display());
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        files
+    };
+
+    let total_size = files.iter().map(|file| file.size).sum();
+    let piece_length = match options.piece_length {
+        Some(piece_length) => {
+            anyhow::ensure!(
+                piece_length.is_power_of_two() && piece_length >= MIN_PIECE_LENGTH,
+                "Piece length must be a power of two of at least {} bytes",
+                MIN_PIECE_LENGTH
+            );
+            piece_length
+        }
+        None => auto_piece_length(total_size),
+    };
+
+    let mut info = HashMap::new();
+    info.insert(b"name".to_vec(), Content::Str(name));
+    info.insert(b"piece length".to_vec(), Content::Int(piece_length as i64));
+    info.insert(
+        b"pieces".to_vec(),
+        Content::Bytes(hash_pieces(&files, total_size, piece_length)?),
+    );
+    if options.private {
+        info.insert(b"private".to_vec(), Content::Int(1));
+    }
+    if single_file {
+        info.insert(b"length".to_vec(), Content::Int(total_size as i64));
+    } else {
+        let files = files
+            .iter()
+            .map(|file| {
+                let mut entry = HashMap::new();
+                entry.insert(b"length".to_vec(), Content::Int(file.size as i64));
+                entry.insert(
+                    b"path".to_vec(),
+                    Content::List(file.path.iter().cloned().map(Content::Str).collect()),
+                );
+                Content::Dict(entry)
+            })
+            .collect();
+        info.insert(b"files".to_vec(), Content::List(files));
+    }
+    let info = Content::Dict(info);
+    let info_hash = Sha1::digest(&encode(&info)).to_vec();
+
+    let mut torrent = HashMap::new();
+    torrent.insert(b"info".to_vec(), info);
+    let trackers: Vec<Vec<String>> = options
+        .trackers
+        .iter()
+        .filter(|tier| !tier.is_empty())
+        .cloned()
+        .collect();
+    if let Some(tier) = trackers.first() {
+        torrent.insert(b"announce".to_vec(), Content::Str(tier[0].clone()));
+    }
+    if trackers.concat().len() > 1 {
+        let tiers = trackers
+            .into_iter()
+            .map(|tier| Content::List(tier.into_iter().map(Content::Str).collect()))
+            .collect();
+        torrent.insert(b"announce-list".to_vec(), Content::List(tiers));
+    }
+    if let Some(comment) = &options.comment {
+        torrent.insert(b"comment".to_vec(), Content::Str(comment.clone()));
+    }
+    if let Some(created_by) = &options.created_by {
+        torrent.insert(b"created by".to_vec(), Content::Str(created_by.clone()));
+    }
+    if let Some(creation_date) = options.creation_date {
+        torrent.insert(b"creation date".to_vec(), Content::Int(creation_date));
+    }
+    if !options.web_seeds.is_empty() {
+        let urls = options
+            .web_seeds
+            .iter()
+            .cloned()
+            .map(Content::Str)
+            .collect();
+        torrent.insert(b"url-list".to_vec(), Content::List(urls));
+    }
+    if !options.nodes.is_empty() {
+        let nodes = options
+            .nodes
+            .iter()
+            .map(|(host, port)| {
+                Content::List(vec![Content::Str(host.clone()), Content::Int(*port as i64)])
+            })
+            .collect();
+        torrent.insert(b"nodes".to_vec(), Content::List(nodes));
+    }
+
+    Ok((encode(&Content::Dict(torrent)), info_hash))
+}
+
+fn walk_directory(
+    dir: &Path,
+    relative_path: &mut Vec<String>,
+    files: &mut Vec<InputFile>,
+) -> anyhow::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|name| anyhow::anyhow!("Name {:?} isn't UTF-8", name))?;
+        // Symlinks are followed
+        let metadata = fs::metadata(entry.path())?;
+        relative_path.push(name);
+        if metadata.is_dir() {
+            walk_directory(&entry.path(), relative_path, files)?;
+        } else {
+            files.push(InputFile {
+                path: relative_path.clone(),
+                full_path: entry.path(),
+                size: metadata.len() as usize,
+            });
+        }
+        relative_path.pop();
+    }
+    Ok(())
+}
+
+fn auto_piece_length(total_size: usize) -> usize {
+    (total_size / TARGET_PIECES)
+        .next_power_of_two()
+        .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+// Every thread hashes its own share of pieces, reading them straight from the files
+fn hash_pieces(
+    files: &[InputFile],
+    total_size: usize,
+    piece_length: usize,
+) -> anyhow::Result<Vec<u8>> {
+    let opened = files
+        .iter()
+        .map(|file| fs::File::open(&file.full_path))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let pieces = total_size.div_ceil(piece_length);
+    let threads = std::thread::available_parallelism()
+        .map(|threads| threads.get())
+        .unwrap_or(1)
+        .min(pieces.max(1));
+
+    let mut hashes = vec![0; pieces * 20];
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|thread| {
+                let opened = &opened;
+                scope.spawn(move || -> anyhow::Result<Vec<(usize, Vec<u8>)>> {
+                    let mut buf = vec![0; piece_length];
+                    let mut hashed = Vec::new();
+                    for index in (thread..pieces).step_by(threads) {
+                        let offset = index * piece_length;
+                        let len = piece_length.min(total_size - offset);
+                        read_at(files, opened, offset, &mut buf[..len])?;
+                        hashed.push((index, Sha1::digest(&buf[..len]).to_vec()));
+                    }
+                    Ok(hashed)
+                })
+            })
+            .collect();
+        for worker in workers {
+            let hashed = worker
+                .join()
+                .map_err(|_| anyhow::anyhow!("Hashing thread panicked"))??;
+            for (index, hash) in hashed {
+                hashes[index * 20..(index + 1) * 20].copy_from_slice(&hash);
+            }
+        }
+        Ok(hashes)
+    })
+}
+
+// Fills `buf` with the data at `offset` in the concatenation of all files
+fn read_at(
+    files: &[InputFile],
+    opened: &[fs::File],
+    mut offset: usize,
+    mut buf: &mut [u8],
+) -> anyhow::Result<()> {
+    for (file, opened) in files.iter().zip(opened) {
+        if buf.is_empty() {
+            break;
+        }
+        if offset >= file.size {
+            offset -= file.size;
+            continue;
+        }
+        let len = buf.len().min(file.size - offset);
+        opened.read_exact_at(&mut buf[..len], offset as u64)?;
+        buf = &mut buf[len..];
+        offset = 0;
+    }
+    anyhow::ensure!(buf.is_empty(), "Files changed while hashing");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent_file_handler::{torrent_data_extractor, torrent_file_parser};
+
+    #[test]
+    fn piece_lengths() {
+        assert_eq!(auto_piece_length(0), MIN_PIECE_LENGTH);
+        assert_eq!(auto_piece_length(1500 * 300 * 1024), 512 * 1024);
+        assert_eq!(auto_piece_length(usize::MAX / 2), MAX_PIECE_LENGTH);
+    }
+
+    #[test]
+    fn single_file_info_hash() {
+        let dir =
+            std::env::temp_dir().join(format!("rusty_torrent_create_file_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.txt");
+        fs::write(&path, b"hello").unwrap();
+
+        let (torrent, info_hash) = create_torrent(&path, &TorrentOptions::default()).unwrap();
+
+        // The info dictionary as any canonical encoder writes it
+        let mut info = b"d6:lengthi5e4:name5:a.txt12:piece lengthi16384e6:pieces20:".to_vec();
+        info.extend_from_slice(&Sha1::digest(b"hello"));
+        info.push(b'e');
+        assert_eq!(info_hash, Sha1::digest(&info).to_vec());
+        let mut expected = b"d4:info".to_vec();
+        expected.extend_from_slice(&info);
+        expected.push(b'e');
+        assert_eq!(torrent, expected);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn directory_round_trip() {
+        let dir =
+            std::env::temp_dir().join(format!("rusty_torrent_create_dir_{}", std::process::id()));
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        let data: Vec<u8> = (0..40000).map(|i| (i * 13 % 251) as u8).collect();
+        fs::write(root.join("b"), &data[..30000]).unwrap();
+        fs::write(root.join("sub").join("a"), &data[30000..]).unwrap();
+        fs::write(root.join("a"), b"").unwrap();
+
+        let options = TorrentOptions {
+            trackers: vec![
+                vec!["http://a.example/announce".to_string()],
+                vec!["udp://b.example:80".to_string()],
+            ],
+            comment: Some("test".to_string()),
+            creation_date: Some(1_600_000_000),
+            private: true,
+            web_seeds: vec!["http://seed.example/".to_string()],
+            nodes: vec![("router.example".to_string(), 6881)],
+            ..Default::default()
+        };
+        let (torrent, info_hash) = create_torrent(&root, &options).unwrap();
+        let torrent_path = dir.join("root.torrent");
+        fs::write(&torrent_path, &torrent).unwrap();
+
+        let (contents, parsed_hash) =
+            torrent_file_parser::parse_torrent_file(torrent_path.to_str().unwrap().to_string())
+                .unwrap();
+        assert_eq!(parsed_hash, info_hash);
+        assert_eq!(encode(&Content::Dict(contents.clone())), torrent);
+        let torrent_data = torrent_data_extractor::extract_data(contents).unwrap();
+        assert_eq!(torrent_data.announce, "http://a.example/announce");
+        assert_eq!(torrent_data.announce_list.unwrap().len(), 2);
+        assert_eq!(torrent_data.url_list, vec!["http://seed.example/"]);
+        assert!(torrent_data.private);
+        assert_eq!(torrent_data.piece_length, MIN_PIECE_LENGTH);
+        let paths: Vec<_> = torrent_data
+            .files
+            .iter()
+            .map(|file| (file.path_to_file.join("/"), file.size))
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                ("root/a".to_string(), 0),
+                ("root/b".to_string(), 30000),
+                ("root/sub/a".to_string(), 10000),
+            ]
+        );
+        let expected: Vec<Vec<u8>> = data
+            .chunks(MIN_PIECE_LENGTH)
+            .map(|piece| Sha1::digest(piece).to_vec())
+            .collect();
+        assert_eq!(torrent_data.pieces, expected);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
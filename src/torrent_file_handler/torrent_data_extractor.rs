@@ -1,43 +1,260 @@
 use super::bencode_content::Content;
+use super::merkle::{self, HASH_SIZE};
 use std::collections::HashMap;
+use std::ops::Range;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct TorrentData {
     pub pieces: Vec<Vec<u8>>,
     pub piece_length: usize,
     pub files: Vec<File>,
     pub announce: String,
-    pub announce_list: Option<Vec<String>>,
+    pub announce_list: Option<Vec<Vec<String>>>,
+    pub meta_version: MetaVersion,
+    // v2: the merkle hash of every piece, empty for pieces whose layer the torrent lacks
+    pub piece_hashes_v2: Vec<Vec<u8>>,
+    // Web seeds (BEP 19)
+    pub url_list: Vec<String>,
+    // HTTP seeds (BEP 17)
+    pub http_seeds: Vec<String>,
+    // BEP 27: peers may only come from the torrent's own trackers
+    pub private: bool,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MetaVersion {
+    #[default]
+    V1,
+    // BEP 52
+    V2,
+    // v1 piece hashes and v2 merkle trees over the same data, aligned by padding files
+    Hybrid,
+}
+
+impl TorrentData {
+    pub fn piece_count(&self) -> usize {
+        self.pieces.len().max(self.piece_hashes_v2.len())
+    }
+
+    pub fn total_size(&self) -> usize {
+        self.files.iter().map(|file| file.size).sum()
+    }
+
+    // Only the last piece (of every file in v2 torrents) may be shorter than `piece_length`
+    pub fn piece_size(&self, index: usize) -> usize {
+        let piece_start = index * self.piece_length;
+        let piece_end = piece_start + self.piece_length;
+        (0..self.files.len())
+            .map(|file| {
+                let file_start = self.file_offset(file);
+                let file_end = file_start + self.files[file].size;
+                piece_end
+                    .min(file_end)
+                    .saturating_sub(piece_start.max(file_start))
+            })
+            .sum()
+    }
+
+    // Where the file starts in the concatenation of all files. Files of v2 torrents start
+    // at piece boundaries.
+    pub fn file_offset(&self, file: usize) -> usize {
+        self.files[..file]
+            .iter()
+            .map(|file| match self.meta_version {
+                MetaVersion::V1 | MetaVersion::Hybrid => file.size,
+                MetaVersion::V2 => file.size.div_ceil(self.piece_length) * self.piece_length,
+            })
+            .sum()
+    }
+
+    // The file holding the piece, the first one if it spans several
+    pub fn piece_file(&self, index: usize) -> Option<usize> {
+        (0..self.files.len()).find(|file| self.file_pieces(*file).contains(&index))
+    }
+
+    // The pieces holding some of the file's data, none for an empty file
+    pub fn file_pieces(&self, file: usize) -> Range<usize> {
+        let start = self.file_offset(file);
+        let end = start + self.files[file].size;
+        if start == end {
+            return 0..0;
+        }
+        start / self.piece_length..(end - 1) / self.piece_length + 1
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct File {
     pub path_to_file: Vec<String>,
     pub size: usize,
+    // v2: root of the merkle tree of the file, none for empty files
+    pub pieces_root: Option<Vec<u8>>,
+    pub attributes: FileAttributes,
+    // Where a symlink points, relative to the torrent's root
+    pub symlink_path: Option<Vec<String>>,
+}
+
+// BEP 47: the 'attr' string of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FileAttributes {
+    // Zeros aligning the next file to a piece boundary, never written to disk
+    pub padding: bool,
+    pub executable: bool,
+    pub hidden: bool,
+    pub symlink: bool,
 }
 
-pub fn extract_data(torrent_data: HashMap<String, Content>) -> anyhow::Result<TorrentData> {
+impl FileAttributes {
+    // Unknown attributes are ignored
+    pub fn parse(attr: &str) -> FileAttributes {
+        FileAttributes {
+            padding: attr.contains('p'),
+            executable: attr.contains('x'),
+            hidden: attr.contains('h'),
+            symlink: attr.contains('l'),
+        }
+    }
+}
+
+pub fn extract_data(torrent_data: HashMap<Vec<u8>, Content>) -> anyhow::Result<TorrentData> {
     let info = torrent_data
-        .get("info")
+        .get(&b"info"[..])
         .ok_or(anyhow::anyhow!("No 'info' field in torrent_data"))?
         .get_dict()
         .ok_or(anyhow::anyhow!("Couldn't get dictionary"))?;
 
-    let files_data = info.get("files");
+    let piece_length: usize = *info
+        .get(&b"piece length"[..])
+        .ok_or(anyhow::anyhow!("No 'piece length' field"))?
+        .get_int()
+        .ok_or(anyhow::anyhow!("Couldn't get list"))? as usize;
+
+    // BEP 52: v2 torrents describe files by a tree and hash them with SHA-256 merkle trees
+    let meta_version = match info.get(&b"meta version"[..]).and_then(Content::get_int) {
+        Some(&2) if info.contains_key(&b"pieces"[..]) => MetaVersion::Hybrid,
+        Some(&2) => MetaVersion::V2,
+        _ => MetaVersion::V1,
+    };
+    let (files, pieces, piece_hashes_v2) = match meta_version {
+        MetaVersion::V1 => {
+            let (files, pieces) = extract_files_v1(info)?;
+            (files, pieces, Vec::new())
+        }
+        MetaVersion::V2 => {
+            let (files, piece_hashes_v2) =
+                extract_files_v2(info, torrent_data.get(&b"piece layers"[..]), piece_length)?;
+            (files, Vec::new(), piece_hashes_v2)
+        }
+        MetaVersion::Hybrid => {
+            let (mut files, pieces) = extract_files_v1(info)?;
+            let (files_v2, piece_hashes_v2) =
+                extract_files_v2(info, torrent_data.get(&b"piece layers"[..]), piece_length)?;
+            check_hybrid(&mut files, &files_v2, piece_length)?;
+            anyhow::ensure!(
+                pieces.len() == piece_hashes_v2.len(),
+                "Hybrid torrent has {} v1 pieces and {} v2 ones",
+                pieces.len(),
+                piece_hashes_v2.len()
+            );
+            (files, pieces, piece_hashes_v2)
+        }
+    };
+
+    for file in &files {
+        anyhow::ensure!(
+            file.path_to_file
+                .iter()
+                .all(|component| stays_inside(component)),
+            "File {} leaves the torrent",
+            file.path_to_file.join("/")
+        );
+    }
+
+    // Trackerless torrents rely on DHT nodes instead
+    let announce = match torrent_data.get(&b"announce"[..]) {
+        Some(announce) => announce
+            .get_str()
+            .ok_or(anyhow::anyhow!("Couldn't get str"))?
+            .to_string(),
+        None => String::new(),
+    };
+
+    // BEP 12: every element of announce-list is a tier of trackers
+    let mut announce_list_vec = Vec::new();
+    let announce_list = match torrent_data.get(&b"announce-list"[..]) {
+        Some(content) => {
+            for elem in content
+                .get_list()
+                .ok_or(anyhow::anyhow!("Couldn't get list"))?
+            {
+                let mut tier = Vec::new();
+                for tracker in elem
+                    .get_list()
+                    .ok_or(anyhow::anyhow!("Couldn't get list"))?
+                {
+                    tier.push(
+                        tracker
+                            .get_str()
+                            .ok_or(anyhow::anyhow!("Couldn't get str"))?
+                            .clone(),
+                    );
+                }
+                if !tier.is_empty() {
+                    announce_list_vec.push(tier);
+                }
+            }
+            Some(announce_list_vec)
+        }
+        None => None,
+    };
+
+    Ok(TorrentData {
+        pieces,
+        piece_length,
+        files,
+        announce,
+        announce_list,
+        meta_version,
+        piece_hashes_v2,
+        url_list: get_urls(torrent_data.get(&b"url-list"[..]))?,
+        http_seeds: get_urls(torrent_data.get(&b"httpseeds"[..]))?,
+        private: info.get(&b"private"[..]).and_then(Content::get_int) == Some(&1),
+    })
+}
+
+// A single url or a list of them
+fn get_urls(content: Option<&Content>) -> anyhow::Result<Vec<String>> {
+    let urls = match content {
+        Some(Content::Str(url)) => vec![url.clone()],
+        Some(Content::List(urls)) => urls
+            .iter()
+            .map(|url| {
+                url.get_str()
+                    .cloned()
+                    .ok_or(anyhow::anyhow!("Couldn't get str"))
+            })
+            .collect::<anyhow::Result<_>>()?,
+        _ => Vec::new(),
+    };
+    Ok(urls.into_iter().filter(|url| !url.is_empty()).collect())
+}
+
+fn extract_files_v1(info: &HashMap<Vec<u8>, Content>) -> anyhow::Result<(Vec<File>, Vec<Vec<u8>>)> {
+    let files_data = info.get(&b"files"[..]);
 
     let hashes = info
-        .get("pieces")
+        .get(&b"pieces"[..])
         .ok_or(anyhow::anyhow!("No 'pieces' field in hashes data"))?
         .get_bytes()
         .ok_or(anyhow::anyhow!("Couldn't get bytes"))?;
 
     let mut pieces: Vec<Vec<u8>> = Vec::new();
-    let piece_length: usize;
+
     let mut files: Vec<File> = Vec::new();
 
-    if files_data.is_some() {
+    if let Some(files_data) = files_data {
         let directory = info
-            .get("name")
+            .get(&b"name"[..])
             .ok_or(anyhow::anyhow!(
                 "No 'name' field for directory data in torrent file"
             ))?
@@ -46,7 +263,6 @@ pub fn extract_data(torrent_data: HashMap<String, Content>) -> anyhow::Result<To
             .clone()
             .to_string();
         let files_data = files_data
-            .unwrap()
             .get_list()
             .ok_or(anyhow::anyhow!("Couldn't get list"))?;
         for file in files_data.iter() {
@@ -55,7 +271,7 @@ pub fn extract_data(torrent_data: HashMap<String, Content>) -> anyhow::Result<To
             let path = file
                 .get_dict()
                 .ok_or(anyhow::anyhow!("Couldn't get dictionary"))?
-                .get("path")
+                .get(&b"path"[..])
                 .ok_or(anyhow::anyhow!("Couldn't define path in torrent file"))?
                 .get_list()
                 .ok_or(anyhow::anyhow!("Couldn't get list"))?;
@@ -68,40 +284,42 @@ pub fn extract_data(torrent_data: HashMap<String, Content>) -> anyhow::Result<To
                         .to_string(),
                 );
             }
+            let file = file
+                .get_dict()
+                .ok_or(anyhow::anyhow!("Couldn't get dictionary"))?;
+            let (attributes, symlink_path) = get_attributes(file)?;
             files.push(File {
-                path_to_file: path_to_file,
+                path_to_file,
                 size: *file
-                    .get_dict()
-                    .ok_or(anyhow::anyhow!("Couldn't get dictionary"))?
-                    .get("length")
+                    .get(&b"length"[..])
                     .ok_or(anyhow::anyhow!("No'length' field"))?
                     .get_int()
                     .ok_or(anyhow::anyhow!("Couldn't get int"))? as usize,
+                pieces_root: None,
+                attributes,
+                symlink_path,
             });
         }
     } else {
         files.push(File {
             path_to_file: vec![info
-                .get("name")
+                .get(&b"name"[..])
                 .ok_or(anyhow::anyhow!("No 'name' field"))?
                 .get_str()
                 .ok_or(anyhow::anyhow!("Couldn't get str"))?
                 .clone()
                 .to_string()],
             size: *info
-                .get("length")
+                .get(&b"length"[..])
                 .ok_or(anyhow::anyhow!("No 'length' field"))?
                 .get_int()
                 .ok_or(anyhow::anyhow!("Couldn't get int"))? as usize,
+            pieces_root: None,
+            attributes: get_attributes(info)?.0,
+            symlink_path: None,
         });
     }
 
-    piece_length = *info
-        .get("piece length")
-        .ok_or(anyhow::anyhow!("No 'piece length' field"))?
-        .get_int()
-        .ok_or(anyhow::anyhow!("Couldn't get list"))? as usize;
-
     let mut hash: Vec<u8> = Vec::new();
     for (index, byte) in hashes.iter().enumerate() {
         hash.push(*byte);
@@ -111,42 +329,460 @@ pub fn extract_data(torrent_data: HashMap<String, Content>) -> anyhow::Result<To
         }
     }
 
-    let announce = torrent_data
-        .get("announce")
-        .ok_or(anyhow::anyhow!("No 'announce' field"))?
+    Ok((files, pieces))
+}
+
+fn extract_files_v2(
+    info: &HashMap<Vec<u8>, Content>,
+    piece_layers: Option<&Content>,
+    piece_length: usize,
+) -> anyhow::Result<(Vec<File>, Vec<Vec<u8>>)> {
+    anyhow::ensure!(
+        piece_length >= merkle::BLOCK_SIZE && piece_length.is_power_of_two(),
+        "Wrong piece length {}",
+        piece_length
+    );
+    let name = info
+        .get(&b"name"[..])
+        .ok_or(anyhow::anyhow!("No 'name' field"))?
         .get_str()
         .ok_or(anyhow::anyhow!("Couldn't get str"))?
-        .to_string();
+        .clone();
+    let file_tree = info
+        .get(&b"file tree"[..])
+        .ok_or(anyhow::anyhow!("No 'file tree' field"))?
+        .get_dict()
+        .ok_or(anyhow::anyhow!("Couldn't get dictionary"))?;
 
-    let mut announce_list_vec = Vec::new();
-    let announce_list;
-    match torrent_data.get("announce-list") {
-        Some(content) => {
-            for elem in content
-                .get_list()
-                .ok_or(anyhow::anyhow!("Couldn't get list"))?
-            {
-                announce_list_vec.push(
-                    (*elem
-                        .get_list()
-                        .ok_or(anyhow::anyhow!("Couldn't get list"))?[0]
-                        .get_str()
-                        .ok_or(anyhow::anyhow!("Couldn't get str"))?)
-                    .clone(),
+    let mut files = Vec::new();
+    walk_file_tree(file_tree, &mut Vec::new(), &mut files)?;
+    anyhow::ensure!(!files.is_empty(), "No files in 'file tree'");
+    // A single file named like the torrent isn't put in a directory, as in v1 torrents
+    if files.len() != 1 || files[0].path_to_file != [name.clone()] {
+        for file in &mut files {
+            file.path_to_file.insert(0, name.clone());
+        }
+    }
+
+    let piece_layers = match piece_layers {
+        Some(piece_layers) => Some(
+            piece_layers
+                .get_dict()
+                .ok_or(anyhow::anyhow!("Couldn't get dictionary"))?,
+        ),
+        None => None,
+    };
+    let mut piece_hashes = Vec::new();
+    for file in &files {
+        let pieces_root = match &file.pieces_root {
+            Some(pieces_root) => pieces_root,
+            None => continue,
+        };
+        let pieces = file.size.div_ceil(piece_length);
+        if pieces == 1 {
+            piece_hashes.push(pieces_root.clone());
+            continue;
+        }
+        // Missing or wrong layers are asked from peers
+        let layer = piece_layers
+            .and_then(|piece_layers| piece_layers.get(pieces_root))
+            .and_then(get_raw_bytes)
+            .filter(|layer| layer.len() == pieces * HASH_SIZE)
+            .map(|layer| layer.chunks(HASH_SIZE).map(<[u8]>::to_vec).collect())
+            .filter(|layer: &Vec<Vec<u8>>| {
+                merkle::root_from_piece_layer(layer, piece_length) == *pieces_root
+            });
+        match layer {
+            Some(layer) => piece_hashes.extend(layer),
+            None => piece_hashes.extend(vec![Vec::new(); pieces]),
+        }
+    }
+    Ok((files, piece_hashes))
+}
+
+// Files are the nodes with an empty key, in the order of their sorted paths
+fn walk_file_tree(
+    tree: &HashMap<Vec<u8>, Content>,
+    path: &mut Vec<String>,
+    files: &mut Vec<File>,
+) -> anyhow::Result<()> {
+    let mut names: Vec<&Vec<u8>> = tree.keys().collect();
+    names.sort();
+    for name in names {
+        let node = tree[name]
+            .get_dict()
+            .ok_or(anyhow::anyhow!("Couldn't get dictionary"))?;
+        path.push(String::from_utf8(name.clone())?);
+        match node.get(&b""[..]) {
+            Some(file) => {
+                let file = file
+                    .get_dict()
+                    .ok_or(anyhow::anyhow!("Couldn't get dictionary"))?;
+                let size = *file
+                    .get(&b"length"[..])
+                    .ok_or(anyhow::anyhow!("No 'length' field"))?
+                    .get_int()
+                    .ok_or(anyhow::anyhow!("Couldn't get int"))?
+                    as usize;
+                let pieces_root = match file.get(&b"pieces root"[..]) {
+                    Some(pieces_root) => Some(
+                        get_raw_bytes(pieces_root)
+                            .filter(|pieces_root| pieces_root.len() == HASH_SIZE)
+                            .ok_or(anyhow::anyhow!("Wrong 'pieces root' field"))?,
+                    ),
+                    None => None,
+                };
+                anyhow::ensure!(
+                    size == 0 || pieces_root.is_some(),
+                    "No 'pieces root' field for {}",
+                    path.join("/")
                 );
+                let (attributes, symlink_path) = get_attributes(file)?;
+                files.push(File {
+                    path_to_file: path.clone(),
+                    size,
+                    pieces_root,
+                    attributes,
+                    symlink_path,
+                });
             }
-            announce_list = Some(announce_list_vec);
+            None => walk_file_tree(node, path, files)?,
+        }
+        path.pop();
+    }
+    Ok(())
+}
+
+fn get_attributes(
+    file: &HashMap<Vec<u8>, Content>,
+) -> anyhow::Result<(FileAttributes, Option<Vec<String>>)> {
+    let attributes = match file.get(&b"attr"[..]) {
+        Some(attr) => {
+            FileAttributes::parse(attr.get_str().ok_or(anyhow::anyhow!("Couldn't get str"))?)
         }
-        None => {
-            announce_list = None;
+        None => FileAttributes::default(),
+    };
+    let symlink_path = match file.get(&b"symlink path"[..]) {
+        Some(path) if attributes.symlink => Some(
+            path.get_list()
+                .ok_or(anyhow::anyhow!("Couldn't get list"))?
+                .iter()
+                .map(|elem| {
+                    elem.get_str()
+                        .cloned()
+                        .ok_or(anyhow::anyhow!("Couldn't get str"))
+                })
+                .collect::<anyhow::Result<Vec<String>>>()?,
+        ),
+        _ => None,
+    };
+    anyhow::ensure!(
+        !attributes.symlink || symlink_path.is_some(),
+        "Symlink without 'symlink path' field"
+    );
+    if let Some(target) = &symlink_path {
+        anyhow::ensure!(
+            !target.is_empty() && target.iter().all(|component| stays_inside(component)),
+            "Symlink to {} leaves the torrent",
+            target.join("/")
+        );
+    }
+    Ok((attributes, symlink_path))
+}
+
+// A path component which can't lead out of the torrent's directory
+fn stays_inside(component: &str) -> bool {
+    !component.is_empty()
+        && component != "."
+        && component != ".."
+        && !component.contains('/')
+        && !component.contains('\0')
+}
+
+// The v1 file list of a hybrid torrent has to describe the same files as the v2 tree, each
+// starting at a piece boundary, so both hash the same pieces
+fn check_hybrid(files: &mut [File], files_v2: &[File], piece_length: usize) -> anyhow::Result<()> {
+    let mut files_v2 = files_v2.iter();
+    let mut offset = 0;
+    for file in files.iter_mut() {
+        let file_offset = offset;
+        offset += file.size;
+        if file.attributes.padding {
+            continue;
         }
+        let file_v2 = files_v2.next().ok_or(anyhow::anyhow!(
+            "{} is missing from the v2 file tree",
+            file.path_to_file.join("/")
+        ))?;
+        anyhow::ensure!(
+            file.path_to_file == file_v2.path_to_file && file.size == file_v2.size,
+            "{} differs between the v1 and v2 file lists",
+            file.path_to_file.join("/")
+        );
+        anyhow::ensure!(
+            file.size == 0 || file_offset.is_multiple_of(piece_length),
+            "{} isn't aligned to a piece boundary",
+            file.path_to_file.join("/")
+        );
+        file.pieces_root = file_v2.pieces_root.clone();
     }
+    anyhow::ensure!(
+        files_v2.next().is_none(),
+        "The v2 file tree has files missing from the v1 list"
+    );
+    Ok(())
+}
 
-    Ok(TorrentData {
-        pieces,
-        piece_length,
-        files,
-        announce,
-        announce_list,
-    })
+// Binary strings which happen to be valid UTF-8 are parsed as text
+fn get_raw_bytes(content: &Content) -> Option<Vec<u8>> {
+    match content {
+        Content::Str(string) => Some(string.as_bytes().to_vec()),
+        Content::Bytes(bytes) => Some(bytes.clone()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent_file_handler::bencode_content::encode;
+    use crate::torrent_file_handler::torrent_file_parser;
+    use sha1::Sha1;
+    use sha2::{Digest, Sha256};
+
+    const PIECE_LENGTH: usize = 2 * merkle::BLOCK_SIZE;
+
+    fn dict(entries: Vec<(&str, Content)>) -> Content {
+        Content::Dict(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key.as_bytes().to_vec(), value))
+                .collect(),
+        )
+    }
+
+    fn file_node(data: &[u8]) -> Content {
+        let mut file = vec![("length", Content::Int(data.len() as i64))];
+        if !data.is_empty() {
+            file.push(("pieces root", Content::Bytes(file_root(data))));
+        }
+        dict(vec![("", dict(file))])
+    }
+
+    fn piece_layer(data: &[u8]) -> Vec<Vec<u8>> {
+        data.chunks(PIECE_LENGTH)
+            .map(|piece| merkle::piece_hash(piece, data.len(), PIECE_LENGTH))
+            .collect()
+    }
+
+    fn file_root(data: &[u8]) -> Vec<u8> {
+        if data.len() <= PIECE_LENGTH {
+            merkle::piece_hash(data, data.len(), PIECE_LENGTH)
+        } else {
+            merkle::root_from_piece_layer(&piece_layer(data), PIECE_LENGTH)
+        }
+    }
+
+    // "dir" holds a small file "a", a big one "b" and an empty "c"
+    fn v2_torrent(big: &[u8], with_layers: bool) -> Vec<u8> {
+        let file_tree = dict(vec![
+            ("b", file_node(big)),
+            ("a", file_node(b"small")),
+            ("c", file_node(b"")),
+        ]);
+        let info = dict(vec![
+            ("meta version", Content::Int(2)),
+            ("name", Content::Str("dir".to_string())),
+            ("piece length", Content::Int(PIECE_LENGTH as i64)),
+            ("file tree", file_tree),
+        ]);
+        let mut torrent = vec![
+            ("announce", Content::Str("http://t.example/a".to_string())),
+            ("info", info),
+        ];
+        if with_layers {
+            let mut piece_layers = HashMap::new();
+            piece_layers.insert(file_root(big), Content::Bytes(piece_layer(big).concat()));
+            torrent.push(("piece layers", Content::Dict(piece_layers)));
+        }
+        encode(&dict(torrent))
+    }
+
+    #[test]
+    fn v2_files_and_piece_hashes() {
+        let big: Vec<u8> = (0..5 * merkle::BLOCK_SIZE)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let path = std::env::temp_dir().join(format!("rusty_torrent_v2_{}", std::process::id()));
+        std::fs::write(&path, v2_torrent(&big, true)).unwrap();
+        let (contents, info_hash) =
+            torrent_file_parser::parse_torrent_file(path.to_str().unwrap().to_string()).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let info = encode(&contents[&b"info"[..]]);
+        assert_eq!(info_hash, Sha256::digest(&info)[..20].to_vec());
+        let torrent_data = extract_data(contents).unwrap();
+        assert_eq!(torrent_data.meta_version, MetaVersion::V2);
+        let files: Vec<_> = torrent_data
+            .files
+            .iter()
+            .map(|file| (file.path_to_file.join("/"), file.size))
+            .collect();
+        assert_eq!(
+            files,
+            vec![
+                ("dir/a".to_string(), 5),
+                ("dir/b".to_string(), big.len()),
+                ("dir/c".to_string(), 0),
+            ]
+        );
+
+        // Every file starts a new piece
+        assert_eq!(torrent_data.piece_count(), 4);
+        assert_eq!(torrent_data.file_pieces(1), 1..4);
+        assert_eq!(torrent_data.piece_size(0), 5);
+        assert_eq!(torrent_data.piece_size(3), merkle::BLOCK_SIZE);
+        assert_eq!(torrent_data.piece_file(2), Some(1));
+        let mut expected = vec![file_root(b"small")];
+        expected.extend(piece_layer(&big));
+        assert_eq!(torrent_data.piece_hashes_v2, expected);
+    }
+
+    fn torrent_with_symlink(target: &[&str]) -> Vec<u8> {
+        let target = target
+            .iter()
+            .map(|component| Content::Str(component.to_string()))
+            .collect();
+        let link = dict(vec![
+            ("attr", Content::Str("l".to_string())),
+            ("length", Content::Int(0)),
+            ("symlink path", Content::List(target)),
+        ]);
+        let file_tree = dict(vec![
+            ("a", file_node(b"small")),
+            ("link", dict(vec![("", link)])),
+        ]);
+        let info = dict(vec![
+            ("meta version", Content::Int(2)),
+            ("name", Content::Str("dir".to_string())),
+            ("piece length", Content::Int(PIECE_LENGTH as i64)),
+            ("file tree", file_tree),
+        ]);
+        encode(&dict(vec![("info", info)]))
+    }
+
+    #[test]
+    fn symlinks_must_stay_inside_the_torrent() {
+        let (contents, _) =
+            torrent_file_parser::parse_byte_data(&torrent_with_symlink(&["a"])).unwrap();
+        let torrent_data = extract_data(contents).unwrap();
+        assert_eq!(
+            torrent_data.files[1].symlink_path,
+            Some(vec!["a".to_string()])
+        );
+
+        for target in [&["..", "etc", "passwd"][..], &["/etc"], &["a", ""], &[]] {
+            let (contents, _) =
+                torrent_file_parser::parse_byte_data(&torrent_with_symlink(target)).unwrap();
+            assert!(extract_data(contents).is_err());
+        }
+    }
+
+    #[test]
+    fn missing_piece_layers_are_left_empty() {
+        let big = vec![3; 3 * merkle::BLOCK_SIZE];
+        let (contents, _) = torrent_file_parser::parse_byte_data(&v2_torrent(&big, false)).unwrap();
+        let torrent_data = extract_data(contents).unwrap();
+        assert_eq!(torrent_data.piece_hashes_v2.len(), 3);
+        assert!(!torrent_data.piece_hashes_v2[0].is_empty());
+        assert!(torrent_data.piece_hashes_v2[1..].iter().all(Vec::is_empty));
+    }
+
+    // "a" is padded to a piece boundary for the v1 file list unless `padded` is false
+    fn hybrid_torrent(big: &[u8], padded: bool) -> Vec<u8> {
+        let v1_file = |path: Vec<&str>, size: usize, attr: &str| {
+            let mut file = vec![
+                ("length", Content::Int(size as i64)),
+                (
+                    "path",
+                    Content::List(path.iter().map(|p| Content::Str(p.to_string())).collect()),
+                ),
+            ];
+            if !attr.is_empty() {
+                file.push(("attr", Content::Str(attr.to_string())));
+            }
+            dict(file)
+        };
+        let mut files = vec![v1_file(vec!["a"], 5, "")];
+        let mut data = b"small".to_vec();
+        if padded {
+            files.push(v1_file(vec![".pad", "32763"], PIECE_LENGTH - 5, "p"));
+            data.resize(PIECE_LENGTH, 0);
+        }
+        files.push(v1_file(vec!["b"], big.len(), "x"));
+        data.extend_from_slice(big);
+        let pieces: Vec<u8> = data
+            .chunks(PIECE_LENGTH)
+            .flat_map(|piece| Sha1::digest(piece).to_vec())
+            .collect();
+
+        let file_tree = dict(vec![("a", file_node(b"small")), ("b", file_node(big))]);
+        let info = dict(vec![
+            ("meta version", Content::Int(2)),
+            ("name", Content::Str("dir".to_string())),
+            ("piece length", Content::Int(PIECE_LENGTH as i64)),
+            ("file tree", file_tree),
+            ("files", Content::List(files)),
+            ("pieces", Content::Bytes(pieces)),
+        ]);
+        let mut piece_layers = HashMap::new();
+        piece_layers.insert(file_root(big), Content::Bytes(piece_layer(big).concat()));
+        encode(&dict(vec![
+            ("info", info),
+            ("piece layers", Content::Dict(piece_layers)),
+        ]))
+    }
+
+    #[test]
+    fn hybrid_torrent_with_padding() {
+        let big: Vec<u8> = (0..3 * merkle::BLOCK_SIZE)
+            .map(|i| (i % 249) as u8)
+            .collect();
+        let path =
+            std::env::temp_dir().join(format!("rusty_torrent_hybrid_{}", std::process::id()));
+        std::fs::write(&path, hybrid_torrent(&big, true)).unwrap();
+        let (contents, info_hashes) =
+            torrent_file_parser::parse_torrent_file_hashes(path.to_str().unwrap().to_string())
+                .unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        // Both swarms
+        let info = encode(&contents[&b"info"[..]]);
+        assert_eq!(
+            info_hashes,
+            vec![
+                Sha1::digest(&info).to_vec(),
+                Sha256::digest(&info)[..20].to_vec()
+            ]
+        );
+        let torrent_data = extract_data(contents).unwrap();
+        assert_eq!(torrent_data.meta_version, MetaVersion::Hybrid);
+        assert_eq!(torrent_data.files.len(), 3);
+        assert!(torrent_data.files[1].attributes.padding);
+        assert!(torrent_data.files[2].attributes.executable);
+        assert_eq!(torrent_data.files[2].pieces_root, Some(file_root(&big)));
+        assert_eq!(torrent_data.piece_count(), 3);
+        assert_eq!(torrent_data.file_pieces(2), 1..3);
+        let mut expected = vec![file_root(b"small")];
+        expected.extend(piece_layer(&big));
+        assert_eq!(torrent_data.piece_hashes_v2, expected);
+    }
+
+    #[test]
+    fn hybrid_torrent_without_padding_is_refused() {
+        let big = vec![1; 3 * merkle::BLOCK_SIZE];
+        let (contents, _) =
+            torrent_file_parser::parse_byte_data(&hybrid_torrent(&big, false)).unwrap();
+        assert!(extract_data(contents).is_err());
+    }
 }
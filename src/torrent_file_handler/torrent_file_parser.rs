@@ -1,194 +1,216 @@
 use super::bencode_content::Content;
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
 use std::collections::HashMap;
 use std::fs::read;
+use std::ops::Range;
 
 // https://habr.com/ru/post/119753/
 // https://en.wikipedia.org/wiki/Bencode
 
-static mut INFO_START: usize = 0;
-static mut INFO_END: usize = 0;
+pub type TorrentContents = HashMap<Vec<u8>, Content>;
 
-pub fn parse_torrent_file(filename: String) -> anyhow::Result<(HashMap<String, Content>, Vec<u8>)> {
+pub fn parse_torrent_file(filename: String) -> anyhow::Result<(TorrentContents, Vec<u8>)> {
+    let (torrent_contents, mut info_hashes) = parse_torrent_file_hashes(filename)?;
+    Ok((torrent_contents, info_hashes.remove(0)))
+}
+
+// Hybrid torrents are shared in two swarms: by their v1 info hash first, then by the v2 one
+pub fn parse_torrent_file_hashes(
+    filename: String,
+) -> anyhow::Result<(TorrentContents, Vec<Vec<u8>>)> {
     let binary_contents = read(filename)?;
-    let torrent_contents = parse_byte_data(&binary_contents)?;
-    let info_hash = create_info_hash(&binary_contents);
-    Ok((torrent_contents, info_hash))
+    let (torrent_contents, info_span) = parse_byte_data(&binary_contents)?;
+    let (info, info_span) = match (
+        torrent_contents
+            .get(&b"info"[..])
+            .and_then(Content::get_dict),
+        info_span,
+    ) {
+        (Some(info), Some(info_span)) => (info, info_span),
+        _ => anyhow::bail!("The torrent has no info dictionary"),
+    };
+    let v2 = info.get(&b"meta version"[..]) == Some(&Content::Int(2));
+    let v1 = info.contains_key(&b"pieces"[..]);
+
+    let mut info_hashes = Vec::new();
+    let info_bytes = &binary_contents[info_span];
+    if v1 {
+        info_hashes.push(create_info_hash(info_bytes));
+    }
+    if v2 {
+        info_hashes.push(create_info_hash_v2(info_bytes));
+    }
+    Ok((torrent_contents, info_hashes))
 }
 
-pub fn parse_byte_data(data: &Vec<u8>) -> anyhow::Result<HashMap<String, Content>> {
+// The dictionary and where the value of its own "info" key is in `data`. Keys named "info" deeper
+// down, like a file of that name in a v2 file tree, don't count.
+pub fn parse_byte_data(data: &[u8]) -> anyhow::Result<(TorrentContents, Option<Range<usize>>)> {
     anyhow::ensure!(
-        data[0] == 'd' as u8,
+        data.first() == Some(&b'd'),
         "Is it possible for .torrent file to start not from 'd'?"
     );
 
     let mut current_index: usize = 1;
-    parse_dict(data, &mut current_index)
+    let mut info_span = None;
+    let contents = parse_dict(data, &mut current_index, &mut info_span)?;
+    Ok((contents, info_span))
 }
 
-fn create_info_hash(contents: &Vec<u8>) -> Vec<u8> {
+fn create_info_hash(info: &[u8]) -> Vec<u8> {
     let mut hasher = Sha1::new();
-    unsafe {
-        hasher.update(&contents[INFO_START..INFO_END]);
-    }
+    hasher.update(info);
     hasher.finalize().to_vec()
 }
 
-fn parse_int(contents: &Vec<u8>, current_index: &mut usize) -> anyhow::Result<i64> {
+// BEP 52: v2 torrents are identified by the SHA-256 of the info dictionary, truncated to 20 bytes
+// in handshakes and tracker requests
+fn create_info_hash_v2(info: &[u8]) -> Vec<u8> {
+    let mut info_hash = Sha256::digest(info).to_vec();
+    info_hash.truncate(20);
+    info_hash
+}
+
+// Truncated data ends with an error, not out of bounds
+fn byte_at(contents: &[u8], index: usize) -> anyhow::Result<u8> {
+    contents
+        .get(index)
+        .copied()
+        .ok_or(anyhow::anyhow!("Unexpected end of data"))
+}
+
+fn parse_int(contents: &[u8], current_index: &mut usize) -> anyhow::Result<i64> {
     let mut str_num = String::new();
-    let mut symbol = contents[*current_index];
+    let mut symbol = byte_at(contents, *current_index)?;
 
-    while symbol != 'e' as u8 {
+    while symbol != b'e' {
         str_num.push(symbol as char);
         *current_index += 1;
-        symbol = contents[*current_index];
+        symbol = byte_at(contents, *current_index)?;
     }
     *current_index += 1;
     Ok(str_num.parse::<i64>()?)
 }
 
-fn parse_bytes(contents: &Vec<u8>, current_index: &mut usize) -> anyhow::Result<Vec<u8>> {
+fn parse_bytes(contents: &[u8], current_index: &mut usize) -> anyhow::Result<Vec<u8>> {
     let mut len_str = String::new();
-    let mut symbol = contents[*current_index];
+    let mut symbol = byte_at(contents, *current_index)?;
 
-    while symbol != ':' as u8 {
+    while symbol != b':' {
         len_str.push(symbol as char);
         *current_index += 1;
-        symbol = contents[*current_index];
+        symbol = byte_at(contents, *current_index)?;
     }
     let len_str = len_str.parse::<usize>()?;
 
     *current_index += 1;
-    let mut bytes = Vec::<u8>::new();
-    bytes.reserve(len_str);
-
-    for _ in 0..len_str {
-        bytes.push(contents[*current_index]);
-        *current_index += 1;
-    }
+    anyhow::ensure!(
+        len_str <= contents.len() - *current_index,
+        "String of {} bytes is longer than the data",
+        len_str
+    );
+    let bytes = contents[*current_index..*current_index + len_str].to_vec();
+    *current_index += len_str;
 
     Ok(bytes)
 }
 
-fn parse_string(contents: &Vec<u8>, current_index: &mut usize) -> anyhow::Result<String> {
-    Ok(String::from_utf8(parse_bytes(&contents, current_index)?)?)
+fn parse_string(contents: &[u8], current_index: &mut usize) -> anyhow::Result<String> {
+    Ok(String::from_utf8(parse_bytes(contents, current_index)?)?)
+}
+
+// Byte strings which aren't valid UTF-8 (e.g. peer ids) are kept as bytes
+fn parse_string_or_bytes(contents: &[u8], current_index: &mut usize) -> anyhow::Result<Content> {
+    let start_index = *current_index;
+    match parse_string(contents, current_index) {
+        Ok(string) => Ok(Content::Str(string)),
+        Err(_) => {
+            *current_index = start_index;
+            Ok(Content::Bytes(parse_bytes(contents, current_index)?))
+        }
+    }
 }
 
-fn parse_list(contents: &Vec<u8>, current_index: &mut usize) -> anyhow::Result<Vec<Content>> {
+fn parse_list(contents: &[u8], current_index: &mut usize) -> anyhow::Result<Vec<Content>> {
     let mut list = Vec::<Content>::new();
-    let mut symbol = contents[*current_index];
-    while symbol != 'e' as u8 {
-        if symbol == 'i' as u8 {
+    let mut symbol = byte_at(contents, *current_index)?;
+    while symbol != b'e' {
+        if symbol == b'i' {
             *current_index += 1;
             list.push(Content::Int(parse_int(contents, current_index)?));
-        } else if symbol >= '0' as u8 && symbol <= '9' as u8 {
-            list.push(Content::Str(parse_string(contents, current_index)?));
-        } else if symbol == 'l' as u8 {
+        } else if symbol.is_ascii_digit() {
+            list.push(parse_string_or_bytes(contents, current_index)?);
+        } else if symbol == b'l' {
             *current_index += 1;
             list.push(Content::List(parse_list(contents, current_index)?));
-        } else if symbol == 'd' as u8 {
+        } else if symbol == b'd' {
             *current_index += 1;
-            list.push(Content::Dict(parse_dict(contents, current_index)?));
+            list.push(Content::Dict(parse_dict(
+                contents,
+                current_index,
+                &mut None,
+            )?));
         } else {
             anyhow::bail!("Unknown type {}", symbol as char);
         }
-        symbol = contents[*current_index];
+        symbol = byte_at(contents, *current_index)?;
     }
     *current_index += 1;
     Ok(list)
 }
 
+// `info_span` is set to where the value of an "info" key of this dictionary is
 fn parse_dict(
-    contents: &Vec<u8>,
+    contents: &[u8],
     current_index: &mut usize,
-) -> anyhow::Result<HashMap<String, Content>> {
-    let mut dict_content = HashMap::<String, Content>::new();
-    let mut key = String::from("");
+    info_span: &mut Option<Range<usize>>,
+) -> anyhow::Result<HashMap<Vec<u8>, Content>> {
+    let mut dict_content = HashMap::<Vec<u8>, Content>::new();
+    let mut key = Vec::new();
     let mut reading_key = true;
-    let mut symbol = contents[*current_index];
-    let mut info_key_met = false;
-
-    while symbol != 'e' as u8 {
-        if !info_key_met && key == "info" && !reading_key {
-            info_key_met = true;
-            unsafe {
-                INFO_START = *current_index;
-            }
-        }
+    let mut symbol = byte_at(contents, *current_index)?;
 
-        if symbol == 'i' as u8 {
-            *current_index += 1;
-            anyhow::ensure!(!reading_key, "Dictionary keys must be byte strings");
-            dict_content.insert(
-                key.clone(),
-                Content::Int(parse_int(contents, current_index)?),
+    while symbol != b'e' {
+        if reading_key {
+            anyhow::ensure!(
+                symbol.is_ascii_digit(),
+                "Dictionary keys must be byte strings"
             );
-            if info_key_met {
-                unsafe {
-                    INFO_END = *current_index;
-                }
-            }
-            reading_key = true;
-        } else if symbol >= '0' as u8 && symbol <= '9' as u8 {
-            if reading_key {
-                key = parse_string(contents, current_index)?;
-                reading_key = false;
-                anyhow::ensure!(
-                    dict_content.get(&key).is_none(),
-                    "Dictionary has a duplicate key"
-                );
-            } else {
-                if key != "pieces" && key != "peers" && key != "peers6" {
-                    // 2nd and 3rd for IPv4 and IPv6 respectively
-                    dict_content.insert(
-                        key.clone(),
-                        Content::Str(parse_string(contents, current_index)?),
-                    );
-                } else {
-                    dict_content.insert(
-                        key.clone(),
-                        Content::Bytes(parse_bytes(contents, current_index)?),
-                    );
-                }
-                if info_key_met {
-                    info_key_met = false;
-                    unsafe {
-                        INFO_END = *current_index;
-                    }
-                }
-                reading_key = true;
-            }
-        } else if symbol == 'l' as u8 {
-            *current_index += 1;
-            anyhow::ensure!(!reading_key, "Dictionary keys must be byte strings");
-
-            dict_content.insert(
-                key.clone(),
-                Content::List(parse_list(contents, current_index)?),
+            key = parse_bytes(contents, current_index)?;
+            anyhow::ensure!(
+                !dict_content.contains_key(&key),
+                "Dictionary has a duplicate key"
             );
-            if info_key_met {
-                unsafe {
-                    INFO_END = *current_index;
-                }
-            }
-            reading_key = true;
-        } else if symbol == 'd' as u8 {
-            *current_index += 1;
-            anyhow::ensure!(!reading_key, "Dictionary keys must be byte strings");
-            dict_content.insert(
-                key.clone(),
-                Content::Dict(parse_dict(contents, current_index)?),
-            );
-            if info_key_met {
-                unsafe {
-                    INFO_END = *current_index;
+            reading_key = false;
+        } else {
+            let value_start = *current_index;
+            let value = if symbol == b'i' {
+                *current_index += 1;
+                Content::Int(parse_int(contents, current_index)?)
+            } else if symbol.is_ascii_digit() {
+                // 2nd and 3rd for IPv4 and IPv6 respectively
+                if key != b"pieces" && key != b"peers" && key != b"peers6" {
+                    parse_string_or_bytes(contents, current_index)?
+                } else {
+                    Content::Bytes(parse_bytes(contents, current_index)?)
                 }
+            } else if symbol == b'l' {
+                *current_index += 1;
+                Content::List(parse_list(contents, current_index)?)
+            } else if symbol == b'd' {
+                *current_index += 1;
+                Content::Dict(parse_dict(contents, current_index, &mut None)?)
+            } else {
+                anyhow::bail!("Unknown type {}", symbol as char);
+            };
+            if key == b"info" {
+                *info_span = Some(value_start..*current_index);
             }
+            dict_content.insert(key.clone(), value);
             reading_key = true;
-        } else {
-            anyhow::bail!("Unknown type {}", symbol as char);
         }
-        symbol = contents[*current_index];
+        symbol = byte_at(contents, *current_index)?;
     }
     *current_index += 1;
 
@@ -198,7 +220,6 @@ fn parse_dict(
 // I assume that .torrent file is OK, so I don't check some Bencode restrictions (like "i-0e" or "i-000532e" and so on)
 
 #[cfg(test)]
-
 mod tests {
 
     use std::collections::HashMap;
@@ -209,7 +230,7 @@ mod tests {
     fn parsing_positive_int() {
         let mut index = 0;
         assert_eq!(
-            super::parse_int(&"42e".to_string().as_bytes().to_vec(), &mut index).unwrap(),
+            super::parse_int("42e".to_string().as_bytes(), &mut index).unwrap(),
             42
         );
         assert_eq!(index, 3);
@@ -219,7 +240,7 @@ mod tests {
     fn parsing_zero_int() {
         let mut index = 0;
         assert_eq!(
-            super::parse_int(&"0e".to_string().as_bytes().to_vec(), &mut index).unwrap(),
+            super::parse_int("0e".to_string().as_bytes(), &mut index).unwrap(),
             0
         );
         assert_eq!(index, 2);
@@ -229,7 +250,7 @@ mod tests {
     fn parsing_negative_int() {
         let mut index = 0;
         assert_eq!(
-            super::parse_int(&"-75637e".to_string().as_bytes().to_vec(), &mut index).unwrap(),
+            super::parse_int("-75637e".to_string().as_bytes(), &mut index).unwrap(),
             -75637
         );
         assert_eq!(index, 7);
@@ -239,7 +260,7 @@ mod tests {
     fn parsing_string_1() {
         let mut index = 0;
         assert_eq!(
-            super::parse_string(&"4:spam".to_string().as_bytes().to_vec(), &mut index).unwrap(),
+            super::parse_string("4:spam".to_string().as_bytes(), &mut index).unwrap(),
             "spam"
         );
         assert_eq!(index, 6);
@@ -249,11 +270,7 @@ mod tests {
     fn parsing_string_2() {
         let mut index = 0;
         assert_eq!(
-            super::parse_string(
-                &"13:parrot sketch".to_string().as_bytes().to_vec(),
-                &mut index
-            )
-            .unwrap(),
+            super::parse_string("13:parrot sketch".to_string().as_bytes(), &mut index).unwrap(),
             "parrot sketch"
         );
         assert_eq!(index, 16);
@@ -263,11 +280,7 @@ mod tests {
     fn parsing_list() {
         let mut index = 0;
         let result: Vec<super::Content> = {
-            super::parse_list(
-                &"13:parrot sketchi42ee".to_string().as_bytes().to_vec(),
-                &mut index,
-            )
-            .unwrap()
+            super::parse_list("13:parrot sketchi42ee".to_string().as_bytes(), &mut index).unwrap()
         };
         assert_eq!(result[0], super::Content::Str("parrot sketch".to_string()));
         assert_eq!(result[1], super::Content::Int(42));
@@ -277,32 +290,82 @@ mod tests {
     #[test]
     fn parsing_dict() {
         let mut index = 0;
-        let result: HashMap<String, super::Content> = {
+        let result: HashMap<Vec<u8>, super::Content> = {
             super::parse_dict(
-                &"3:bar4:spam3:fooi42ee".to_string().as_bytes().to_vec(),
+                "3:bar4:spam3:fooi42ee".to_string().as_bytes(),
                 &mut index,
+                &mut None,
             )
             .unwrap()
         };
         assert_eq!(
-            *result.get("bar").unwrap(),
+            *result.get(&b"bar"[..]).unwrap(),
             super::Content::Str("spam".to_string())
         );
-        assert_eq!(*result.get("foo").unwrap(), super::Content::Int(42));
+        assert_eq!(*result.get(&b"foo"[..]).unwrap(), super::Content::Int(42));
         assert_eq!(index, 21);
     }
 
     #[test]
-    fn testing_info_hash() {
+    fn parsing_binary_string() {
+        let mut index = 0;
+        assert_eq!(
+            super::parse_string_or_bytes(b"2:\xff\x00", &mut index).unwrap(),
+            super::Content::Bytes(vec![0xFF, 0])
+        );
+        assert_eq!(index, 4);
+    }
+
+    #[test]
+    fn parsing_dict_with_binary_keys() {
         let mut index = 0;
-        let example = "4:info4:spam3:fooi42ee".to_string().as_bytes().to_vec();
-        let _ = super::parse_dict(&example, &mut index).unwrap();
-        unsafe {
-            assert_eq!(super::INFO_START, 6);
-            assert_eq!(super::INFO_END, 12);
+        let mut example = b"20:".to_vec();
+        example.extend_from_slice(&[0xFF; 20]);
+        example.extend_from_slice(b"i1ee");
+        let result = super::parse_dict(&example, &mut index, &mut None).unwrap();
+        assert_eq!(
+            *result.get(&[0xFF; 20][..]).unwrap(),
+            super::Content::Int(1)
+        );
+    }
+
+    #[test]
+    fn malformed_data_is_an_error() {
+        for data in [
+            &b""[..],
+            b"d",
+            b"d3:foo",
+            b"d3:fooi42",
+            b"d3:foo5:ab",
+            b"d3:fool",
+            b"d3:food3:bar",
+            // Lengths far beyond the data, which must not be allocated
+            b"d3:foo18446744073709551615:x",
+            b"d3:foo999999999999999999999:x",
+        ] {
+            assert!(super::parse_byte_data(data).is_err());
         }
+    }
+
+    #[test]
+    fn binary_keys_round_trip() {
+        // "\xff" alone and "ÿ" in UTF-8 are different keys
+        let example = b"d2:\xc3\xbfi2e1:\xffi1ee";
+        let (result, _) = super::parse_byte_data(example).unwrap();
+        assert_eq!(result.len(), 2);
+        assert_eq!(
+            super::super::bencode_content::encode(&super::Content::Dict(result)),
+            example
+        );
+    }
+
+    #[test]
+    fn testing_info_hash() {
+        let example = b"d4:info4:spam3:fooi42ee";
+        let (_, info_span) = super::parse_byte_data(example).unwrap();
+        assert_eq!(info_span, Some(7..13));
         assert_eq!(
-            super::create_info_hash(&example),
+            super::create_info_hash(&example[7..13]),
             vec![
                 151, 39, 109, 243, 254, 149, 209, 1, 232, 44, 41, 51, 88, 33, 38, 89, 2, 164, 15,
                 144
@@ -312,22 +375,55 @@ mod tests {
 
     #[test]
     fn testing_info_hash_2() {
-        let mut index = 0;
-        let example = "4:infod5:filesld6:lengthi615e4:patheeee"
-            .to_string()
-            .as_bytes()
-            .to_vec();
-        let _ = super::parse_dict(&example, &mut index).unwrap();
-        unsafe {
-            assert_eq!(super::INFO_START, 6);
-            assert_eq!(super::INFO_END, 38);
-        }
+        let example = b"d4:infod5:filesld6:lengthi615e4:patheeeee";
+        let (_, info_span) = super::parse_byte_data(example).unwrap();
+        assert_eq!(info_span, Some(7..39));
         assert_eq!(
-            super::create_info_hash(&example),
+            super::create_info_hash(&example[7..39]),
             vec![
                 4, 126, 211, 231, 220, 45, 82, 116, 37, 135, 96, 198, 181, 86, 85, 175, 170, 126,
                 67, 178
             ]
         );
     }
+
+    #[test]
+    fn info_hash_with_keys_after_info() {
+        let example = b"d4:infod4:name1:ae8:url-listl1:bee";
+        let (_, info_span) = super::parse_byte_data(example).unwrap();
+        assert_eq!(&example[info_span.unwrap()], b"d4:name1:ae");
+    }
+
+    #[test]
+    fn info_span_is_the_top_level_one() {
+        // A v2 file named "info", after the "info" key of the torrent
+        let info = b"d9:file treed4:infod0:d6:lengthi1eeee12:meta versioni2e4:name1:ae";
+        let mut example = b"d4:info".to_vec();
+        example.extend_from_slice(info);
+        example.push(b'e');
+        let (_, info_span) = super::parse_byte_data(&example).unwrap();
+        assert_eq!(&example[info_span.unwrap()], info);
+
+        let (_, info_span) = super::parse_byte_data(b"d3:food4:infoi1eee").unwrap();
+        assert_eq!(info_span, None);
+    }
+
+    #[test]
+    fn torrents_without_info_are_refused() {
+        let dir =
+            std::env::temp_dir().join(format!("rusty_torrent_no_info_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let with_info = dir.join("with_info.torrent");
+        std::fs::write(&with_info, b"d4:infod6:lengthi1e4:name1:a6:pieces0:ee").unwrap();
+        let without_info = dir.join("without_info.torrent");
+        std::fs::write(&without_info, b"d3:fooi1ee").unwrap();
+        let not_a_dict = dir.join("not_a_dict.torrent");
+        std::fs::write(&not_a_dict, b"d4:infoi1ee").unwrap();
+
+        let path = |path: &std::path::Path| path.to_str().unwrap().to_string();
+        assert!(super::parse_torrent_file_hashes(path(&with_info)).is_ok());
+        assert!(super::parse_torrent_file_hashes(path(&without_info)).is_err());
+        assert!(super::parse_torrent_file_hashes(path(&not_a_dict)).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
@@ -0,0 +1,401 @@
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::join_all;
+use tokio::sync::{mpsc, watch, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use super::tier::{self, tracker_tiers, TrackerTier};
+use super::{AnnounceEvent, AnnounceRequest, TrackerClient, Transferred};
+use crate::torrent_file_handler::torrent_data_extractor::TorrentData;
+
+/*
+ *   Every swarm is announced to from its own task, walking the tiers in order until a tracker
+ *   answers. After a successful announce the next one is made after `interval` seconds. The
+ *   download can ask for more peers earlier, but trackers are never contacted again before the
+ *   `min interval` has passed. When every tier failed the announce is retried with exponential
+ *   backoff. A torrent in several swarms (v1 and v2 info hashes) is announced once per swarm.
+ *
+ *   Announces carry what the torrent has transferred at the time. The first one that gets through
+ *   is `started`. When the download stops the trackers are told `completed` if it finished, then
+ *   `stopped`, unless no tracker ever heard from us.
+ */
+
+// Peers from one announce and what we told that tracker, the handshake has to match it
+pub struct AnnouncedPeers {
+    pub info_hash: Vec<u8>,
+    pub peer_id: Vec<u8>,
+    pub peers: Vec<SocketAddr>,
+}
+
+const DEFAULT_MIN_INTERVAL: Duration = Duration::from_secs(60);
+// Intervals a tracker asks for are kept within these, so a broken or hostile tracker can neither
+// get hammered nor stop us from announcing
+const SHORTEST_INTERVAL: Duration = Duration::from_secs(60);
+const LONGEST_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+const INITIAL_BACKOFF: Duration = Duration::from_secs(15);
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+// How long a stopping download waits for the last announces, they go on in the background after
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct AnnounceScheduler {
+    peers: mpsc::UnboundedReceiver<AnnouncedPeers>,
+    more_peers_wanted: Arc<Notify>,
+    // Set to whether the download completed when it stops. Dropping it stops the announces too.
+    stopping: watch::Sender<bool>,
+    loops: Vec<JoinHandle<()>>,
+}
+
+impl AnnounceScheduler {
+    pub fn start(
+        client: Arc<TrackerClient>,
+        torrent_data: &TorrentData,
+        requests: Vec<AnnounceRequest>,
+        transferred: Arc<Transferred>,
+    ) -> AnnounceScheduler {
+        let (peers_tx, peers) = mpsc::unbounded_channel();
+        let more_peers_wanted = Arc::new(Notify::new());
+        let (stopping, stopping_rx) = watch::channel(false);
+
+        let mut loops = Vec::new();
+        for request in requests {
+            let tiers = tracker_tiers(torrent_data);
+            if tiers.is_empty() {
+                continue;
+            }
+            loops.push(tokio::spawn(announce_loop(
+                tiers,
+                Arc::clone(&client),
+                request,
+                Arc::clone(&transferred),
+                peers_tx.clone(),
+                Arc::clone(&more_peers_wanted),
+                stopping_rx.clone(),
+            )));
+        }
+
+        AnnounceScheduler {
+            peers,
+            more_peers_wanted,
+            stopping,
+            loops,
+        }
+    }
+
+    // Tells the trackers the download completed, if it did since it started, and that it stopped
+    pub async fn stop(self, completed: bool) {
+        let _ = self.stopping.send(completed);
+        let _ = tokio::time::timeout(STOP_TIMEOUT, join_all(self.loops)).await;
+    }
+
+    // Returns peers from the next successful announce of any swarm
+    pub async fn next_peers(&mut self) -> Option<AnnouncedPeers> {
+        self.peers.recv().await
+    }
+
+    // Asks every swarm to re-announce as soon as its min interval allows
+    pub fn request_more_peers(&self) {
+        self.more_peers_wanted.notify_waiters();
+    }
+}
+
+// The request with what was transferred up to now
+fn current_request(
+    request: &AnnounceRequest,
+    transferred: &Transferred,
+    event: AnnounceEvent,
+) -> AnnounceRequest {
+    AnnounceRequest {
+        uploaded: transferred.uploaded.load(Ordering::Relaxed),
+        downloaded: transferred.downloaded.load(Ordering::Relaxed),
+        left: transferred.left.load(Ordering::Relaxed),
+        event,
+        ..request.clone()
+    }
+}
+
+async fn announce_loop(
+    mut tiers: Vec<TrackerTier>,
+    client: Arc<TrackerClient>,
+    request: AnnounceRequest,
+    transferred: Arc<Transferred>,
+    peers_tx: mpsc::UnboundedSender<AnnouncedPeers>,
+    more_peers_wanted: Arc<Notify>,
+    mut stopping: watch::Receiver<bool>,
+) {
+    let mut schedule = TrackerSchedule::new(Instant::now());
+    let mut event = AnnounceEvent::Started;
+
+    let completed = loop {
+        tokio::select! {
+            _ = stopping.changed() => break *stopping.borrow(),
+            _ = tokio::time::sleep_until(schedule.next_announce) => {}
+            _ = more_peers_wanted.notified() => {
+                tokio::select! {
+                    _ = stopping.changed() => break *stopping.borrow(),
+                    _ = tokio::time::sleep_until(schedule.earliest_reannounce()) => {}
+                }
+            }
+        }
+
+        let current = current_request(&request, &transferred, event);
+        match tier::announce(&mut tiers, &client, &current).await {
+            Ok((response, peer_id)) => {
+                event = AnnounceEvent::None;
+                if let Some(warning) = &response.warning {
+                    println!("Tracker warning: {}", warning);
+                }
+                schedule.announced(Instant::now(), response.interval, response.min_interval);
+                let announced = AnnouncedPeers {
+                    info_hash: request.info_hash.clone(),
+                    peer_id,
+                    peers: response.peers,
+                };
+                // The download may be stopping meanwhile
+                let _ = peers_tx.send(announced);
+            }
+            Err(err) => {
+                schedule.failed(Instant::now());
+                println!("{:?}", err);
+            }
+        }
+    };
+
+    if event == AnnounceEvent::Started {
+        return;
+    }
+    let mut last_events = vec![AnnounceEvent::Stopped];
+    if completed {
+        last_events.insert(0, AnnounceEvent::Completed);
+    }
+    for event in last_events {
+        let current = current_request(&request, &transferred, event);
+        if let Err(err) = tier::announce(&mut tiers, &client, &current).await {
+            println!("{:?}", err);
+        }
+    }
+}
+
+struct TrackerSchedule {
+    min_interval: Duration,
+    failures: u32,
+    last_announce: Option<Instant>,
+    next_announce: Instant,
+}
+
+impl TrackerSchedule {
+    fn new(now: Instant) -> TrackerSchedule {
+        TrackerSchedule {
+            min_interval: DEFAULT_MIN_INTERVAL,
+            failures: 0,
+            last_announce: None,
+            next_announce: now,
+        }
+    }
+
+    fn announced(&mut self, now: Instant, interval: u64, min_interval: Option<u64>) {
+        let interval = Duration::from_secs(interval).clamp(SHORTEST_INTERVAL, LONGEST_INTERVAL);
+        self.min_interval = min_interval
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_MIN_INTERVAL)
+            .clamp(SHORTEST_INTERVAL, interval);
+        self.failures = 0;
+        self.last_announce = Some(now);
+        self.next_announce = now + interval;
+    }
+
+    fn failed(&mut self, now: Instant) {
+        self.failures += 1;
+        self.next_announce = now + backoff(self.failures);
+    }
+
+    fn earliest_reannounce(&self) -> Instant {
+        match self.last_announce {
+            Some(last_announce) if self.failures == 0 => {
+                (last_announce + self.min_interval).min(self.next_announce)
+            }
+            _ => self.next_announce,
+        }
+    }
+}
+
+fn backoff(failures: u32) -> Duration {
+    let exponent = failures.saturating_sub(1).min(16);
+    INITIAL_BACKOFF
+        .checked_mul(1 << exponent)
+        .unwrap_or(MAX_BACKOFF)
+        .min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Mutex;
+
+    // An HTTP tracker with no peers, keeping the query of every announce
+    fn http_tracker(queries: Arc<Mutex<Vec<String>>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || loop {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let len = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..len]);
+            }
+            let request = String::from_utf8_lossy(&request).to_string();
+            let path = request.split(' ').nth(1).unwrap();
+            queries.lock().unwrap().push(path.to_string());
+            let body = b"d8:intervali1800e5:peers0:e";
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            stream.write_all(head.as_bytes()).unwrap();
+            stream.write_all(body).unwrap();
+        });
+        format!("http://{}/announce", addr)
+    }
+
+    fn torrent_data(announce: String) -> TorrentData {
+        TorrentData {
+            announce,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn announces_carry_counters_and_events() {
+        let queries = Arc::new(Mutex::new(Vec::new()));
+        let torrent_data = torrent_data(http_tracker(Arc::clone(&queries)));
+        let request = AnnounceRequest::new(&torrent_data, vec![1; 20], vec![2; 20], 1);
+        let transferred = Arc::new(Transferred::default());
+        transferred.left.store(300, Ordering::Relaxed);
+        let client = Arc::new(TrackerClient::new(&Default::default()).await.unwrap());
+        let mut scheduler = AnnounceScheduler::start(
+            client,
+            &torrent_data,
+            vec![request],
+            Arc::clone(&transferred),
+        );
+
+        scheduler.next_peers().await.unwrap();
+        transferred.downloaded.store(300, Ordering::Relaxed);
+        transferred.uploaded.store(100, Ordering::Relaxed);
+        transferred.left.store(0, Ordering::Relaxed);
+        scheduler.stop(true).await;
+
+        let queries = queries.lock().unwrap();
+        assert_eq!(queries.len(), 3);
+        let has = |query: &str, parameters: &[&str]| {
+            parameters
+                .iter()
+                .all(|parameter| query.contains(&format!("&{}", parameter)))
+        };
+        assert!(has(
+            &queries[0],
+            &["downloaded=0", "event=started", "left=300", "uploaded=0"]
+        ));
+        assert!(has(
+            &queries[1],
+            &[
+                "downloaded=300",
+                "event=completed",
+                "left=0",
+                "uploaded=100"
+            ]
+        ));
+        assert!(has(
+            &queries[2],
+            &["downloaded=300", "event=stopped", "left=0", "uploaded=100"]
+        ));
+    }
+
+    #[tokio::test]
+    async fn unfinished_downloads_only_stop() {
+        let queries = Arc::new(Mutex::new(Vec::new()));
+        let torrent_data = torrent_data(http_tracker(Arc::clone(&queries)));
+        let request = AnnounceRequest::new(&torrent_data, vec![1; 20], vec![2; 20], 1);
+        let client = Arc::new(TrackerClient::new(&Default::default()).await.unwrap());
+        let mut scheduler =
+            AnnounceScheduler::start(client, &torrent_data, vec![request], Default::default());
+
+        scheduler.next_peers().await.unwrap();
+        scheduler.stop(false).await;
+
+        let queries = queries.lock().unwrap();
+        assert_eq!(queries.len(), 2);
+        assert!(queries[1].contains("&event=stopped"));
+    }
+
+    #[test]
+    fn first_announce_is_immediate() {
+        let now = Instant::now();
+        let schedule = TrackerSchedule::new(now);
+        assert_eq!(schedule.next_announce, now);
+        assert_eq!(schedule.earliest_reannounce(), now);
+    }
+
+    #[test]
+    fn reannounce_honours_interval_and_min_interval() {
+        let now = Instant::now();
+        let mut schedule = TrackerSchedule::new(now);
+        schedule.announced(now, 1800, Some(300));
+        assert_eq!(schedule.next_announce, now + Duration::from_secs(1800));
+        assert_eq!(
+            schedule.earliest_reannounce(),
+            now + Duration::from_secs(300)
+        );
+    }
+
+    #[test]
+    fn min_interval_never_exceeds_interval() {
+        let now = Instant::now();
+        let mut schedule = TrackerSchedule::new(now);
+        schedule.announced(now, 90, Some(600));
+        assert_eq!(
+            schedule.earliest_reannounce(),
+            now + Duration::from_secs(90)
+        );
+    }
+
+    #[test]
+    fn intervals_are_kept_in_bounds() {
+        let now = Instant::now();
+        let mut schedule = TrackerSchedule::new(now);
+        schedule.announced(now, 0, Some(0));
+        assert_eq!(schedule.next_announce, now + SHORTEST_INTERVAL);
+        assert_eq!(schedule.earliest_reannounce(), now + SHORTEST_INTERVAL);
+
+        schedule.announced(now, u64::MAX, Some(u64::MAX));
+        assert_eq!(schedule.next_announce, now + LONGEST_INTERVAL);
+        assert_eq!(schedule.min_interval, LONGEST_INTERVAL);
+    }
+
+    #[test]
+    fn failures_back_off_exponentially() {
+        let now = Instant::now();
+        let mut schedule = TrackerSchedule::new(now);
+        schedule.failed(now);
+        assert_eq!(schedule.next_announce, now + Duration::from_secs(15));
+        schedule.failed(now);
+        assert_eq!(schedule.next_announce, now + Duration::from_secs(30));
+        schedule.failed(now);
+        assert_eq!(schedule.next_announce, now + Duration::from_secs(60));
+        assert_eq!(schedule.earliest_reannounce(), schedule.next_announce);
+
+        for _ in 0..20 {
+            schedule.failed(now);
+        }
+        assert_eq!(schedule.next_announce, now + MAX_BACKOFF);
+
+        schedule.announced(now, 900, None);
+        assert_eq!(schedule.failures, 0);
+    }
+}
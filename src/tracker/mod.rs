@@ -1,72 +1,297 @@
+use crate::proxy::{ProxyKind, ProxySettings};
 use crate::torrent_file_handler::torrent_data_extractor::TorrentData;
 use futures::future::join_all;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::AtomicU64;
 use url::Url;
 
+const DEFAULT_NUM_WANT: u32 = 50;
+
+pub mod announce_scheduler;
 mod tcp_connection;
+mod tier;
 mod udp_connection;
 
-pub async fn request_peers(
-    torrent_data: &TorrentData,
-    peer_id: &Vec<u8>,
-    port: u16,
-    info_hash: &Vec<u8>,
-) -> anyhow::Result<(Vec<String>, i64)> {
-    let mut announce_list = Vec::new();
+// Numbered as in UDP announces (BEP 15)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    None = 0,
+    Completed = 1,
+    Started = 2,
+    Stopped = 3,
+}
 
-    match &torrent_data.announce_list {
-        Some(content) => {
-            announce_list = content.to_vec();
-        }
-        None => {
-            announce_list.push(torrent_data.announce.clone());
+impl AnnounceEvent {
+    // The `event` parameter of HTTP announces, left out for regular ones
+    pub fn name(self) -> Option<&'static str> {
+        match self {
+            AnnounceEvent::None => None,
+            AnnounceEvent::Completed => Some("completed"),
+            AnnounceEvent::Started => Some("started"),
+            AnnounceEvent::Stopped => Some("stopped"),
         }
     }
+}
+
+// What a torrent has transferred since it was started and what it still needs, kept up to date
+// by the download and read at every announce
+#[derive(Debug, Default)]
+pub struct Transferred {
+    pub uploaded: AtomicU64,
+    pub downloaded: AtomicU64,
+    pub left: AtomicU64,
+}
 
-    Ok(make_requests(announce_list, torrent_data, peer_id, port, info_hash).await?)
+#[derive(Clone)]
+pub struct AnnounceRequest {
+    pub info_hash: Vec<u8>,
+    pub peer_id: Vec<u8>,
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: AnnounceEvent,
+    pub key: u32,
+    pub num_want: Option<u32>,
+    // BEP 7: our addresses of the other family, as a tracker only sees the one we connect from
+    pub ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<Ipv6Addr>,
 }
 
-async fn make_requests(
-    announce_list: Vec<String>,
-    torrent_data: &TorrentData,
-    peer_id: &Vec<u8>,
-    port: u16,
-    info_hash: &Vec<u8>,
-) -> anyhow::Result<(Vec<String>, i64)> {
-    let mut peers = Vec::new();
-    let mut interval = 0;
-
-    let trackers_responce = join_all(
-        announce_list
-            .iter()
-            .map(|tracker| make_request(tracker, torrent_data, peer_id, port, info_hash)),
-    )
-    .await
-    .into_iter()
-    .collect::<Result<Vec<_>, _>>()?;
-
-    for (peers_in_responce, interval_result) in trackers_responce {
-        for peer in peers_in_responce {
-            peers.push(peer);
+impl AnnounceRequest {
+    pub fn new(
+        torrent_data: &TorrentData,
+        info_hash: Vec<u8>,
+        peer_id: Vec<u8>,
+        port: u16,
+    ) -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash,
+            peer_id,
+            port,
+            uploaded: 0,
+            downloaded: 0,
+            left: torrent_data.files.iter().map(|file| file.size as u64).sum(),
+            event: AnnounceEvent::None,
+            key: rand::random(),
+            num_want: Some(DEFAULT_NUM_WANT),
+            ipv4: match public_address("8.8.8.8:53") {
+                Some(IpAddr::V4(ip)) => Some(ip),
+                _ => None,
+            },
+            ipv6: match public_address("[2001:4860:4860::8888]:53") {
+                Some(IpAddr::V6(ip)) => Some(ip),
+                _ => None,
+            },
         }
-        interval = interval_result;
     }
-    // println!("{:?}", peers);
-    Ok((peers, interval))
 }
 
-async fn make_request(
-    tracker: &String,
-    torrent_data: &TorrentData,
-    peer_id: &Vec<u8>,
-    port: u16,
-    info_hash: &Vec<u8>,
-) -> anyhow::Result<(Vec<String>, i64)> {
-    // println!("{}", tracker);
-    let url = Url::parse(&tracker)?;
-    let is_udp = url.scheme() == "udp";
-    if is_udp {
-        udp_connection::make_udp_request(url, torrent_data, peer_id, port, info_hash).await
+// The address the system would use to reach `target`, if it's publicly routable.
+// Connecting a UDP socket doesn't send anything.
+fn public_address(target: &str) -> Option<IpAddr> {
+    let unspecified = if target.starts_with('[') {
+        "[::]:0"
+    } else {
+        "0.0.0.0:0"
+    };
+    let socket = UdpSocket::bind(unspecified).ok()?;
+    socket.connect(target).ok()?;
+    let ip = socket.local_addr().ok()?.ip();
+
+    let is_public = match ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified())
+        }
+        IpAddr::V6(ip) => {
+            let first_segment = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || first_segment & 0xffc0 == 0xfe80 // link-local
+                || first_segment & 0xfe00 == 0xfc00) // unique local
+        }
+    };
+    if is_public {
+        Some(ip)
     } else {
-        tcp_connection::make_tcp_request(tracker, torrent_data, peer_id, port, info_hash).await
+        None
+    }
+}
+
+// Compact peer lists are 4 (IPv4) or 16 (IPv6) bytes of address followed by 2 bytes of port
+pub fn parse_compact_peers(peers: &[u8], ipv6: bool) -> anyhow::Result<Vec<SocketAddr>> {
+    let entry_len = if ipv6 { 18 } else { 6 };
+    anyhow::ensure!(
+        peers.len().is_multiple_of(entry_len),
+        "Corrupted peers data"
+    );
+
+    Ok(peers
+        .chunks_exact(entry_len)
+        .map(|peer| {
+            let port = u16::from_be_bytes([peer[entry_len - 2], peer[entry_len - 1]]);
+            let ip = if ipv6 {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&peer[..16]);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            } else {
+                IpAddr::V4(Ipv4Addr::new(peer[0], peer[1], peer[2], peer[3]))
+            };
+            SocketAddr::new(ip, port)
+        })
+        .collect())
+}
+
+pub struct AnnounceResponse {
+    pub peers: Vec<SocketAddr>,
+    pub interval: u64,
+    pub min_interval: Option<u64>,
+    pub seeders: Option<u32>,
+    pub leechers: Option<u32>,
+    pub warning: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScrapeStats {
+    pub info_hash: Vec<u8>,
+    pub complete: u32,
+    pub incomplete: u32,
+    pub downloaded: u32,
+}
+
+// Every tracker of the torrent, tier by tier
+pub fn tracker_urls(torrent_data: &TorrentData) -> Vec<String> {
+    match &torrent_data.announce_list {
+        Some(tiers) if !tiers.is_empty() => tiers.concat(),
+        _ if torrent_data.announce.is_empty() => Vec::new(),
+        _ => vec![torrent_data.announce.clone()],
+    }
+}
+
+// Shared by every torrent, so all UDP trackers are contacted through a single socket
+// and HTTP connections to trackers are reused. A missing client is for trackers that can't be
+// reached through the proxy while direct connections are refused.
+pub struct TrackerClient {
+    udp: Option<udp_connection::UdpTrackerClient>,
+    http: Option<tcp_connection::HttpTrackerClient>,
+}
+
+impl TrackerClient {
+    pub async fn new(proxies: &ProxySettings) -> anyhow::Result<TrackerClient> {
+        let udp = match &proxies.trackers {
+            Some(proxy) if proxy.kind == ProxyKind::Socks5 => {
+                Some(udp_connection::UdpTrackerClient::bind_through(proxy).await?)
+            }
+            _ if proxies.force => None,
+            _ => Some(udp_connection::UdpTrackerClient::bind().await?),
+        };
+        let http = if proxies.trackers.is_none() && proxies.force {
+            None
+        } else {
+            Some(tcp_connection::HttpTrackerClient::new(
+                proxies.trackers.as_ref(),
+            )?)
+        };
+        Ok(TrackerClient { udp, http })
+    }
+
+    fn udp(&self) -> anyhow::Result<&udp_connection::UdpTrackerClient> {
+        self.udp.as_ref().ok_or(anyhow::anyhow!(
+            "UDP trackers can't be reached through the proxy"
+        ))
+    }
+
+    fn http(&self) -> anyhow::Result<&tcp_connection::HttpTrackerClient> {
+        self.http
+            .as_ref()
+            .ok_or(anyhow::anyhow!("Trackers can't be reached without a proxy"))
+    }
+
+    // With `failover` a silent UDP tracker is given up on sooner, as other trackers are waiting
+    pub async fn announce(
+        &self,
+        tracker: &str,
+        request: &AnnounceRequest,
+        failover: bool,
+    ) -> anyhow::Result<AnnounceResponse> {
+        let url = Url::parse(tracker)?;
+        let is_udp = url.scheme() == "udp";
+        if is_udp {
+            let retransmissions = if failover {
+                udp_connection::FAILOVER_RETRANSMISSIONS
+            } else {
+                udp_connection::MAX_RETRANSMISSIONS
+            };
+            self.udp()?.announce(&url, request, retransmissions).await
+        } else {
+            self.http()?.announce(tracker, request).await
+        }
+    }
+
+    // Trackers leave out torrents they don't know, so the result may be shorter than `info_hashes`.
+    // Someone is waiting on a scrape, so silent UDP trackers get the short schedule.
+    pub async fn scrape(
+        &self,
+        tracker: &str,
+        info_hashes: &[Vec<u8>],
+    ) -> anyhow::Result<Vec<ScrapeStats>> {
+        let url = Url::parse(tracker)?;
+        let is_udp = url.scheme() == "udp";
+        if is_udp {
+            self.udp()?
+                .scrape(&url, info_hashes, udp_connection::FAILOVER_RETRANSMISSIONS)
+                .await
+        } else {
+            self.http()?.scrape(tracker, info_hashes).await
+        }
+    }
+
+    // Asks every tracker of the torrent for the state of its swarm
+    pub async fn scrape_torrent(
+        &self,
+        torrent_data: &TorrentData,
+        info_hash: &[u8],
+    ) -> Vec<(String, anyhow::Result<Option<ScrapeStats>>)> {
+        let trackers = tracker_urls(torrent_data);
+        let info_hashes = vec![info_hash.to_vec()];
+
+        let responses = join_all(
+            trackers
+                .iter()
+                .map(|tracker| self.scrape(tracker, &info_hashes)),
+        )
+        .await;
+
+        trackers
+            .into_iter()
+            .zip(responses)
+            .map(|(tracker, response)| (tracker, response.map(|stats| stats.into_iter().next())))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn compact_peers() {
+        let peers =
+            super::parse_compact_peers(&[127, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0, 80], false)
+                .unwrap();
+        assert_eq!(
+            peers,
+            [
+                "127.0.0.1:6881".parse().unwrap(),
+                "10.0.0.2:80".parse().unwrap()
+            ]
+        );
+
+        let mut peers6 = vec![0; 15];
+        peers6.extend_from_slice(&[1, 0x1a, 0xe1]);
+        assert_eq!(
+            super::parse_compact_peers(&peers6, true).unwrap(),
+            ["[::1]:6881".parse().unwrap()]
+        );
+
+        assert!(super::parse_compact_peers(&peers6[..17], true).is_err());
     }
 }
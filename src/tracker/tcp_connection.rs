@@ -1,116 +1,313 @@
-use crate::torrent_file_handler::torrent_data_extractor::TorrentData;
+use super::{parse_compact_peers, AnnounceRequest, AnnounceResponse, ScrapeStats};
+use crate::proxy::{self, Proxy};
+use crate::torrent_file_handler::bencode_content::Content;
 use crate::torrent_file_handler::torrent_file_parser::parse_byte_data;
-use curl::easy::Easy;
-
-pub async fn make_tcp_request(
-    tracker: &String,
-    torrent_data: &TorrentData,
-    peer_id: &Vec<u8>,
-    port: u16,
-    info_hash: &Vec<u8>,
-) -> anyhow::Result<(Vec<String>, i64)> {
-    let mut data = Vec::new();
-    let mut peers_list: Vec<String> = Vec::new();
-
-    let url = create_tcp_tracker_url(tracker, torrent_data, peer_id, port, info_hash);
-    let mut tracker = Easy::new();
-    tracker.url(&url)?;
-    tracker.timeout(std::time::Duration::from_millis(20000))?;
-    {
-        let mut transfer = tracker.transfer();
-        transfer.write_function(|new_data| {
-            data.extend_from_slice(new_data);
-            Ok(new_data.len())
-        })?;
-        transfer.perform()?;
-    }
-    let response_data = parse_byte_data(&data)?;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/*
+ *   Specs can be found here: https://www.bittorrent.org/beps/bep_0003.html#trackers
+ *   and here (compact peer lists): https://www.bittorrent.org/beps/bep_0023.html
+ *   and here (IPv6 peers): https://www.bittorrent.org/beps/bep_0007.html
+ */
+
+const TRACKER_TIMEOUT: Duration = Duration::from_secs(20);
+const MAX_REDIRECTS: usize = 5;
+
+pub struct HttpTrackerClient {
+    client: proxy::HttpClient,
+    // 'tracker id' values to echo back, by tracker url and info hash
+    tracker_ids: Mutex<HashMap<(String, Vec<u8>), String>>,
+}
+
+impl HttpTrackerClient {
+    pub fn new(proxy: Option<&Proxy>) -> anyhow::Result<HttpTrackerClient> {
+        let builder = reqwest::Client::builder()
+            .timeout(TRACKER_TIMEOUT)
+            .redirect(reqwest::redirect::Policy::limited(MAX_REDIRECTS))
+            .gzip(true);
+        let client = proxy::http_client(builder, proxy)?;
+
+        Ok(HttpTrackerClient {
+            client,
+            tracker_ids: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub async fn announce(
+        &self,
+        tracker: &str,
+        request: &AnnounceRequest,
+    ) -> anyhow::Result<AnnounceResponse> {
+        let tracker_id_key = (tracker.to_string(), request.info_hash.clone());
+        let tracker_id = self
+            .tracker_ids
+            .lock()
+            .unwrap()
+            .get(&tracker_id_key)
+            .cloned();
+
+        let url = create_tcp_tracker_url(tracker, request, tracker_id.as_deref());
+        let response_data = self.get(&url).await?;
+        anyhow::ensure!(
+            !response_data.contains_key(&b"failure reason"[..]),
+            "Announce failure response: {:?}",
+            response_data.get(&b"failure reason"[..]).unwrap()
+        );
+
+        let response = parse_announce_response(&response_data)?;
+        if let Some(tracker_id) = get_optional_str(&response_data, "tracker id")? {
+            self.tracker_ids
+                .lock()
+                .unwrap()
+                .insert(tracker_id_key, tracker_id);
+        }
+        Ok(response)
+    }
+
+    pub async fn scrape(
+        &self,
+        tracker: &str,
+        info_hashes: &[Vec<u8>],
+    ) -> anyhow::Result<Vec<ScrapeStats>> {
+        let url = create_tcp_scrape_url(tracker, info_hashes)?;
+        let response_data = self.get(&url).await?;
+        anyhow::ensure!(
+            !response_data.contains_key(&b"failure reason"[..]),
+            "Scrape failure response: {:?}",
+            response_data.get(&b"failure reason"[..]).unwrap()
+        );
+
+        let files = response_data
+            .get(&b"files"[..])
+            .ok_or(anyhow::anyhow!("No 'files' field in responce"))?
+            .get_dict()
+            .ok_or(anyhow::anyhow!("Couldn't get dictionary"))?;
+
+        let mut stats = Vec::new();
+        for info_hash in info_hashes {
+            let file = match files.get(info_hash) {
+                Some(file) => file
+                    .get_dict()
+                    .ok_or(anyhow::anyhow!("Couldn't get dictionary"))?,
+                None => continue, // tracker doesn't know this torrent
+            };
+            stats.push(ScrapeStats {
+                info_hash: info_hash.clone(),
+                complete: get_optional_int(file, "complete")?.unwrap_or(0) as u32,
+                incomplete: get_optional_int(file, "incomplete")?.unwrap_or(0) as u32,
+                downloaded: get_optional_int(file, "downloaded")?.unwrap_or(0) as u32,
+            });
+        }
+
+        Ok(stats)
+    }
+
+    async fn get(&self, url: &str) -> anyhow::Result<HashMap<Vec<u8>, Content>> {
+        let response = self.client.get(url).send().await?.error_for_status()?;
+        let data = response.bytes().await?;
+        Ok(parse_byte_data(&data)?.0)
+    }
+}
+
+fn parse_announce_response(
+    response_data: &HashMap<Vec<u8>, Content>,
+) -> anyhow::Result<AnnounceResponse> {
     anyhow::ensure!(
-        response_data.get("failure reason").is_none(),
-        "Announce failure response: {:?}",
-        response_data.get("failure reason").unwrap()
+        response_data.contains_key(&b"peers"[..]) || response_data.contains_key(&b"peers6"[..]),
+        "No 'peers' field in responce"
     );
-
-    let peers = response_data
-        .get("peers")
-        .ok_or(anyhow::anyhow!("No 'peers' field in responce"))?
-        .get_bytes()
-        .ok_or(anyhow::anyhow!("Couldn't get bytes"))?;
+    let mut peers = match response_data.get(&b"peers"[..]) {
+        Some(peers) => parse_peers(peers)?,
+        None => Vec::new(),
+    };
+    if let Some(peers6) = response_data.get(&b"peers6"[..]) {
+        peers.extend(parse_compact_peers(
+            peers6
+                .get_bytes()
+                .ok_or(anyhow::anyhow!("Couldn't get bytes"))?,
+            true,
+        )?);
+    }
 
     let interval = *response_data
-        .get("interval")
+        .get(&b"interval"[..])
         .ok_or(anyhow::anyhow!("No 'interval' field in responce"))?
         .get_int()
         .ok_or(anyhow::anyhow!("Couldn't get int"))?;
 
-    anyhow::ensure!(peers.len() % 6 == 0, "Corrupted peers data");
+    let interval =
+        u64::try_from(interval).map_err(|_| anyhow::anyhow!("Wrong interval {}", interval))?;
+    let min_interval = get_optional_int(response_data, "min interval")?
+        .map(|int| u64::try_from(int).map_err(|_| anyhow::anyhow!("Wrong min interval {}", int)))
+        .transpose()?;
+    let seeders = get_optional_int(response_data, "complete")?.map(|int| int as u32);
+    let leechers = get_optional_int(response_data, "incomplete")?.map(|int| int as u32);
+    let warning = get_optional_str(response_data, "warning message")?;
 
-    let mut ip = String::new();
-    let mut port: u16 = 0;
+    Ok(AnnounceResponse {
+        peers,
+        interval,
+        min_interval,
+        seeders,
+        leechers,
+        warning,
+    })
+}
 
-    for (index, number) in peers.iter().enumerate() {
-        match index % 6 {
-            0 => {
-                ip = String::new();
-                port = 0;
-                ip.push_str(&*number.to_string());
-                ip.push('.');
-            }
-            3 => {
-                ip.push_str(&*number.to_string());
-                ip.push(':');
-            }
-            4 => {
-                port += (*number as u16) * 256;
-            }
-            5 => {
-                port += *number as u16;
-                ip.push_str(&port.to_string());
-                peers_list.push(ip.clone());
-            }
-            _ => {
-                ip.push_str(&*number.to_string());
-                ip.push('.');
+// Peers come either as a compact string of 6-byte entries or as a list of dictionaries
+fn parse_peers(peers: &Content) -> anyhow::Result<Vec<SocketAddr>> {
+    let mut peers_list: Vec<SocketAddr> = Vec::new();
+
+    match peers {
+        Content::Bytes(peers) => {
+            peers_list = parse_compact_peers(peers, false)?;
+        }
+        Content::List(peers) => {
+            for peer in peers {
+                let peer = peer
+                    .get_dict()
+                    .ok_or(anyhow::anyhow!("Couldn't get dictionary"))?;
+                let ip = get_optional_str(peer, "ip")?
+                    .ok_or(anyhow::anyhow!("No 'ip' field in peer"))?;
+                let port = get_optional_int(peer, "port")?
+                    .ok_or(anyhow::anyhow!("No 'port' field in peer"))?;
+                // peers given by DNS name or with a port out of range are skipped
+                if let (Ok(ip), Ok(port)) = (ip.parse::<IpAddr>(), u16::try_from(port)) {
+                    peers_list.push(SocketAddr::new(ip, port));
+                }
             }
         }
+        _ => anyhow::bail!("Couldn't get peers"),
     }
 
-    Ok((peers_list, interval))
+    Ok(peers_list)
+}
+
+fn get_optional_int(dict: &HashMap<Vec<u8>, Content>, key: &str) -> anyhow::Result<Option<i64>> {
+    match dict.get(key.as_bytes()) {
+        Some(content) => Ok(Some(
+            *content
+                .get_int()
+                .ok_or(anyhow::anyhow!("Couldn't get int"))?,
+        )),
+        None => Ok(None),
+    }
+}
+
+fn get_optional_str(dict: &HashMap<Vec<u8>, Content>, key: &str) -> anyhow::Result<Option<String>> {
+    match dict.get(key.as_bytes()) {
+        Some(content) => Ok(Some(
+            content
+                .get_str()
+                .ok_or(anyhow::anyhow!("Couldn't get str"))?
+                .clone(),
+        )),
+        None => Ok(None),
+    }
 }
 
 fn create_tcp_tracker_url(
-    tracker: &String,
-    torrent_data: &TorrentData,
-    peer_id: &Vec<u8>,
-    port: u16,
-    info_hash: &Vec<u8>,
+    tracker: &str,
+    request: &AnnounceRequest,
+    tracker_id: Option<&str>,
 ) -> String {
     let mut url = String::new();
 
     url.push_str(tracker);
-    url.push_str("?compact=1&downloaded=0&info_hash=");
+    if tracker.contains('?') {
+        url.push('&');
+    } else {
+        url.push('?');
+    }
+    url.push_str("compact=1&downloaded=");
+    url.push_str(&request.downloaded.to_string());
+
+    if let Some(event) = request.event.name() {
+        url.push_str("&event=");
+        url.push_str(event);
+    }
 
-    url.push_str(&bytes_to_url(info_hash));
+    url.push_str("&info_hash=");
+    url.push_str(&bytes_to_url(&request.info_hash));
+
+    if let Some(ipv4) = request.ipv4 {
+        url.push_str("&ipv4=");
+        url.push_str(&ipv4.to_string());
+    }
+
+    if let Some(ipv6) = request.ipv6 {
+        url.push_str("&ipv6=");
+        url.push_str(&ipv6.to_string().replace(':', "%3A"));
+    }
+
+    url.push_str("&key=");
+    url.push_str(&format!("{:08X}", request.key));
 
     url.push_str("&left=");
-    let mut length: usize = 0;
-    for file in &torrent_data.files {
-        length += file.size;
+    url.push_str(&request.left.to_string());
+
+    if let Some(num_want) = request.num_want {
+        url.push_str("&numwant=");
+        url.push_str(&num_want.to_string());
     }
-    url.push_str(&length.to_string());
 
     url.push_str("&peer_id=");
-    url.push_str(&bytes_to_url(peer_id));
+    url.push_str(&bytes_to_url(&request.peer_id));
 
     url.push_str("&port=");
-    url.push_str(&(port as i32).to_string());
+    url.push_str(&request.port.to_string());
+
+    if let Some(tracker_id) = tracker_id {
+        url.push_str("&trackerid=");
+        url.push_str(&bytes_to_url(tracker_id.as_bytes()));
+    }
 
-    url.push_str("&uploaded=0");
+    url.push_str("&uploaded=");
+    url.push_str(&request.uploaded.to_string());
 
     url
 }
 
-fn bytes_to_url(bytes: &Vec<u8>) -> String {
+// By convention the scrape URL is the announce URL with the last 'announce' path segment
+// replaced by 'scrape'. Trackers which don't follow it don't support scraping.
+fn create_tcp_scrape_url(tracker: &str, info_hashes: &[Vec<u8>]) -> anyhow::Result<String> {
+    let (path, query) = match tracker.find('?') {
+        Some(index) => (&tracker[..index], Some(&tracker[index + 1..])),
+        None => (tracker, None),
+    };
+    let last_segment_start = path.rfind('/').map(|index| index + 1).unwrap_or(0);
+    anyhow::ensure!(
+        path[last_segment_start..].starts_with("announce"),
+        "Tracker {} doesn't support scrape",
+        tracker
+    );
+
+    let mut url = String::new();
+    url.push_str(&path[..last_segment_start]);
+    url.push_str("scrape");
+    url.push_str(&path[last_segment_start + "announce".len()..]);
+
+    let mut separator = '?';
+    if let Some(query) = query {
+        url.push('?');
+        url.push_str(query);
+        separator = '&';
+    }
+    for info_hash in info_hashes {
+        url.push(separator);
+        url.push_str("info_hash=");
+        url.push_str(&bytes_to_url(info_hash));
+        separator = '&';
+    }
+
+    Ok(url)
+}
+
+fn bytes_to_url(bytes: &[u8]) -> String {
     let mut url = String::new();
     for number in bytes {
         url.push('%');
@@ -124,14 +321,157 @@ fn u8_decimal_to_hex(decimal: &u8) -> String {
     let first_digit = decimal / 16;
     let second_digit = decimal % 16;
     if first_digit > 9 {
-        hex.push(('A' as u8 + first_digit - 10) as char);
+        hex.push((b'A' + first_digit - 10) as char);
     } else {
-        hex.push(('0' as u8 + first_digit) as char);
+        hex.push((b'0' + first_digit) as char);
     }
     if second_digit > 9 {
-        hex.push(('A' as u8 + second_digit - 10) as char);
+        hex.push((b'A' + second_digit - 10) as char);
     } else {
-        hex.push(('0' as u8 + second_digit) as char);
+        hex.push((b'0' + second_digit) as char);
     }
     hex
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracker::AnnounceEvent;
+
+    fn announce_request() -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: vec![0xAB; 20],
+            peer_id: vec![0x01; 20],
+            port: 6881,
+            uploaded: 0,
+            downloaded: 10,
+            left: 100,
+            event: AnnounceEvent::Started,
+            key: 0xDEADBEEF,
+            num_want: Some(50),
+            ipv4: None,
+            ipv6: Some("2001:db8::1".parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn announce_url_contains_every_parameter() {
+        let url = create_tcp_tracker_url(
+            "http://example.com/announce?passkey=1",
+            &announce_request(),
+            Some("id 1"),
+        );
+        assert_eq!(
+            url,
+            format!(
+                "http://example.com/announce?passkey=1&compact=1&downloaded=10&event=started&info_hash={}\
+                 &ipv6=2001%3Adb8%3A%3A1&key=DEADBEEF&left=100&numwant=50&peer_id={}&port=6881&trackerid=%69%64%20%31\
+                 &uploaded=0",
+                "%AB".repeat(20),
+                "%01".repeat(20)
+            )
+        );
+    }
+
+    #[test]
+    fn announce_response_with_compact_peers() {
+        let response = parse_byte_data(
+            b"d8:completei5e10:incompletei3e8:intervali1800e12:min intervali60e\
+              5:peers12:\x7f\x00\x00\x01\x1a\xe1\x0a\x00\x00\x02\x00\x50\
+              15:warning message6:bewaree",
+        )
+        .unwrap()
+        .0;
+        let response = parse_announce_response(&response).unwrap();
+        assert_eq!(
+            response.peers,
+            [
+                "127.0.0.1:6881".parse().unwrap(),
+                "10.0.0.2:80".parse().unwrap()
+            ]
+        );
+        assert_eq!(response.interval, 1800);
+        assert_eq!(response.min_interval, Some(60));
+        assert_eq!(response.seeders, Some(5));
+        assert_eq!(response.leechers, Some(3));
+        assert_eq!(response.warning.as_deref(), Some("beware"));
+    }
+
+    #[test]
+    fn announce_response_with_ipv6_peers() {
+        let mut data = b"d8:intervali900e5:peers0:6:peers618:".to_vec();
+        data.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8]);
+        data.extend_from_slice(&[0; 11]);
+        data.extend_from_slice(&[1, 0x1a, 0xe1]);
+        data.push(b'e');
+
+        let response = parse_announce_response(&parse_byte_data(&data).unwrap().0).unwrap();
+        assert_eq!(response.peers, ["[2001:db8::1]:6881".parse().unwrap()]);
+    }
+
+    #[test]
+    fn announce_response_with_dictionary_peers() {
+        let mut data = b"d8:intervali900e5:peersld2:ip8:10.0.0.17:peer id20:".to_vec();
+        data.extend_from_slice(&[0xFF; 20]);
+        data.extend_from_slice(
+            b"4:porti6881eed2:ip3:::14:porti80eed2:ip8:10.0.0.24:porti70000eeee",
+        );
+
+        let response = parse_announce_response(&parse_byte_data(&data).unwrap().0).unwrap();
+        assert_eq!(
+            response.peers,
+            [
+                "10.0.0.1:6881".parse().unwrap(),
+                "[::1]:80".parse().unwrap()
+            ]
+        );
+        assert_eq!(response.min_interval, None);
+        assert_eq!(response.warning, None);
+    }
+
+    #[test]
+    fn negative_intervals_are_refused() {
+        for data in [
+            &b"d8:intervali-1e5:peers0:e"[..],
+            b"d8:intervali900e12:min intervali-60e5:peers0:e",
+        ] {
+            let (response, _) = parse_byte_data(data).unwrap();
+            assert!(parse_announce_response(&response).is_err());
+        }
+    }
+
+    #[test]
+    fn scrape_url_from_announce_url() {
+        let info_hash = vec![0xAB; 20];
+        let encoded = "%AB".repeat(20);
+        assert_eq!(
+            create_tcp_scrape_url(
+                "http://example.com/announce",
+                std::slice::from_ref(&info_hash)
+            )
+            .unwrap(),
+            format!("http://example.com/scrape?info_hash={}", encoded)
+        );
+        assert_eq!(
+            create_tcp_scrape_url("http://example.com/x/announce.php", &[]).unwrap(),
+            "http://example.com/x/scrape.php"
+        );
+        assert_eq!(
+            create_tcp_scrape_url(
+                "http://example.com/announce?passkey=1234",
+                &[info_hash.clone(), info_hash]
+            )
+            .unwrap(),
+            format!(
+                "http://example.com/scrape?passkey=1234&info_hash={}&info_hash={}",
+                encoded, encoded
+            )
+        );
+    }
+
+    #[test]
+    fn scrape_unsupported_without_announce_segment() {
+        assert!(create_tcp_scrape_url("http://example.com/a", &[]).is_err());
+        assert!(create_tcp_scrape_url("http://example.com/announce/x", &[]).is_err());
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use super::{AnnounceRequest, AnnounceResponse, TrackerClient};
+use crate::torrent_file_handler::torrent_data_extractor::TorrentData;
+
+/*
+ *   Specs can be found here: https://www.bittorrent.org/beps/bep_0012.html
+ *
+ *   Trackers inside a tier are shuffled once and then tried in order. The first tracker that
+ *   answers is moved to the front of its tier, so it is the one asked next time. Tiers are tried in
+ *   order too, the next one only once every tracker of the one before failed. Silent UDP trackers
+ *   with others behind them are given up on early, so they can't hold up the rest.
+ *   Private torrents (BEP 27) announce a different peer id to every tracker, so trackers can't
+ *   link our sessions through it.
+ */
+
+pub struct TrackerTier {
+    trackers: Vec<String>,
+    // Only filled for private torrents, otherwise the request's peer id is used
+    peer_ids: HashMap<String, Vec<u8>>,
+}
+
+impl TrackerTier {
+    pub fn new(mut trackers: Vec<String>, private: bool) -> TrackerTier {
+        trackers.shuffle(&mut rand::thread_rng());
+        let mut rng = rand::thread_rng();
+        let peer_ids = if private {
+            trackers
+                .iter()
+                .map(|tracker| (tracker.clone(), (0..20).map(|_| rng.gen::<u8>()).collect()))
+                .collect()
+        } else {
+            HashMap::new()
+        };
+        TrackerTier { trackers, peer_ids }
+    }
+
+    // The peer id announced to the tracker that answered last, peers it returned know us by it
+    pub fn peer_id<'a>(&'a self, request: &'a AnnounceRequest) -> &'a [u8] {
+        self.trackers
+            .first()
+            .and_then(|tracker| self.peer_ids.get(tracker))
+            .unwrap_or(&request.peer_id)
+    }
+
+    // `more_tiers` when tiers after this one are left to try
+    pub async fn announce(
+        &mut self,
+        client: &TrackerClient,
+        request: &AnnounceRequest,
+        more_tiers: bool,
+    ) -> anyhow::Result<AnnounceResponse> {
+        let mut errors = Vec::new();
+
+        for index in 0..self.trackers.len() {
+            let tracker = &self.trackers[index];
+            let failover = index + 1 < self.trackers.len() || more_tiers;
+            let result = match self.peer_ids.get(tracker) {
+                Some(peer_id) => {
+                    let request = AnnounceRequest {
+                        peer_id: peer_id.clone(),
+                        ..request.clone()
+                    };
+                    client.announce(tracker, &request, failover).await
+                }
+                None => client.announce(tracker, request, failover).await,
+            };
+            match result {
+                Ok(response) => {
+                    self.promote(index);
+                    return Ok(response);
+                }
+                Err(err) => errors.push(format!("{}: {}", tracker, err)),
+            }
+        }
+
+        anyhow::bail!("Every tracker in tier failed: {:?}", errors)
+    }
+
+    fn promote(&mut self, index: usize) {
+        let tracker = self.trackers.remove(index);
+        self.trackers.insert(0, tracker);
+    }
+}
+
+// The answer of the first tier where a tracker answered, with the peer id we announced to it
+pub async fn announce(
+    tiers: &mut [TrackerTier],
+    client: &TrackerClient,
+    request: &AnnounceRequest,
+) -> anyhow::Result<(AnnounceResponse, Vec<u8>)> {
+    let mut errors = Vec::new();
+    let tier_count = tiers.len();
+    for (index, tier) in tiers.iter_mut().enumerate() {
+        match tier.announce(client, request, index + 1 < tier_count).await {
+            Ok(response) => return Ok((response, tier.peer_id(request).to_vec())),
+            Err(err) => errors.push(err.to_string()),
+        }
+    }
+    anyhow::bail!("Every tier failed: {:?}", errors)
+}
+
+// If 'announce-list' is present, 'announce' is ignored
+pub fn tracker_tiers(torrent_data: &TorrentData) -> Vec<TrackerTier> {
+    match &torrent_data.announce_list {
+        Some(tiers) if !tiers.is_empty() => tiers
+            .iter()
+            .map(|tier| TrackerTier::new(tier.clone(), torrent_data.private))
+            .collect(),
+        _ if torrent_data.announce.is_empty() => Vec::new(),
+        _ => vec![TrackerTier::new(
+            vec![torrent_data.announce.clone()],
+            torrent_data.private,
+        )],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // An HTTP tracker with no peers, counting the announces it gets
+    fn http_tracker(announces: Arc<AtomicUsize>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || loop {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let len = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..len]);
+            }
+            announces.fetch_add(1, Ordering::SeqCst);
+            let body = b"d8:intervali1800e5:peers0:e";
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            stream.write_all(head.as_bytes()).unwrap();
+            stream.write_all(body).unwrap();
+        });
+        format!("http://{}/announce", addr)
+    }
+
+    fn torrent_data(announce_list: Option<Vec<Vec<String>>>, private: bool) -> TorrentData {
+        TorrentData {
+            pieces: Vec::new(),
+            piece_length: 0,
+            files: Vec::new(),
+            announce: "http://announce.example/announce".to_string(),
+            announce_list,
+            private,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn announce_is_used_without_announce_list() {
+        let tiers = tracker_tiers(&torrent_data(None, false));
+        assert_eq!(tiers.len(), 1);
+        assert_eq!(tiers[0].trackers, ["http://announce.example/announce"]);
+    }
+
+    #[test]
+    fn announce_list_replaces_announce() {
+        let tiers = tracker_tiers(&torrent_data(
+            Some(vec![
+                vec!["udp://a:1".to_string(), "udp://b:2".to_string()],
+                vec!["http://c/announce".to_string()],
+            ]),
+            false,
+        ));
+        assert_eq!(tiers.len(), 2);
+
+        let mut first_tier = tiers[0].trackers.clone();
+        first_tier.sort();
+        assert_eq!(first_tier, ["udp://a:1", "udp://b:2"]);
+        assert_eq!(tiers[1].trackers, ["http://c/announce"]);
+    }
+
+    #[test]
+    fn successful_tracker_is_promoted() {
+        let mut tier = TrackerTier {
+            trackers: vec!["a".to_string(), "b".to_string(), "c".to_string()],
+            peer_ids: HashMap::new(),
+        };
+        tier.promote(2);
+        assert_eq!(tier.trackers, ["c", "a", "b"]);
+        tier.promote(0);
+        assert_eq!(tier.trackers, ["c", "a", "b"]);
+    }
+
+    #[test]
+    fn private_trackers_get_their_own_peer_ids() {
+        let request = AnnounceRequest::new(&TorrentData::default(), vec![1; 20], vec![2; 20], 1);
+        let tiers = tracker_tiers(&torrent_data(None, false));
+        assert_eq!(tiers[0].peer_id(&request), request.peer_id.as_slice());
+
+        let mut tiers = tracker_tiers(&torrent_data(
+            Some(vec![vec!["udp://a:1".to_string(), "udp://b:2".to_string()]]),
+            true,
+        ));
+        let first = tiers[0].peer_id(&request).to_vec();
+        assert_ne!(first, request.peer_id);
+        assert_eq!(first.len(), 20);
+        tiers[0].promote(1);
+        assert_ne!(tiers[0].peer_id(&request), first.as_slice());
+    }
+
+    #[tokio::test]
+    async fn tiers_are_tried_in_order() {
+        let second_tier = Arc::new(AtomicUsize::new(0));
+        let third_tier = Arc::new(AtomicUsize::new(0));
+        // Nothing listens on port 1, so the first tier fails at once
+        let announce_list = vec![
+            vec!["http://127.0.0.1:1/announce".to_string()],
+            vec![http_tracker(Arc::clone(&second_tier))],
+            vec![http_tracker(Arc::clone(&third_tier))],
+        ];
+        let mut tiers = tracker_tiers(&torrent_data(Some(announce_list), false));
+        let request = AnnounceRequest::new(&torrent_data(None, false), vec![1; 20], vec![2; 20], 1);
+        let client = TrackerClient::new(&Default::default()).await.unwrap();
+
+        for _ in 0..2 {
+            let (response, peer_id) = announce(&mut tiers, &client, &request).await.unwrap();
+            assert_eq!(response.interval, 1800);
+            assert_eq!(peer_id, request.peer_id);
+        }
+        assert_eq!(second_tier.load(Ordering::SeqCst), 2);
+        assert_eq!(third_tier.load(Ordering::SeqCst), 0);
+    }
+}
@@ -1,216 +1,648 @@
-use crate::torrent_file_handler::torrent_data_extractor::TorrentData;
-use portpicker::pick_unused_port;
+use super::{parse_compact_peers, AnnounceRequest, AnnounceResponse, ScrapeStats};
+use crate::proxy::{self, Proxy, UdpRelay};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
 use std::convert::TryInto;
-use std::net::UdpSocket;
-use std::time;
-use url::Url;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use url::{Host, Url};
 
 /*
  *   Specs can be found here: https://www.bittorrent.org/beps/bep_0015.html
+ *
+ *   All UDP trackers are contacted through one socket. Responses are matched to requests by
+ *   their transaction id, unanswered requests are retransmitted after 15 * 2 ^ n seconds and
+ *   connection ids are reused while they are valid (one minute). The full schedule is over two
+ *   hours of silence (n up to 8); when other trackers are waiting to be tried a tracker is
+ *   given up on after n = 2, under two minutes.
+ *
+ *   The socket is dual-stack when the system supports IPv6, IPv4 trackers are then addressed
+ *   through IPv4-mapped addresses. Trackers reached over IPv6 return IPv6 peers (BEP 7).
+ *
+ *   Behind a SOCKS5 proxy, every datagram goes through its UDP relay with the tracker's address
+ *   in front. Tracker names are still resolved here, as answers are matched by address.
  */
 
-pub async fn make_udp_request(
-    url: Url,
-    torrent_data: &TorrentData,
-    peer_id: &Vec<u8>,
-    port: u16,
-    info_hash: &Vec<u8>,
-) -> anyhow::Result<(Vec<String>, i64)> {
-    let binding_port = pick_unused_port().ok_or(anyhow::anyhow!("Couldn't pick unused port"))?;
-    let binding_ip = format!("0.0.0.0:{}", binding_port);
-
-    let link = format!(
-        "{}:{}",
-        url.host()
-            .ok_or(anyhow::anyhow!("Couldn't get url hostname"))?,
-        url.port().ok_or(anyhow::anyhow!("Couldn't get url port"))?
-    );
+const RETRANSMISSION_BASE_TIMEOUT: Duration = Duration::from_secs(15);
+pub const MAX_RETRANSMISSIONS: u32 = 8;
+pub const FAILOVER_RETRANSMISSIONS: u32 = 2;
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+const MAX_RESPONSE_SIZE: usize = 4096;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+type PendingRequests = Arc<Mutex<HashMap<u32, (SocketAddr, oneshot::Sender<Vec<u8>>)>>>;
+
+pub struct UdpTrackerClient {
+    socket: Arc<UdpSocket>,
+    dual_stack: bool,
+    pending: PendingRequests,
+    connection_ids: Mutex<HashMap<SocketAddr, (u64, Instant)>>,
+    receiver: JoinHandle<()>,
+    base_timeout: Duration,
+    relay: Option<UdpRelay>,
+}
 
-    let socket = UdpSocket::bind(binding_ip).expect("couldn't bind to address");
-    socket.set_read_timeout(Some(time::Duration::new(20, 0)))?;
-    socket.set_write_timeout(Some(time::Duration::new(20, 0)))?;
+impl UdpTrackerClient {
+    pub async fn bind() -> anyhow::Result<UdpTrackerClient> {
+        UdpTrackerClient::bind_with_timeout(RETRANSMISSION_BASE_TIMEOUT).await
+    }
 
-    socket.connect(link)?;
+    pub async fn bind_through(proxy: &Proxy) -> anyhow::Result<UdpTrackerClient> {
+        let relay = proxy::udp_associate(proxy).await?;
+        let socket = if relay.address.is_ipv4() {
+            UdpSocket::bind("0.0.0.0:0").await?
+        } else {
+            UdpSocket::bind("[::]:0").await?
+        };
+        Ok(UdpTrackerClient::start(
+            socket,
+            false,
+            Some(relay),
+            RETRANSMISSION_BASE_TIMEOUT,
+        ))
+    }
 
-    let (handshake, transaction_id) = create_udp_handshake();
-    socket.send(&handshake).expect("couldn't send message");
-    let mut connect_response: [u8; 16] = [0; 16];
-    let _bytes_recieved = socket.recv(&mut connect_response)?;
+    async fn bind_with_timeout(base_timeout: Duration) -> anyhow::Result<UdpTrackerClient> {
+        let (socket, dual_stack) = match bind_dual_stack() {
+            Ok(socket) => (socket, true),
+            Err(_) => (UdpSocket::bind("0.0.0.0:0").await?, false),
+        };
+        Ok(UdpTrackerClient::start(
+            socket,
+            dual_stack,
+            None,
+            base_timeout,
+        ))
+    }
 
-    let connection_id = check_udp_response(connect_response, transaction_id)?;
+    fn start(
+        socket: UdpSocket,
+        dual_stack: bool,
+        relay: Option<UdpRelay>,
+        base_timeout: Duration,
+    ) -> UdpTrackerClient {
+        let socket = Arc::new(socket);
+        let pending: PendingRequests = Arc::new(Mutex::new(HashMap::new()));
+        let receiver = tokio::spawn(receive_responses(
+            Arc::clone(&socket),
+            Arc::clone(&pending),
+            relay.as_ref().map(|relay| relay.address),
+        ));
+
+        UdpTrackerClient {
+            socket,
+            dual_stack,
+            pending,
+            connection_ids: Mutex::new(HashMap::new()),
+            receiver,
+            base_timeout,
+            relay,
+        }
+    }
 
-    let (announce_msg, transaction_id) =
-        create_udp_announce(connection_id, torrent_data, peer_id, port, info_hash);
+    pub async fn announce(
+        &self,
+        url: &Url,
+        request: &AnnounceRequest,
+        retransmissions: u32,
+    ) -> anyhow::Result<AnnounceResponse> {
+        let tracker = self.resolve(url).await?;
+        let response = self
+            .request(tracker, retransmissions, |connection_id, transaction_id| {
+                create_udp_announce(connection_id, transaction_id, request)
+            })
+            .await?;
+        parse_udp_announce_response(&response, is_ipv6(tracker))
+    }
 
-    socket.send(&announce_msg)?;
+    pub async fn scrape(
+        &self,
+        url: &Url,
+        info_hashes: &[Vec<u8>],
+        retransmissions: u32,
+    ) -> anyhow::Result<Vec<ScrapeStats>> {
+        let tracker = self.resolve(url).await?;
+        let response = self
+            .request(tracker, retransmissions, |connection_id, transaction_id| {
+                create_udp_scrape(connection_id, transaction_id, info_hashes)
+            })
+            .await?;
+        parse_udp_scrape_response(&response, info_hashes)
+    }
 
-    let mut announce_response: [u8; 1024] = [0; 1024];
-    let bytes_recieved = socket.recv(&mut announce_response)?;
+    // Sends a request built by `create_msg(connection_id, transaction_id)`, retransmitting
+    // it up to `retransmissions` times until the tracker answers
+    async fn request<F>(
+        &self,
+        tracker: SocketAddr,
+        retransmissions: u32,
+        create_msg: F,
+    ) -> anyhow::Result<Vec<u8>>
+    where
+        F: Fn(u64, u32) -> Vec<u8>,
+    {
+        for attempt in 0..=retransmissions {
+            let timeout = self.base_timeout * 2u32.pow(attempt);
+
+            let connection_id = match self.cached_connection_id(tracker) {
+                Some(connection_id) => connection_id,
+                None => {
+                    let response = match self
+                        .transact(tracker, timeout, |transaction_id| {
+                            create_udp_handshake(transaction_id)
+                        })
+                        .await?
+                    {
+                        Some(response) => response,
+                        None => continue,
+                    };
+                    let connection_id = check_udp_response(&response)?;
+                    self.connection_ids
+                        .lock()
+                        .unwrap()
+                        .insert(tracker, (connection_id, Instant::now()));
+                    connection_id
+                }
+            };
+
+            let result = self
+                .transact(tracker, timeout, |transaction_id| {
+                    create_msg(connection_id, transaction_id)
+                })
+                .await;
+            match result {
+                Ok(Some(response)) => return Ok(response),
+                Ok(None) => continue,
+                Err(err) => {
+                    // the tracker may have forgotten our connection id
+                    self.connection_ids.lock().unwrap().remove(&tracker);
+                    return Err(err);
+                }
+            }
+        }
 
-    parse_udp_announce_response(
-        announce_response[0..bytes_recieved].to_vec(),
-        transaction_id,
-    )
-}
+        anyhow::bail!("Tracker {} didn't respond", tracker)
+    }
 
-fn create_udp_handshake() -> (Vec<u8>, u32) {
-    let mut handshake_bytes = Vec::new();
-    let protocol_id: u64 = 4497486125440; // magical constant 0x41727101980
-    let action: u32 = 0; // connect
-    let transaction_id: u32 = 54676;
+    // Returns `None` if the tracker didn't respond in time
+    async fn transact<F>(
+        &self,
+        tracker: SocketAddr,
+        timeout: Duration,
+        create_msg: F,
+    ) -> anyhow::Result<Option<Vec<u8>>>
+    where
+        F: FnOnce(u32) -> Vec<u8>,
+    {
+        let (response_tx, response_rx) = oneshot::channel();
+        let transaction_id = {
+            let mut pending = self.pending.lock().unwrap();
+            let mut transaction_id: u32 = rand::random();
+            while pending.contains_key(&transaction_id) {
+                transaction_id = rand::random();
+            }
+            pending.insert(transaction_id, (tracker, response_tx));
+            transaction_id
+        };
+
+        let msg = create_msg(transaction_id);
+        let sent = match &self.relay {
+            Some(relay) => {
+                self.socket
+                    .send_to(&proxy::udp_datagram(tracker, &msg), relay.address)
+                    .await
+            }
+            None => self.socket.send_to(&msg, tracker).await,
+        };
+        let result = match sent {
+            Ok(_) => tokio::time::timeout(timeout, response_rx).await,
+            Err(err) => {
+                self.pending.lock().unwrap().remove(&transaction_id);
+                return Err(err.into());
+            }
+        };
+
+        match result {
+            Ok(Ok(response)) => {
+                check_action(&response, action_of(&msg))?;
+                Ok(Some(response))
+            }
+            Ok(Err(_)) => anyhow::bail!("UDP tracker client stopped"),
+            Err(_) => {
+                self.pending.lock().unwrap().remove(&transaction_id);
+                Ok(None)
+            }
+        }
+    }
+
+    // Returns the tracker address in the form used by the socket
+    async fn resolve(&self, url: &Url) -> anyhow::Result<SocketAddr> {
+        let host = url
+            .host()
+            .ok_or(anyhow::anyhow!("Couldn't get url hostname"))?;
+        let port = url.port().ok_or(anyhow::anyhow!("Couldn't get url port"))?;
+
+        let mut addrs: Vec<SocketAddr> = match host {
+            Host::Ipv4(ip) => vec![SocketAddr::new(IpAddr::V4(ip), port)],
+            Host::Ipv6(ip) => vec![SocketAddr::new(IpAddr::V6(ip), port)],
+            Host::Domain(domain) => tokio::net::lookup_host((domain, port)).await?.collect(),
+        };
+        // IPv4 first, as it works on every network
+        addrs.sort_by_key(|addr| addr.is_ipv6());
+
+        addrs
+            .into_iter()
+            .filter_map(|addr| match addr {
+                // The relay reaches either family
+                addr if self.relay.is_some() => Some(addr),
+                SocketAddr::V4(v4) if self.dual_stack => Some(SocketAddr::V6(SocketAddrV6::new(
+                    v4.ip().to_ipv6_mapped(),
+                    v4.port(),
+                    0,
+                    0,
+                ))),
+                SocketAddr::V6(_) if !self.dual_stack => None,
+                addr => Some(addr),
+            })
+            .next()
+            .ok_or(anyhow::anyhow!("Couldn't resolve {}", host))
+    }
 
-    for byte in protocol_id.to_be_bytes().iter() {
-        handshake_bytes.push(*byte);
+    fn cached_connection_id(&self, tracker: SocketAddr) -> Option<u64> {
+        let mut connection_ids = self.connection_ids.lock().unwrap();
+        match connection_ids.get(&tracker) {
+            Some((connection_id, obtained)) if obtained.elapsed() < CONNECTION_ID_LIFETIME => {
+                Some(*connection_id)
+            }
+            Some(_) => {
+                connection_ids.remove(&tracker);
+                None
+            }
+            None => None,
+        }
     }
+}
 
-    for byte in action.to_be_bytes().iter() {
-        handshake_bytes.push(*byte);
+impl Drop for UdpTrackerClient {
+    fn drop(&mut self) {
+        self.receiver.abort();
     }
+}
 
-    for byte in transaction_id.to_be_bytes().iter() {
-        handshake_bytes.push(*byte);
+async fn receive_responses(
+    socket: Arc<UdpSocket>,
+    pending: PendingRequests,
+    relay: Option<SocketAddr>,
+) {
+    let mut buf = [0; MAX_RESPONSE_SIZE];
+    loop {
+        let (bytes_recieved, sender) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(_) => continue, // e.g. ICMP port unreachable reported for an earlier send
+        };
+        let (response, sender) = match relay {
+            Some(relay) if sender != relay => continue,
+            Some(_) => match proxy::parse_udp_datagram(&buf[..bytes_recieved]) {
+                Ok((sender, response)) => (response, sender),
+                Err(_) => continue,
+            },
+            None => (&buf[..bytes_recieved], sender),
+        };
+        if response.len() < 8 {
+            continue;
+        }
+
+        let transaction_id =
+            u32::from_be_bytes([response[4], response[5], response[6], response[7]]);
+        let mut pending = pending.lock().unwrap();
+        let is_expected = match pending.get(&transaction_id) {
+            Some((tracker, _)) => *tracker == sender,
+            None => false,
+        };
+        if is_expected {
+            let (_, response_tx) = pending.remove(&transaction_id).unwrap();
+            let _ = response_tx.send(response.to_vec());
+        }
     }
+}
 
-    (handshake_bytes, transaction_id)
+fn bind_dual_stack() -> anyhow::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(false)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0).into())?;
+    Ok(UdpSocket::from_std(socket.into())?)
 }
 
-fn check_udp_response(response: [u8; 16], transaction_id: u32) -> anyhow::Result<u64> {
-    anyhow::ensure!(
-        u32::from_be_bytes(response[0..4].try_into()?) == 0,
-        "Wrong connection id"
-    );
+fn is_ipv6(addr: SocketAddr) -> bool {
+    match addr {
+        SocketAddr::V4(_) => false,
+        SocketAddr::V6(v6) => v6.ip().to_ipv4_mapped().is_none(),
+    }
+}
 
-    anyhow::ensure!(
-        u32::from_be_bytes(response[4..8].try_into()?) == transaction_id,
-        "Wrong transaction id"
-    );
+fn action_of(msg: &[u8]) -> u32 {
+    u32::from_be_bytes([msg[8], msg[9], msg[10], msg[11]])
+}
+
+fn check_action(response: &[u8], expected_action: u32) -> anyhow::Result<()> {
+    let action = u32::from_be_bytes(response[0..4].try_into()?);
+    if action == ACTION_ERROR {
+        anyhow::bail!("Tracker error: {}", String::from_utf8_lossy(&response[8..]));
+    }
+    anyhow::ensure!(action == expected_action, "Wrong action id");
+    Ok(())
+}
+
+fn create_udp_handshake(transaction_id: u32) -> Vec<u8> {
+    let mut handshake_bytes = Vec::new();
+    let protocol_id: u64 = 4497486125440; // magical constant 0x41727101980
+
+    handshake_bytes.extend_from_slice(&protocol_id.to_be_bytes());
+    handshake_bytes.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+    handshake_bytes.extend_from_slice(&transaction_id.to_be_bytes());
 
-    Ok(u64::from_be_bytes(response[8..].try_into()?))
+    handshake_bytes
+}
+
+fn check_udp_response(response: &[u8]) -> anyhow::Result<u64> {
+    anyhow::ensure!(response.len() >= 16, "Connect response is too short");
+    Ok(u64::from_be_bytes(response[8..16].try_into()?))
 }
 
 fn create_udp_announce(
     connection_id: u64,
-    torrent_data: &TorrentData,
-    peer_id: &Vec<u8>,
-    port: u16,
-    info_hash: &Vec<u8>,
-) -> (Vec<u8>, u32) {
+    transaction_id: u32,
+    request: &AnnounceRequest,
+) -> Vec<u8> {
     let mut announce_bytes = Vec::new();
 
-    for byte in connection_id.to_be_bytes().iter() {
-        announce_bytes.push(*byte);
-    }
+    announce_bytes.extend_from_slice(&connection_id.to_be_bytes());
+    announce_bytes.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+    announce_bytes.extend_from_slice(&transaction_id.to_be_bytes());
+    announce_bytes.extend_from_slice(&request.info_hash);
+    announce_bytes.extend_from_slice(&request.peer_id);
+    announce_bytes.extend_from_slice(&request.downloaded.to_be_bytes());
+    announce_bytes.extend_from_slice(&request.left.to_be_bytes());
+    announce_bytes.extend_from_slice(&request.uploaded.to_be_bytes());
 
-    let action: u32 = 1;
-    for byte in action.to_be_bytes().iter() {
-        announce_bytes.push(*byte);
-    }
+    announce_bytes.extend_from_slice(&(request.event as u32).to_be_bytes());
 
-    let transaction_id: u32 = 542178; // some random number
-    for byte in transaction_id.to_be_bytes().iter() {
-        announce_bytes.push(*byte);
-    }
+    let ip_address: u32 = 0; // default value
+    announce_bytes.extend_from_slice(&ip_address.to_be_bytes());
 
-    for byte in info_hash {
-        announce_bytes.push(*byte);
-    }
+    announce_bytes.extend_from_slice(&request.key.to_be_bytes());
 
-    for byte in peer_id {
-        announce_bytes.push(*byte);
-    }
+    let num_want: i32 = match request.num_want {
+        Some(num_want) => num_want as i32,
+        None => -1, // default value
+    };
+    announce_bytes.extend_from_slice(&num_want.to_be_bytes());
 
-    let downloaded: u64 = 0;
-    for byte in downloaded.to_be_bytes().iter() {
-        announce_bytes.push(*byte);
-    }
+    announce_bytes.extend_from_slice(&request.port.to_be_bytes());
 
-    let mut left: u64 = 0;
-    for file in &torrent_data.files {
-        left += file.size as u64;
-    }
-    for byte in left.to_be_bytes().iter() {
-        announce_bytes.push(*byte);
-    }
+    announce_bytes
+}
+
+fn parse_udp_announce_response(response: &[u8], ipv6: bool) -> anyhow::Result<AnnounceResponse> {
+    anyhow::ensure!(response.len() >= 20, "Announce response is too short");
+
+    let interval = u32::from_be_bytes(response[8..12].try_into()?);
+    let leechers = u32::from_be_bytes(response[12..16].try_into()?);
+    let seeders = u32::from_be_bytes(response[16..20].try_into()?);
+
+    let peers_list = parse_compact_peers(&response[20..], ipv6)?;
+
+    Ok(AnnounceResponse {
+        peers: peers_list,
+        interval: u64::from(interval),
+        min_interval: None,
+        seeders: Some(seeders),
+        leechers: Some(leechers),
+        warning: None,
+    })
+}
+
+fn create_udp_scrape(connection_id: u64, transaction_id: u32, info_hashes: &[Vec<u8>]) -> Vec<u8> {
+    let mut scrape_bytes = Vec::new();
 
-    let uploaded: u64 = 0;
-    for byte in uploaded.to_be_bytes().iter() {
-        announce_bytes.push(*byte);
+    scrape_bytes.extend_from_slice(&connection_id.to_be_bytes());
+    scrape_bytes.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+    scrape_bytes.extend_from_slice(&transaction_id.to_be_bytes());
+
+    for info_hash in info_hashes {
+        scrape_bytes.extend_from_slice(info_hash);
     }
 
-    let event: u32 = 0;
-    for byte in event.to_be_bytes().iter() {
-        announce_bytes.push(*byte);
+    scrape_bytes
+}
+
+fn parse_udp_scrape_response(
+    response: &[u8],
+    info_hashes: &[Vec<u8>],
+) -> anyhow::Result<Vec<ScrapeStats>> {
+    // seeders, completed and leechers for every requested info hash, in the same order
+    let stats = response[8..].chunks_exact(12);
+    anyhow::ensure!(
+        stats.len() == info_hashes.len(),
+        "Expected stats for {} torrents, got {}",
+        info_hashes.len(),
+        stats.len()
+    );
+
+    Ok(stats
+        .zip(info_hashes)
+        .map(|(stats, info_hash)| ScrapeStats {
+            info_hash: info_hash.clone(),
+            complete: u32::from_be_bytes([stats[0], stats[1], stats[2], stats[3]]),
+            downloaded: u32::from_be_bytes([stats[4], stats[5], stats[6], stats[7]]),
+            incomplete: u32::from_be_bytes([stats[8], stats[9], stats[10], stats[11]]),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Answers connect and scrape requests, ignoring the first `drop_first` datagrams
+    async fn fake_tracker(
+        bind_addr: &str,
+        drop_first: usize,
+        error: Option<&'static str>,
+    ) -> (Url, Arc<Mutex<u32>>) {
+        let socket = UdpSocket::bind(bind_addr).await.unwrap();
+        let url = Url::parse(&format!("udp://{}", socket.local_addr().unwrap())).unwrap();
+        let connects = Arc::new(Mutex::new(0));
+        let connects_clone = Arc::clone(&connects);
+
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            let mut received = 0;
+            loop {
+                let (len, client) = socket.recv_from(&mut buf).await.unwrap();
+                received += 1;
+                if received <= drop_first {
+                    continue;
+                }
+
+                let action = action_of(&buf[..len]);
+                let mut response = Vec::new();
+                if let Some(error) = error {
+                    response.extend_from_slice(&ACTION_ERROR.to_be_bytes());
+                    response.extend_from_slice(&buf[12..16]);
+                    response.extend_from_slice(error.as_bytes());
+                } else if action == ACTION_CONNECT {
+                    *connects_clone.lock().unwrap() += 1;
+                    response.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+                    response.extend_from_slice(&buf[12..16]);
+                    response.extend_from_slice(&77u64.to_be_bytes());
+                } else {
+                    assert_eq!(buf[..8], 77u64.to_be_bytes());
+                    response.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+                    response.extend_from_slice(&buf[12..16]);
+                    for _ in 0..(len - 16) / 20 {
+                        response.extend_from_slice(&[0, 0, 0, 5, 0, 0, 0, 10, 0, 0, 0, 3]);
+                    }
+                }
+                socket.send_to(&response, client).await.unwrap();
+            }
+        });
+
+        (url, connects)
     }
 
-    let ip_address: u32 = 0; // default value
-    for byte in ip_address.to_be_bytes().iter() {
-        announce_bytes.push(*byte);
+    #[tokio::test]
+    async fn scrape_reuses_connection_id() {
+        let (url, connects) = fake_tracker("127.0.0.1:0", 0, None).await;
+        let client = UdpTrackerClient::bind().await.unwrap();
+        let info_hashes = vec![vec![1; 20], vec![2; 20]];
+
+        for _ in 0..2 {
+            let stats = client
+                .scrape(&url, &info_hashes, MAX_RETRANSMISSIONS)
+                .await
+                .unwrap();
+            assert_eq!(stats.len(), 2);
+            assert_eq!(stats[1].info_hash, vec![2; 20]);
+            assert_eq!(
+                (stats[1].complete, stats[1].downloaded, stats[1].incomplete),
+                (5, 10, 3)
+            );
+        }
+        assert_eq!(*connects.lock().unwrap(), 1);
     }
 
-    let key: u32 = 7561816; // random number
-    for byte in key.to_be_bytes().iter() {
-        announce_bytes.push(*byte);
+    #[tokio::test]
+    async fn lost_requests_are_retransmitted() {
+        let (url, connects) = fake_tracker("127.0.0.1:0", 2, None).await;
+        let client = UdpTrackerClient::bind_with_timeout(Duration::from_millis(20))
+            .await
+            .unwrap();
+
+        let stats = client
+            .scrape(&url, &[vec![1; 20]], MAX_RETRANSMISSIONS)
+            .await
+            .unwrap();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(*connects.lock().unwrap(), 1);
     }
 
-    let num_want: i32 = -1; // default value
-    for byte in num_want.to_be_bytes().iter() {
-        announce_bytes.push(*byte);
+    #[tokio::test]
+    async fn silent_tracker_is_given_up_on() {
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("udp://{}", silent.local_addr().unwrap())).unwrap();
+        let client = UdpTrackerClient::bind_with_timeout(Duration::from_millis(20))
+            .await
+            .unwrap();
+
+        let started = Instant::now();
+        let scrape = client
+            .scrape(&url, &[vec![1; 20]], FAILOVER_RETRANSMISSIONS)
+            .await;
+        assert!(scrape.is_err());
+        // 20 + 40 + 80 ms, the full schedule would take ten seconds
+        assert!(started.elapsed() < Duration::from_secs(2));
     }
 
-    for byte in port.to_be_bytes().iter() {
-        announce_bytes.push(*byte);
+    #[tokio::test]
+    async fn error_response_is_reported() {
+        let (url, _) = fake_tracker("127.0.0.1:0", 0, Some("torrent not registered")).await;
+        let client = UdpTrackerClient::bind().await.unwrap();
+
+        let err = client
+            .scrape(&url, &[vec![1; 20]], MAX_RETRANSMISSIONS)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("torrent not registered"));
     }
 
-    (announce_bytes, transaction_id)
-}
+    #[tokio::test]
+    async fn tracker_reachable_over_ipv6() {
+        let (url, connects) = fake_tracker("[::1]:0", 0, None).await;
+        let client = UdpTrackerClient::bind().await.unwrap();
+
+        let stats = client
+            .scrape(&url, &[vec![1; 20]], MAX_RETRANSMISSIONS)
+            .await
+            .unwrap();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(*connects.lock().unwrap(), 1);
+    }
 
-fn parse_udp_announce_response(
-    response: Vec<u8>,
-    transaction_id: u32,
-) -> anyhow::Result<(Vec<String>, i64)> {
-    let mut peers_list: Vec<String> = Vec::new();
-    anyhow::ensure!(
-        u32::from_be_bytes(response[0..4].try_into()?) == 1,
-        "Wrong action id"
-    );
+    #[tokio::test]
+    async fn tracker_reachable_through_socks5_relay() {
+        let (url, connects) = fake_tracker("127.0.0.1:0", 0, None).await;
+        let proxy: Proxy = format!("socks5://{}", proxy::stand_in::socks5(None).await)
+            .parse()
+            .unwrap();
+        let client = UdpTrackerClient::bind_through(&proxy).await.unwrap();
+
+        let stats = client
+            .scrape(&url, &[vec![1; 20]], MAX_RETRANSMISSIONS)
+            .await
+            .unwrap();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(*connects.lock().unwrap(), 1);
+    }
 
-    anyhow::ensure!(
-        u32::from_be_bytes(response[4..8].try_into()?) == transaction_id,
-        "Wrong transaction id"
-    );
+    #[test]
+    fn announce_response_peers_follow_tracker_address_family() {
+        let mut response = vec![0, 0, 0, 1, 0, 0, 0, 1];
+        response.extend_from_slice(&[0, 0, 7, 8, 0, 0, 0, 1, 0, 0, 0, 2]);
+        response.extend_from_slice(&[0; 15]);
+        response.extend_from_slice(&[1, 0x1a, 0xe1]);
+
+        let announce = parse_udp_announce_response(&response, true).unwrap();
+        assert_eq!(announce.peers, ["[::1]:6881".parse().unwrap()]);
+        assert_eq!(announce.interval, 1800);
+        assert_eq!((announce.leechers, announce.seeders), (Some(1), Some(2)));
+
+        assert_eq!(
+            parse_udp_announce_response(&response, false)
+                .unwrap()
+                .peers
+                .len(),
+            3
+        );
+        assert!(is_ipv6("[::1]:80".parse().unwrap()));
+        assert!(!is_ipv6("[::ffff:127.0.0.1]:80".parse().unwrap()));
+    }
 
-    let interval = u32::from_be_bytes(response[8..12].try_into()?);
-    let _number_of_leechers = u32::from_be_bytes(response[12..16].try_into()?);
-    let _number_of_seeders = u32::from_be_bytes(response[16..20].try_into()?);
-
-    let mut ip = String::new();
-    let mut port: u16 = 0;
-
-    for (index, number) in response[20..].iter().enumerate() {
-        match index % 6 {
-            0 => {
-                ip = String::new();
-                port = 0;
-                ip.push_str(&*number.to_string());
-                ip.push('.');
-            }
-            3 => {
-                ip.push_str(&*number.to_string());
-                ip.push(':');
-            }
-            4 => {
-                port += (*number as u16) * 256;
-            }
-            5 => {
-                port += *number as u16;
-                ip.push_str(&port.to_string());
-                peers_list.push(ip.clone());
-            }
-            _ => {
-                ip.push_str(&*number.to_string());
-                ip.push('.');
-            }
-        }
+    #[test]
+    fn short_scrape_response_is_rejected() {
+        let mut response = vec![0, 0, 0, 2, 0, 0, 0, 1];
+        response.extend_from_slice(&[0; 12]);
+        assert!(parse_udp_scrape_response(&response, &[vec![1; 20]]).is_ok());
+        assert!(parse_udp_scrape_response(&response[..14], &[vec![1; 20]]).is_err());
     }
-    Ok((peers_list, interval as i64))
 }
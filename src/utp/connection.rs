@@ -0,0 +1,517 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+use super::packet::{seq_after, Packet, PacketType, HEADER_SIZE};
+use super::stream::{Shared, UtpStream};
+
+pub const MAX_PAYLOAD: usize = 1400 - HEADER_SIZE;
+const RECV_BUFFER_SIZE: usize = 1 << 20;
+// Packets after the last in-order one we keep, also the most we have in flight
+const MAX_OUT_OF_ORDER: u16 = 1024;
+const SELECTIVE_ACK_BITS: usize = 32;
+
+// LEDBAT: the window grows while the extra queuing delay we cause stays below the target
+const TARGET_DELAY_MICROS: f64 = 100_000.0;
+const MAX_WINDOW_INCREASE_PER_RTT: f64 = 3000.0;
+const MIN_WINDOW: f64 = MAX_PAYLOAD as f64;
+const INITIAL_WINDOW: f64 = 4.0 * MAX_PAYLOAD as f64;
+const DELAY_HISTORY_PERIOD: Duration = Duration::from_secs(60);
+
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_SYN_RETRANSMISSIONS: u32 = 3;
+const MAX_RETRANSMISSIONS: u32 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    SynSent,
+    Connected,
+    Closed,
+}
+
+struct InFlight {
+    seq_nr: u16,
+    packet_type: PacketType,
+    payload: Vec<u8>,
+    sent_at: Instant,
+    transmissions: u32,
+    resent_fast: bool,
+}
+
+// The base delay is the smallest one seen in the last one or two minutes, so a changed route
+// doesn't keep us on a stale minimum
+struct DelayHistory {
+    current: u32,
+    previous: u32,
+    period_started: Instant,
+}
+
+impl DelayHistory {
+    fn add(&mut self, delay: u32, now: Instant) {
+        if now - self.period_started > DELAY_HISTORY_PERIOD {
+            self.previous = self.current;
+            self.current = u32::MAX;
+            self.period_started = now;
+        }
+        self.current = self.current.min(delay);
+    }
+
+    fn base(&self) -> u32 {
+        self.current.min(self.previous)
+    }
+}
+
+/*
+ *   One uTP connection, driven by the socket's task: incoming packets, data written to the stream
+ *   and timer ticks go in, packets to send come out.
+ */
+pub struct Connection {
+    shared: Arc<Mutex<Shared>>,
+    recv_id: u16,
+    send_id: u16,
+    state: State,
+    seq_nr: u16,
+    ack_nr: u16,
+    in_flight: VecDeque<InFlight>,
+    max_window: f64,
+    peer_window: usize,
+    recv_window: usize,
+    rtt_micros: Option<(f64, f64)>,
+    timeout: Duration,
+    delays: DelayHistory,
+    reply_micro: u32,
+    out_of_order: HashMap<u16, Vec<u8>>,
+    last_ack: u16,
+    dup_acks: u32,
+    timeouts: u32,
+    fin_sent: bool,
+    fin_acked: bool,
+    fin_received: Option<u16>,
+    // Handed out once the connection is established
+    connected: Option<(UtpStream, oneshot::Sender<io::Result<UtpStream>>)>,
+}
+
+impl Connection {
+    fn new(shared: Arc<Mutex<Shared>>, recv_id: u16, send_id: u16, now: Instant) -> Connection {
+        Connection {
+            shared,
+            recv_id,
+            send_id,
+            state: State::SynSent,
+            seq_nr: 1,
+            ack_nr: 0,
+            in_flight: VecDeque::new(),
+            max_window: INITIAL_WINDOW,
+            peer_window: MAX_PAYLOAD,
+            recv_window: RECV_BUFFER_SIZE,
+            rtt_micros: None,
+            timeout: INITIAL_TIMEOUT,
+            delays: DelayHistory {
+                current: u32::MAX,
+                previous: u32::MAX,
+                period_started: now,
+            },
+            reply_micro: 0,
+            out_of_order: HashMap::new(),
+            last_ack: 0,
+            dup_acks: 0,
+            timeouts: 0,
+            fin_sent: false,
+            fin_acked: false,
+            fin_received: None,
+            connected: None,
+        }
+    }
+
+    pub fn connect(
+        stream: UtpStream,
+        shared: Arc<Mutex<Shared>>,
+        recv_id: u16,
+        reply: oneshot::Sender<io::Result<UtpStream>>,
+        now: Instant,
+    ) -> (Connection, Packet) {
+        let mut connection = Connection::new(shared, recv_id, recv_id.wrapping_add(1), now);
+        connection.connected = Some((stream, reply));
+        let syn = connection.send_new(PacketType::Syn, Vec::new(), now);
+        (connection, syn)
+    }
+
+    pub fn accept(
+        shared: Arc<Mutex<Shared>>,
+        syn: &Packet,
+        now_micros: u32,
+        now: Instant,
+    ) -> (Connection, Packet) {
+        let mut connection = Connection::new(
+            shared,
+            syn.connection_id.wrapping_add(1),
+            syn.connection_id,
+            now,
+        );
+        connection.state = State::Connected;
+        connection.seq_nr = rand::random();
+        connection.ack_nr = syn.seq_nr;
+        connection.peer_window = syn.window_size as usize;
+        connection.reply_micro = now_micros.wrapping_sub(syn.timestamp);
+        let state = connection.state_packet();
+        (connection, state)
+    }
+
+    pub fn recv_id(&self) -> u16 {
+        self.recv_id
+    }
+
+    // Done when both sides finished sending or the connection broke. A selective ACK can cover
+    // our FIN before the data sent ahead of it.
+    pub fn is_finished(&self) -> bool {
+        let stream_dropped = Arc::strong_count(&self.shared) == 1 && self.connected.is_none();
+        self.state == State::Closed
+            || (self.fin_acked
+                && self.in_flight.is_empty()
+                && (self.shared.lock().unwrap().eof || stream_dropped))
+    }
+
+    fn build(&mut self, packet_type: PacketType, seq_nr: u16, payload: Vec<u8>) -> Packet {
+        let mut packet = Packet::new(
+            packet_type,
+            if packet_type == PacketType::Syn {
+                self.recv_id
+            } else {
+                self.send_id
+            },
+        );
+        packet.timestamp_difference = self.reply_micro;
+        packet.window_size = self.recv_window as u32;
+        packet.seq_nr = seq_nr;
+        packet.ack_nr = self.ack_nr;
+        packet.payload = payload;
+        packet
+    }
+
+    fn state_packet(&mut self) -> Packet {
+        let mut packet = self.build(PacketType::State, self.seq_nr, Vec::new());
+        packet.selective_ack = self.selective_ack();
+        packet
+    }
+
+    fn selective_ack(&self) -> Option<Vec<u8>> {
+        if self.out_of_order.is_empty() && self.fin_received.is_none() {
+            return None;
+        }
+        let mut mask = vec![0; SELECTIVE_ACK_BITS / 8];
+        for bit in 0..SELECTIVE_ACK_BITS {
+            let seq_nr = self.ack_nr.wrapping_add(2 + bit as u16);
+            if self.out_of_order.contains_key(&seq_nr) || self.fin_received == Some(seq_nr) {
+                mask[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        Some(mask)
+    }
+
+    fn send_new(&mut self, packet_type: PacketType, payload: Vec<u8>, now: Instant) -> Packet {
+        let packet = self.build(packet_type, self.seq_nr, payload.clone());
+        self.in_flight.push_back(InFlight {
+            seq_nr: self.seq_nr,
+            packet_type,
+            payload,
+            sent_at: now,
+            transmissions: 1,
+            resent_fast: false,
+        });
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        packet
+    }
+
+    fn resend(&mut self, position: usize, now: Instant) -> Packet {
+        let entry = &mut self.in_flight[position];
+        entry.sent_at = now;
+        entry.transmissions += 1;
+        let (packet_type, seq_nr, payload) =
+            (entry.packet_type, entry.seq_nr, entry.payload.clone());
+        self.build(packet_type, seq_nr, payload)
+    }
+
+    fn refresh_recv_window(&mut self) {
+        let buffered = self.shared.lock().unwrap().recv_buffer.len();
+        self.recv_window = RECV_BUFFER_SIZE.saturating_sub(buffered);
+    }
+
+    fn fail(&mut self, error: io::ErrorKind) {
+        self.state = State::Closed;
+        self.shared.lock().unwrap().fail(error);
+        if let Some((_, reply)) = self.connected.take() {
+            let _ = reply.send(Err(error.into()));
+        }
+    }
+
+    pub fn on_packet(&mut self, packet: &Packet, now_micros: u32, now: Instant) -> Vec<Packet> {
+        let mut out = Vec::new();
+        if self.state == State::Closed {
+            return out;
+        }
+        self.refresh_recv_window();
+        if packet.timestamp != 0 {
+            self.reply_micro = now_micros.wrapping_sub(packet.timestamp);
+        }
+        self.peer_window = packet.window_size as usize;
+
+        match packet.packet_type {
+            PacketType::Reset => {
+                self.fail(io::ErrorKind::ConnectionReset);
+                return out;
+            }
+            // Our answer to it got lost
+            PacketType::Syn => {
+                out.push(self.state_packet());
+                return out;
+            }
+            _ => {}
+        }
+
+        if self.state == State::SynSent {
+            if packet.packet_type != PacketType::State {
+                return out;
+            }
+            self.state = State::Connected;
+            self.ack_nr = packet.seq_nr.wrapping_sub(1);
+            if let Some((stream, reply)) = self.connected.take() {
+                // Nobody waits for the connection anymore: the stream is dropped and closes it
+                let _ = reply.send(Ok(stream));
+            }
+        }
+
+        self.process_ack(packet, now, &mut out);
+        if let PacketType::Data | PacketType::Fin = packet.packet_type {
+            self.receive(packet);
+            out.push(self.state_packet());
+        }
+        out.extend(self.send_data(now));
+        out
+    }
+
+    fn receive(&mut self, packet: &Packet) {
+        let seq_nr = packet.seq_nr;
+        if !seq_after(seq_nr, self.ack_nr) || seq_nr.wrapping_sub(self.ack_nr) > MAX_OUT_OF_ORDER {
+            // Already delivered (our ACK got lost) or too far ahead
+            return;
+        }
+        if packet.packet_type == PacketType::Fin {
+            self.fin_received = Some(seq_nr);
+        } else {
+            self.out_of_order.insert(seq_nr, packet.payload.clone());
+        }
+
+        let mut shared = self.shared.lock().unwrap();
+        let mut delivered = false;
+        loop {
+            let next = self.ack_nr.wrapping_add(1);
+            if let Some(payload) = self.out_of_order.remove(&next) {
+                shared.recv_buffer.extend(payload);
+                self.ack_nr = next;
+                delivered = true;
+            } else if self.fin_received == Some(next) {
+                self.ack_nr = next;
+                shared.eof = true;
+                delivered = true;
+                break;
+            } else {
+                break;
+            }
+        }
+        if delivered {
+            shared.wake();
+        }
+    }
+
+    fn acknowledge(&mut self, position: usize, now: Instant) -> usize {
+        let entry = self.in_flight.remove(position).unwrap();
+        if entry.transmissions == 1 {
+            self.update_rtt(now - entry.sent_at);
+        }
+        if entry.packet_type == PacketType::Fin {
+            self.fin_acked = true;
+        }
+        entry.payload.len()
+    }
+
+    fn process_ack(&mut self, packet: &Packet, now: Instant, out: &mut Vec<Packet>) {
+        let ack_nr = packet.ack_nr;
+        let mut acked = 0;
+        let mut any_acked = false;
+        while self
+            .in_flight
+            .front()
+            .is_some_and(|entry| !seq_after(entry.seq_nr, ack_nr))
+        {
+            acked += self.acknowledge(0, now);
+            any_acked = true;
+        }
+
+        let mut highest_acked = ack_nr;
+        if let Some(mask) = &packet.selective_ack {
+            for bit in 0..mask.len() * 8 {
+                if mask[bit / 8] & (1 << (bit % 8)) == 0 {
+                    continue;
+                }
+                let seq_nr = ack_nr.wrapping_add(2 + bit as u16);
+                highest_acked = seq_nr;
+                if let Some(position) = self.in_flight.iter().position(|e| e.seq_nr == seq_nr) {
+                    acked += self.acknowledge(position, now);
+                    any_acked = true;
+                }
+            }
+        }
+
+        // A packet is lost when three packets sent after it arrived, or the same ACK came three times
+        if packet.packet_type == PacketType::State
+            && !any_acked
+            && ack_nr == self.last_ack
+            && !self.in_flight.is_empty()
+        {
+            self.dup_acks += 1;
+        } else if any_acked {
+            self.dup_acks = 0;
+        }
+        self.last_ack = ack_nr;
+
+        let lost: Vec<usize> = (0..self.in_flight.len())
+            .filter(|&position| {
+                let entry = &self.in_flight[position];
+                !entry.resent_fast
+                    && (seq_after(highest_acked, entry.seq_nr.wrapping_add(2))
+                        || (position == 0 && self.dup_acks >= 3))
+            })
+            .collect();
+        if !lost.is_empty() {
+            self.max_window = (self.max_window / 2.0).max(MIN_WINDOW);
+        }
+        for position in lost {
+            self.in_flight[position].resent_fast = true;
+            out.push(self.resend(position, now));
+        }
+
+        if any_acked {
+            // Progress again, undo the timeout backoff
+            self.timeouts = 0;
+            self.reset_timeout();
+            if packet.timestamp_difference != 0 {
+                self.congestion_control(acked, packet.timestamp_difference, now);
+            }
+        }
+    }
+
+    fn congestion_control(&mut self, acked: usize, their_delay: u32, now: Instant) {
+        self.delays.add(their_delay, now);
+        let our_delay = their_delay.wrapping_sub(self.delays.base()) as f64;
+        let off_target = ((TARGET_DELAY_MICROS - our_delay) / TARGET_DELAY_MICROS).clamp(-1.0, 1.0);
+        let acked = acked as f64;
+        let window_factor = acked.min(self.max_window) / acked.max(self.max_window);
+        self.max_window = (self.max_window
+            + MAX_WINDOW_INCREASE_PER_RTT * off_target * window_factor)
+            .max(MIN_WINDOW);
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        let sample = sample.as_micros() as f64;
+        let (rtt, rtt_var) = match self.rtt_micros {
+            None => (sample, sample / 2.0),
+            Some((rtt, rtt_var)) => (
+                rtt + (sample - rtt) / 8.0,
+                rtt_var + ((rtt - sample).abs() - rtt_var) / 4.0,
+            ),
+        };
+        self.rtt_micros = Some((rtt, rtt_var));
+        self.reset_timeout();
+    }
+
+    fn reset_timeout(&mut self) {
+        if let Some((rtt, rtt_var)) = self.rtt_micros {
+            self.timeout = Duration::from_micros((rtt + 4.0 * rtt_var) as u64).max(MIN_TIMEOUT);
+        }
+    }
+
+    // Packetizes written data as far as the congestion and the peer's receive windows allow
+    pub fn send_data(&mut self, now: Instant) -> Vec<Packet> {
+        let mut out = Vec::new();
+        if self.state != State::Connected {
+            return out;
+        }
+        let window_was_closed = self.recv_window < MAX_PAYLOAD;
+        self.refresh_recv_window();
+        if window_was_closed && self.recv_window >= MAX_PAYLOAD {
+            out.push(self.state_packet());
+        }
+
+        let shared = Arc::clone(&self.shared);
+        let mut shared = shared.lock().unwrap();
+        let window = (self.max_window as usize).min(self.peer_window);
+        let mut bytes_in_flight: usize = self.in_flight.iter().map(|e| e.payload.len()).sum();
+        let mut sent = false;
+        while !shared.send_buffer.is_empty() && self.in_flight.len() < MAX_OUT_OF_ORDER as usize {
+            let len = MAX_PAYLOAD.min(shared.send_buffer.len());
+            // A single packet always goes out, so a closed window gets probed
+            if bytes_in_flight + len > window && !self.in_flight.is_empty() {
+                break;
+            }
+            let payload: Vec<u8> = shared.send_buffer.drain(..len).collect();
+            out.push(self.send_new(PacketType::Data, payload, now));
+            bytes_in_flight += len;
+            sent = true;
+        }
+        if sent {
+            if let Some(waker) = shared.write_waker.take() {
+                waker.wake();
+            }
+        }
+
+        if shared.closing && shared.send_buffer.is_empty() && !self.fin_sent {
+            self.fin_sent = true;
+            out.push(self.send_new(PacketType::Fin, Vec::new(), now));
+        }
+        out
+    }
+
+    pub fn on_tick(&mut self, now: Instant) -> Vec<Packet> {
+        if self.state == State::Closed {
+            return Vec::new();
+        }
+        if let Some((_, reply)) = &self.connected {
+            if reply.is_closed() {
+                // The connect call was given up
+                self.state = State::Closed;
+                return Vec::new();
+            }
+        }
+
+        let mut out = Vec::new();
+        let timed_out = self
+            .in_flight
+            .front()
+            .is_some_and(|entry| now - entry.sent_at >= self.timeout);
+        if timed_out {
+            self.timeouts += 1;
+            let max_retransmissions = if self.state == State::SynSent {
+                MAX_SYN_RETRANSMISSIONS
+            } else {
+                MAX_RETRANSMISSIONS
+            };
+            if self.timeouts > max_retransmissions {
+                self.fail(io::ErrorKind::TimedOut);
+                return out;
+            }
+            self.max_window = MIN_WINDOW;
+            self.timeout = (self.timeout * 2).min(MAX_TIMEOUT);
+            // Later losses in the window can be detected from the ACKs again
+            for entry in self.in_flight.iter_mut() {
+                entry.resent_fast = false;
+            }
+            out.push(self.resend(0, now));
+        }
+        out.extend(self.send_data(now));
+        out
+    }
+}
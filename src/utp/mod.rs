@@ -0,0 +1,412 @@
+mod connection;
+mod packet;
+mod stream;
+
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot, Notify};
+
+use connection::Connection;
+use packet::{Packet, PacketType};
+use stream::Shared;
+pub use stream::UtpStream;
+
+const TICK: Duration = Duration::from_millis(20);
+// Incoming connections waiting to be accepted. SYNs past it are dropped, so the peer tries again
+// later or connects over TCP.
+const ACCEPT_BACKLOG: usize = 32;
+
+/*
+ *   uTP (BEP 29): reliable, ordered streams over UDP with LEDBAT congestion control, which backs
+ *   off as soon as it notices queuing delay so it doesn't hurt other traffic. A single task owns
+ *   the UDP socket and every connection on it; streams talk to it through shared buffers.
+ */
+
+enum Command {
+    Connect {
+        peer: SocketAddr,
+        reply: oneshot::Sender<io::Result<UtpStream>>,
+    },
+}
+
+// Cheap to clone, every clone uses the same UDP socket
+#[derive(Clone)]
+pub struct UtpSocket {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    commands: mpsc::UnboundedSender<Command>,
+    incoming: tokio::sync::Mutex<mpsc::Receiver<(UtpStream, SocketAddr)>>,
+    local_addr: SocketAddr,
+}
+
+impl UtpSocket {
+    // Listens on IPv4 and IPv6 if the system allows it. Port 0 lets the system pick one.
+    pub async fn bind(port: u16) -> anyhow::Result<UtpSocket> {
+        let (socket, dual_stack) = match bind_dual_stack(port) {
+            Ok(socket) => (socket, true),
+            Err(_) => (
+                UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port)).await?,
+                false,
+            ),
+        };
+        let local_addr = socket.local_addr()?;
+
+        let (commands, commands_rx) = mpsc::unbounded_channel();
+        let (incoming_tx, incoming) = mpsc::channel(ACCEPT_BACKLOG);
+        let driver = Driver {
+            socket,
+            dual_stack,
+            connections: HashMap::new(),
+            commands: commands_rx,
+            commands_tx: commands.downgrade(),
+            incoming: incoming_tx,
+            notify: Arc::new(Notify::new()),
+            epoch: Instant::now(),
+        };
+        tokio::spawn(driver.run());
+
+        Ok(UtpSocket {
+            inner: Arc::new(Inner {
+                commands,
+                incoming: tokio::sync::Mutex::new(incoming),
+                local_addr,
+            }),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.inner.local_addr
+    }
+
+    pub async fn connect(&self, peer: SocketAddr) -> anyhow::Result<UtpStream> {
+        let (reply, connected) = oneshot::channel();
+        self.inner
+            .commands
+            .send(Command::Connect { peer, reply })
+            .map_err(|_| anyhow::anyhow!("uTP socket is closed"))?;
+        Ok(connected.await??)
+    }
+
+    pub async fn accept(&self) -> anyhow::Result<(UtpStream, SocketAddr)> {
+        self.inner
+            .incoming
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or(anyhow::anyhow!("uTP socket is closed"))
+    }
+}
+
+fn bind_dual_stack(port: u16) -> anyhow::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(false)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port).into())?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+struct Driver {
+    socket: UdpSocket,
+    dual_stack: bool,
+    // By peer and the connection id its packets carry
+    connections: HashMap<(SocketAddr, u16), Connection>,
+    commands: mpsc::UnboundedReceiver<Command>,
+    // Handed to new streams, which keep the driver alive while they are used. A strong sender
+    // here would keep it running, and the port bound, after every handle is gone.
+    commands_tx: mpsc::WeakUnboundedSender<Command>,
+    incoming: mpsc::Sender<(UtpStream, SocketAddr)>,
+    notify: Arc<Notify>,
+    epoch: Instant,
+}
+
+impl Driver {
+    async fn run(mut self) {
+        let mut tick = tokio::time::interval(TICK);
+        tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut buf = vec![0; 65536];
+        let notify = Arc::clone(&self.notify);
+        // Every socket handle and stream is gone, the connections left are closing
+        let mut closing = false;
+
+        loop {
+            let mut to_send = Vec::new();
+            tokio::select! {
+                command = self.commands.recv(), if !closing => match command {
+                    Some(Command::Connect { peer, reply }) => to_send.extend(self.connect(peer, reply)),
+                    None => closing = true,
+                },
+                received = self.socket.recv_from(&mut buf) => {
+                    // Errors are ICMP messages about earlier packets, nothing to do with them
+                    if let Ok((len, from)) = received {
+                        if let Ok(packet) = Packet::parse(&buf[..len]) {
+                            to_send = self.on_packet(packet, canonical(from));
+                        }
+                    }
+                }
+                _ = notify.notified() => {
+                    let now = Instant::now();
+                    for (&(peer, _), connection) in self.connections.iter_mut() {
+                        to_send.extend(connection.send_data(now).into_iter().map(|p| (peer, p)));
+                    }
+                }
+                _ = tick.tick() => {
+                    let now = Instant::now();
+                    for (&(peer, _), connection) in self.connections.iter_mut() {
+                        to_send.extend(connection.on_tick(now).into_iter().map(|p| (peer, p)));
+                    }
+                }
+            }
+
+            for (peer, mut packet) in to_send {
+                packet.timestamp = self.now_micros();
+                let _ = self
+                    .socket
+                    .send_to(&packet.serialize(), self.map(peer))
+                    .await;
+            }
+            self.connections
+                .retain(|_, connection| !connection.is_finished());
+            if closing && self.connections.is_empty() {
+                return;
+            }
+        }
+    }
+
+    fn now_micros(&self) -> u32 {
+        self.epoch.elapsed().as_micros() as u32
+    }
+
+    // None once the socket is closing
+    fn new_stream(&self) -> Option<(UtpStream, Arc<Mutex<Shared>>)> {
+        let shared = Arc::new(Mutex::new(Shared::default()));
+        let stream = UtpStream::new(
+            Arc::clone(&shared),
+            Arc::clone(&self.notify),
+            self.commands_tx.upgrade()?,
+        );
+        Some((stream, shared))
+    }
+
+    fn connect(
+        &mut self,
+        peer: SocketAddr,
+        reply: oneshot::Sender<io::Result<UtpStream>>,
+    ) -> Option<(SocketAddr, Packet)> {
+        // Packets from the peer will carry our receive id
+        let recv_id = loop {
+            let recv_id: u16 = rand::random();
+            if !self.connections.contains_key(&(peer, recv_id)) {
+                break recv_id;
+            }
+        };
+        let (stream, shared) = self.new_stream()?;
+        let (connection, syn) = Connection::connect(stream, shared, recv_id, reply, Instant::now());
+        self.connections.insert((peer, recv_id), connection);
+        Some((peer, syn))
+    }
+
+    fn on_packet(&mut self, packet: Packet, from: SocketAddr) -> Vec<(SocketAddr, Packet)> {
+        let now = Instant::now();
+        let now_micros = self.now_micros();
+        let key = if packet.packet_type == PacketType::Syn {
+            (from, packet.connection_id.wrapping_add(1))
+        } else {
+            (from, packet.connection_id)
+        };
+
+        let packets = match self.connections.get_mut(&key) {
+            Some(connection) => connection.on_packet(&packet, now_micros, now),
+            None if packet.packet_type == PacketType::Syn => {
+                let (stream, shared) = match self.new_stream() {
+                    Some(stream) => stream,
+                    None => return Vec::new(),
+                };
+                let (connection, state) = Connection::accept(shared, &packet, now_micros, now);
+                if self.incoming.try_send((stream, from)).is_err() {
+                    return Vec::new();
+                }
+                self.connections
+                    .insert((from, connection.recv_id()), connection);
+                vec![state]
+            }
+            None if packet.packet_type != PacketType::Reset => {
+                let mut reset = Packet::new(PacketType::Reset, packet.connection_id);
+                reset.ack_nr = packet.seq_nr;
+                vec![reset]
+            }
+            None => Vec::new(),
+        };
+        packets.into_iter().map(|packet| (from, packet)).collect()
+    }
+
+    // A dual-stack socket sends to IPv4 peers through IPv4-mapped addresses
+    fn map(&self, peer: SocketAddr) -> SocketAddr {
+        match peer {
+            SocketAddr::V4(v4) if self.dual_stack => {
+                SocketAddr::V6(SocketAddrV6::new(v4.ip().to_ipv6_mapped(), v4.port(), 0, 0))
+            }
+            peer => peer,
+        }
+    }
+}
+
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), v6.port()),
+            None => addr,
+        },
+        addr => addr,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn test_data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    // Forwards datagrams between the client and `server`, dropping `loss` of them either way
+    async fn lossy_relay(server: SocketAddr, loss: f64) -> SocketAddr {
+        let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_addr = relay.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut client = None;
+            let mut buf = vec![0; 65536];
+            loop {
+                let (len, from) = relay.recv_from(&mut buf).await.unwrap();
+                let to = if from == server {
+                    match client {
+                        Some(client) => client,
+                        None => continue,
+                    }
+                } else {
+                    client = Some(from);
+                    server
+                };
+                if rand::thread_rng().gen::<f64>() >= loss {
+                    let _ = relay.send_to(&buf[..len], to).await;
+                }
+            }
+        });
+        relay_addr
+    }
+
+    async fn transfer(loss: f64, len: usize) {
+        let server = UtpSocket::bind(0).await.unwrap();
+        let server_addr =
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), server.local_addr().port());
+        let relay = lossy_relay(server_addr, loss).await;
+        let client = UtpSocket::bind(0).await.unwrap();
+        let data = test_data(len);
+
+        let expected = data.clone();
+        let accepting = tokio::spawn(async move {
+            let (mut stream, _) = server.accept().await.unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, expected);
+            stream.write_all(b"thanks").await.unwrap();
+            stream.shutdown().await.unwrap();
+            // The driver delivers the rest after the stream is gone
+        });
+
+        let mut stream = client.connect(relay).await.unwrap();
+        stream.write_all(&data).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut answer = Vec::new();
+        stream.read_to_end(&mut answer).await.unwrap();
+        assert_eq!(answer, b"thanks");
+        accepting.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn transfer_over_loopback() {
+        transfer(0.0, 500_000).await;
+    }
+
+    #[tokio::test]
+    async fn transfer_with_packet_loss() {
+        transfer(0.1, 200_000).await;
+    }
+
+    #[tokio::test]
+    async fn connecting_to_nobody_fails() {
+        let client = UtpSocket::bind(0).await.unwrap();
+        let nobody = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let result = tokio::time::timeout(
+            Duration::from_secs(1),
+            client.connect(nobody.local_addr().unwrap()),
+        )
+        .await;
+        assert!(!matches!(result, Ok(Ok(_))));
+    }
+
+    #[tokio::test]
+    async fn syns_past_the_backlog_are_dropped() {
+        let server = UtpSocket::bind(0).await.unwrap();
+        let client = UtpSocket::bind(0).await.unwrap();
+        let server_addr =
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), server.local_addr().port());
+
+        let mut streams = Vec::new();
+        for _ in 0..ACCEPT_BACKLOG {
+            streams.push(client.connect(server_addr).await.unwrap());
+        }
+        let result =
+            tokio::time::timeout(Duration::from_millis(500), client.connect(server_addr)).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn port_is_released_once_everything_is_dropped() {
+        let server = UtpSocket::bind(0).await.unwrap();
+        let client = UtpSocket::bind(0).await.unwrap();
+        let server_addr =
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), server.local_addr().port());
+        let (connected, accepted) = tokio::join!(client.connect(server_addr), server.accept());
+        let ports = [server.local_addr().port(), client.local_addr().port()];
+        drop((server, client, connected.unwrap(), accepted.unwrap()));
+
+        // The connection is closed first
+        for port in ports {
+            let mut attempts = 0;
+            while UtpSocket::bind(port).await.is_err() {
+                attempts += 1;
+                assert!(attempts < 100, "Port {} is still bound", port);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn ipv6_connection() {
+        let server = UtpSocket::bind(0).await.unwrap();
+        let client = UtpSocket::bind(0).await.unwrap();
+        let server_addr =
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), server.local_addr().port());
+
+        let accepting = tokio::spawn(async move {
+            let (mut stream, from) = server.accept().await.unwrap();
+            assert!(from.is_ipv6());
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+        });
+        let mut stream = client.connect(server_addr).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        accepting.await.unwrap();
+    }
+}
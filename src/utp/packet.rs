@@ -0,0 +1,181 @@
+use std::convert::TryInto;
+
+pub const HEADER_SIZE: usize = 20;
+const VERSION: u8 = 1;
+const EXTENSION_NONE: u8 = 0;
+const EXTENSION_SELECTIVE_ACK: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    Data,
+    Fin,
+    State,
+    Reset,
+    Syn,
+}
+
+impl PacketType {
+    fn from_u8(value: u8) -> anyhow::Result<PacketType> {
+        Ok(match value {
+            0 => PacketType::Data,
+            1 => PacketType::Fin,
+            2 => PacketType::State,
+            3 => PacketType::Reset,
+            4 => PacketType::Syn,
+            _ => anyhow::bail!("Unknown uTP packet type {}", value),
+        })
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            PacketType::Data => 0,
+            PacketType::Fin => 1,
+            PacketType::State => 2,
+            PacketType::Reset => 3,
+            PacketType::Syn => 4,
+        }
+    }
+}
+
+/*
+ *   A uTP packet (BEP 29). Of the extensions only selective ACK is understood: bit i of its mask
+ *   acknowledges packet ack_nr + 2 + i.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub packet_type: PacketType,
+    pub connection_id: u16,
+    pub timestamp: u32,
+    pub timestamp_difference: u32,
+    pub window_size: u32,
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+    pub selective_ack: Option<Vec<u8>>,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    pub fn new(packet_type: PacketType, connection_id: u16) -> Packet {
+        Packet {
+            packet_type,
+            connection_id,
+            timestamp: 0,
+            timestamp_difference: 0,
+            window_size: 0,
+            seq_nr: 0,
+            ack_nr: 0,
+            selective_ack: None,
+            payload: Vec::new(),
+        }
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut msg = Vec::with_capacity(HEADER_SIZE + self.payload.len());
+        msg.push(self.packet_type.as_u8() << 4 | VERSION);
+        msg.push(if self.selective_ack.is_some() {
+            EXTENSION_SELECTIVE_ACK
+        } else {
+            EXTENSION_NONE
+        });
+        msg.extend_from_slice(&self.connection_id.to_be_bytes());
+        msg.extend_from_slice(&self.timestamp.to_be_bytes());
+        msg.extend_from_slice(&self.timestamp_difference.to_be_bytes());
+        msg.extend_from_slice(&self.window_size.to_be_bytes());
+        msg.extend_from_slice(&self.seq_nr.to_be_bytes());
+        msg.extend_from_slice(&self.ack_nr.to_be_bytes());
+        if let Some(mask) = &self.selective_ack {
+            msg.push(EXTENSION_NONE);
+            msg.push(mask.len() as u8);
+            msg.extend_from_slice(mask);
+        }
+        msg.extend_from_slice(&self.payload);
+        msg
+    }
+
+    pub fn parse(msg: &[u8]) -> anyhow::Result<Packet> {
+        anyhow::ensure!(msg.len() >= HEADER_SIZE, "uTP packet is too short");
+        anyhow::ensure!(msg[0] & 0x0F == VERSION, "Unknown uTP version");
+        let u16_at = |from: usize| u16::from_be_bytes(msg[from..from + 2].try_into().unwrap());
+        let u32_at = |from: usize| u32::from_be_bytes(msg[from..from + 4].try_into().unwrap());
+
+        let mut packet = Packet {
+            packet_type: PacketType::from_u8(msg[0] >> 4)?,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_difference: u32_at(8),
+            window_size: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            selective_ack: None,
+            payload: Vec::new(),
+        };
+
+        let mut extension = msg[1];
+        let mut index = HEADER_SIZE;
+        while extension != EXTENSION_NONE {
+            anyhow::ensure!(msg.len() >= index + 2, "Corrupted uTP extension");
+            let next_extension = msg[index];
+            let len = msg[index + 1] as usize;
+            index += 2;
+            anyhow::ensure!(msg.len() >= index + len, "Corrupted uTP extension");
+            if extension == EXTENSION_SELECTIVE_ACK {
+                anyhow::ensure!(
+                    len >= 4 && len.is_multiple_of(4),
+                    "Wrong selective ACK length"
+                );
+                packet.selective_ack = Some(msg[index..index + len].to_vec());
+            }
+            extension = next_extension;
+            index += len;
+        }
+        packet.payload = msg[index..].to_vec();
+        Ok(packet)
+    }
+}
+
+// Sequence numbers wrap around, `a` comes after `b` if it's less than half the space ahead
+pub fn seq_after(a: u16, b: u16) -> bool {
+    a != b && a.wrapping_sub(b) < 0x8000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut packet = Packet::new(PacketType::State, 1234);
+        packet.timestamp = 1;
+        packet.timestamp_difference = 2;
+        packet.window_size = 1 << 20;
+        packet.seq_nr = 65535;
+        packet.ack_nr = 7;
+        packet.selective_ack = Some(vec![0b101, 0, 0, 0]);
+        let msg = packet.serialize();
+        assert_eq!(&msg[..2], &[0x21, 1]);
+        assert_eq!(Packet::parse(&msg).unwrap(), packet);
+
+        let mut data = Packet::new(PacketType::Data, 1);
+        data.payload = b"payload".to_vec();
+        assert_eq!(Packet::parse(&data.serialize()).unwrap(), data);
+    }
+
+    #[test]
+    fn invalid_packets() {
+        assert!(Packet::parse(&[0x41; 10]).is_err());
+        let mut msg = Packet::new(PacketType::Syn, 1).serialize();
+        msg[0] = 0x42;
+        assert!(Packet::parse(&msg).is_err());
+        msg[0] = 0x41;
+        msg[1] = 1; // selective ACK without its data
+        assert!(Packet::parse(&msg).is_err());
+    }
+
+    #[test]
+    fn wrapping_sequence_numbers() {
+        assert!(seq_after(2, 1));
+        assert!(seq_after(0, 65535));
+        assert!(!seq_after(65535, 0));
+        assert!(!seq_after(5, 5));
+    }
+}
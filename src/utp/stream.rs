@@ -0,0 +1,140 @@
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{mpsc, Notify};
+
+use super::Command;
+
+// Data the application wrote and the driver hasn't packetized yet
+const SEND_BUFFER_SIZE: usize = 256 * 1024;
+
+/*
+ *   State shared between a stream and the socket's driver task, which does all the packet work.
+ *   The stream fills `send_buffer` and drains `recv_buffer`, the driver does the opposite and
+ *   wakes the stream up.
+ */
+#[derive(Default)]
+pub struct Shared {
+    pub send_buffer: VecDeque<u8>,
+    pub recv_buffer: VecDeque<u8>,
+    pub read_waker: Option<Waker>,
+    pub write_waker: Option<Waker>,
+    // The peer finished sending and everything it sent was delivered
+    pub eof: bool,
+    // Our side is done writing (shutdown or dropped)
+    pub closing: bool,
+    pub error: Option<io::ErrorKind>,
+}
+
+impl Shared {
+    pub fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+
+    pub fn fail(&mut self, error: io::ErrorKind) {
+        self.error = Some(error);
+        self.wake();
+    }
+}
+
+pub struct UtpStream {
+    shared: Arc<Mutex<Shared>>,
+    driver: Arc<Notify>,
+    // The driver runs until every socket handle and stream is gone
+    _commands: mpsc::UnboundedSender<Command>,
+}
+
+impl UtpStream {
+    pub(super) fn new(
+        shared: Arc<Mutex<Shared>>,
+        driver: Arc<Notify>,
+        commands: mpsc::UnboundedSender<Command>,
+    ) -> UtpStream {
+        UtpStream {
+            shared,
+            driver,
+            _commands: commands,
+        }
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut shared = self.shared.lock().unwrap();
+        if !shared.recv_buffer.is_empty() {
+            let len = shared.recv_buffer.len().min(buf.remaining());
+            let (front, back) = shared.recv_buffer.as_slices();
+            let from_front = len.min(front.len());
+            buf.put_slice(&front[..from_front]);
+            buf.put_slice(&back[..len - from_front]);
+            shared.recv_buffer.drain(..len);
+            // The receive window opened up
+            self.driver.notify_one();
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(error) = shared.error {
+            return Poll::Ready(Err(error.into()));
+        }
+        if shared.eof {
+            return Poll::Ready(Ok(()));
+        }
+        shared.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut shared = self.shared.lock().unwrap();
+        if let Some(error) = shared.error {
+            return Poll::Ready(Err(error.into()));
+        }
+        if shared.closing {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let free = SEND_BUFFER_SIZE.saturating_sub(shared.send_buffer.len());
+        if free == 0 {
+            shared.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let len = free.min(buf.len());
+        shared.send_buffer.extend(&buf[..len]);
+        self.driver.notify_one();
+        Poll::Ready(Ok(len))
+    }
+
+    // Buffered data is sent by the driver as soon as the congestion window allows
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.shared.lock().unwrap().closing = true;
+        self.driver.notify_one();
+        Poll::Ready(Ok(()))
+    }
+}
+
+// The driver still sends what was written, then closes the connection
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        self.shared.lock().unwrap().closing = true;
+        self.driver.notify_one();
+    }
+}